@@ -244,6 +244,7 @@ fn bench_bulk(version: rustls::ProtocolVersion, suite: &'static rustls::Supporte
     let mut server = ServerSession::new(&server_config);
 
     do_handshake(&mut client, &mut server);
+    server.set_buffer_limit(None);
 
     let mut buf = Vec::new();
     buf.resize(1024 * 1024, 0u8);
@@ -1,5 +1,7 @@
 use ring;
 use std::io::Write;
+use std::sync::Arc;
+use std::cmp;
 use msgs::codec;
 use msgs::codec::Codec;
 use msgs::enums::{ContentType, ProtocolVersion};
@@ -22,9 +24,17 @@ pub trait MessageDecrypter : Send + Sync {
     fn decrypt(&self, m: Message, seq: u64) -> Result<Message, TLSError>;
 }
 
+/// Decides how many bytes of padding to add to a TLS1.3 record,
+/// given the length of its content plus content type byte.
+pub type RecordPadding = Arc<Fn(usize) -> usize + Send + Sync>;
+
 /// Objects with this trait can encrypt TLS messages.
 pub trait MessageEncrypter : Send + Sync {
     fn encrypt(&self, m: BorrowMessage, seq: u64) -> Result<Message, TLSError>;
+
+    /// Use `padding` to pad the records we make.  Only TLS1.3
+    /// records have padding, so by default this does nothing.
+    fn set_padding(&mut self, _padding: Option<RecordPadding>) {}
 }
 
 impl MessageEncrypter {
@@ -265,6 +275,7 @@ struct TLS13MessageEncrypter {
     alg: &'static ring::aead::Algorithm,
     enc_key: ring::aead::SealingKey,
     enc_offset: [u8; 12],
+    padding: Option<RecordPadding>,
 }
 
 struct TLS13MessageDecrypter {
@@ -291,9 +302,19 @@ impl MessageEncrypter for TLS13MessageEncrypter {
         codec::put_u64(seq, &mut nonce[4..]);
         xor(&mut nonce, &self.enc_offset);
 
-        // make output buffer with room for content type and tag
+        // The padding can't take the inner plaintext over the
+        // maximum fragment size, plus one for the content type.
+        let inner_len = msg.payload.len() + 1;
+        let padding_len = match self.padding {
+            Some(ref padding) => {
+                cmp::min(padding(inner_len), (MAX_FRAGMENT_LEN + 1).saturating_sub(inner_len))
+            }
+            None => 0,
+        };
+
+        // make output buffer with room for content type, padding and tag
         let tag_len = self.alg.tag_len();
-        let total_len = msg.payload.len() + 1 + tag_len;
+        let total_len = inner_len + padding_len + tag_len;
         let mut buf = Vec::with_capacity(total_len);
         buf.extend_from_slice(msg.payload);
         msg.typ.encode(&mut buf);
@@ -308,10 +329,23 @@ impl MessageEncrypter for TLS13MessageEncrypter {
             payload: MessagePayload::new_opaque(buf),
         })
     }
+
+    fn set_padding(&mut self, padding: Option<RecordPadding>) {
+        self.padding = padding;
+    }
 }
 
 impl MessageDecrypter for TLS13MessageDecrypter {
     fn decrypt(&self, mut msg: Message, seq: u64) -> Result<Message, TLSError> {
+        // The outer type of every protected record is application_data;
+        // the true type is inside the TLSInnerPlaintext.
+        if msg.typ != ContentType::ApplicationData {
+            return Err(TLSError::InappropriateMessage {
+                expect_types: vec![ ContentType::ApplicationData ],
+                got_type: msg.typ,
+            });
+        }
+
         let mut nonce = [0u8; 12];
         codec::put_u64(seq, &mut nonce[4..]);
         xor(&mut nonce, &self.dec_offset);
@@ -330,10 +364,15 @@ impl MessageDecrypter for TLS13MessageDecrypter {
 
         buf.truncate(plain_len);
 
+        // A plaintext of only zeroes has no content type.
         let content_type = unpad_tls13(&mut buf);
         if content_type == ContentType::Unknown(0) {
-            let msg = "peer sent bad TLSInnerPlaintext".to_string();
-            return Err(TLSError::PeerMisbehavedError(msg));
+            return Err(TLSError::InappropriateMessage {
+                expect_types: vec![ ContentType::Alert,
+                                    ContentType::Handshake,
+                                    ContentType::ApplicationData ],
+                got_type: content_type,
+            });
         }
 
         if buf.len() > MAX_FRAGMENT_LEN {
@@ -357,6 +396,7 @@ impl TLS13MessageEncrypter {
             alg: alg,
             enc_key: ring::aead::SealingKey::new(alg, enc_key).unwrap(),
             enc_offset: [0u8; 12],
+            padding: None,
         };
 
         ret.enc_offset.as_mut().write_all(enc_iv).unwrap();
@@ -508,3 +548,124 @@ impl MessageDecrypter for InvalidMessageDecrypter {
         Err(TLSError::DecryptError)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use msgs::enums::{ContentType, ProtocolVersion};
+    use msgs::message::{BorrowMessage, Message, MessagePayload};
+    use error::TLSError;
+    use suites::TLS13_AES_128_GCM_SHA256;
+
+    const SECRET: &'static [u8] = &[ 0x42; 32 ];
+
+    fn roundtrip(typ: ContentType, payload: &[u8], pad: Option<usize>)
+                 -> Result<Message, TLSError> {
+        let mut enc = super::new_tls13_write(&TLS13_AES_128_GCM_SHA256, SECRET);
+        let dec = super::new_tls13_read(&TLS13_AES_128_GCM_SHA256, SECRET);
+
+        if let Some(pad) = pad {
+            enc.set_padding(Some(Arc::new(move |_| pad)));
+        }
+
+        let msg = BorrowMessage {
+            typ: typ,
+            version: ProtocolVersion::TLSv1_3,
+            payload: payload,
+        };
+
+        let encr = enc.encrypt(msg, 0).unwrap();
+        assert_eq!(encr.typ, ContentType::ApplicationData);
+        dec.decrypt(encr, 0)
+    }
+
+    fn payload(mut msg: Message) -> Vec<u8> {
+        msg.take_opaque_payload().unwrap().0
+    }
+
+    #[test]
+    fn test_tls13_inner_type() {
+        let msg = roundtrip(ContentType::Handshake, b"hello", None).unwrap();
+        assert_eq!(msg.typ, ContentType::Handshake);
+        assert_eq!(payload(msg), b"hello".to_vec());
+    }
+
+    #[test]
+    fn test_tls13_inner_type_padded() {
+        let msg = roundtrip(ContentType::Alert, b"\x01\x00", Some(100)).unwrap();
+        assert_eq!(msg.typ, ContentType::Alert);
+        assert_eq!(payload(msg), b"\x01\x00".to_vec());
+    }
+
+    #[test]
+    fn test_tls13_rejects_all_zero_plaintext() {
+        let err = roundtrip(ContentType::Unknown(0), b"", Some(16)).unwrap_err();
+        assert_eq!(err,
+                   TLSError::InappropriateMessage {
+                       expect_types: vec![ ContentType::Alert,
+                                           ContentType::Handshake,
+                                           ContentType::ApplicationData ],
+                       got_type: ContentType::Unknown(0),
+                   });
+    }
+
+    fn encrypt_hello() -> Message {
+        let enc = super::new_tls13_write(&TLS13_AES_128_GCM_SHA256, SECRET);
+        let msg = BorrowMessage {
+            typ: ContentType::ApplicationData,
+            version: ProtocolVersion::TLSv1_3,
+            payload: b"hello",
+        };
+        enc.encrypt(msg, 0).unwrap()
+    }
+
+    fn decrypt_altered<F: FnOnce(&mut Vec<u8>)>(alter: F) -> Result<Message, TLSError> {
+        let dec = super::new_tls13_read(&TLS13_AES_128_GCM_SHA256, SECRET);
+        let mut body = payload(encrypt_hello());
+        alter(&mut body);
+
+        let encr = Message {
+            typ: ContentType::ApplicationData,
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::new_opaque(body),
+        };
+        dec.decrypt(encr, 0)
+    }
+
+    #[test]
+    fn test_tls13_accepts_valid_record() {
+        let msg = decrypt_altered(|_| {}).unwrap();
+        assert_eq!(payload(msg), b"hello".to_vec());
+    }
+
+    #[test]
+    fn test_tls13_rejects_flipped_ciphertext() {
+        let err = decrypt_altered(|body| body[0] ^= 0x01).unwrap_err();
+        assert_eq!(err, TLSError::DecryptError);
+    }
+
+    #[test]
+    fn test_tls13_rejects_flipped_tag() {
+        // The tag is at the end.
+        let err = decrypt_altered(|body| {
+            let last = body.len() - 1;
+            body[last] ^= 0x01;
+        }).unwrap_err();
+        assert_eq!(err, TLSError::DecryptError);
+    }
+
+    #[test]
+    fn test_tls13_rejects_bad_outer_type() {
+        let enc = super::new_tls13_write(&TLS13_AES_128_GCM_SHA256, SECRET);
+        let dec = super::new_tls13_read(&TLS13_AES_128_GCM_SHA256, SECRET);
+        let msg = BorrowMessage {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_3,
+            payload: b"hello",
+        };
+
+        let mut encr = enc.encrypt(msg, 0).unwrap();
+        encr.typ = ContentType::Handshake;
+        assert!(dec.decrypt(encr, 0).is_err());
+    }
+}
@@ -1,24 +1,39 @@
 use msgs::enums::CipherSuite;
 use msgs::enums::{AlertDescription, HandshakeType, ExtensionType};
 use session::{Session, SessionSecrets, SessionRandoms, SessionCommon};
+use session::{DEFAULT_MAX_WARNING_ALERTS, Resumption};
+#[cfg(feature = "dangerous_debug")]
+use session::ExtractedSecrets;
 use suites::{SupportedCipherSuite, ALL_CIPHERSUITES};
 use msgs::handshake::{CertificatePayload, DigitallySignedStruct, SessionID};
-use msgs::enums::SignatureScheme;
+use msgs::handshake::DelegatedCredentialPayload;
+use msgs::handshake::ClientHelloPayload;
+use msgs::handshake::{NamedGroups, SupportedGroups};
+use msgs::handshake::{SupportedSignatureSchemes, SupportedMandatedSignatureSchemes};
+use msgs::enums::{SignatureScheme, NamedGroup};
 use msgs::enums::{ContentType, ProtocolVersion};
 use msgs::message::Message;
+use msgs::hsjoiner::DEFAULT_MAX_HANDSHAKE_SIZE;
 use msgs::persist;
 use client_hs;
-use hash_hs;
+use hash_hs::{self, HandshakeMessageSummary};
 use verify;
 use anchors;
 use sign;
 use suites;
+use kem::{self, KeyEncapsulation};
+use compress::CertCompressor;
 use error::TLSError;
 use key;
+use cipher::RecordPadding;
+use rand::{SecureRandom, SystemRandom};
 
 use std::collections;
 use std::sync::{Arc, Mutex};
 use std::io;
+use std::cmp;
+use std::mem;
+use std::time::Duration;
 
 /// A trait for the ability to store client session data.
 /// The keys and values are opaque.
@@ -34,6 +49,15 @@ pub trait StoresClientSessions : Send + Sync {
     /// Returns the latest value for `key`.  Returns `None`
     /// if there's no such value.
     fn get(&mut self, key: &[u8]) -> Option<Vec<u8>>;
+
+    /// Like `get`, but for values we only use once, such as
+    /// tickets: implementors may remove the value returned, so a
+    /// later call returns an earlier value, if they store several.
+    ///
+    /// By default, this is the same as `get`.
+    fn take(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.get(key)
+    }
 }
 
 /// An implementor of `StoresClientSessions` which does nothing.
@@ -50,24 +74,42 @@ impl StoresClientSessions for NoSessionStorage {
 }
 
 /// An implementor of `StoresClientSessions` that stores everything
-/// in memory.  It enforces a limit on the number of sessions
-/// to bound memory usage.
+/// in memory.  It enforces a limit on the number of servers it
+/// stores sessions for, and how many tickets it keeps for each, to
+/// bound memory usage.
 pub struct ClientSessionMemoryCache {
-    cache: collections::HashMap<Vec<u8>, Vec<u8>>,
+    cache: collections::HashMap<Vec<u8>, collections::VecDeque<Vec<u8>>>,
     max_entries: usize,
+    max_per_server: usize,
 }
 
 impl ClientSessionMemoryCache {
     /// Make a new ClientSessionMemoryCache.  `size` is the
-    /// maximum number of stored sessions.
+    /// maximum number of servers we store sessions for.  We keep
+    /// one ticket for each, unless `set_max_tickets_per_server`
+    /// says otherwise.
     pub fn new(size: usize) -> Box<ClientSessionMemoryCache> {
         debug_assert!(size > 0);
         Box::new(ClientSessionMemoryCache {
             cache: collections::HashMap::new(),
             max_entries: size,
+            max_per_server: 1,
         })
     }
 
+    /// Keep up to `max` tickets for each server, forgetting the
+    /// oldest when we get more.  We resume using the newest, and
+    /// each ticket is only used once.
+    pub fn set_max_tickets_per_server(&mut self, max: usize) {
+        debug_assert!(max > 0);
+        self.max_per_server = max;
+        for values in self.cache.values_mut() {
+            while values.len() > max {
+                values.pop_front();
+            }
+        }
+    }
+
     fn limit_size(&mut self) {
         while self.cache.len() > self.max_entries {
             let k = self.cache.keys().next().unwrap().clone();
@@ -78,13 +120,33 @@ impl ClientSessionMemoryCache {
 
 impl StoresClientSessions for ClientSessionMemoryCache {
     fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> bool {
-        self.cache.insert(key, value);
+        {
+            let values = self.cache.entry(key).or_insert_with(collections::VecDeque::new);
+            values.push_back(value);
+            while values.len() > self.max_per_server {
+                values.pop_front();
+            }
+        }
         self.limit_size();
         true
     }
 
     fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
-        self.cache.get(key).cloned()
+        self.cache.get(key)
+            .and_then(|values| values.back())
+            .cloned()
+    }
+
+    fn take(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let (value, now_empty) = match self.cache.get_mut(key) {
+            Some(values) => (values.pop_back(), values.is_empty()),
+            None => return None,
+        };
+
+        if now_empty {
+            self.cache.remove(key);
+        }
+        value
     }
 }
 
@@ -168,10 +230,24 @@ pub struct ClientConfig {
     /// Collection of root certificates.
     pub root_store: anchors::RootCertStore,
 
+    /// SHA-256 hashes of the DER-encoded SubjectPublicKeyInfos we
+    /// pin the server to.  If this isn't empty, some certificate the
+    /// server sends must have a key matching one of these, as well
+    /// as the chain being valid.
+    ///
+    /// The default is empty, which means we don't pin keys.
+    pub spki_pins: Vec<[u8; 32]>,
+
     /// Which ALPN protocols we include in our client hello.
     /// If empty, no ALPN extension is sent.
     pub alpn_protocols: Vec<String>,
 
+    /// Our settings for each ALPN protocol supporting the
+    /// application_settings (ALPS) extension, which exchanges them
+    /// during a TLS1.3 handshake.  ALPS is negotiated for the agreed
+    /// ALPN protocol if both sides have settings for it.
+    pub application_settings: collections::HashMap<String, Vec<u8>>,
+
     /// How we store session data or tickets.
     pub session_persistence: Mutex<Box<StoresClientSessions>>,
 
@@ -192,8 +268,159 @@ pub struct ClientConfig {
     /// is all supported versions.
     pub versions: Vec<ProtocolVersion>,
 
+    /// Whether to offer post-handshake client authentication in
+    /// TLS1.3.  If the server then asks for our certificate after
+    /// the handshake, we use `client_auth_cert_resolver` to answer.
+    ///
+    /// The default is false.
+    pub enable_post_handshake_auth: bool,
+
+    /// Signature schemes we would like the server's certificate
+    /// chain to be signed with.  If this differs from the schemes
+    /// we accept for handshake signatures, we tell the server so
+    /// using the signature_algorithms_cert extension.
+    ///
+    /// This is only advice to the server: it's free to send a
+    /// chain signed otherwise, and we don't reject one which is,
+    /// so long as it otherwise validates.
+    ///
+    /// The default is the same as for handshake signatures.
+    pub cert_sigschemes: Vec<SignatureScheme>,
+
+    /// Key exchange groups we offer, in preference order.  The
+    /// server must choose one of these.  Use `set_groups` to
+    /// change this.
+    ///
+    /// The default is all supported groups.
+    pub kx_groups: Vec<NamedGroup>,
+
+    /// A key encapsulation mechanism.  If set, we offer the hybrid
+    /// post-quantum key exchange group `X25519MLKEM768` in TLS1.3,
+    /// as well as `kx_groups`.
+    ///
+    /// The default is None.
+    pub kem: Option<Box<KeyEncapsulation>>,
+
+    /// Certificate compression algorithms we support, in our order
+    /// of preference.  If non-empty, we offer these in TLS1.3 and
+    /// the server may send its certificate chain compressed.
+    ///
+    /// The default is empty.
+    pub cert_compressors: Vec<Box<CertCompressor>>,
+
+    /// The largest handshake message we'll accept from the peer,
+    /// including its four byte header.  Longer messages are rejected
+    /// with a `decode_error` alert as soon as we see their header.
+    ///
+    /// The default is 128KB.
+    pub max_handshake_size: usize,
+
+    /// The largest Certificate message we'll accept from the peer,
+    /// including its four byte header.  Longer messages are rejected
+    /// with a `decode_error` alert as soon as we see their header,
+    /// before we allocate anything for the certificates themselves.
+    /// For a compressed certificate, this limits both the compressed
+    /// and uncompressed lengths.  `max_handshake_size` applies too.
+    ///
+    /// The default is 128KB.
+    pub max_certificate_size: usize,
+
+    /// How many warning alerts we accept from the peer in a row,
+    /// with no other messages between them.  Beyond this, we abort
+    /// with an `unexpected_message` alert.
+    ///
+    /// The default is 4.
+    pub max_warning_alerts: usize,
+
+    /// Whether to offer ciphersuites without forward secrecy,
+    /// such as those using static RSA key exchange.  When false
+    /// they're left out of our ClientHello, even if they're in
+    /// `ciphersuites`.
+    ///
+    /// The default is false.
+    pub allow_non_forward_secret: bool,
+
+    /// Whether to refuse the ChangeCipherSpecs TLS1.3 peers send for
+    /// middlebox compatibility, aborting with an `unexpected_message`
+    /// alert.  We don't send them either.  Some middleboxes break
+    /// TLS1.3 handshakes without them, so only set this where that
+    /// isn't a concern.
+    ///
+    /// The default is false.
+    pub reject_tls13_compat_ccs: bool,
+
+    /// The largest record we're willing to receive, which we tell
+    /// the peer using the record_size_limit extension (RFC8449).  For
+    /// TLS1.3 this counts the record's content type byte and padding
+    /// too.  If the peer advertises a limit as well, the records each
+    /// side sends fit within the smaller of the two.
+    ///
+    /// This must be at least 64, and at most 16385 for TLS1.3 or
+    /// 16384 for TLS1.2 (RFC8449 section 4): otherwise the handshake
+    /// fails.  The default is None, which means we don't advertise
+    /// a limit.
+    pub record_size_limit: Option<u16>,
+
+    /// Whether to offer TLS1.3 early data when resuming with a
+    /// ticket which invites it.  Early data is written with
+    /// `ClientSession::write_early_data`.
+    ///
+    /// Early data can be replayed by an attacker, so only send
+    /// requests which are safe to repeat.
+    ///
+    /// The default is false.
+    pub enable_early_data: bool,
+
+    /// Whether to use TLS1.2 False Start (RFC7918): sending
+    /// application data straight after our Finished, without
+    /// waiting for the server's.  This saves a round trip on full
+    /// handshakes.
+    ///
+    /// We only do this when it's safe: the suite must use ECDHE
+    /// and an AEAD, and we must have agreed an ALPN protocol with
+    /// the server.  See `ClientSession::is_false_start_safe`.
+    ///
+    /// The default is false.
+    pub enable_false_start: bool,
+
+    /// Whether to accept a TLS1.3 server's delegated credential
+    /// (RFC9345) in place of its certificate's key.  We check the
+    /// credential is signed by the certificate, which must allow
+    /// delegation, and that it's valid now and for at most
+    /// another seven days.
+    ///
+    /// The default is false.
+    pub enable_delegated_credentials: bool,
+
+    /// Where we get random material, such as our handshake random
+    /// and ephemeral keys.
+    ///
+    /// The default is `SystemRandom`.
+    pub rng: Box<SecureRandom>,
+
     /// How to verify the server certificate chain.
     verifier: Box<verify::ServerCertVerifier>,
+
+    /// Where we get the current time.
+    time: Arc<Box<verify::TimeProvider>>,
+
+    /// How far our clock may be from the server's.
+    clock_skew: Duration,
+
+    /// The smallest RSA key we accept in a certificate chain.
+    min_rsa_key_bits: usize,
+
+    /// Whether we resume sessions.
+    resumption: Resumption,
+
+    /// Called with each ClientHello before we send it.
+    client_hello_callback: Option<Box<Fn(&mut ClientHelloPayload) + Send + Sync>>,
+
+    /// Called with the server's name and each ticket it issues.
+    new_ticket_callback: Option<Box<Fn(&str, &[u8]) + Send + Sync>>,
+
+    /// How to pad the TLS1.3 records we send.
+    record_padding: Option<RecordPadding>,
 }
 
 impl ClientConfig {
@@ -204,13 +431,37 @@ impl ClientConfig {
         ClientConfig {
             ciphersuites: ALL_CIPHERSUITES.to_vec(),
             root_store: anchors::RootCertStore::empty(),
+            spki_pins: Vec::new(),
             alpn_protocols: Vec::new(),
+            application_settings: collections::HashMap::new(),
             session_persistence: Mutex::new(Box::new(NoSessionStorage {})),
             mtu: None,
             client_auth_cert_resolver: Box::new(FailResolveClientCert {}),
             enable_tickets: true,
             versions: vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
-            verifier: Box::new(verify::WebPKIVerifier {})
+            enable_post_handshake_auth: false,
+            cert_sigschemes: SupportedSignatureSchemes::supported_verify(),
+            kx_groups: NamedGroups::supported(),
+            kem: None,
+            cert_compressors: Vec::new(),
+            max_handshake_size: DEFAULT_MAX_HANDSHAKE_SIZE,
+            max_certificate_size: DEFAULT_MAX_HANDSHAKE_SIZE,
+            max_warning_alerts: DEFAULT_MAX_WARNING_ALERTS,
+            allow_non_forward_secret: false,
+            reject_tls13_compat_ccs: false,
+            record_size_limit: None,
+            enable_early_data: false,
+            enable_false_start: false,
+            enable_delegated_credentials: false,
+            rng: Box::new(SystemRandom::new()),
+            verifier: Box::new(verify::WebPKIVerifier::new()),
+            time: Arc::new(Box::new(verify::SystemTimeProvider {})),
+            clock_skew: Duration::from_secs(verify::DEFAULT_CLOCK_SKEW_SECS),
+            min_rsa_key_bits: verify::DEFAULT_MIN_RSA_KEY_BITS,
+            resumption: Resumption::Enabled,
+            client_hello_callback: None,
+            new_ticket_callback: None,
+            record_padding: None,
         }
     }
 
@@ -219,6 +470,21 @@ impl ClientConfig {
         self.verifier.as_ref()
     }
 
+    #[doc(hidden)]
+    pub fn get_time_provider(&self) -> &verify::TimeProvider {
+        &**self.time
+    }
+
+    #[doc(hidden)]
+    pub fn get_clock_skew(&self) -> Duration {
+        self.clock_skew
+    }
+
+    #[doc(hidden)]
+    pub fn resumption_enabled(&self) -> bool {
+        self.resumption == Resumption::Enabled
+    }
+
     /// Set the ALPN protocol list to the given protocol names.
     /// Overwrites any existing configured protocols.
     /// The first element in the `protocols` list is the most
@@ -262,6 +528,121 @@ impl ClientConfig {
                                                                                     &key_der));
     }
 
+    /// Sets a function which can inspect and modify each ClientHello
+    /// before it is sent: for example, to reorder its extensions or
+    /// to add new ones.
+    ///
+    /// If `callback` makes the ClientHello invalid (for example, by
+    /// duplicating an extension, changing the random or session id,
+    /// or moving the pre-shared key extension from last place), its
+    /// changes are discarded and the ClientHello is sent unmodified.
+    pub fn set_client_hello_callback<F>(&mut self, callback: F)
+        where F: Fn(&mut ClientHelloPayload) + Send + Sync + 'static
+    {
+        self.client_hello_callback = Some(Box::new(callback));
+    }
+
+    #[doc(hidden)]
+    pub fn get_new_ticket_callback(&self) -> Option<&Fn(&str, &[u8])> {
+        self.new_ticket_callback
+            .as_ref()
+            .map(|cb| &**cb as &Fn(&str, &[u8]))
+    }
+
+    /// Sets a function called whenever the server issues us a ticket:
+    /// in a TLS1.2 handshake, or after a TLS1.3 one.  It's given the
+    /// name of the server, as passed to `ClientSession::new`, and the
+    /// ticket.
+    ///
+    /// This is called whether or not we keep the ticket for
+    /// resumption.
+    pub fn set_new_ticket_callback<F>(&mut self, callback: F)
+        where F: Fn(&str, &[u8]) + Send + Sync + 'static
+    {
+        self.new_ticket_callback = Some(Box::new(callback));
+    }
+
+    /// Enables hybrid post-quantum key exchange, using `kem`
+    /// for the post-quantum half.
+    pub fn set_kem(&mut self, kem: Box<KeyEncapsulation>) {
+        self.kem = Some(kem);
+    }
+
+    /// Pads each TLS1.3 record we send with the number of zero
+    /// bytes `padding` returns, to hide the length of its content.
+    /// `padding` is given the length of the record's content plus
+    /// one.  For example, to pad to a multiple of 256 bytes:
+    ///
+    /// ```
+    /// # let mut config = rustls::ClientConfig::new();
+    /// config.set_record_padding(|len| (256 - len % 256) % 256);
+    /// ```
+    ///
+    /// Padding is limited so no record exceeds the maximum size.
+    /// TLS1.2 records aren't padded.
+    pub fn set_record_padding<F>(&mut self, padding: F)
+        where F: Fn(usize) -> usize + Send + Sync + 'static
+    {
+        self.record_padding = Some(Arc::new(padding));
+    }
+
+    /// Only offer, and accept, key exchange with `groups`, in
+    /// that preference order.  If the server supports none of
+    /// them, the handshake fails.
+    pub fn set_groups(&mut self, groups: &[NamedGroup]) {
+        self.kx_groups = groups.to_vec();
+    }
+
+    /// Sets where we get the current time when checking the
+    /// server's certificate, or delegated credential, has not
+    /// expired.  The default is the system clock.
+    ///
+    /// This replaces any certificate verifier set through
+    /// `dangerous()`.
+    pub fn set_time_provider(&mut self, time: Box<verify::TimeProvider>) {
+        self.time = Arc::new(time);
+        self.rebuild_verifier();
+    }
+
+    /// Sets whether we resume sessions.  With `Resumption::Disabled`
+    /// we offer no session ID or ticket, whatever `enable_tickets`
+    /// and `session_persistence` say, and save none the server
+    /// gives us.  The default is `Resumption::Enabled`.
+    pub fn set_resumption(&mut self, resumption: Resumption) {
+        self.resumption = resumption;
+    }
+
+    /// Sets how far our clock may be from the server's, and from
+    /// those of certificate issuers.  We accept certificates which
+    /// are valid at any time within `skew` of now, and keep using
+    /// tickets for `skew` beyond their lifetime.  The default is
+    /// five minutes.
+    ///
+    /// Like `set_time_provider`, this replaces any certificate
+    /// verifier set through `dangerous()`.
+    pub fn set_clock_skew(&mut self, skew: Duration) {
+        self.clock_skew = skew;
+        self.rebuild_verifier();
+    }
+
+    /// Sets the smallest RSA key, in bits, we accept in the server's certificate chain.
+    /// We never accept keys smaller than 2048 bits, so a smaller
+    /// `bits` has no effect.  The default is 2048.
+    ///
+    /// Like `set_time_provider`, this replaces any certificate
+    /// verifier set through `dangerous()`.
+    pub fn set_min_rsa_key_bits(&mut self, bits: usize) {
+        self.min_rsa_key_bits = bits;
+        self.rebuild_verifier();
+    }
+
+    fn rebuild_verifier(&mut self) {
+        let verifier = verify::WebPKIVerifier::with_min_rsa_key_bits(self.time.clone(),
+                                                                     self.clock_skew,
+                                                                     self.min_rsa_key_bits);
+        self.verifier = Box::new(verifier);
+    }
+
     /// Access configuration options whose use is dangerous and requires
     /// extra care.
     #[cfg(feature = "dangerous_configuration")]
@@ -303,6 +684,7 @@ pub struct ClientHandshakeData {
     pub randoms: SessionRandoms,
     pub must_issue_new_ticket: bool,
     pub using_ems: bool,
+    pub false_start_safe: bool,
     pub new_ticket: Vec<u8>,
     pub new_ticket_lifetime: u32,
     pub doing_client_auth: bool,
@@ -310,11 +692,19 @@ pub struct ClientHandshakeData {
     pub client_auth_cert: Option<CertificatePayload>,
     pub client_auth_key: Option<Arc<Box<sign::Signer>>>,
     pub client_auth_context: Option<Vec<u8>>,
+    pub peer_sigschemes: Option<Vec<SignatureScheme>>,
+    pub peer_versions: Option<Vec<ProtocolVersion>>,
+    pub peer_application_settings: Option<Vec<u8>>,
+    pub kx_group: Option<NamedGroup>,
+    pub server_sigscheme: Option<SignatureScheme>,
+    pub server_delegated_credential: Option<DelegatedCredentialPayload>,
+    pub max_early_data_size: Option<u32>,
     pub offered_key_shares: Vec<suites::KeyExchange>,
+    pub offered_hybrid_share: Option<kem::HybridKeyExchange>,
 }
 
 impl ClientHandshakeData {
-    fn new(host_name: &str) -> ClientHandshakeData {
+    fn new(host_name: &str, rng: &SecureRandom) -> ClientHandshakeData {
         ClientHandshakeData {
             server_cert_chain: Vec::new(),
             dns_name: host_name.to_string(),
@@ -324,9 +714,10 @@ impl ClientHandshakeData {
             server_kx_sig: None,
             transcript: hash_hs::HandshakeHash::new(),
             resuming_session: None,
-            randoms: SessionRandoms::for_client(),
+            randoms: SessionRandoms::for_client(rng),
             must_issue_new_ticket: false,
             using_ems: false,
+            false_start_safe: false,
             new_ticket: Vec::new(),
             new_ticket_lifetime: 0,
             doing_client_auth: false,
@@ -334,14 +725,86 @@ impl ClientHandshakeData {
             client_auth_cert: None,
             client_auth_key: None,
             client_auth_context: None,
+            peer_sigschemes: None,
+            peer_versions: None,
+            peer_application_settings: None,
+            kx_group: None,
+            server_sigscheme: None,
+            server_delegated_credential: None,
+            max_early_data_size: None,
             offered_key_shares: Vec::new(),
+            offered_hybrid_share: None,
+        }
+    }
+}
+
+/// How far we've got with sending TLS1.3 early data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EarlyDataState {
+    /// We didn't offer early data.
+    Disabled,
+
+    /// We offered early data, and may send some until we get
+    /// the ServerHello.
+    Ready,
+
+    /// We got the ServerHello, and wait to learn whether the
+    /// server accepted our early data.
+    Sent,
+
+    /// The server accepted our early data.
+    Accepted,
+
+    /// The server rejected our early data.
+    Rejected,
+}
+
+pub struct EarlyData {
+    pub state: EarlyDataState,
+    left: usize,
+    sent: Vec<u8>,
+}
+
+impl EarlyData {
+    fn new() -> EarlyData {
+        EarlyData {
+            state: EarlyDataState::Disabled,
+            left: 0,
+            sent: Vec::new(),
         }
     }
+
+    /// We offered early data, and may send up to `max` bytes.
+    pub fn enable(&mut self, max: usize) {
+        self.state = EarlyDataState::Ready;
+        self.left = max;
+    }
+
+    /// We sent `data` as early data.  We keep a copy, in case
+    /// we need to send it again.
+    fn record_sent(&mut self, data: &[u8]) {
+        self.left -= data.len();
+        self.sent.extend_from_slice(data);
+    }
+
+    pub fn accepted(&mut self) {
+        self.state = EarlyDataState::Accepted;
+        self.sent = Vec::new();
+    }
+
+    /// The server rejected our early data: returns what we
+    /// sent, so it can be sent again.
+    pub fn rejected(&mut self) -> Vec<u8> {
+        self.state = EarlyDataState::Rejected;
+        self.left = 0;
+        mem::replace(&mut self.sent, Vec::new())
+    }
 }
 
 pub struct ClientSessionImpl {
     pub config: Arc<ClientConfig>,
     pub handshake_data: ClientHandshakeData,
+    pub early_data: EarlyData,
     pub secrets: Option<SessionSecrets>,
     pub alpn_protocol: Option<String>,
     pub common: SessionCommon,
@@ -353,14 +816,20 @@ impl ClientSessionImpl {
     pub fn new(config: &Arc<ClientConfig>, hostname: &str) -> ClientSessionImpl {
         let mut cs = ClientSessionImpl {
             config: config.clone(),
-            handshake_data: ClientHandshakeData::new(hostname),
+            handshake_data: ClientHandshakeData::new(hostname, config.rng.as_ref()),
+            early_data: EarlyData::new(),
             secrets: None,
             alpn_protocol: None,
-            common: SessionCommon::new(config.mtu, true),
+            common: SessionCommon::new(config.mtu, config.record_padding.clone(), true),
             error: None,
             state: &client_hs::EXPECT_SERVER_HELLO,
         };
 
+        cs.common.handshake_joiner.set_max_size(cs.config.max_handshake_size);
+        cs.common.handshake_joiner.set_max_certificate_size(cs.config.max_certificate_size);
+        cs.common.max_warning_alerts = cs.config.max_warning_alerts;
+        cs.common.reject_compat_ccs = cs.config.reject_tls13_compat_ccs;
+
         if cs.config.client_auth_cert_resolver.has_certs() {
             cs.handshake_data.transcript.set_client_auth_enabled();
         }
@@ -372,7 +841,7 @@ impl ClientSessionImpl {
     pub fn get_cipher_suites(&self) -> Vec<CipherSuite> {
         let mut ret = Vec::new();
 
-        for cs in &self.config.ciphersuites {
+        for cs in self.offered_suites() {
             ret.push(cs.suite);
         }
 
@@ -386,14 +855,24 @@ impl ClientSessionImpl {
         self.common.start_encryption_tls12(self.secrets.as_ref().unwrap());
     }
 
-    pub fn find_cipher_suite(&self, suite: CipherSuite) -> Option<&'static SupportedCipherSuite> {
-        for scs in &self.config.ciphersuites {
-            if scs.suite == suite {
-                return Some(scs);
-            }
-        }
+    /// The application's ClientHello callback, if it set one.
+    pub fn get_client_hello_callback(&self) -> Option<&Fn(&mut ClientHelloPayload)> {
+        self.config
+            .client_hello_callback
+            .as_ref()
+            .map(|cb| &**cb as &Fn(&mut ClientHelloPayload))
+    }
 
-        None
+    /// Our configured ciphersuites, less any we're not allowed to offer.
+    fn offered_suites(&self) -> Vec<&'static SupportedCipherSuite> {
+        suites::reduce_given_forward_secrecy(&self.config.ciphersuites,
+                                             self.config.allow_non_forward_secret)
+    }
+
+    pub fn find_cipher_suite(&self, suite: CipherSuite) -> Option<&'static SupportedCipherSuite> {
+        self.offered_suites()
+            .into_iter()
+            .find(|scs| scs.suite == suite)
     }
 
     pub fn wants_read(&self) -> bool {
@@ -410,21 +889,57 @@ impl ClientSessionImpl {
         !self.common.sendable_tls.is_empty()
     }
 
+    pub fn pending_write_len(&self) -> usize {
+        self.common.sendable_tls.len()
+    }
+
+    pub fn handshake_transcript(&self) -> Vec<HandshakeMessageSummary> {
+        self.handshake_data.transcript.get_summaries().to_vec()
+    }
+
+    #[cfg(feature = "dangerous_debug")]
+    pub fn dangerous_extract_secrets(&self) -> Option<ExtractedSecrets> {
+        self.common.extract_secrets(self.secrets.as_ref())
+    }
+
     pub fn is_handshaking(&self) -> bool {
         !self.common.traffic
     }
 
-    pub fn set_buffer_limit(&mut self, len: usize) {
+    pub fn set_buffer_limit(&mut self, len: Option<usize>) {
         self.common.set_buffer_limit(len)
     }
 
     pub fn process_msg(&mut self, mut msg: Message) -> Result<(), TLSError> {
+        // Drop TLS1.3 middlebox compatibility ChangeCipherSpecs, unless
+        // we're refusing them.
+        if self.common.is_tls13_compat_ccs(&msg) {
+            if self.common.reject_compat_ccs {
+                self.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+                return Err(TLSError::PeerMisbehavedError("received TLS1.3 compatibility \
+                                                          ChangeCipherSpec"
+                    .to_string()));
+            }
+
+            trace!("Dropping compatibility ChangeCipherSpec");
+            return Ok(());
+        }
+
         // Decrypt if demanded by current state.
         if self.common.peer_encrypting {
             let dm = self.common.decrypt_incoming(msg)?;
             msg = dm;
         }
 
+        // Handshake messages may be fragmented across records, but
+        // those records mustn't be interleaved with other types.
+        if !self.common.handshake_joiner.is_empty() &&
+           !self.common.handshake_joiner.want_message(&msg) {
+            self.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+            let why = "handshake message interleaved with other content";
+            return Err(TLSError::PeerMisbehavedError(why.to_string()));
+        }
+
         // For handshake messages, we need to join them before parsing
         // and processing.
         if self.common.handshake_joiner.want_message(&msg) {
@@ -478,6 +993,9 @@ impl ClientSessionImpl {
     /// that state expects, enforced via a `Expectation`.  Finally, we ask the handler
     /// to handle the message.
     fn process_main_protocol(&mut self, msg: Message) -> Result<(), TLSError> {
+        trace!("Received {:?} message {:?}", msg.typ, msg.get_handshake_type());
+        self.common.reset_warning_alerts();
+
         if msg.is_handshake_type(HandshakeType::HelloRequest) && !self.common.is_tls13() {
             self.process_hello_req();
             return Ok(());
@@ -534,9 +1052,59 @@ impl ClientSessionImpl {
         self.alpn_protocol.clone()
     }
 
+    pub fn get_peer_application_settings(&self, proto: &str) -> Option<Vec<u8>> {
+        match self.alpn_protocol {
+            Some(ref agreed) if agreed == proto => {
+                self.handshake_data.peer_application_settings.clone()
+            }
+            _ => None,
+        }
+    }
+
     pub fn get_protocol_version(&self) -> Option<ProtocolVersion> {
         self.common.negotiated_version
     }
+
+    pub fn get_peer_signature_schemes(&self) -> Option<&[SignatureScheme]> {
+        self.handshake_data.peer_sigschemes
+            .as_ref()
+            .map(|schemes| schemes.as_slice())
+    }
+
+    pub fn get_peer_supported_versions(&self) -> Option<&[ProtocolVersion]> {
+        self.handshake_data.peer_versions
+            .as_ref()
+            .map(|versions| versions.as_slice())
+    }
+
+    pub fn get_key_exchange_group(&self) -> Option<NamedGroup> {
+        self.handshake_data.kx_group
+    }
+
+    pub fn get_server_signature_scheme(&self) -> Option<SignatureScheme> {
+        self.handshake_data.server_sigscheme
+    }
+
+    pub fn send_fatal_alert(&mut self, desc: AlertDescription) {
+        if self.error.is_some() {
+            return;
+        }
+
+        self.common.send_fatal_alert(desc);
+        self.error = Some(TLSError::General(format!("session aborted with {:?} alert", desc)));
+    }
+
+    pub fn write_early_data(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.early_data.state != EarlyDataState::Ready {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "early data cannot be sent now"));
+        }
+
+        let take = cmp::min(data.len(), self.early_data.left);
+        let sent = self.common.send_early_plaintext(&data[..take]);
+        self.early_data.record_sent(&data[..sent]);
+        Ok(sent)
+    }
 }
 
 /// This represents a single TLS client session.
@@ -552,6 +1120,50 @@ impl ClientSession {
     pub fn new(config: &Arc<ClientConfig>, hostname: &str) -> ClientSession {
         ClientSession { imp: ClientSessionImpl::new(config, hostname) }
     }
+
+    /// Returns the largest amount of early data, in bytes, that
+    /// the server invited us to send in its TLS1.3 tickets.
+    ///
+    /// This is None until we receive a ticket which invites
+    /// early data.
+    pub fn get_max_early_data_size(&self) -> Option<u32> {
+        self.imp.handshake_data.max_early_data_size
+    }
+
+    /// Sends `data` as TLS1.3 early data, returning how much of it
+    /// was sent.  As with `write`, call `write_tls` to output the
+    /// corresponding TLS records.
+    ///
+    /// This only works after we've offered early data (see
+    /// `ClientConfig::enable_early_data`) and before we process the
+    /// server's reply: otherwise it fails.  We send no more than the
+    /// server's ticket allows.
+    ///
+    /// If the server rejects the early data, we send it again once
+    /// the handshake completes, so it arrives either way.
+    pub fn write_early_data(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.imp.write_early_data(data)
+    }
+
+    /// Returns true if the server accepted the early data we sent.
+    ///
+    /// This is false until the server tells us, and if we didn't
+    /// offer early data.
+    pub fn is_early_data_accepted(&self) -> bool {
+        self.imp.early_data.state == EarlyDataState::Accepted
+    }
+
+    /// Returns true if this handshake may use False Start: it's a
+    /// full TLS1.2 handshake using ECDHE and an AEAD, in which we
+    /// agreed an ALPN protocol.
+    ///
+    /// This is false until we've sent our Finished.  If it's true
+    /// and `ClientConfig::enable_false_start` is set, data written
+    /// from then on is sent without waiting for the server's
+    /// Finished.
+    pub fn is_false_start_safe(&self) -> bool {
+        self.imp.handshake_data.false_start_safe
+    }
 }
 
 impl Session for ClientSession {
@@ -576,18 +1188,51 @@ impl Session for ClientSession {
         self.imp.wants_write()
     }
 
+    fn pending_write_len(&self) -> usize {
+        self.imp.pending_write_len()
+    }
+
+    fn handshake_transcript(&self) -> Vec<HandshakeMessageSummary> {
+        self.imp.handshake_transcript()
+    }
+
+    #[cfg(feature = "dangerous_debug")]
+    fn dangerous_extract_secrets(&self) -> Option<ExtractedSecrets> {
+        self.imp.dangerous_extract_secrets()
+    }
+
     fn is_handshaking(&self) -> bool {
         self.imp.is_handshaking()
     }
 
-    fn set_buffer_limit(&mut self, len: usize) {
+    fn set_buffer_limit(&mut self, len: Option<usize>) {
         self.imp.set_buffer_limit(len)
     }
 
+    fn flush_plaintext(&mut self) {
+        self.imp.common.flush_plaintext()
+    }
+
     fn send_close_notify(&mut self) {
         self.imp.common.send_close_notify()
     }
 
+    fn send_fatal_alert(&mut self, desc: AlertDescription) {
+        self.imp.send_fatal_alert(desc)
+    }
+
+    fn take_received_warnings(&mut self) -> Vec<AlertDescription> {
+        self.imp.common.take_received_warnings()
+    }
+
+    fn peek_plaintext(&self) -> &[u8] {
+        self.imp.common.peek_plaintext()
+    }
+
+    fn consume_plaintext(&mut self, amt: usize) {
+        self.imp.common.consume_plaintext(amt)
+    }
+
     fn get_peer_certificates(&self) -> Option<Vec<key::Certificate>> {
         self.imp.get_peer_certificates()
     }
@@ -596,9 +1241,45 @@ impl Session for ClientSession {
         self.imp.get_alpn_protocol()
     }
 
+    fn get_peer_application_settings(&self, proto: &str) -> Option<Vec<u8>> {
+        self.imp.get_peer_application_settings(proto)
+    }
+
     fn get_protocol_version(&self) -> Option<ProtocolVersion> {
         self.imp.get_protocol_version()
     }
+
+    fn negotiated_record_size_limit(&self) -> usize {
+        self.imp.common.get_record_size_limit()
+    }
+
+    fn has_forward_secrecy(&self) -> bool {
+        self.imp.common.has_forward_secrecy()
+    }
+
+    fn client_random(&self) -> Option<[u8; 32]> {
+        self.imp.handshake_data.randoms.get_client()
+    }
+
+    fn server_random(&self) -> Option<[u8; 32]> {
+        self.imp.handshake_data.randoms.get_server()
+    }
+
+    fn get_peer_signature_schemes(&self) -> Option<&[SignatureScheme]> {
+        self.imp.get_peer_signature_schemes()
+    }
+
+    fn get_peer_supported_versions(&self) -> Option<&[ProtocolVersion]> {
+        self.imp.get_peer_supported_versions()
+    }
+
+    fn get_key_exchange_group(&self) -> Option<NamedGroup> {
+        self.imp.get_key_exchange_group()
+    }
+
+    fn get_server_signature_scheme(&self) -> Option<SignatureScheme> {
+        self.imp.get_server_signature_scheme()
+    }
 }
 
 impl io::Read for ClientSession {
@@ -7,10 +7,11 @@ use msgs::handshake::{SessionID, Random, ServerHelloPayload};
 use msgs::handshake::{ClientExtension, ServerExtension, HasServerExtensions};
 use msgs::handshake::{SupportedSignatureSchemes, SupportedMandatedSignatureSchemes};
 use msgs::handshake::DecomposedSignatureScheme;
-use msgs::handshake::{NamedGroups, SupportedGroups, KeyShareEntry, EncryptedExtensions};
+use msgs::handshake::{NamedGroups, KeyShareEntry, EncryptedExtensions};
 use msgs::handshake::{ECPointFormatList, SupportedPointFormats};
 use msgs::handshake::{ProtocolNameList, ConvertProtocolNameList};
 use msgs::handshake::{CertificatePayloadTLS13, CertificateEntry};
+use msgs::handshake::CertificateRequestPayloadTLS13;
 use msgs::handshake::ServerKeyExchangePayload;
 use msgs::handshake::DigitallySignedStruct;
 use msgs::handshake::{PresharedKeyIdentity, PresharedKeyOffer, HelloRetryRequest};
@@ -18,11 +19,14 @@ use msgs::enums::{ClientCertificateType, PSKKeyExchangeMode, ECPointFormat};
 use msgs::codec::Codec;
 use msgs::persist;
 use msgs::ccs::ChangeCipherSpecPayload;
-use client::ClientSessionImpl;
+use client::{ClientSessionImpl, EarlyDataState};
 use session::SessionSecrets;
 use key_schedule::{KeySchedule, SecretKind};
 use cipher;
 use suites;
+use kem;
+use compress;
+use delegated;
 use hash_hs;
 use verify;
 use rand;
@@ -77,12 +81,22 @@ fn check_aligned_handshake(sess: &mut ClientSessionImpl) -> Result<(), TLSError>
     }
 }
 
+/// Whether we offer and ask for tickets.
+fn tickets_enabled(sess: &ClientSessionImpl) -> bool {
+    sess.config.enable_tickets && sess.config.resumption_enabled()
+}
+
 fn find_session(sess: &mut ClientSessionImpl) -> Option<persist::ClientSessionValue> {
+    if !sess.config.resumption_enabled() {
+        info!("Resumption disabled");
+        return None;
+    }
+
     let key = persist::ClientSessionKey::session_for_dns_name(&sess.handshake_data.dns_name);
     let key_buf = key.get_encoding();
 
     let mut persist = sess.config.session_persistence.lock().unwrap();
-    let maybe_value = persist.get(&key_buf);
+    let maybe_value = persist.take(&key_buf);
 
     if maybe_value.is_none() {
         info!("No cached session for {:?}", sess.handshake_data.dns_name);
@@ -90,8 +104,9 @@ fn find_session(sess: &mut ClientSessionImpl) -> Option<persist::ClientSessionVa
     }
 
     let value = maybe_value.unwrap();
-    if let Some(result) = persist::ClientSessionValue::read_bytes(&value) {
-        if result.has_expired(ticket_timebase()) {
+    if let Some(result) = persist::ClientSessionValue::from_bytes(&value) {
+        let skew = sess.config.get_clock_skew().as_secs();
+        if result.has_expired(ticket_timebase().saturating_sub(skew)) {
             None
         } else {
             Some(result)
@@ -110,6 +125,17 @@ fn find_kx_hint(sess: &mut ClientSessionImpl) -> Option<NamedGroup> {
     maybe_value.and_then(|enc| NamedGroup::read_bytes(&enc))
 }
 
+/// The group for our first key share: the one we've been told the
+/// server supports if we still offer it, otherwise our most preferred.
+fn choose_initial_key_share_group(sess: &mut ClientSessionImpl) -> Option<NamedGroup> {
+    let hint = find_kx_hint(sess);
+    let groups = &sess.config.kx_groups;
+
+    hint.into_iter()
+        .chain(groups.iter().cloned())
+        .find(|grp| !kem::is_hybrid(*grp) && groups.contains(grp))
+}
+
 fn save_kx_hint(sess: &mut ClientSessionImpl, group: NamedGroup) {
     let key = persist::ClientSessionKey::hint_for_dns_name(&sess.handshake_data.dns_name);
 
@@ -119,10 +145,11 @@ fn save_kx_hint(sess: &mut ClientSessionImpl, group: NamedGroup) {
 
 /// If we have a ticket, we use the sessionid as a signal that we're
 /// doing an abbreviated handshake.  See section 3.4 in RFC5077.
-fn randomise_sessionid_for_ticket(csv: &mut persist::ClientSessionValue) {
+fn randomise_sessionid_for_ticket(csv: &mut persist::ClientSessionValue,
+                                  rng: &rand::SecureRandom) {
     if csv.ticket.len() > 0 {
         let mut random_id = [0u8; 32];
-        rand::fill_random(&mut random_id);
+        rand::fill_random(rng, &mut random_id);
         csv.session_id = SessionID::new(&random_id);
     }
 }
@@ -156,6 +183,61 @@ pub fn fill_in_psk_binder(sess: &mut ClientSessionImpl, hmp: &mut HandshakeMessa
     };
 }
 
+/// Is `hello` still a valid ClientHello after the application
+/// changed it?
+fn client_hello_still_valid(sess: &ClientSessionImpl,
+                            hello: &ClientHelloPayload,
+                            session_id: &SessionID,
+                            has_binder: bool) -> bool {
+    // It must round-trip through our own parser.
+    let encoding = hello.get_encoding();
+    let reparsed = ClientHelloPayload::read_bytes(&encoding);
+    if reparsed.map(|ch| ch.get_encoding()) != Some(encoding) {
+        return false;
+    }
+
+    // We depend on these.
+    let random = Random::from_slice(&sess.handshake_data.randoms.client);
+    if hello.random.get_encoding() != random.get_encoding() ||
+        hello.session_id != *session_id {
+        return false;
+    }
+
+    if hello.has_duplicate_extension() {
+        return false;
+    }
+
+    !has_binder || hello.check_psk_ext_is_last()
+}
+
+/// Give the application a chance to change `hello`, if it asked
+/// for one.  If its changes are unacceptable, we discard them.
+fn call_client_hello_callback(sess: &ClientSessionImpl,
+                              hello: &mut ClientHelloPayload,
+                              has_binder: bool) {
+    let callback = match sess.get_client_hello_callback() {
+        Some(callback) => callback,
+        None => return,
+    };
+
+    // The callback changes a copy, which we keep only if it's valid.
+    let mut changed = match ClientHelloPayload::read_bytes(&hello.get_encoding()) {
+        Some(copy) => copy,
+        None => {
+            warn!("Can't copy ClientHello for callback");
+            return;
+        }
+    };
+    let session_id = hello.session_id;
+    callback(&mut changed);
+
+    if client_hello_still_valid(sess, &changed, &session_id, has_binder) {
+        *hello = changed;
+    } else {
+        warn!("Discarding invalid changes to ClientHello");
+    }
+}
+
 pub fn emit_client_hello(sess: &mut ClientSessionImpl) -> &'static State {
     emit_client_hello_for_retry(sess, None)
 }
@@ -163,13 +245,20 @@ pub fn emit_client_hello(sess: &mut ClientSessionImpl) -> &'static State {
 fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
                                retryreq: Option<&HelloRetryRequest>)
                                -> &'static State {
-    // Do we have a SessionID or ticket cached for this host?
-    sess.handshake_data.resuming_session = find_session(sess);
-    let (session_id, ticket, resume_version) = if sess.handshake_data.resuming_session.is_some() {
-        let mut resuming = sess.handshake_data.resuming_session.as_mut().unwrap();
-        if resuming.version == ProtocolVersion::TLSv1_2 {
-            randomise_sessionid_for_ticket(resuming);
+    // Do we have a SessionID or ticket cached for this host?  Taking
+    // it from the cache uses it up, so when retrying we offer the one
+    // we found for our first ClientHello.
+    if retryreq.is_none() {
+        sess.handshake_data.resuming_session = find_session(sess);
+        if let Some(ref mut resuming) = sess.handshake_data.resuming_session {
+            if resuming.version == ProtocolVersion::TLSv1_2 {
+                randomise_sessionid_for_ticket(resuming, sess.config.rng.as_ref());
+            }
         }
+    }
+
+    let (session_id, ticket, resume_version) = if sess.handshake_data.resuming_session.is_some() {
+        let resuming = sess.handshake_data.resuming_session.as_ref().unwrap();
         info!("Resuming session");
         (resuming.session_id, resuming.ticket.0.clone(), resuming.version)
     } else {
@@ -196,15 +285,28 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
         // - if we've been asked via HelloRetryRequest for a specific
         //   one, do that.
         // - if not, we might have a hint of what the server supports
-        // - if not, send just X25519.
+        // - if not, send just our most preferred group.
         //
-        let groups = retryreq.and_then(|req| req.get_requested_key_share_group())
-            .or_else(|| find_kx_hint(sess))
-            .or_else(|| Some(NamedGroup::X25519))
+        // If we can do hybrid key exchange, we offer that too.
+        let mut groups = retryreq.and_then(|req| req.get_requested_key_share_group())
+            .or_else(|| choose_initial_key_share_group(sess))
             .map(|grp| vec![ grp ])
-            .unwrap();
+            .unwrap_or_else(Vec::new);
+
+        if retryreq.is_none() && sess.config.kem.is_some() &&
+            !groups.contains(&NamedGroup::X25519MLKEM768) {
+            groups.insert(0, NamedGroup::X25519MLKEM768);
+        }
 
         for group in groups {
+            if kem::is_hybrid(group) {
+                if let Some(hybrid_share) = start_hybrid_key_share(sess, group) {
+                    key_shares.push(KeyShareEntry::new(group, &hybrid_share.pubkey));
+                    sess.handshake_data.offered_hybrid_share = Some(hybrid_share);
+                }
+                continue;
+            }
+
             // in reply to HelloRetryRequest, we must not alter any existing key
             // shares
             if let Some(already_offered_share) = find_key_share(sess, group) {
@@ -213,7 +315,8 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
                 continue;
             }
 
-            if let Some(key_share) = suites::KeyExchange::start_ecdhe(group) {
+            if let Some(key_share) = suites::KeyExchange::start_ecdhe(group,
+                                                                      sess.config.rng.as_ref()) {
                 key_shares.push(KeyShareEntry::new(group, &key_share.pubkey));
                 sess.handshake_data.offered_key_shares.push(key_share);
             }
@@ -226,8 +329,18 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
     }
     exts.push(ClientExtension::make_sni(&sess.handshake_data.dns_name));
     exts.push(ClientExtension::ECPointFormats(ECPointFormatList::supported()));
-    exts.push(ClientExtension::NamedGroups(NamedGroups::supported()));
-    exts.push(ClientExtension::SignatureAlgorithms(SupportedSignatureSchemes::supported_verify()));
+    exts.push(ClientExtension::NamedGroups(supported_groups(sess)));
+    let mut sigschemes = SupportedSignatureSchemes::supported_verify();
+    if !support_tls13 {
+        // We only sign and check TLS1.2 key exchanges with schemes
+        // matching the ciphersuite, and there's none for Ed25519.
+        sigschemes.retain(|scheme| *scheme != SignatureScheme::ED25519);
+    }
+    exts.push(ClientExtension::SignatureAlgorithms(sigschemes));
+    if sess.config.cert_sigschemes != SupportedSignatureSchemes::supported_verify() {
+        let cert_sigschemes = sess.config.cert_sigschemes.clone();
+        exts.push(ClientExtension::SignatureAlgorithmsCert(cert_sigschemes));
+    }
     exts.push(ClientExtension::ExtendedMasterSecretRequest);
 
     if support_tls13 {
@@ -238,19 +351,61 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
         exts.push(ClientExtension::Cookie(cookie.clone()));
     }
 
-    if support_tls13 && sess.config.enable_tickets {
+    if support_tls13 && sess.config.enable_post_handshake_auth {
+        exts.push(ClientExtension::PostHandshakeAuth);
+    }
+
+    if support_tls13 && !sess.config.cert_compressors.is_empty() {
+        let algs = compress::algorithms(&sess.config.cert_compressors);
+        exts.push(ClientExtension::CertificateCompressionAlgorithms(algs));
+    }
+
+    if support_tls13 && sess.config.enable_delegated_credentials {
+        exts.push(ClientExtension::DelegatedCredential(delegated::supported_schemes()));
+    }
+
+    if support_tls13 && tickets_enabled(sess) {
         // We could support PSK_KE here too. Such connections don't
         // have forward secrecy, and are similar to TLS1.2 resumption.
         let psk_modes = vec![ PSKKeyExchangeMode::PSK_DHE_KE ];
         exts.push(ClientExtension::PresharedKeyModes(psk_modes));
     }
 
+    if let Some(limit) = sess.config.record_size_limit {
+        exts.push(ClientExtension::RecordSizeLimit(limit));
+    }
+
     if !sess.config.alpn_protocols.is_empty() {
         exts.push(ClientExtension::Protocols(ProtocolNameList::from_strings(&sess.config
             .alpn_protocols)));
     }
 
-    let fill_in_binder = if support_tls13 && sess.config.enable_tickets &&
+    // Offer ALPS for the protocols we have settings for.
+    let alps_protocols = sess.config.alpn_protocols
+        .iter()
+        .filter(|proto| sess.config.application_settings.contains_key(*proto))
+        .cloned()
+        .collect::<Vec<String>>();
+    if support_tls13 && !alps_protocols.is_empty() {
+        exts.push(ClientExtension::ApplicationSettings(ProtocolNameList::from_strings(
+            &alps_protocols)));
+    }
+
+    // Offer early data if we're resuming a TLS1.3 ticket which invites it.
+    // Like everything else, this must precede the PSK extension.
+    let max_early_data_size = match sess.handshake_data.resuming_session {
+        Some(ref resuming) if support_tls13 && tickets_enabled(sess) &&
+                              sess.config.enable_early_data && retryreq.is_none() &&
+                              resuming.version == ProtocolVersion::TLSv1_3 &&
+                              !ticket.is_empty() => resuming.max_early_data_size,
+        _ => 0,
+    };
+
+    if max_early_data_size > 0 {
+        exts.push(ClientExtension::EarlyData);
+    }
+
+    let fill_in_binder = if support_tls13 && tickets_enabled(sess) &&
                             resume_version == ProtocolVersion::TLSv1_3 &&
                             !ticket.is_empty() {
         // Finally, and only for TLS1.3 with a ticket resumption, include a binder
@@ -273,7 +428,7 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
         let psk_ext = PresharedKeyOffer::new(psk_identity, binder);
         exts.push(ClientExtension::PresharedKey(psk_ext));
         true
-    } else if sess.config.enable_tickets {
+    } else if tickets_enabled(sess) {
         // If we have a ticket, include it.  Otherwise, request one.
         if ticket.is_empty() {
             exts.push(ClientExtension::SessionTicketRequest);
@@ -285,21 +440,25 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
         false
     };
 
+    let mut hello = ClientHelloPayload {
+        client_version: ProtocolVersion::TLSv1_2,
+        random: Random::from_slice(&sess.handshake_data.randoms.client),
+        session_id: session_id,
+        cipher_suites: sess.get_cipher_suites(),
+        compression_methods: vec![Compression::Null],
+        extensions: exts,
+    };
+
+    call_client_hello_callback(sess, &mut hello, fill_in_binder);
+
     // Note what extensions we sent.
-    sess.handshake_data.sent_extensions = exts.iter()
+    sess.handshake_data.sent_extensions = hello.extensions.iter()
         .map(|ext| ext.get_type())
         .collect();
 
     let mut chp = HandshakeMessagePayload {
         typ: HandshakeType::ClientHello,
-        payload: HandshakePayload::ClientHello(ClientHelloPayload {
-            client_version: ProtocolVersion::TLSv1_2,
-            random: Random::from_slice(&sess.handshake_data.randoms.client),
-            session_id: session_id,
-            cipher_suites: sess.get_cipher_suites(),
-            compression_methods: vec![Compression::Null],
-            extensions: exts,
-        }),
+        payload: HandshakePayload::ClientHello(hello),
     };
 
     if fill_in_binder {
@@ -317,6 +476,11 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
     sess.handshake_data.transcript.add_message(&ch);
     sess.common.send_msg(ch, false);
 
+    if max_early_data_size > 0 &&
+       sess.handshake_data.sent_extensions.contains(&ExtensionType::EarlyData) {
+        start_early_traffic(sess, max_early_data_size as usize);
+    }
+
     if support_tls13 && retryreq.is_none() {
         &EXPECT_TLS13_SERVER_HELLO_OR_RETRY
     } else {
@@ -324,6 +488,36 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
     }
 }
 
+/// Start encrypting with our early traffic keys, which are derived
+/// from the ticket we're resuming.  We may then send up to
+/// `max_early_data_size` bytes of early data.
+fn start_early_traffic(sess: &mut ClientSessionImpl, max_early_data_size: usize) {
+    let (suite, write_key) = {
+        let resuming = sess.handshake_data.resuming_session.as_ref().unwrap();
+        let suite = sess.find_cipher_suite(resuming.cipher_suite).unwrap();
+        let hash = suite.get_hash();
+        let client_hello_hash = sess.handshake_data.transcript.get_hash_given(hash, &[]);
+
+        let mut key_schedule = KeySchedule::new(hash);
+        key_schedule.input_secret(&resuming.master_secret.0);
+        (suite,
+         key_schedule.derive(SecretKind::ClientEarlyTrafficSecret, &client_hello_hash))
+    };
+
+    // Our compatibility ChangeCipherSpec goes before the early data.
+    sess.common.send_tls13_compat_ccs();
+    sess.common.set_message_encrypter(cipher::new_tls13_write(suite, &write_key));
+    sess.early_data.enable(max_early_data_size);
+    info!("Offering {} bytes of early data", max_early_data_size);
+}
+
+/// Stop using our early traffic keys, and start on our handshake keys.
+fn start_handshake_write_traffic(sess: &mut ClientSessionImpl) {
+    let suite = sess.common.get_suite();
+    let write_key = sess.common.get_key_schedule().current_client_traffic_secret.clone();
+    sess.common.set_message_encrypter(cipher::new_tls13_write(suite, &write_key));
+}
+
 fn sent_unsolicited_extensions(sess: &ClientSessionImpl,
                                received_exts: &[ServerExtension],
                                allowed_unsolicited: &[ExtensionType]) -> bool {
@@ -340,11 +534,66 @@ fn sent_unsolicited_extensions(sess: &ClientSessionImpl,
     false
 }
 
+/// The groups we support, most preferred first.  This includes
+/// the hybrid group if we have a KEM for it.
+fn supported_groups(sess: &ClientSessionImpl) -> NamedGroups {
+    let mut groups: NamedGroups = sess.config.kx_groups
+        .iter()
+        .cloned()
+        .filter(|grp| !kem::is_hybrid(*grp))
+        .collect();
+    if sess.config.kem.is_some() {
+        groups.insert(0, NamedGroup::X25519MLKEM768);
+    }
+    groups
+}
+
 fn has_key_share(sess: &mut ClientSessionImpl,
                  group: NamedGroup) -> bool {
     sess.handshake_data.offered_key_shares
         .iter()
-        .any(|share| share.group == group)
+        .any(|share| share.group == group) ||
+        sess.handshake_data.offered_hybrid_share
+            .as_ref()
+            .map(|share| share.group == group)
+            .unwrap_or(false)
+}
+
+/// Start a hybrid key exchange in `group`, reusing our existing
+/// share if we already offered one.
+fn start_hybrid_key_share(sess: &mut ClientSessionImpl,
+                          group: NamedGroup)
+                          -> Option<kem::HybridKeyExchange> {
+    if let Some(already_offered_share) = sess.handshake_data.offered_hybrid_share.take() {
+        if already_offered_share.group == group {
+            return Some(already_offered_share);
+        }
+    }
+
+    let kem = try_ret!(sess.config.kem.as_ref());
+    kem::HybridKeyExchange::start(group, &**kem, sess.config.rng.as_ref())
+}
+
+/// Complete the key exchange with the server's `share`, discarding
+/// all our other shares.
+fn complete_key_share(sess: &mut ClientSessionImpl,
+                      share: &KeyShareEntry)
+                      -> Result<suites::KeyExchangeResult, TLSError> {
+    let hybrid_share = sess.handshake_data.offered_hybrid_share.take();
+
+    let shared = if kem::is_hybrid(share.group) {
+        sess.handshake_data.offered_key_shares.clear();
+        let our_key_share = hybrid_share
+            .and_then(|kx| if kx.group == share.group { Some(kx) } else { None })
+            .ok_or_else(|| illegal_param(sess, "wrong group for key share"))?;
+        let kem = sess.config.kem.as_ref().unwrap();
+        our_key_share.complete(&**kem, &share.payload.0)
+    } else {
+        let our_key_share = find_key_share_and_discard_others(sess, share.group)?;
+        our_key_share.complete(&share.payload.0)
+    };
+
+    shared.ok_or_else(|| illegal_param(sess, "server sent invalid key share"))
 }
 
 fn find_key_share(sess: &mut ClientSessionImpl,
@@ -368,7 +617,8 @@ fn find_key_share_and_discard_others(sess: &mut ClientSessionImpl,
 // Extensions we expect in plaintext in the ServerHello.
 static ALLOWED_PLAINTEXT_EXTS: &'static [ExtensionType] = &[
     ExtensionType::KeyShare,
-    ExtensionType::PreSharedKey
+    ExtensionType::PreSharedKey,
+    ExtensionType::SupportedVersions,
 ];
 
 // Only the intersection of things we offer, and those disallowed
@@ -383,6 +633,10 @@ static DISALLOWED_TLS13_EXTS: &'static [ExtensionType] = &[
 fn validate_server_hello_tls13(sess: &mut ClientSessionImpl,
                                server_hello: &ServerHelloPayload)
                                -> Result<(), TLSError> {
+    // The TLS1.3 draft we implement has no legacy_session_id_echo in
+    // its ServerHello, so there's no echo to check.  Our session id is
+    // covered by the handshake keys instead: if anyone changes it, we
+    // can't decrypt what the server sends next.
     for ext in &server_hello.extensions {
         if !ALLOWED_PLAINTEXT_EXTS.contains(&ext.get_type()) {
             sess.common.send_fatal_alert(AlertDescription::UnsupportedExtension);
@@ -431,12 +685,10 @@ fn start_handshake_traffic(sess: &mut ClientSessionImpl,
             TLSError::PeerMisbehavedError("missing key share".to_string())
             })?;
 
-    let our_key_share = find_key_share_and_discard_others(sess,
-                                                          their_key_share.group)?;
-    let shared = our_key_share.complete(&their_key_share.payload.0)
-        .ok_or_else(|| TLSError::PeerMisbehavedError("key exchange failed"
-                                                     .to_string()))?;
+    let shared = complete_key_share(sess, their_key_share)?;
 
+    debug!("Key exchange group is {:?}", their_key_share.group);
+    sess.handshake_data.kx_group = Some(their_key_share.group);
     save_kx_hint(sess, their_key_share.group);
     key_schedule.input_secret(&shared.premaster_secret);
 
@@ -445,12 +697,19 @@ fn start_handshake_traffic(sess: &mut ClientSessionImpl,
     let handshake_hash = sess.handshake_data.transcript.get_current_hash();
     let write_key = key_schedule.derive(SecretKind::ClientHandshakeTrafficSecret, &handshake_hash);
     let read_key = key_schedule.derive(SecretKind::ServerHandshakeTrafficSecret, &handshake_hash);
-    sess.common.set_message_encrypter(cipher::new_tls13_write(suite, &write_key));
     sess.common.set_message_decrypter(cipher::new_tls13_read(suite, &read_key));
     key_schedule.current_client_traffic_secret = write_key;
     key_schedule.current_server_traffic_secret = read_key;
     sess.common.set_key_schedule(key_schedule);
 
+    // If we offered early data, we keep our early traffic keys until
+    // we learn whether the server accepted it.
+    if sess.early_data.state == EarlyDataState::Ready {
+        sess.early_data.state = EarlyDataState::Sent;
+    } else {
+        start_handshake_write_traffic(sess);
+    }
+
     Ok(())
 }
 
@@ -470,7 +729,18 @@ fn handle_server_hello(sess: &mut ClientSessionImpl, m: Message) -> StateResult
     let server_hello = extract_handshake!(m, HandshakePayload::ServerHello).unwrap();
     debug!("We got ServerHello {:#?}", server_hello);
 
-    match server_hello.server_version {
+    // A server may choose TLS1.3 in the supported_versions extension,
+    // with TLS1.2 in the legacy field.  It can't choose anything else
+    // that way.
+    if let Some(version) = server_hello.get_supported_versions() {
+        if server_hello.server_version != ProtocolVersion::TLSv1_2 ||
+            version == ProtocolVersion::TLSv1_2 {
+            return Err(illegal_param(sess, "server sent bad supported_versions"));
+        }
+    }
+
+    let selected_version = server_hello.get_selected_version();
+    match selected_version {
         ProtocolVersion::TLSv1_2 if sess.config.versions.contains(&ProtocolVersion::TLSv1_2) => {
             sess.common.negotiated_version = Some(ProtocolVersion::TLSv1_2);
         }
@@ -487,6 +757,9 @@ fn handle_server_hello(sess: &mut ClientSessionImpl, m: Message) -> StateResult
         }
     };
 
+    debug!("Negotiated version {:?}", sess.common.negotiated_version);
+    sess.handshake_data.peer_versions = Some(vec![ selected_version ]);
+
     if server_hello.compression_method != Compression::Null {
         sess.common.send_fatal_alert(AlertDescription::HandshakeFailure);
         return Err(TLSError::PeerMisbehavedError("server chose non-Null compression".to_string()));
@@ -503,27 +776,41 @@ fn handle_server_hello(sess: &mut ClientSessionImpl, m: Message) -> StateResult
         return Err(TLSError::PeerMisbehavedError("server sent unsolicited extension".to_string()));
     }
 
+    // We offered secure renegotiation with the SCSV.  This is the first
+    // handshake on the connection, even if we're resuming, so the server's
+    // renegotiated_connection must be empty (RFC5746).
+    if let Some(ri) = server_hello.get_renegotiation_info() {
+        if !ri.0.is_empty() {
+            sess.common.send_fatal_alert(AlertDescription::HandshakeFailure);
+            return Err(TLSError::PeerMisbehavedError("server sent non-empty renegotiation \
+                                                      info"
+                .to_string()));
+        }
+    }
+
     // Extract ALPN protocol
     if !sess.common.is_tls13() {
         process_alpn_protocol(sess, server_hello.get_alpn_protocol())?;
+        process_record_size_limit(sess, server_hello.get_record_size_limit())?;
     }
 
     // If ECPointFormats extension is supplied by the server, it must contain
-    // Uncompressed.  But it's allowed to be omitted.
+    // Uncompressed.  But it's allowed to be omitted.  We check this before
+    // we see the server's ECDHE share, so we never try to use a point in
+    // a format we don't both support.
     if let Some(point_fmts) = server_hello.get_ecpoints_extension() {
         if !point_fmts.contains(&ECPointFormat::Uncompressed) {
-            sess.common.send_fatal_alert(AlertDescription::HandshakeFailure);
-            return Err(TLSError::PeerMisbehavedError("server does not support uncompressed points"
-                                                     .to_string()));
+            return Err(illegal_param(sess, "server does not support uncompressed points"));
         }
     }
 
+    // The server must choose one of the suites we offered.  We only
+    // look in our configured suites, so a server choosing the
+    // renegotiation SCSV is rejected here too.
     let scs = sess.find_cipher_suite(server_hello.cipher_suite);
 
     if scs.is_none() {
-        sess.common.send_fatal_alert(AlertDescription::HandshakeFailure);
-        return Err(TLSError::PeerMisbehavedError("server chose non-offered ciphersuite"
-            .to_string()));
+        return Err(illegal_param(sess, "server chose non-offered ciphersuite"));
     }
 
     info!("Using ciphersuite {:?}", server_hello.cipher_suite);
@@ -538,6 +825,9 @@ fn handle_server_hello(sess: &mut ClientSessionImpl, m: Message) -> StateResult
     sess.handshake_data.transcript.start_hash(sess.common.get_suite().get_hash());
     sess.handshake_data.transcript.add_message(&m);
 
+    // Save ServerRandom.  TLS1.3 doesn't use it, but it's reported.
+    sess.handshake_data.randoms.set_peer(&server_hello.random);
+
     // For TLS1.3, start message encryption using
     // handshake_traffic_secret.
     if sess.common.is_tls13() {
@@ -548,8 +838,7 @@ fn handle_server_hello(sess: &mut ClientSessionImpl, m: Message) -> StateResult
 
     // TLS1.2 only from here-on
 
-    // Save ServerRandom and SessionID
-    server_hello.random.write_slice(&mut sess.handshake_data.randoms.server);
+    // Save SessionID
     sess.handshake_data.session_id = server_hello.session_id;
 
     // Doing EMS?
@@ -563,10 +852,13 @@ fn handle_server_hello(sess: &mut ClientSessionImpl, m: Message) -> StateResult
         sess.handshake_data.must_issue_new_ticket = true;
     }
 
-    // See if we're successfully resuming.
+    // See if we're successfully resuming.  The server resumes by echoing
+    // the session ID we offered; any other ID means a full handshake.
+    // An empty ID never means resumption.
     let mut abbreviated_handshake = false;
     if let Some(ref resuming) = sess.handshake_data.resuming_session {
-        if resuming.session_id == sess.handshake_data.session_id {
+        if !sess.handshake_data.session_id.is_empty() &&
+           resuming.session_id == sess.handshake_data.session_id {
             info!("Server agreed to resume");
             abbreviated_handshake = true;
 
@@ -626,7 +918,7 @@ fn handle_hello_retry_request(sess: &mut ClientSessionImpl,
 
     // Or asks for us to retry on an unsupported group.
     if let Some(group) = req_group {
-        if !NamedGroups::supported().contains(&group) {
+        if !supported_groups(sess).contains(&group) {
             return Err(illegal_param(sess, "server requested hrr with bad group"));
         }
     }
@@ -662,6 +954,21 @@ fn handle_hello_retry_request(sess: &mut ClientSessionImpl,
         }
     }
 
+    // The server skips any early data we sent, and we may not offer
+    // it again: we send it after the handshake instead.  Our second
+    // ClientHello goes in plaintext, not under our early traffic keys.
+    if sess.early_data.state == EarlyDataState::Ready {
+        info!("Abandoning early data after retry request");
+        let sent = sess.early_data.rejected();
+        sess.common.resend_early_plaintext(sent);
+        sess.common.clear_message_encrypter();
+    }
+
+    // The server may now send compatibility ChangeCipherSpecs, and
+    // we send ours before our second ClientHello.
+    sess.common.negotiated_version = Some(ProtocolVersion::TLSv1_3);
+    sess.common.send_tls13_compat_ccs();
+
     Ok(emit_client_hello_for_retry(sess, Some(hrr)))
 }
 
@@ -709,6 +1016,69 @@ fn validate_encrypted_extensions(sess: &mut ClientSessionImpl,
     Ok(())
 }
 
+/// Learn whether the server accepted the early data we sent.  If it
+/// didn't, we send it again after the handshake.
+fn process_early_data_decision(sess: &mut ClientSessionImpl,
+                               exts: &EncryptedExtensions) -> Result<(), TLSError> {
+    if sess.early_data.state != EarlyDataState::Sent {
+        return Ok(());
+    }
+
+    if !exts.early_data_extension_offered() {
+        info!("Server rejected early data");
+        let sent = sess.early_data.rejected();
+        sess.common.resend_early_plaintext(sent);
+        start_handshake_write_traffic(sess);
+        return Ok(());
+    }
+
+    // Early data is encrypted with keys for the suite we're resuming,
+    // so the server can only accept it if it resumed into that suite.
+    let suite = sess.common.get_suite().suite;
+    let resumed_same_suite = sess.handshake_data.resuming_session
+        .as_ref()
+        .map(|resuming| resuming.cipher_suite == suite)
+        .unwrap_or(false);
+    if !resumed_same_suite {
+        return Err(illegal_param(sess, "server accepted early data without resuming"));
+    }
+
+    info!("Server accepted early data");
+    sess.early_data.accepted();
+    Ok(())
+}
+
+/// Learn the server's record size limit, if it sent one.  It can only
+/// have done so if we advertised ours.
+fn process_record_size_limit(sess: &mut ClientSessionImpl,
+                             theirs: Option<u16>) -> Result<(), TLSError> {
+    match (sess.config.record_size_limit, theirs) {
+        (Some(ours), Some(theirs)) => sess.common.negotiate_record_size_limit(ours, theirs),
+        _ => Ok(()),
+    }
+}
+
+/// Learn the server's ALPS settings, if it sent them.  It may only
+/// do so for the agreed protocol, and if we offered ALPS for it.
+fn process_application_settings(sess: &mut ClientSessionImpl,
+                                exts: &EncryptedExtensions) -> Result<(), TLSError> {
+    let settings = match exts.get_application_settings() {
+        Some(settings) => settings.to_vec(),
+        None => return Ok(()),
+    };
+
+    let offered = match sess.alpn_protocol {
+        Some(ref proto) => sess.config.application_settings.contains_key(proto),
+        None => false,
+    };
+    if !offered {
+        return Err(illegal_param(sess, "server sent application settings for wrong protocol"));
+    }
+
+    sess.handshake_data.peer_application_settings = Some(settings);
+    Ok(())
+}
+
 fn handle_encrypted_extensions(sess: &mut ClientSessionImpl,
                                m: Message)
                                -> StateResult {
@@ -718,6 +1088,9 @@ fn handle_encrypted_extensions(sess: &mut ClientSessionImpl,
 
     validate_encrypted_extensions(sess, exts)?;
     process_alpn_protocol(sess, exts.get_alpn_protocol())?;
+    process_application_settings(sess, exts)?;
+    process_record_size_limit(sess, exts.get_record_size_limit())?;
+    process_early_data_decision(sess, exts)?;
 
     if sess.handshake_data.resuming_session.is_some() {
         Ok(&EXPECT_TLS13_FINISHED)
@@ -735,9 +1108,43 @@ static EXPECT_TLS13_ENCRYPTED_EXTENSIONS: State = State {
 };
 
 fn handle_certificate_tls13(sess: &mut ClientSessionImpl, m: Message) -> StateResult {
+    if m.is_handshake_type(HandshakeType::CompressedCertificate) {
+        return handle_compressed_certificate_tls13(sess, m);
+    }
+
     let cert_chain = extract_handshake!(m, HandshakePayload::CertificateTLS13).unwrap();
     sess.handshake_data.transcript.add_message(&m);
+    check_certificate_tls13(sess, cert_chain)
+}
+
+fn handle_compressed_certificate_tls13(sess: &mut ClientSessionImpl,
+                                       m: Message) -> StateResult {
+    let compressed = extract_handshake!(m, HandshakePayload::CompressedCertificate).unwrap();
+    sess.handshake_data.transcript.add_message(&m);
+
+    let config = sess.config.clone();
+    let compressor = compress::find(&config.cert_compressors, compressed.alg)
+        .ok_or_else(|| illegal_param(sess, "server used unoffered cert compression"))?;
+
+    // Refuse to decompress a certificate we'd refuse to receive
+    // uncompressed.
+    if compressed.uncompressed_len as usize + 4 > config.max_certificate_size {
+        sess.common.send_fatal_alert(AlertDescription::DecodeError);
+        return Err(TLSError::PeerMisbehavedError("compressed certificate too large"
+                                                 .to_string()));
+    }
 
+    let cert_chain = compress::decompress(compressor, compressed)
+        .map_err(|err| {
+            sess.common.send_fatal_alert(AlertDescription::BadCertificate);
+            err
+        })?;
+
+    check_certificate_tls13(sess, &cert_chain)
+}
+
+fn check_certificate_tls13(sess: &mut ClientSessionImpl,
+                           cert_chain: &CertificatePayloadTLS13) -> StateResult {
     // This is only non-empty for client auth.
     if cert_chain.context.len() > 0 {
         warn!("certificate with non-empty context during handshake");
@@ -745,13 +1152,23 @@ fn handle_certificate_tls13(sess: &mut ClientSessionImpl, m: Message) -> StateRe
         return Err(TLSError::CorruptMessagePayload(ContentType::Handshake));
     }
 
+    // We don't ask for OCSP or SCTs, so any extension is unsolicited,
+    // except a delegated credential if we asked for one.
+    let allowed_exts = if sess.config.enable_delegated_credentials {
+        vec![ ExtensionType::DelegatedCredential ]
+    } else {
+        Vec::new()
+    };
+
     if cert_chain.any_entry_has_duplicate_extension() ||
-        cert_chain.any_entry_has_unknown_extension() {
+        cert_chain.any_entry_has_extension_except(&allowed_exts) {
         warn!("certificate chain contains unsolicited/unknown extension");
         sess.common.send_fatal_alert(AlertDescription::UnsupportedExtension);
         return Err(TLSError::PeerMisbehavedError("bad cert chain extensions".to_string()));
     }
 
+    sess.handshake_data.server_delegated_credential =
+        cert_chain.get_end_entity_delegated_credential().cloned();
     sess.handshake_data.server_cert_chain = cert_chain.convert();
     Ok(&EXPECT_TLS13_CERTIFICATE_VERIFY)
 }
@@ -759,7 +1176,7 @@ fn handle_certificate_tls13(sess: &mut ClientSessionImpl, m: Message) -> StateRe
 static EXPECT_TLS13_CERTIFICATE: State = State {
     expect: Expectation {
         content_types: &[ContentType::Handshake],
-        handshake_types: &[HandshakeType::Certificate],
+        handshake_types: &[HandshakeType::Certificate, HandshakeType::CompressedCertificate],
     },
     handle: handle_certificate_tls13,
 };
@@ -782,17 +1199,19 @@ static EXPECT_TLS12_CERTIFICATE: State = State {
 
 fn handle_certificate_or_cert_req(sess: &mut ClientSessionImpl,
                                   m: Message) -> StateResult {
-    if m.is_handshake_type(HandshakeType::Certificate) {
-        handle_certificate_tls13(sess, m)
-    } else {
+    if m.is_handshake_type(HandshakeType::CertificateRequest) {
         handle_certificate_req_tls13(sess, m)
+    } else {
+        handle_certificate_tls13(sess, m)
     }
 }
 
 static EXPECT_TLS13_CERTIFICATE_OR_CERTREQ: State = State {
     expect: Expectation {
         content_types: &[ContentType::Handshake],
-        handshake_types: &[HandshakeType::Certificate, HandshakeType::CertificateRequest],
+        handshake_types: &[HandshakeType::Certificate,
+                           HandshakeType::CompressedCertificate,
+                           HandshakeType::CertificateRequest],
     },
     handle: handle_certificate_or_cert_req,
 };
@@ -815,6 +1234,13 @@ fn handle_server_kx(sess: &mut ClientSessionImpl, m: Message) -> StateResult {
 
     if let ServerKeyExchangePayload::ECDHE(ecdhe) = decoded_kx {
         info!("ECDHE curve is {:?}", ecdhe.params.curve_params);
+
+        // The server may choose any group we offered, but only those.
+        if !supported_groups(sess).contains(&ecdhe.params.curve_params.named_group) {
+            return Err(illegal_param(sess, "server chose unoffered kx group"));
+        }
+
+        sess.handshake_data.kx_group = Some(ecdhe.params.curve_params.named_group);
     }
 
     Ok(&EXPECT_TLS12_SERVER_DONE_OR_CERTREQ)
@@ -844,14 +1270,31 @@ fn handle_certificate_verify(sess: &mut ClientSessionImpl,
     sess.config.get_verifier().verify_server_cert(&sess.config.root_store,
                                                   &sess.handshake_data.server_cert_chain,
                                                   &sess.handshake_data.dns_name)?;
+    verify::verify_spki_pins(&sess.config.root_store,
+                             &sess.handshake_data.server_cert_chain,
+                             &sess.config.spki_pins)?;
 
-    // 2. Verify their signature on the handshake.
+    // 2. Verify their signature on the handshake.  If they sent a
+    //    delegated credential, that's made with the credential's key,
+    //    so check the credential first.
     let handshake_hash = sess.handshake_data.transcript.get_current_hash();
-    verify::verify_tls13(&sess.handshake_data.server_cert_chain[0],
-                         &cert_verify,
-                         &handshake_hash,
-                         b"TLS 1.3, server CertificateVerify\x00")?;
+    if let Some(ref dc) = sess.handshake_data.server_delegated_credential {
+        delegated::verify(&sess.handshake_data.server_cert_chain[0],
+                          dc,
+                          &delegated::supported_schemes(),
+                          sess.config.get_time_provider().now())?;
+        delegated::verify_tls13(dc,
+                                &cert_verify,
+                                &handshake_hash,
+                                verify::SERVER_CERT_VERIFY_CONTEXT)?;
+    } else {
+        verify::verify_tls13(&sess.handshake_data.server_cert_chain[0],
+                             &cert_verify,
+                             &handshake_hash,
+                             verify::SERVER_CERT_VERIFY_CONTEXT)?;
+    }
 
+    sess.handshake_data.server_sigscheme = Some(cert_verify.scheme);
     sess.handshake_data.transcript.add_message(&m);
 
     Ok(&EXPECT_TLS13_FINISHED)
@@ -912,7 +1355,7 @@ fn emit_certverify(sess: &mut ClientSessionImpl) {
     let sigscheme = sess.handshake_data
         .client_auth_sigscheme
         .unwrap();
-    let sig = key.sign(sigscheme, &message)
+    let sig = key.sign(sigscheme, &message, sess.config.rng.as_ref())
         .expect("client auth signing failed unexpectedly");
     let body = DigitallySignedStruct::new(sigscheme, sig);
 
@@ -965,6 +1408,7 @@ fn handle_certificate_req(sess: &mut ClientSessionImpl, m: Message) -> StateResu
     let certreq = extract_handshake!(m, HandshakePayload::CertificateRequest).unwrap();
     sess.handshake_data.transcript.add_message(&m);
     sess.handshake_data.doing_client_auth = true;
+    sess.handshake_data.peer_sigschemes = Some(certreq.sigschemes.clone());
     info!("Got CertificateRequest {:?}", certreq);
 
     // The RFC jovially describes the design here as 'somewhat complicated'
@@ -1019,6 +1463,21 @@ fn handle_certificate_req_tls13(sess: &mut ClientSessionImpl,
         return Err(TLSError::CorruptMessagePayload(ContentType::Handshake));
     }
 
+    choose_client_auth_tls13(sess, certreq)?;
+    Ok(&EXPECT_TLS13_CERTIFICATE)
+}
+
+// Decide how to answer a TLS1.3 CertificateRequest, during or after
+// the handshake.
+fn choose_client_auth_tls13(sess: &mut ClientSessionImpl,
+                            certreq: &CertificateRequestPayloadTLS13) -> Result<(), TLSError> {
+    // Forget what we chose for any earlier request, in case we
+    // choose nothing for this one.
+    sess.handshake_data.client_auth_cert = None;
+    sess.handshake_data.client_auth_key = None;
+    sess.handshake_data.client_auth_sigscheme = None;
+    sess.handshake_data.peer_sigschemes = Some(certreq.sigschemes.clone());
+
     let tls13_sign_schemes = SupportedSignatureSchemes::supported_sign_tls13();
     let compat_sigschemes = certreq.sigschemes
         .iter()
@@ -1031,6 +1490,9 @@ fn handle_certificate_req_tls13(sess: &mut ClientSessionImpl,
         return Err(TLSError::PeerIncompatibleError("server sent bad certreq schemes".to_string()));
     }
 
+    // Our Certificate must echo the request's context, even if empty.
+    sess.handshake_data.client_auth_context = Some(certreq.context.0.clone());
+
     let canames = certreq.canames
         .iter()
         .map(|p| p.0.as_slice())
@@ -1045,12 +1507,11 @@ fn handle_certificate_req_tls13(sess: &mut ClientSessionImpl,
         sess.handshake_data.client_auth_cert = Some(cert);
         sess.handshake_data.client_auth_key = Some(key);
         sess.handshake_data.client_auth_sigscheme = maybe_sigscheme;
-        sess.handshake_data.client_auth_context = Some(certreq.context.0.clone());
     } else {
         info!("Client auth requested but no cert selected");
     }
 
-    Ok(&EXPECT_TLS13_CERTIFICATE)
+    Ok(())
 }
 
 fn handle_server_done_or_certreq(sess: &mut ClientSessionImpl, m: Message) -> StateResult {
@@ -1096,15 +1557,18 @@ fn handle_server_hello_done(sess: &mut ClientSessionImpl,
     sess.config.get_verifier().verify_server_cert(&sess.config.root_store,
                                                   &sess.handshake_data.server_cert_chain,
                                                   &sess.handshake_data.dns_name)?;
+    verify::verify_spki_pins(&sess.config.root_store,
+                             &sess.handshake_data.server_cert_chain,
+                             &sess.config.spki_pins)?;
 
     // 2.
     // Build up the contents of the signed message.
     // It's ClientHello.random || ServerHello.random || ServerKeyExchange.params
     {
-        let mut message = Vec::new();
-        message.extend_from_slice(&sess.handshake_data.randoms.client);
-        message.extend_from_slice(&sess.handshake_data.randoms.server);
-        message.extend_from_slice(&sess.handshake_data.server_kx_params);
+        let message = {
+            let hs = &sess.handshake_data;
+            verify::construct_tls12_server_kx_message(&hs.randoms, &hs.server_kx_params)
+        };
 
         // Check the signature is compatible with the ciphersuite.
         let sig = sess.handshake_data.server_kx_sig.as_ref().unwrap();
@@ -1119,6 +1583,7 @@ fn handle_server_hello_done(sess: &mut ClientSessionImpl,
         verify::verify_signed_struct(&message,
                                      &sess.handshake_data.server_cert_chain[0],
                                      sig)?;
+        sess.handshake_data.server_sigscheme = Some(sig.scheme);
     }
 
     // 3.
@@ -1128,8 +1593,8 @@ fn handle_server_hello_done(sess: &mut ClientSessionImpl,
 
     // 4a.
     let kxd = sess.common.get_suite()
-        .do_client_kx(&sess.handshake_data.server_kx_params)
-        .ok_or_else(|| TLSError::PeerMisbehavedError("key exchange failed".to_string()))?;
+        .do_client_kx(&sess.handshake_data.server_kx_params, sess.config.rng.as_ref())
+        .ok_or_else(|| illegal_param(sess, "server sent invalid key exchange public key"))?;
 
     // 4b.
     emit_clientkx(sess, &kxd);
@@ -1161,6 +1626,14 @@ fn handle_server_hello_done(sess: &mut ClientSessionImpl,
     // 5.
     emit_finished(sess);
 
+    // 6. False Start, if we can.
+    sess.handshake_data.false_start_safe = false_start_safe(sess);
+    if sess.config.enable_false_start && sess.handshake_data.false_start_safe {
+        info!("False starting");
+        sess.common.we_now_encrypting();
+        sess.common.start_false_start();
+    }
+
     if sess.handshake_data.must_issue_new_ticket {
         Ok(&EXPECT_TLS12_NEW_TICKET)
     } else {
@@ -1168,6 +1641,14 @@ fn handle_server_hello_done(sess: &mut ClientSessionImpl,
     }
 }
 
+/// Whether we may send data before the server's Finished (RFC7918).
+/// The key exchange must be forward secret, and we must have agreed
+/// an ALPN protocol so that no other protocol's data is sent early.
+/// RFC7918 also wants an AEAD bulk cipher, but all of ours are.
+fn false_start_safe(sess: &ClientSessionImpl) -> bool {
+    sess.common.has_forward_secrecy() && sess.alpn_protocol.is_some()
+}
+
 static EXPECT_TLS12_SERVER_HELLO_DONE: State = State {
     expect: Expectation {
         content_types: &[ContentType::Handshake],
@@ -1201,11 +1682,23 @@ static EXPECT_TLS12_CCS: State = State {
     handle: handle_ccs,
 };
 
+/// Tell the application, if it asked, about `ticket` from the server.
+fn notify_new_ticket(sess: &ClientSessionImpl, ticket: &[u8]) {
+    if let Some(callback) = sess.config.get_new_ticket_callback() {
+        callback(&sess.handshake_data.dns_name, ticket);
+    }
+}
+
 fn handle_new_ticket(sess: &mut ClientSessionImpl, m: Message) -> StateResult {
     let ticket = extract_handshake!(m, HandshakePayload::NewSessionTicket).unwrap();
     sess.handshake_data.transcript.add_message(&m);
     sess.handshake_data.new_ticket = ticket.ticket.0.clone();
     sess.handshake_data.new_ticket_lifetime = ticket.lifetime_hint;
+
+    // An empty ticket means the server won't issue one after all.
+    if !ticket.ticket.0.is_empty() {
+        notify_new_ticket(sess, &ticket.ticket.0);
+    }
     Ok(&EXPECT_TLS12_CCS)
 }
 
@@ -1256,6 +1749,11 @@ fn save_session(sess: &mut ClientSessionImpl) {
         return;
     }
 
+    if !sess.config.resumption_enabled() {
+        info!("Session not saved: resumption disabled");
+        return;
+    }
+
     let key = persist::ClientSessionKey::session_for_dns_name(&sess.handshake_data.dns_name);
 
     let scs = sess.common.get_suite();
@@ -1274,7 +1772,7 @@ fn save_session(sess: &mut ClientSessionImpl) {
     }
 
     let mut persist = sess.config.session_persistence.lock().unwrap();
-    let worked = persist.put(key.get_encoding(), value.get_encoding());
+    let worked = persist.put(key.get_encoding(), value.to_bytes());
 
     if worked {
         info!("Session saved");
@@ -1319,14 +1817,13 @@ fn emit_certverify_tls13(sess: &mut ClientSessionImpl) -> Result<(), TLSError> {
         return Ok(());
     }
 
-    let mut message = Vec::new();
-    message.resize(64, 0x20u8);
-    message.extend_from_slice(b"TLS 1.3, client CertificateVerify\x00");
-    message.extend_from_slice(&sess.handshake_data.transcript.get_current_hash());
+    let handshake_hash = sess.handshake_data.transcript.get_current_hash();
+    let message = verify::construct_tls13_verify_message(&handshake_hash,
+                                                         verify::CLIENT_CERT_VERIFY_CONTEXT);
 
     let scheme = sess.handshake_data.client_auth_sigscheme.take().unwrap();
     let key = sess.handshake_data.client_auth_key.take().unwrap();
-    let sig = key.sign(scheme, &message)
+    let sig = key.sign(scheme, &message, sess.config.rng.as_ref())
         .map_err(|_| TLSError::General("cannot sign".to_string()))?;
     let dss = DigitallySignedStruct::new(scheme, sig);
 
@@ -1344,6 +1841,43 @@ fn emit_certverify_tls13(sess: &mut ClientSessionImpl) -> Result<(), TLSError> {
     Ok(())
 }
 
+fn emit_end_of_early_data_tls13(sess: &mut ClientSessionImpl) {
+    let m = Message {
+        typ: ContentType::Handshake,
+        version: ProtocolVersion::TLSv1_3,
+        payload: MessagePayload::Handshake(HandshakeMessagePayload {
+            typ: HandshakeType::EndOfEarlyData,
+            payload: HandshakePayload::EndOfEarlyData,
+        }),
+    };
+
+    sess.handshake_data.transcript.add_message(&m);
+    sess.common.send_msg(m, true);
+}
+
+/// Send our ALPS settings, in our own EncryptedExtensions message.
+fn emit_encrypted_extensions_tls13(sess: &mut ClientSessionImpl) {
+    let settings = sess.alpn_protocol
+        .as_ref()
+        .and_then(|proto| sess.config.application_settings.get(proto))
+        .cloned()
+        .unwrap();
+
+    let m = Message {
+        typ: ContentType::Handshake,
+        version: ProtocolVersion::TLSv1_3,
+        payload: MessagePayload::Handshake(HandshakeMessagePayload {
+            typ: HandshakeType::EncryptedExtensions,
+            payload: HandshakePayload::EncryptedExtensions(vec![
+                ServerExtension::ApplicationSettings(Payload::new(settings))
+            ]),
+        }),
+    };
+
+    sess.handshake_data.transcript.add_message(&m);
+    sess.common.send_msg(m, true);
+}
+
 fn emit_finished_tls13(sess: &mut ClientSessionImpl) {
     let handshake_hash = sess.handshake_data.transcript.get_current_hash();
     let verify_data = sess.common
@@ -1394,8 +1928,21 @@ fn handle_finished_tls13(sess: &mut ClientSessionImpl, m: Message) -> StateResul
         .get_mut_key_schedule()
         .current_server_traffic_secret = read_key;
 
+    /* If the server accepted our early data, we end it with our early
+     * traffic keys, and move to our handshake keys. */
+    if sess.early_data.state == EarlyDataState::Accepted {
+        emit_end_of_early_data_tls13(sess);
+        start_handshake_write_traffic(sess);
+    }
+
     /* Send our authentication/finished messages.  These are still encrypted
      * with our handshake keys. */
+    sess.common.send_tls13_compat_ccs();
+
+    if sess.handshake_data.peer_application_settings.is_some() {
+        emit_encrypted_extensions_tls13(sess);
+    }
+
     if sess.handshake_data.doing_client_auth {
         emit_certificate_tls13(sess);
         emit_certverify_tls13(sess)?;
@@ -1509,6 +2056,8 @@ fn handle_traffic_tls13(sess: &mut ClientSessionImpl, m: Message) -> StateResult
         handle_new_ticket_tls13(sess, m)?;
     } else if m.is_handshake_type(HandshakeType::KeyUpdate) {
         handle_key_update(sess, m)?;
+    } else if m.is_handshake_type(HandshakeType::CertificateRequest) {
+        handle_post_handshake_certreq(sess, m)?;
     }
 
     Ok(&EXPECT_TLS13_TRAFFIC)
@@ -1528,10 +2077,23 @@ fn handle_new_ticket_tls13(sess: &mut ClientSessionImpl, m: Message) -> Result<(
                     nst.lifetime,
                     nst.age_add);
 
+    if let Some(sz) = nst.get_max_early_data_size() {
+        info!("Ticket invites {} bytes of early data", sz);
+        value.max_early_data_size = sz;
+        sess.handshake_data.max_early_data_size = Some(sz);
+    }
+
+    notify_new_ticket(sess, &nst.ticket.0);
+
+    if !sess.config.resumption_enabled() {
+        info!("Ticket not saved: resumption disabled");
+        return Ok(());
+    }
+
     let key = persist::ClientSessionKey::session_for_dns_name(&sess.handshake_data.dns_name);
 
     let mut persist = sess.config.session_persistence.lock().unwrap();
-    let worked = persist.put(key.get_encoding(), value.get_encoding());
+    let worked = persist.put(key.get_encoding(), value.to_bytes());
 
     if worked {
         info!("Ticket saved");
@@ -1546,10 +2108,51 @@ fn handle_key_update(sess: &mut ClientSessionImpl, m: Message) -> Result<(), TLS
     sess.common.process_key_update(kur, SecretKind::ServerApplicationTrafficSecret)
 }
 
+// Post-handshake client authentication.  We answer immediately with
+// our Certificate, CertificateVerify and Finished.
+fn handle_post_handshake_certreq(sess: &mut ClientSessionImpl,
+                                 m: Message) -> Result<(), TLSError> {
+    if !sess.config.enable_post_handshake_auth {
+        sess.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+        return Err(TLSError::PeerMisbehavedError("server sent unsolicited post-handshake \
+                                                  certreq".to_string()));
+    }
+
+    let certreq = extract_handshake!(m, HandshakePayload::CertificateRequestTLS13).unwrap();
+    info!("Got post-handshake CertificateRequest {:?}", certreq);
+
+    // Must be non-empty after handshake.
+    if certreq.context.len() == 0 {
+        warn!("Server sent empty post-handshake certreq context");
+        sess.common.send_fatal_alert(AlertDescription::DecodeError);
+        return Err(TLSError::CorruptMessagePayload(ContentType::Handshake));
+    }
+
+    // This exchange's transcript continues from our Finished, but
+    // mustn't change the transcript used for later tickets or
+    // exchanges.
+    let mut transcript = sess.handshake_data.transcript.clone();
+    transcript.add_message(&m);
+    let main_transcript = mem::replace(&mut sess.handshake_data.transcript, transcript);
+
+    choose_client_auth_tls13(sess, certreq)?;
+    emit_certificate_tls13(sess);
+    emit_certverify_tls13(sess)?;
+
+    // Our current traffic secret is now the application one, which
+    // is what the Finished is keyed with here.
+    emit_finished_tls13(sess);
+
+    sess.handshake_data.transcript = main_transcript;
+    Ok(())
+}
+
 static EXPECT_TLS13_TRAFFIC: State = State {
     expect: Expectation {
         content_types: &[ContentType::ApplicationData, ContentType::Handshake],
-        handshake_types: &[HandshakeType::NewSessionTicket, HandshakeType::KeyUpdate],
+        handshake_types: &[HandshakeType::NewSessionTicket,
+                           HandshakeType::KeyUpdate,
+                           HandshakeType::CertificateRequest],
     },
     handle: handle_traffic_tls13,
 };
@@ -0,0 +1,203 @@
+// Certificate compression, as in RFC8879.
+//
+// A TLS1.3 client lists the compression algorithms it supports
+// in the `compress_certificate` extension; the server may then send
+// its Certificate message compressed with one of them.
+//
+// We don't implement any compression algorithms ourselves: callers
+// provide them through `CertCompressor`.
+
+use msgs::enums::CertificateCompressionAlgorithm;
+use msgs::handshake::{CertificatePayloadTLS13, CompressedCertificatePayload};
+use msgs::handshake::CertificateCompressionAlgorithms;
+use msgs::base::PayloadU24;
+use msgs::codec::{Codec, Reader};
+use error::TLSError;
+
+/// We refuse to decompress a certificate message larger than this,
+/// whatever length the peer claims it has.  It's well beyond the size
+/// of certificate chain we'll parse.
+pub const MAX_UNCOMPRESSED_LEN: usize = 0x20000;
+
+/// A certificate compression algorithm.
+pub trait CertCompressor : Send + Sync {
+    /// Which algorithm this is.
+    fn algorithm(&self) -> CertificateCompressionAlgorithm;
+
+    /// Compress `input`.  Returns None on failure, in which case
+    /// the certificate is sent uncompressed.
+    fn compress(&self, input: &[u8]) -> Option<Vec<u8>>;
+
+    /// Decompress `input` into `output`, returning the number of
+    /// bytes written.  `output` is as long as the peer says the
+    /// certificate is: implementations must fail rather than write
+    /// beyond it, so a malicious peer can't make us expand a small
+    /// `input` without limit.  Returns None if `input` is invalid.
+    fn decompress(&self, input: &[u8], output: &mut [u8]) -> Option<usize>;
+}
+
+/// List the algorithms of `compressors`, for the `compress_certificate`
+/// extension.
+pub fn algorithms(compressors: &[Box<CertCompressor>]) -> CertificateCompressionAlgorithms {
+    compressors.iter()
+        .map(|c| c.algorithm())
+        .collect()
+}
+
+/// Compress `cert` with our most preferred algorithm out of those the
+/// peer `offered`.  Returns None if there's no such algorithm, or
+/// compression fails.
+pub fn compress(compressors: &[Box<CertCompressor>],
+                offered: &[CertificateCompressionAlgorithm],
+                cert: &CertificatePayloadTLS13)
+                -> Option<CompressedCertificatePayload> {
+    let compressor = try_ret!(compressors.iter()
+                              .find(|c| offered.contains(&c.algorithm())));
+    let uncompressed = cert.get_encoding();
+    let compressed = try_ret!(compressor.compress(&uncompressed));
+
+    Some(CompressedCertificatePayload {
+        alg: compressor.algorithm(),
+        uncompressed_len: uncompressed.len() as u32,
+        compressed: PayloadU24::new(compressed),
+    })
+}
+
+/// Find the compressor in `compressors` for `alg`.
+pub fn find(compressors: &[Box<CertCompressor>],
+            alg: CertificateCompressionAlgorithm)
+            -> Option<&CertCompressor> {
+    compressors.iter()
+        .find(|c| c.algorithm() == alg)
+        .map(|c| &**c)
+}
+
+/// Decompress and parse `compressed` using `compressor`.
+pub fn decompress(compressor: &CertCompressor,
+                  compressed: &CompressedCertificatePayload)
+                  -> Result<CertificatePayloadTLS13, TLSError> {
+    let corrupt = |why: &str| TLSError::PeerMisbehavedError(why.to_string());
+
+    let uncompressed_len = compressed.uncompressed_len as usize;
+    if uncompressed_len > MAX_UNCOMPRESSED_LEN {
+        return Err(corrupt("compressed certificate too large"));
+    }
+
+    // The declared length is a hard cap on how much we decompress.
+    let mut uncompressed = vec![0u8; uncompressed_len];
+    let used = compressor.decompress(&compressed.compressed.0, &mut uncompressed)
+        .ok_or_else(|| corrupt("certificate decompression failed"))?;
+
+    if used != uncompressed_len {
+        return Err(corrupt("compressed certificate has wrong length"));
+    }
+
+    let mut rd = Reader::init(&uncompressed);
+    let cert = CertificatePayloadTLS13::read(&mut rd)
+        .ok_or_else(|| corrupt("compressed certificate is corrupt"))?;
+
+    if rd.any_left() {
+        return Err(corrupt("compressed certificate has trailing data"));
+    }
+
+    Ok(cert)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use msgs::enums::CertificateCompressionAlgorithm;
+    use msgs::handshake::{CertificatePayloadTLS13, CertificateEntry};
+    use msgs::base::PayloadU24;
+    use key;
+
+    /// A 'compression' algorithm that doesn't compress.
+    struct IdentityCompressor {}
+
+    impl CertCompressor for IdentityCompressor {
+        fn algorithm(&self) -> CertificateCompressionAlgorithm {
+            CertificateCompressionAlgorithm::Zlib
+        }
+
+        fn compress(&self, input: &[u8]) -> Option<Vec<u8>> {
+            Some(input.to_vec())
+        }
+
+        fn decompress(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+            if input.len() > output.len() {
+                return None;
+            }
+
+            output[..input.len()].copy_from_slice(input);
+            Some(input.len())
+        }
+    }
+
+    fn make_cert() -> CertificatePayloadTLS13 {
+        let mut cert = CertificatePayloadTLS13::new();
+        cert.list.push(CertificateEntry {
+            cert: key::Certificate(vec![ 0x30, 0x00 ]),
+            exts: Vec::new(),
+        });
+        cert
+    }
+
+    fn compressors() -> Vec<Box<CertCompressor>> {
+        vec![ Box::new(IdentityCompressor {}) as Box<CertCompressor> ]
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let compressed = compress(&compressors(),
+                                  &[ CertificateCompressionAlgorithm::Zlib ],
+                                  &make_cert()).unwrap();
+        assert_eq!(compressed.alg, CertificateCompressionAlgorithm::Zlib);
+
+        let cert = decompress(&IdentityCompressor {}, &compressed).unwrap();
+        assert_eq!(cert.get_encoding(), make_cert().get_encoding());
+    }
+
+    #[test]
+    fn test_no_common_algorithm() {
+        assert!(compress(&compressors(),
+                         &[ CertificateCompressionAlgorithm::Brotli ],
+                         &make_cert()).is_none());
+        assert!(find(&compressors(), CertificateCompressionAlgorithm::Zstd).is_none());
+    }
+
+    #[test]
+    fn test_rejects_huge_uncompressed_len() {
+        let compressed = CompressedCertificatePayload {
+            alg: CertificateCompressionAlgorithm::Zlib,
+            uncompressed_len: 0xffffff,
+            compressed: PayloadU24::new(make_cert().get_encoding()),
+        };
+        assert!(decompress(&IdentityCompressor {}, &compressed).is_err());
+
+        let compressed = CompressedCertificatePayload {
+            alg: CertificateCompressionAlgorithm::Zlib,
+            uncompressed_len: MAX_UNCOMPRESSED_LEN as u32 + 1,
+            compressed: PayloadU24::new(vec![ 0u8; MAX_UNCOMPRESSED_LEN + 1 ]),
+        };
+        assert!(decompress(&IdentityCompressor {}, &compressed).is_err());
+    }
+
+    #[test]
+    fn test_expansion_is_capped() {
+        let compressed = CompressedCertificatePayload {
+            alg: CertificateCompressionAlgorithm::Zlib,
+            uncompressed_len: 16,
+            compressed: PayloadU24::new(vec![ 0u8; 0x10000 ]),
+        };
+        assert!(decompress(&IdentityCompressor {}, &compressed).is_err());
+    }
+
+    #[test]
+    fn test_rejects_wrong_uncompressed_len() {
+        let mut compressed = compress(&compressors(),
+                                      &[ CertificateCompressionAlgorithm::Zlib ],
+                                      &make_cert()).unwrap();
+        compressed.uncompressed_len += 1;
+        assert!(decompress(&IdentityCompressor {}, &compressed).is_err());
+    }
+}
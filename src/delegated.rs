@@ -0,0 +1,309 @@
+// Delegated credentials, as in RFC9345.
+//
+// A delegated credential is a short-lived public key, signed by the
+// key of the server's certificate.  A TLS1.3 server which has one
+// signs its CertificateVerify with the credential's key instead, so
+// the certificate's key is only needed to issue credentials.
+//
+// The client says which signature schemes it accepts for credentials
+// in the `delegated_credential` extension; the server sends the
+// credential in an extension on its end-entity certificate.
+
+use msgs::enums::SignatureScheme;
+use msgs::handshake::{Credential, DelegatedCredentialPayload, DigitallySignedStruct};
+use msgs::handshake::SupportedSignatureSchemes;
+use msgs::handshake::SupportedMandatedSignatureSchemes;
+use msgs::base::{PayloadU16, PayloadU24};
+use msgs::codec::Codec;
+use error::TLSError;
+use key;
+use rand::SecureRandom;
+use sign;
+use verify;
+use x509;
+
+use ring::signature;
+use untrusted;
+use webpki;
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The longest a delegated credential may be valid for, in seconds:
+/// seven days.
+pub const MAX_VALIDITY: u64 = 7 * 24 * 60 * 60;
+
+/// The DER-encoded OID of the DelegationUsage certificate extension.
+/// Clients only accept credentials signed by certificates which
+/// have it.
+const DELEGATION_USAGE: &'static [u8] = &[ 0x2b, 0x06, 0x01, 0x04, 0x01,
+                                           0x82, 0xda, 0x4b, 0x2c ];
+
+/// The context string a credential's signature covers, with its
+/// terminating NUL.
+static SIGNATURE_CONTEXT: &'static [u8] = b"TLS, server delegated credentials\x00";
+
+/// AlgorithmIdentifier contents for an RSA key.
+static RSA_ENCRYPTION: &'static [u8] = &[ 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d,
+                                          0x01, 0x01, 0x01, 0x05, 0x00 ];
+
+/// AlgorithmIdentifier contents for a P-256 ECDSA key.
+static EC_PUBLIC_KEY_P256: &'static [u8] = &[ 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02,
+                                              0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d,
+                                              0x03, 0x01, 0x07 ];
+
+/// AlgorithmIdentifier contents for a P-384 ECDSA key.
+static EC_PUBLIC_KEY_P384: &'static [u8] = &[ 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02,
+                                              0x01, 0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22 ];
+
+/// AlgorithmIdentifier contents for an Ed25519 key.
+static ED25519_KEY: &'static [u8] = &[ 0x06, 0x03, 0x2b, 0x65, 0x70 ];
+
+/// A delegated credential, with a `Signer` for its key.
+pub struct DelegatedCredential {
+    cert: key::Certificate,
+    credential: DelegatedCredentialPayload,
+    signer: Arc<Box<sign::Signer>>,
+}
+
+impl DelegatedCredential {
+    /// Issue a credential for `signer`'s key, whose DER-encoded
+    /// SubjectPublicKeyInfo is `public_key`.  The credential is
+    /// signed by `cert_signer`, which is the key for end-entity
+    /// certificate `cert`, and expires at `expiry`.  Any random
+    /// material the signature needs comes from `rng`.
+    ///
+    /// Clients refuse credentials valid for more than seven days,
+    /// and those whose certificate lacks the DelegationUsage
+    /// extension.
+    pub fn new(cert: &key::Certificate,
+               cert_signer: &sign::Signer,
+               public_key: Vec<u8>,
+               signer: Box<sign::Signer>,
+               expiry: SystemTime,
+               rng: &SecureRandom)
+               -> Result<DelegatedCredential, TLSError> {
+        let fail = |why: &str| TLSError::General(why.to_string());
+
+        let not_before = x509::CertificateInfo::parse(&cert.0)
+            .ok_or_else(|| fail("cannot parse certificate"))?
+            .not_before;
+        let expiry = unix_time(expiry);
+        if expiry <= not_before || expiry - not_before > 0xffff_ffff {
+            return Err(fail("delegated credential expiry out of range"));
+        }
+
+        let tls13_schemes = SupportedSignatureSchemes::supported_sign_tls13();
+        let dc_scheme = signer.choose_scheme(&tls13_schemes)
+            .ok_or_else(|| fail("delegated key has no TLS1.3 signature scheme"))?;
+        let cert_scheme = cert_signer.choose_scheme(&tls13_schemes)
+            .ok_or_else(|| fail("certificate key has no TLS1.3 signature scheme"))?;
+
+        let cred = Credential {
+            valid_time: (expiry - not_before) as u32,
+            dc_cert_verify_algorithm: dc_scheme,
+            public_key: PayloadU24::new(public_key),
+        };
+
+        let message = construct_signed_message(cert, &cred, cert_scheme);
+        let sig = cert_signer.sign(cert_scheme, &message, rng)
+            .map_err(|_| fail("cannot sign delegated credential"))?;
+
+        Ok(DelegatedCredential {
+            cert: cert.clone(),
+            credential: DelegatedCredentialPayload {
+                cred: cred,
+                algorithm: cert_scheme,
+                sig: PayloadU16::new(sig),
+            },
+            signer: Arc::new(signer),
+        })
+    }
+}
+
+fn unix_time(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Build the message a delegated credential's signature covers:
+/// 64 spaces, the context string, the DER-encoded certificate, then
+/// the `cred` and signature scheme `algorithm`.
+pub fn construct_signed_message(cert: &key::Certificate,
+                                cred: &Credential,
+                                algorithm: SignatureScheme)
+                                -> Vec<u8> {
+    let mut msg = Vec::new();
+    msg.resize(64, 0x20u8);
+    msg.extend_from_slice(SIGNATURE_CONTEXT);
+    msg.extend_from_slice(&cert.0);
+    cred.encode(&mut msg);
+    algorithm.encode(&mut msg);
+    msg
+}
+
+/// The signature schemes clients accept for delegated credentials.
+pub fn supported_schemes() -> SupportedSignatureSchemes {
+    SupportedSignatureSchemes::supported_sign_tls13()
+}
+
+/// Choose whether to send `dc` with end-entity certificate `cert`,
+/// given the schemes the client `offered` for credentials.  We only
+/// do so if the credential was issued by `cert`, and the client
+/// accepts its scheme.
+pub fn choose<'a>(dc: Option<&'a DelegatedCredential>,
+                  cert: Option<&key::Certificate>,
+                  offered: Option<&SupportedSignatureSchemes>)
+                  -> Option<&'a DelegatedCredential> {
+    let dc = try_ret!(dc);
+    let cert = try_ret!(cert);
+    let offered = try_ret!(offered);
+
+    if dc.cert == *cert && offered.contains(&dc.credential.cred.dc_cert_verify_algorithm) {
+        Some(dc)
+    } else {
+        None
+    }
+}
+
+/// The credential to send to the client.
+pub fn get_payload(dc: &DelegatedCredential) -> &DelegatedCredentialPayload {
+    &dc.credential
+}
+
+/// The signature scheme and `Signer` for our CertificateVerify.
+pub fn get_signer(dc: &DelegatedCredential) -> (SignatureScheme, &Arc<Box<sign::Signer>>) {
+    (dc.credential.cred.dc_cert_verify_algorithm, &dc.signer)
+}
+
+/// Check `dc` is a valid delegated credential from end-entity
+/// certificate `cert` at time `now`.  Both the credential's scheme
+/// and the scheme of its signature must be in `offered`.  `cert`
+/// must already be verified.
+pub fn verify(cert: &key::Certificate,
+              dc: &DelegatedCredentialPayload,
+              offered: &[SignatureScheme],
+              now: SystemTime)
+              -> Result<(), TLSError> {
+    let bad = |why: &str| TLSError::PeerMisbehavedError(why.to_string());
+
+    if !offered.contains(&dc.cred.dc_cert_verify_algorithm) {
+        return Err(bad("delegated credential uses unoffered sigscheme"));
+    }
+
+    if !offered.contains(&dc.algorithm) {
+        return Err(bad("delegated credential signed with unoffered sigscheme"));
+    }
+
+    let info = x509::CertificateInfo::parse(&cert.0)
+        .ok_or_else(|| bad("cannot parse certificate"))?;
+    if !info.has_extension(DELEGATION_USAGE) {
+        return Err(bad("certificate does not allow delegated credentials"));
+    }
+
+    let now = unix_time(now);
+    let expiry = info.not_before + dc.cred.valid_time as u64;
+    if now >= expiry {
+        return Err(bad("delegated credential expired"));
+    }
+
+    if expiry - now > MAX_VALIDITY {
+        return Err(bad("delegated credential valid for too long"));
+    }
+
+    let msg = construct_signed_message(cert, &dc.cred, dc.algorithm);
+    let dss = DigitallySignedStruct::new(dc.algorithm, dc.sig.0.clone());
+    verify::verify_signed_message_tls13(cert, &dss, &msg)
+}
+
+/// Like `verify::verify_tls13`, but checks `dss` was made using the
+/// key in delegated credential `dc`.
+pub fn verify_tls13(dc: &DelegatedCredentialPayload,
+                    dss: &DigitallySignedStruct,
+                    handshake_hash: &[u8],
+                    context_string_with_0: &[u8])
+                    -> Result<(), TLSError> {
+    if dss.scheme != dc.cred.dc_cert_verify_algorithm {
+        return Err(TLSError::PeerMisbehavedError("signature does not use delegated \
+                                                  credential's sigscheme"
+                                                 .to_string()));
+    }
+
+    let (alg, key_alg): (&signature::VerificationAlgorithm, &[u8]) = match dss.scheme {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => {
+            (&signature::ECDSA_P256_SHA256_ASN1, EC_PUBLIC_KEY_P256)
+        }
+        SignatureScheme::ECDSA_NISTP384_SHA384 => {
+            (&signature::ECDSA_P384_SHA384_ASN1, EC_PUBLIC_KEY_P384)
+        }
+        SignatureScheme::RSA_PSS_SHA256 => (&signature::RSA_PSS_2048_8192_SHA256, RSA_ENCRYPTION),
+        SignatureScheme::RSA_PSS_SHA384 => (&signature::RSA_PSS_2048_8192_SHA384, RSA_ENCRYPTION),
+        SignatureScheme::RSA_PSS_SHA512 => (&signature::RSA_PSS_2048_8192_SHA512, RSA_ENCRYPTION),
+        SignatureScheme::ED25519 => (&signature::ED25519, ED25519_KEY),
+        _ => {
+            let error_msg = format!("received unsupported sig scheme {:?}", dss.scheme);
+            return Err(TLSError::PeerMisbehavedError(error_msg));
+        }
+    };
+
+    let key = match x509::parse_spki(&dc.cred.public_key.0) {
+        Some((spki_alg, key)) if spki_alg == key_alg => key,
+        _ => {
+            let err = webpki::Error::UnsupportedSignatureAlgorithmForPublicKey;
+            return Err(TLSError::WebPKIError(err));
+        }
+    };
+
+    let msg = verify::construct_tls13_verify_message(handshake_hash, context_string_with_0);
+    signature::verify(alg,
+                      untrusted::Input::from(key),
+                      untrusted::Input::from(&msg),
+                      untrusted::Input::from(&dss.sig.0))
+        .map_err(|_| TLSError::WebPKIError(webpki::Error::InvalidSignatureForPublicKey))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use msgs::base::PayloadU24;
+
+    #[test]
+    fn test_signed_message() {
+        let cert = key::Certificate(vec![ 0x30, 0x00 ]);
+        let cred = Credential {
+            valid_time: 0x01020304,
+            dc_cert_verify_algorithm: SignatureScheme::RSA_PSS_SHA256,
+            public_key: PayloadU24::new(vec![ 0xaa ]),
+        };
+
+        let msg = construct_signed_message(&cert, &cred, SignatureScheme::RSA_PSS_SHA512);
+        assert!(msg[..64].iter().all(|b| *b == 0x20));
+        assert_eq!(&msg[64..98], b"TLS, server delegated credentials\x00");
+        assert_eq!(&msg[98..100], &[ 0x30, 0x00 ]);
+        assert_eq!(&msg[100..], &[ 0x01, 0x02, 0x03, 0x04,
+                                   0x08, 0x04,
+                                   0x00, 0x00, 0x01, 0xaa,
+                                   0x08, 0x06 ]);
+    }
+
+    #[test]
+    fn test_verify_rejects_unoffered_signature_scheme() {
+        let cert = key::Certificate(vec![ 0x30, 0x00 ]);
+        let dc = DelegatedCredentialPayload {
+            cred: Credential {
+                valid_time: 0x01020304,
+                dc_cert_verify_algorithm: SignatureScheme::RSA_PSS_SHA256,
+                public_key: PayloadU24::new(vec![ 0xaa ]),
+            },
+            algorithm: SignatureScheme::RSA_PKCS1_SHA256,
+            sig: PayloadU16::new(vec![ 0xbb ]),
+        };
+
+        let err = verify(&cert, &dc, &supported_schemes(), UNIX_EPOCH).unwrap_err();
+        assert_eq!(err,
+                   TLSError::PeerMisbehavedError("delegated credential signed with \
+                                                  unoffered sigscheme"
+                                                 .to_string()));
+    }
+}
@@ -2,6 +2,47 @@ use ring::digest;
 use std::mem;
 use msgs::codec::Codec;
 use msgs::message::{Message, MessagePayload};
+use msgs::handshake::{HandshakeMessagePayload, HandshakePayload};
+use msgs::enums::{HandshakeType, ProtocolVersion, CipherSuite};
+use msgs::base::Payload;
+use util;
+
+/// A summary of one handshake message, for auditing.  This never
+/// includes key material.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandshakeMessageSummary {
+    /// The message's type.
+    pub typ: HandshakeType,
+
+    /// The length of the message, including its four byte header.
+    pub length: usize,
+
+    /// The version in a ClientHello, ServerHello or HelloRetryRequest.
+    pub version: Option<ProtocolVersion>,
+
+    /// The ciphersuite chosen in a ServerHello.
+    pub cipher_suite: Option<CipherSuite>,
+}
+
+impl HandshakeMessageSummary {
+    fn new(hs: &HandshakeMessagePayload, length: usize) -> HandshakeMessageSummary {
+        let (version, cipher_suite) = match hs.payload {
+            HandshakePayload::ClientHello(ref ch) => (Some(ch.client_version), None),
+            HandshakePayload::ServerHello(ref sh) => {
+                (Some(sh.server_version), Some(sh.cipher_suite))
+            }
+            HandshakePayload::HelloRetryRequest(ref hrr) => (Some(hrr.server_version), None),
+            _ => (None, None),
+        };
+
+        HandshakeMessageSummary {
+            typ: hs.typ,
+            length: length,
+            version: version,
+            cipher_suite: cipher_suite,
+        }
+    }
+}
 
 /// This deals with keeping a running hash of the handshake
 /// payloads.  This is computed by buffering initially.  Once
@@ -10,6 +51,7 @@ use msgs::message::{Message, MessagePayload};
 ///
 /// For client auth, we also need to buffer all the messages.
 /// This is disable in cases where client auth is not possible.
+#[derive(Clone)]
 pub struct HandshakeHash {
     /// None before we know what hash function we're using
     alg: Option<&'static digest::Algorithm>,
@@ -22,6 +64,13 @@ pub struct HandshakeHash {
 
     /// buffer for pre-hashing stage and client-auth.
     buffer: Vec<u8>,
+
+    /// what we've hashed, for auditing.
+    summaries: Vec<HandshakeMessageSummary>,
+
+    /// false once the handshake is over: we don't summarise
+    /// the messages which follow it.
+    summarising: bool,
 }
 
 // This hack is needed because rust doesn't provide reference
@@ -40,6 +89,8 @@ impl HandshakeHash {
             ctx: None,
             client_auth_enabled: false,
             buffer: Vec::new(),
+            summaries: Vec::new(),
+            summarising: true,
         }
     }
 
@@ -54,7 +105,13 @@ impl HandshakeHash {
     /// the transcript.
     pub fn abandon_client_auth(&mut self) {
         self.client_auth_enabled = false;
-        self.buffer.drain(..);
+        self.wipe_buffer();
+    }
+
+    /// Overwrite and empty the buffered messages.
+    fn wipe_buffer(&mut self) {
+        util::zero(&mut self.buffer);
+        self.buffer.clear();
     }
 
     /// We now know what hash function the verify_data will use.
@@ -80,17 +137,60 @@ impl HandshakeHash {
 
         // Discard buffer if we don't need it now.
         if !self.client_auth_enabled {
-            self.buffer.drain(..);
+            self.wipe_buffer();
         }
         true
     }
 
+    /// After a HelloRetryRequest, replace the transcript so far --
+    /// which must be just the first ClientHello -- with the synthetic
+    /// `message_hash` message standing in for it.  That's a handshake
+    /// message of type `message_hash`, whose body is the hash of the
+    /// ClientHello using `alg`.  This starts hashing with `alg` if we
+    /// haven't already, and fails if we started with something else.
+    ///
+    /// This is deliberately not wired into the handshake: draft 18,
+    /// which we speak, covers the first ClientHello itself, so nothing
+    /// calls this until we move to a later draft.  `HandshakeHash`
+    /// isn't exported, so this is internal to the crate regardless.
+    #[allow(dead_code)]
+    pub fn rollup_for_hrr(&mut self, alg: &'static digest::Algorithm) -> bool {
+        if let Some(started) = self.alg {
+            if !hash_eq(started, alg) {
+                warn!("altered hash to HandshakeHash::rollup_for_hrr");
+                return false;
+            }
+        }
+
+        let hs = HandshakeMessagePayload {
+            typ: HandshakeType::MessageHash,
+            payload: HandshakePayload::Unknown(Payload::new(self.get_hash_given(alg, &[]))),
+        };
+        let buf = hs.get_encoding();
+
+        let mut ctx = digest::Context::new(alg);
+        ctx.update(&buf);
+        self.alg = Some(alg);
+        self.ctx = Some(ctx);
+
+        self.wipe_buffer();
+        if self.client_auth_enabled {
+            self.buffer.extend_from_slice(&buf);
+        }
+
+        self.summaries = vec![ HandshakeMessageSummary::new(&hs, buf.len()) ];
+        true
+    }
+
     /// Hash/buffer a handshake message.
     pub fn add_message(&mut self, m: &Message) -> &mut HandshakeHash {
         match m.payload {
             MessagePayload::Handshake(ref hs) => {
                 let buf = hs.get_encoding();
                 self.update_raw(&buf);
+                if self.summarising {
+                    self.summaries.push(HandshakeMessageSummary::new(hs, buf.len()));
+                }
             }
             _ => unreachable!(),
         };
@@ -136,6 +236,17 @@ impl HandshakeHash {
         ret
     }
 
+    /// Summarise the messages we've hashed, in order.
+    pub fn get_summaries(&self) -> &[HandshakeMessageSummary] {
+        &self.summaries
+    }
+
+    /// The handshake is over.  Later messages are still hashed,
+    /// in this or a clone of it, but not summarised.
+    pub fn stop_summarising(&mut self) {
+        self.summarising = false;
+    }
+
     /// Takes this object's buffer containing all handshake messages
     /// so far.  This method only works once; it resets the buffer
     /// to empty.
@@ -145,11 +256,27 @@ impl HandshakeHash {
     }
 }
 
+impl Drop for HandshakeHash {
+    fn drop(&mut self) {
+        self.wipe_buffer();
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::HandshakeHash;
+    use msgs::enums::HandshakeType;
     use ring::digest;
 
+    /// The transcript hash after a retry: the synthetic message
+    /// carrying the first ClientHello's hash, then `rest`.
+    fn expected_after_retry(client_hello: &[u8], rest: &[u8]) -> Vec<u8> {
+        let mut synthetic = vec![ 0xfe, 0x00, 0x00, 0x20 ];
+        synthetic.extend_from_slice(digest::digest(&digest::SHA256, client_hello).as_ref());
+        synthetic.extend_from_slice(rest);
+        digest::digest(&digest::SHA256, &synthetic).as_ref().to_vec()
+    }
+
     #[test]
     fn hashes_correctly() {
         let mut hh = HandshakeHash::new();
@@ -202,4 +329,98 @@ mod test {
         assert_eq!(h[2], 0x18);
         assert_eq!(h[3], 0x5c);
     }
+
+    #[test]
+    fn rollup_for_hrr() {
+        let mut hh = HandshakeHash::new();
+        hh.update_raw(b"client hello");
+        assert!(hh.rollup_for_hrr(&digest::SHA256));
+        assert_eq!(hh.buffer.len(), 0);
+        hh.update_raw(b"retry request");
+        assert_eq!(hh.get_current_hash(),
+                   expected_after_retry(b"client hello", b"retry request"));
+
+        let summaries = hh.get_summaries();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].typ, HandshakeType::MessageHash);
+        assert_eq!(summaries[0].length, 4 + 32);
+    }
+
+    #[test]
+    fn rollup_for_hrr_after_start() {
+        let mut hh = HandshakeHash::new();
+        hh.start_hash(&digest::SHA256);
+        hh.update_raw(b"client hello");
+        assert!(hh.rollup_for_hrr(&digest::SHA256));
+        hh.update_raw(b"retry request");
+        assert_eq!(hh.get_current_hash(),
+                   expected_after_retry(b"client hello", b"retry request"));
+    }
+
+    #[test]
+    fn rollup_for_hrr_buffers_synthetic_message() {
+        let mut hh = HandshakeHash::new();
+        hh.set_client_auth_enabled();
+        hh.update_raw(b"client hello");
+        assert!(hh.rollup_for_hrr(&digest::SHA256));
+        hh.update_raw(b"retry request");
+
+        let buf = hh.take_handshake_buf();
+        assert_eq!(&buf[..4], &[ 0xfe, 0x00, 0x00, 0x20 ]);
+        assert_eq!(&buf[4..36], digest::digest(&digest::SHA256, b"client hello").as_ref());
+        assert_eq!(&buf[36..], b"retry request");
+    }
+
+    /// A minimal ClientHello and an RFC8446-style HelloRetryRequest.
+    /// The expected values were computed separately, with Python's
+    /// hashlib.
+    ///
+    /// FIXME: these are not the messages from RFC8448 section 5.  Replace
+    /// them with ClientHello1, the HelloRetryRequest and the transcript
+    /// hash from that trace, transcribed byte for byte.
+    const CLIENT_HELLO1: &'static [u8] = &[
+        0x01, 0x00, 0x00, 0x2b, 0x03, 0x03, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05,
+        0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f, 0x10, 0x11,
+        0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d,
+        0x1e, 0x1f, 0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00, 0x00, 0x00,
+    ];
+    const HELLO_RETRY_REQUEST: &'static [u8] = &[
+        0x02, 0x00, 0x00, 0x2e, 0x03, 0x03, 0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a,
+        0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91, 0xc2, 0xa2,
+        0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8,
+        0x33, 0x9c, 0x00, 0x13, 0x01, 0x00, 0x00, 0x06, 0x00, 0x2b, 0x00, 0x02,
+        0x03, 0x04,
+    ];
+    const SYNTHETIC_MESSAGE_HASH: &'static [u8] = &[
+        0xfe, 0x00, 0x00, 0x20, 0x7d, 0xaf, 0x35, 0xcf, 0x83, 0x1c, 0x5e, 0xe9,
+        0x9a, 0x25, 0xaf, 0xa5, 0x47, 0x5d, 0x47, 0xd1, 0xc3, 0x30, 0xd3, 0x3c,
+        0x9b, 0xc6, 0xa0, 0x2c, 0x97, 0x9a, 0x48, 0xd9, 0x6e, 0x53, 0x85, 0xdc,
+    ];
+    const TRANSCRIPT_HASH: &'static [u8] = &[
+        0x76, 0x16, 0xe5, 0x59, 0x50, 0x82, 0x54, 0xe4, 0xc1, 0x62, 0xb8, 0x71,
+        0xe3, 0xb5, 0x7d, 0x50, 0xf5, 0x87, 0x9d, 0x6e, 0xa1, 0x0e, 0x7f, 0x93,
+        0xb6, 0xca, 0xd5, 0x25, 0xda, 0x7f, 0xdf, 0xa6,
+    ];
+
+    #[test]
+    fn rollup_for_hrr_known_answer() {
+        let mut hh = HandshakeHash::new();
+        hh.set_client_auth_enabled();
+        hh.update_raw(CLIENT_HELLO1);
+        assert!(hh.rollup_for_hrr(&digest::SHA256));
+        hh.update_raw(HELLO_RETRY_REQUEST);
+        assert_eq!(hh.get_current_hash(), TRANSCRIPT_HASH.to_vec());
+
+        let buf = hh.take_handshake_buf();
+        assert_eq!(&buf[..36], SYNTHETIC_MESSAGE_HASH);
+        assert_eq!(&buf[36..], HELLO_RETRY_REQUEST);
+    }
+
+    #[test]
+    fn rollup_for_hrr_rejects_hash_change() {
+        let mut hh = HandshakeHash::new();
+        hh.start_hash(&digest::SHA384);
+        hh.update_raw(b"client hello");
+        assert!(!hh.rollup_for_hrr(&digest::SHA256));
+    }
 }
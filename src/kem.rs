@@ -0,0 +1,238 @@
+// Hybrid post-quantum key exchange.
+//
+// A hybrid group combines a classical ECDH group with a key
+// encapsulation mechanism (KEM).  Each key share is the
+// concatenation of the two halves, as is the resulting shared
+// secret.  The connection is secure if either half is.
+//
+// We don't implement the KEM ourselves: callers provide one
+// through `KeyEncapsulation`.
+
+use msgs::enums::NamedGroup;
+use suites::{KeyExchange, KeyExchangeResult};
+use rand::SecureRandom;
+use util;
+
+/// A key encapsulation mechanism, used for the post-quantum
+/// half of a hybrid key exchange group.
+///
+/// Currently the only hybrid group is `X25519MLKEM768`, so
+/// implementations must provide ML-KEM-768.
+pub trait KeyEncapsulation : Send + Sync {
+    /// Length in bytes of an encapsulation (public) key.
+    fn encapsulation_key_len(&self) -> usize;
+
+    /// Length in bytes of a ciphertext.
+    fn ciphertext_len(&self) -> usize;
+
+    /// Generate a new keypair.  Returns the encapsulation key
+    /// and the decapsulation (secret) key, or None on failure.
+    fn generate(&self) -> Option<(Vec<u8>, Vec<u8>)>;
+
+    /// Encapsulate a fresh shared secret to `encapsulation_key`.
+    /// Returns the ciphertext and the shared secret, or None
+    /// if `encapsulation_key` is invalid.
+    fn encapsulate(&self, encapsulation_key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)>;
+
+    /// Recover the shared secret from `ciphertext` using
+    /// `decapsulation_key`.  Returns None on failure.
+    fn decapsulate(&self, decapsulation_key: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Return the classical half of `group`, if it's a hybrid group.
+pub fn classical_group(group: NamedGroup) -> Option<NamedGroup> {
+    match group {
+        NamedGroup::X25519MLKEM768 => Some(NamedGroup::X25519),
+        _ => None,
+    }
+}
+
+/// Returns true if `group` is a hybrid group.
+pub fn is_hybrid(group: NamedGroup) -> bool {
+    classical_group(group).is_some()
+}
+
+/// Join the post-quantum and classical halves of a key share
+/// or shared secret.  For X25519MLKEM768 the KEM half comes first.
+fn join(pq: &[u8], classical: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(pq.len() + classical.len());
+    ret.extend_from_slice(pq);
+    ret.extend_from_slice(classical);
+    ret
+}
+
+/// Split `share` into its post-quantum and classical halves,
+/// given the length of the post-quantum half.
+fn split(share: &[u8], pq_len: usize) -> Option<(&[u8], &[u8])> {
+    if share.len() <= pq_len {
+        return None;
+    }
+
+    Some(share.split_at(pq_len))
+}
+
+/// A KEM decapsulation key, overwritten when dropped.
+struct DecapsulationKey(Vec<u8>);
+
+impl Drop for DecapsulationKey {
+    fn drop(&mut self) {
+        util::zero(&mut self.0);
+    }
+}
+
+/// A client's in-progress hybrid key exchange.
+pub struct HybridKeyExchange {
+    pub group: NamedGroup,
+    classical: KeyExchange,
+    decapsulation_key: DecapsulationKey,
+    pub pubkey: Vec<u8>,
+}
+
+impl HybridKeyExchange {
+    /// Start a hybrid key exchange in `group`, making our key share.
+    pub fn start(group: NamedGroup,
+                 kem: &KeyEncapsulation,
+                 rng: &SecureRandom)
+                 -> Option<HybridKeyExchange> {
+        let classical_group = try_ret!(classical_group(group));
+        let classical = try_ret!(KeyExchange::start_ecdhe(classical_group, rng));
+        let (encapsulation_key, decapsulation_key) = try_ret!(kem.generate());
+
+        if encapsulation_key.len() != kem.encapsulation_key_len() {
+            return None;
+        }
+
+        let pubkey = join(&encapsulation_key, &classical.pubkey);
+
+        Some(HybridKeyExchange {
+            group: group,
+            classical: classical,
+            decapsulation_key: DecapsulationKey(decapsulation_key),
+            pubkey: pubkey,
+        })
+    }
+
+    /// Complete the key exchange, given the server's key share `peer`.
+    pub fn complete(self, kem: &KeyEncapsulation, peer: &[u8]) -> Option<KeyExchangeResult> {
+        let (ciphertext, classical_peer) = try_ret!(split(peer, kem.ciphertext_len()));
+        let mut pq_secret = try_ret!(kem.decapsulate(&self.decapsulation_key.0, ciphertext));
+        let result = match self.classical.complete(classical_peer) {
+            Some(classical) => {
+                Some(KeyExchangeResult {
+                    pubkey: self.pubkey,
+                    premaster_secret: join(&pq_secret, &classical.premaster_secret),
+                })
+            }
+            None => None,
+        };
+
+        util::zero(&mut pq_secret);
+        result
+    }
+}
+
+/// Do the server's half of a hybrid key exchange in `group`,
+/// given the client's key share `peer`.  The result contains
+/// our key share and the shared secret.
+pub fn server_complete(group: NamedGroup,
+                       kem: &KeyEncapsulation,
+                       peer: &[u8],
+                       rng: &SecureRandom)
+                       -> Option<KeyExchangeResult> {
+    let classical_group = try_ret!(classical_group(group));
+    let (encapsulation_key, classical_peer) = try_ret!(split(peer, kem.encapsulation_key_len()));
+    let (ciphertext, mut pq_secret) = try_ret!(kem.encapsulate(encapsulation_key));
+
+    let result = if ciphertext.len() == kem.ciphertext_len() {
+        KeyExchange::start_ecdhe(classical_group, rng)
+            .and_then(|kx| kx.complete(classical_peer))
+            .map(|classical| KeyExchangeResult {
+                pubkey: join(&ciphertext, &classical.pubkey),
+                premaster_secret: join(&pq_secret, &classical.premaster_secret),
+            })
+    } else {
+        None
+    };
+
+    util::zero(&mut pq_secret);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use msgs::enums::NamedGroup;
+    use rand::SystemRandom;
+
+    /// A wildly insecure KEM, for testing.  The 'encapsulation
+    /// key' is the secret key; the ciphertext is its reverse,
+    /// and the shared secret is the secret key again.
+    struct MockKEM {}
+
+    impl KeyEncapsulation for MockKEM {
+        fn encapsulation_key_len(&self) -> usize { 4 }
+        fn ciphertext_len(&self) -> usize { 4 }
+
+        fn generate(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+            Some((vec![ 1, 2, 3, 4 ], vec![ 1, 2, 3, 4 ]))
+        }
+
+        fn encapsulate(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+            let mut ct = key.to_vec();
+            ct.reverse();
+            Some((ct, key.to_vec()))
+        }
+
+        fn decapsulate(&self, key: &[u8], ct: &[u8]) -> Option<Vec<u8>> {
+            let mut expect = key.to_vec();
+            expect.reverse();
+            if expect == ct { Some(key.to_vec()) } else { None }
+        }
+    }
+
+    #[test]
+    fn test_share_encoding() {
+        let rng = SystemRandom::new();
+        let kx = HybridKeyExchange::start(NamedGroup::X25519MLKEM768, &MockKEM {}, &rng)
+            .unwrap();
+        assert_eq!(kx.pubkey.len(), 4 + 32);
+        assert_eq!(&kx.pubkey[..4], &[ 1, 2, 3, 4 ]);
+
+        let server = server_complete(NamedGroup::X25519MLKEM768, &MockKEM {}, &kx.pubkey, &rng)
+            .unwrap();
+        assert_eq!(server.pubkey.len(), 4 + 32);
+        assert_eq!(&server.pubkey[..4], &[ 4, 3, 2, 1 ]);
+    }
+
+    #[test]
+    fn test_shared_secret() {
+        let rng = SystemRandom::new();
+        let kx = HybridKeyExchange::start(NamedGroup::X25519MLKEM768, &MockKEM {}, &rng)
+            .unwrap();
+        let server = server_complete(NamedGroup::X25519MLKEM768, &MockKEM {}, &kx.pubkey, &rng)
+            .unwrap();
+        let client = kx.complete(&MockKEM {}, &server.pubkey).unwrap();
+
+        assert_eq!(client.premaster_secret, server.premaster_secret);
+        assert_eq!(client.premaster_secret.len(), 4 + 32);
+        assert_eq!(&client.premaster_secret[..4], &[ 1, 2, 3, 4 ]);
+    }
+
+    #[test]
+    fn test_truncated_shares() {
+        let rng = SystemRandom::new();
+        let kx = HybridKeyExchange::start(NamedGroup::X25519MLKEM768, &MockKEM {}, &rng)
+            .unwrap();
+        assert!(server_complete(NamedGroup::X25519MLKEM768, &MockKEM {}, &kx.pubkey[..4], &rng)
+                .is_none());
+        assert!(kx.complete(&MockKEM {}, &[ 4, 3, 2, 1 ]).is_none());
+    }
+
+    #[test]
+    fn test_not_hybrid() {
+        let rng = SystemRandom::new();
+        assert!(HybridKeyExchange::start(NamedGroup::X25519, &MockKEM {}, &rng).is_none());
+        assert!(!is_hybrid(NamedGroup::secp256r1));
+        assert!(is_hybrid(NamedGroup::X25519MLKEM768));
+    }
+}
@@ -2,11 +2,13 @@
 
 use ring::{hmac, digest, hkdf};
 use msgs::codec;
+use util;
 
 /// The kinds of secret we can extract from `KeySchedule`.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SecretKind {
     ResumptionPSKBinderKey,
+    ClientEarlyTrafficSecret,
     ClientHandshakeTrafficSecret,
     ServerHandshakeTrafficSecret,
     ClientApplicationTrafficSecret,
@@ -18,6 +20,7 @@ impl SecretKind {
     fn to_bytes(&self) -> &'static [u8] {
         match *self {
             SecretKind::ResumptionPSKBinderKey => b"resumption psk binder key",
+            SecretKind::ClientEarlyTrafficSecret => b"client early traffic secret",
             SecretKind::ClientHandshakeTrafficSecret => b"client handshake traffic secret",
             SecretKind::ServerHandshakeTrafficSecret => b"server handshake traffic secret",
             SecretKind::ClientApplicationTrafficSecret => b"client application traffic secret",
@@ -29,9 +32,10 @@ impl SecretKind {
 
 /// This is the TLS1.3 key schedule.  It stores the current secret,
 /// the type of hash, plus the two current traffic keys which form their
-/// own lineage of keys over successive key updates.
+/// own lineage of keys over successive key updates.  These are all
+/// overwritten when it's dropped.
 pub struct KeySchedule {
-    current: hmac::SigningKey,
+    current: Vec<u8>,
     hash: &'static digest::Algorithm,
     pub current_client_traffic_secret: Vec<u8>,
     pub current_server_traffic_secret: Vec<u8>,
@@ -39,15 +43,21 @@ pub struct KeySchedule {
 
 impl KeySchedule {
     pub fn new(hash: &'static digest::Algorithm) -> KeySchedule {
-        let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
         KeySchedule {
-            current: hmac::SigningKey::new(hash, &zeroes[..hash.output_len]),
+            current: vec![ 0u8; hash.output_len ],
             hash: hash,
             current_server_traffic_secret: Vec::new(),
             current_client_traffic_secret: Vec::new(),
         }
     }
 
+    /// Overwrite the secrets we hold.
+    pub fn wipe(&mut self) {
+        util::zero(&mut self.current);
+        util::zero(&mut self.current_client_traffic_secret);
+        util::zero(&mut self.current_server_traffic_secret);
+    }
+
     /// Input the empty secret.
     pub fn input_empty(&mut self) {
         let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
@@ -55,17 +65,19 @@ impl KeySchedule {
         self.input_secret(&zeroes[..hash_len]);
     }
 
-    /// Input the given secret.
+    /// Input the given secret.  This is HKDF-Extract, with the
+    /// current secret as the salt.
     pub fn input_secret(&mut self, secret: &[u8]) {
-        let new = hkdf::extract(&self.current, secret);
-        self.current = new
+        let new = hmac::sign(&hmac::SigningKey::new(self.hash, &self.current), secret);
+        util::zero(&mut self.current);
+        self.current = new.as_ref().to_vec();
     }
 
     /// Derive a secret of given `kind`, using current handshake hash `hs_hash`.
     pub fn derive(&self, kind: SecretKind, hs_hash: &[u8]) -> Vec<u8> {
         debug_assert!(hs_hash.len() == self.hash.output_len);
 
-        _hkdf_expand_label(&self.current,
+        _hkdf_expand_label(&hmac::SigningKey::new(self.hash, &self.current),
                            kind.to_bytes(),
                            hs_hash,
                            self.hash.output_len as u16)
@@ -117,6 +129,12 @@ impl KeySchedule {
     }
 }
 
+impl Drop for KeySchedule {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
 fn _hkdf_expand_label(secret: &hmac::SigningKey,
                       label: &[u8],
                       context: &[u8],
@@ -172,4 +190,30 @@ mod test {
                   &fake_handshake_hash);
         ks.derive(SecretKind::ResumptionMasterSecret, &fake_handshake_hash);
     }
+
+    #[test]
+    fn input_secret_is_hkdf_extract() {
+        // RFC5869, test case 1.
+        let mut ks = KeySchedule::new(&digest::SHA256);
+        ks.current = (0u8..13).collect();
+        ks.input_secret(&[ 0x0b; 22 ]);
+        assert_eq!(ks.current,
+                   vec![ 0x07, 0x77, 0x09, 0x36, 0x2c, 0x2e, 0x32, 0xdf,
+                         0x0d, 0xdc, 0x3f, 0x0d, 0xc4, 0x7b, 0xba, 0x63,
+                         0x90, 0xb6, 0xc7, 0x3b, 0xb5, 0x0f, 0x9c, 0x31,
+                         0x22, 0xec, 0x84, 0x4a, 0xd7, 0xc2, 0xb3, 0xe5 ]);
+    }
+
+    #[test]
+    fn wipe_overwrites_all_secrets() {
+        let mut ks = KeySchedule::new(&digest::SHA256);
+        ks.input_secret(&[ 1u8; 32 ]);
+        ks.current_client_traffic_secret = vec![ 2u8; 32 ];
+        ks.current_server_traffic_secret = vec![ 3u8; 32 ];
+
+        ks.wipe();
+        assert_eq!(ks.current, vec![ 0u8; 32 ]);
+        assert_eq!(ks.current_client_traffic_secret, vec![ 0u8; 32 ]);
+        assert_eq!(ks.current_server_traffic_secret, vec![ 0u8; 32 ]);
+    }
 }
@@ -172,11 +172,18 @@
 //!   such as replacing the certificate verification process.  Applications
 //!   requesting this feature should be reviewed carefully.
 //!
+//! - `dangerous_debug`: this feature enables
+//!   `Session::dangerous_extract_secrets()`, which hands out a session's
+//!   secrets for offline analysis of traffic captures.  It's for protocol
+//!   research only: it must never be enabled in production builds.
+//!
 
-// Require docs for public APIs, deny unsafe code, etc.
-#![forbid(unsafe_code,
-          unstable_features)]
-#![deny(trivial_casts,
+// Require docs for public APIs, deny unsafe code, etc.  Unsafe
+// code is denied rather than forbidden so `util::zero` can make
+// writes the optimiser won't remove.
+#![forbid(unstable_features)]
+#![deny(unsafe_code,
+        trivial_casts,
         trivial_numeric_casts,
         missing_docs,
         unused_import_braces,
@@ -208,6 +215,7 @@ extern crate log;
 #[cfg(not(feature = "logging"))]
 #[macro_use]
 mod compile_out_log {
+    macro_rules! trace    ( ($($tt:tt)*) => {{}} );
     macro_rules! debug    ( ($($tt:tt)*) => {{}} );
     macro_rules! info     ( ($($tt:tt)*) => {{}} );
     macro_rules! warn     ( ($($tt:tt)*) => {{}} );
@@ -227,6 +235,7 @@ mod cipher;
 mod key_schedule;
 mod session;
 mod stream;
+mod split;
 mod pemfile;
 mod x509;
 mod anchors;
@@ -237,6 +246,10 @@ mod handshake;
 mod server_hs;
 mod client_hs;
 mod suites;
+mod kem;
+mod compress;
+mod delegated;
+mod sni;
 mod ticketer;
 mod server;
 mod client;
@@ -258,20 +271,33 @@ pub mod internal {
 // The public interface is:
 pub use msgs::enums::ProtocolVersion;
 pub use msgs::enums::SignatureScheme;
+pub use msgs::enums::NamedGroup;
+pub use msgs::enums::AlertDescription;
+pub use msgs::enums::CipherSuite;
+pub use msgs::enums::CertificateCompressionAlgorithm;
 pub use error::TLSError;
-pub use session::Session;
+pub use session::{Session, Resumption};
 pub use stream::Stream;
+pub use split::{SessionReader, SessionWriter};
 pub use anchors::RootCertStore;
 pub use client::{StoresClientSessions, ClientSessionMemoryCache};
 pub use client::{ClientConfig, ClientSession};
 pub use client::ResolvesClientCert;
 pub use server::{StoresServerSessions, ServerSessionMemoryCache};
 pub use server::{ServerConfig, ServerSession};
-pub use server::ResolvesServerCert;
+pub use server::{ResolvesServerCert, ClientHello, MissingSNIPolicy};
 pub use server::ProducesTickets;
+pub use server::{ReplayProtection, ReplayCache};
 pub use ticketer::Ticketer;
 pub use suites::{ALL_CIPHERSUITES, SupportedCipherSuite};
+pub use kem::KeyEncapsulation;
+pub use compress::CertCompressor;
+pub use delegated::DelegatedCredential;
 pub use key::{Certificate, PrivateKey};
+pub use verify::{verify_chain, TimeProvider, SystemTimeProvider};
+pub use rand::{SecureRandom, SystemRandom};
+pub use sni::read_sni;
+pub use hash_hs::HandshakeMessageSummary;
 
 /// Message signing interfaces and implementations.
 pub mod sign;
@@ -282,3 +308,5 @@ pub use verify::{ServerCertVerifier, ClientCertVerifier};
 pub use client::danger::DangerousClientConfig;
 #[cfg(feature = "dangerous_configuration")]
 pub use server::danger::DangerousServerConfig;
+#[cfg(feature = "dangerous_debug")]
+pub use session::ExtractedSecrets;
@@ -161,6 +161,20 @@ pub fn read_u64(r: &mut Reader) -> Option<u64> {
     r.take(8).and_then(decode_u64)
 }
 
+/// Encode a fixed-length field, like a random: just its bytes,
+/// with no length prefix.
+pub fn encode_fixed(v: &[u8], bytes: &mut Vec<u8>) {
+    bytes.extend_from_slice(v);
+}
+
+/// Fill all of `out` from `r`, for reading a fixed-length field.
+/// This returns None if `r` has too few bytes left.
+pub fn read_fixed(r: &mut Reader, out: &mut [u8]) -> Option<()> {
+    let bytes = try_ret!(r.take(out.len()));
+    out.copy_from_slice(bytes);
+    Some(())
+}
+
 pub fn encode_vec_u8<T: Codec>(bytes: &mut Vec<u8>, items: &[T]) {
     let mut sub: Vec<u8> = Vec::new();
     for i in items {
@@ -218,6 +232,23 @@ pub fn read_vec_u16<T: Codec>(r: &mut Reader) -> Option<Vec<T>> {
     Some(ret)
 }
 
+/// Like `read_vec_u16`, but fails if there are more than `max_items`
+/// items.
+pub fn read_vec_u16_max_items<T: Codec>(r: &mut Reader, max_items: usize) -> Option<Vec<T>> {
+    let mut ret: Vec<T> = Vec::new();
+    let len = try_ret!(read_u16(r)) as usize;
+    let mut sub = try_ret!(r.sub(len));
+
+    while sub.any_left() {
+        if ret.len() == max_items {
+            return None;
+        }
+        ret.push(try_ret!(T::read(&mut sub)));
+    }
+
+    Some(ret)
+}
+
 pub fn read_vec_u24_limited<T: Codec>(r: &mut Reader, max_bytes: usize) -> Option<Vec<T>> {
     let mut ret: Vec<T> = Vec::new();
     let len = try_ret!(read_u24(r)) as usize;
@@ -233,3 +264,36 @@ pub fn read_vec_u24_limited<T: Codec>(r: &mut Reader, max_bytes: usize) -> Optio
 
     Some(ret)
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Reader, encode_fixed, read_fixed};
+
+    #[test]
+    fn fixed_round_trip() {
+        let mut random = [0u8; 32];
+        for i in 0..32 {
+            random[i] = i as u8;
+        }
+
+        let mut bytes = Vec::new();
+        encode_fixed(&random, &mut bytes);
+        bytes.push(0xff);
+        assert_eq!(bytes.len(), 33);
+
+        let mut rd = Reader::init(&bytes);
+        let mut out = [0u8; 32];
+        assert_eq!(read_fixed(&mut rd, &mut out), Some(()));
+        assert_eq!(out, random);
+        assert_eq!(rd.rest(), &[ 0xff ]);
+    }
+
+    #[test]
+    fn fixed_too_short() {
+        let bytes = [0x01u8; 31];
+        let mut rd = Reader::init(&bytes);
+        let mut out = [0u8; 32];
+        assert_eq!(read_fixed(&mut rd, &mut out), None);
+        assert_eq!(rd.used(), 0);
+    }
+}
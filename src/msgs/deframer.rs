@@ -1,6 +1,7 @@
 
 use std::collections::VecDeque;
 use std::io;
+use std::mem;
 
 use msgs::codec;
 use msgs::codec::Codec;
@@ -13,6 +14,11 @@ const HEADER_SIZE: usize = 1 + 2 + 2;
 /// for ciphertext overheads.
 const MAX_MESSAGE: usize = 16384 + 2048 + HEADER_SIZE;
 
+/// How many frames' worth of space we keep once the caller has
+/// taken them all.  Beyond this, a burst of messages would leave
+/// us holding its memory indefinitely.
+const FRAMES_RETAINED: usize = 16;
+
 /// This deframer works to reconstruct TLS messages
 /// from arbitrary-sized reads, buffering as neccessary.
 /// The input is `read()`, the output is the `frames` deque.
@@ -25,9 +31,23 @@ pub struct MessageDeframer {
     /// the deframer cannot recover.
     pub desynced: bool,
 
-    /// A variable-size buffer containing the currently-
-    /// accumulating TLS message.
+    /// Set to true, along with `desynced`, if the peer started with
+    /// an SSLv2-format ClientHello.  We don't support SSLv2 framing,
+    /// even for ClientHellos offering later versions.
+    pub sslv2_client_hello: bool,
+
+    /// Have we deframed any messages yet?
+    seen_message: bool,
+
+    /// A buffer containing the currently-accumulating TLS message
+    /// in its first `used` bytes.  The rest is space for reads: we
+    /// zero it once, when it's first needed, rather than per read.
+    /// It may still hold bytes of earlier messages, so nothing may
+    /// look beyond `used`.
     buf: Vec<u8>,
+
+    /// How much of `buf` is data.
+    used: usize,
 }
 
 impl MessageDeframer {
@@ -35,35 +55,86 @@ impl MessageDeframer {
         MessageDeframer {
             frames: VecDeque::new(),
             desynced: false,
+            sslv2_client_hello: false,
+            seen_message: false,
             buf: Vec::with_capacity(MAX_MESSAGE),
+            used: 0,
         }
     }
 
+    /// Discard all buffered data and frames, keeping our
+    /// allocations.
+    pub fn reset(&mut self) {
+        self.frames.clear();
+        self.desynced = false;
+        self.sslv2_client_hello = false;
+        self.seen_message = false;
+        self.used = 0;
+    }
+
     /// Read some bytes from `rd`, and add them to our internal
     /// buffer.  If this means our internal buffer contains
     /// full messages, decode them all.
-    pub fn read(&mut self, rd: &mut io::Read) -> io::Result<usize> {
+    ///
+    /// `rd` may be any reader, including a trait object.
+    pub fn read<R: io::Read + ?Sized>(&mut self, rd: &mut R) -> io::Result<usize> {
+        // If we buffered a burst of frames which have all been taken
+        // since, give back the space beyond what we retain.
+        if self.frames.is_empty() && self.frames.capacity() > FRAMES_RETAINED {
+            self.frames = VecDeque::with_capacity(FRAMES_RETAINED);
+        }
+
         // Try to do the largest reads possible.  Note that if
         // we get a message with a length field out of range here,
         // we do a zero length read.  That looks like an EOF to
         // the next layer up, which is fine.
-        let used = self.buf.len();
-        self.buf.resize(MAX_MESSAGE, 0u8);
-        let rc = rd.read(&mut self.buf[used..MAX_MESSAGE]);
-
-        if rc.is_err() {
-            // Discard indeterminate bytes.
-            self.buf.truncate(used);
-            return rc;
+        //
+        // `rd` may write anywhere in the space we give it, but only
+        // the bytes it says it read become data.  On error, none do.
+        if self.buf.len() < MAX_MESSAGE {
+            self.buf.resize(MAX_MESSAGE, 0u8);
         }
+        let new_bytes = rd.read(&mut self.buf[self.used..])?;
+        debug_assert!(self.used + new_bytes <= self.buf.len());
+        self.used += new_bytes;
+        self.deframe_buffered();
+        Ok(new_bytes)
+    }
 
-        let new_bytes = rc.unwrap();
-        self.buf.truncate(used + new_bytes);
+    /// Take the bytes of any partial message we've buffered, for
+    /// giving to another deframer with `prepend`.  Completed frames
+    /// stay in `frames`; see `take_frames`.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        let buf = self.buf[..self.used].to_vec();
+        self.used = 0;
+        buf
+    }
+
+    /// Take our completed frames, leaving `frames` empty.
+    pub fn take_frames(&mut self) -> VecDeque<Message> {
+        mem::replace(&mut self.frames, VecDeque::new())
+    }
 
+    /// Put `bytes` in front of anything we've buffered, as if we'd
+    /// read them first, and decode any messages that completes.
+    /// This takes bytes from another deframer's `take_buffered`.
+    pub fn prepend(&mut self, bytes: &[u8]) {
+        let mut buf = Vec::with_capacity(MAX_MESSAGE);
+        buf.extend_from_slice(bytes);
+        buf.extend_from_slice(&self.buf[..self.used]);
+        self.used = buf.len();
+        self.buf = buf;
+        self.deframe_buffered();
+    }
+
+    /// Decode all the whole messages in `buf`.
+    fn deframe_buffered(&mut self) {
         loop {
             match self.buf_contains_message() {
                 None => {
                     self.desynced = true;
+                    self.sslv2_client_hello = !self.seen_message &&
+                                              is_sslv2_client_hello(&self.buf[..self.used]);
                     break;
                 }
                 Some(true) => {
@@ -72,26 +143,32 @@ impl MessageDeframer {
                 Some(false) => break,
             }
         }
+    }
 
-        Ok(new_bytes)
+    /// Give back as much memory as we can, while keeping any
+    /// buffered frames and data.
+    pub fn shrink_to_fit(&mut self) {
+        self.frames.shrink_to_fit();
+        self.buf.truncate(self.used);
+        self.buf.shrink_to_fit();
     }
 
     /// Returns true if we have messages for the caller
     /// to process, either whole messages in our output
     /// queue or partial messages in our buffer.
     pub fn has_pending(&self) -> bool {
-        !self.frames.is_empty() || !self.buf.is_empty()
+        !self.frames.is_empty() || self.used > 0
     }
 
     /// Does our `buf` contain a full message?  It does if it is big enough to
     /// contain a header, and that header has a length which falls within `buf`.
     /// This returns None if it contains a header which is invalid.
     fn buf_contains_message(&self) -> Option<bool> {
-        if self.buf.len() < HEADER_SIZE {
+        if self.used < HEADER_SIZE {
             return Some(false);
         }
 
-        let len_maybe = Message::check_header(&self.buf);
+        let len_maybe = Message::check_header(&self.buf[..self.used]);
 
         // Header damaged.
         if len_maybe == None {
@@ -105,28 +182,43 @@ impl MessageDeframer {
             return None;
         }
 
-        let full_message = self.buf.len() >= len + HEADER_SIZE;
+        let full_message = self.used >= len + HEADER_SIZE;
         Some(full_message)
     }
 
     /// Take a TLS message off the front of `buf`, and put it onto the back
     /// of our `frames` deque.
     fn deframe_one(&mut self) {
-        let used = {
-            let mut rd = codec::Reader::init(&self.buf);
+        let taken = {
+            let mut rd = codec::Reader::init(&self.buf[..self.used]);
             let m = Message::read(&mut rd).unwrap();
             self.frames.push_back(m);
             rd.used()
         };
-        self.buf = self.buf.split_off(used);
+
+        // Move the rest of our data to the front.
+        self.buf[..self.used].rotate_left(taken);
+        self.used -= taken;
+        self.seen_message = true;
     }
 }
 
+/// Does `buf` start like an SSLv2-format ClientHello?  That has a
+/// two byte length with its top bit set, then a message type of 1,
+/// then SSLv2 or an SSLv3-compatible version.
+fn is_sslv2_client_hello(buf: &[u8]) -> bool {
+    buf.len() >= HEADER_SIZE &&
+    buf[0] & 0x80 == 0x80 &&
+    buf[2] == 0x01 &&
+    (buf[3..5] == [ 0x00, 0x02 ] || buf[3] == 0x03)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::MessageDeframer;
+    use super::{MessageDeframer, MAX_MESSAGE, FRAMES_RETAINED};
     use std::io;
     use msgs;
+    use msgs::codec::Codec;
 
     const FIRST_MESSAGE: &'static [u8] = include_bytes!("deframer-test.1.bin");
     const SECOND_MESSAGE: &'static [u8] = include_bytes!("deframer-test.2.bin");
@@ -160,11 +252,41 @@ mod tests {
         }
     }
 
+    /// Gives out `bytes` at most `chunk` at a time, scribbling over
+    /// the rest of each buffer it's given.
+    struct ScribblingRead<'a> {
+        bytes: &'a [u8],
+        chunk: usize,
+    }
+
+    impl<'a> io::Read for ScribblingRead<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            for b in buf.iter_mut() {
+                *b = 0xaa;
+            }
+
+            let mut len = 0;
+            while len < buf.len() && len < self.chunk && len < self.bytes.len() {
+                buf[len] = self.bytes[len];
+                len += 1;
+            }
+
+            self.bytes = &self.bytes[len..];
+            Ok(len)
+        }
+    }
+
     fn input_bytes(d: &mut MessageDeframer, bytes: &[u8]) -> io::Result<usize> {
         let mut rd = ByteRead::new(bytes);
         d.read(&mut rd)
     }
 
+    fn input_bytes_dyn(d: &mut MessageDeframer, bytes: &[u8]) -> io::Result<usize> {
+        let mut rd = ByteRead::new(bytes);
+        let rd: &mut io::Read = &mut rd;
+        d.read(rd)
+    }
+
     fn input_whole_incremental(d: &mut MessageDeframer, bytes: &[u8]) {
         let frames_before = d.frames.len();
 
@@ -237,6 +359,121 @@ mod tests {
         assert_eq!(d.has_pending(), false);
     }
 
+    #[test]
+    fn check_generic_read() {
+        let mut d = MessageDeframer::new();
+        let mut rd = ByteRead::new(FIRST_MESSAGE);
+        assert_len(FIRST_MESSAGE.len(), d.read::<ByteRead>(&mut rd));
+        assert_len(0, d.read::<ByteRead>(&mut rd));
+        pop_first(&mut d);
+
+        // Trait objects work too, with the same results.
+        assert_len(FIRST_MESSAGE.len(), input_bytes_dyn(&mut d, FIRST_MESSAGE));
+        assert_len(SECOND_MESSAGE.len(), input_bytes_dyn(&mut d, SECOND_MESSAGE));
+        assert_eq!(d.frames.len(), 2);
+        pop_first(&mut d);
+        pop_second(&mut d);
+        assert_eq!(d.has_pending(), false);
+    }
+
+    #[test]
+    fn check_take_buffered_and_prepend() {
+        let split = FIRST_MESSAGE.len() / 2;
+        let mut d = MessageDeframer::new();
+        assert_len(SECOND_MESSAGE.len(), input_bytes(&mut d, SECOND_MESSAGE));
+        assert_len(split, input_bytes(&mut d, &FIRST_MESSAGE[..split]));
+        assert_eq!(d.frames.len(), 1);
+
+        let partial = d.take_buffered();
+        assert_eq!(&partial[..], &FIRST_MESSAGE[..split]);
+        let mut frames = d.take_frames();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(d.has_pending(), false);
+
+        let mut fresh = MessageDeframer::new();
+        fresh.prepend(&partial);
+        assert_eq!(fresh.frames.len(), 0);
+        assert_eq!(fresh.has_pending(), true);
+        assert_len(FIRST_MESSAGE.len() - split,
+                   input_bytes(&mut fresh, &FIRST_MESSAGE[split..]));
+        assert_eq!(fresh.frames.len(), 1);
+        pop_first(&mut fresh);
+        assert_eq!(fresh.has_pending(), false);
+
+        fresh.frames.append(&mut frames);
+        pop_second(&mut fresh);
+    }
+
+    #[test]
+    fn check_short_reads_frame_only_read_bytes() {
+        let mut both = Vec::new();
+        both.extend_from_slice(FIRST_MESSAGE);
+        both.extend_from_slice(SECOND_MESSAGE);
+
+        for chunk in &[ 1, 3, 7, 100 ] {
+            let mut d = MessageDeframer::new();
+            let mut rd = ScribblingRead { bytes: &both, chunk: *chunk };
+            while d.read(&mut rd).unwrap() > 0 {}
+
+            assert_eq!(d.frames.len(), 2);
+            assert_eq!(d.has_pending(), true);
+            let mut first = Vec::new();
+            d.frames.pop_front().unwrap().encode(&mut first);
+            let mut second = Vec::new();
+            d.frames.pop_front().unwrap().encode(&mut second);
+            assert_eq!(first, FIRST_MESSAGE.to_vec());
+            assert_eq!(second, SECOND_MESSAGE.to_vec());
+            assert_eq!(d.has_pending(), false);
+        }
+    }
+
+    #[test]
+    fn check_shrink_to_fit() {
+        let mut d = MessageDeframer::new();
+        let mut many = Vec::new();
+        for _ in 0..64 {
+            many.extend_from_slice(SECOND_MESSAGE);
+        }
+        assert_len(many.len(), input_bytes(&mut d, &many));
+        assert_eq!(d.frames.len(), 64);
+
+        while !d.frames.is_empty() {
+            pop_second(&mut d);
+        }
+        let grown = d.frames.capacity();
+        assert!(grown >= 64);
+
+        d.shrink_to_fit();
+        assert!(d.frames.capacity() < grown);
+        assert!(d.buf.capacity() < MAX_MESSAGE);
+
+        // We still work afterwards.
+        assert_len(FIRST_MESSAGE.len(), input_bytes(&mut d, FIRST_MESSAGE));
+        pop_first(&mut d);
+        assert_eq!(d.has_pending(), false);
+    }
+
+    #[test]
+    fn check_idle_frames_shrink() {
+        let mut d = MessageDeframer::new();
+        let mut many = Vec::new();
+        for _ in 0..64 {
+            many.extend_from_slice(SECOND_MESSAGE);
+        }
+        assert_len(many.len(), input_bytes(&mut d, &many));
+        while !d.frames.is_empty() {
+            pop_second(&mut d);
+        }
+        let grown = d.frames.capacity();
+
+        // The next read notices we're holding space for nothing, but
+        // keeps enough for an ordinary flight.
+        assert_len(FIRST_MESSAGE.len(), input_bytes(&mut d, FIRST_MESSAGE));
+        assert!(d.frames.capacity() < grown);
+        assert!(d.frames.capacity() >= FRAMES_RETAINED);
+        pop_first(&mut d);
+    }
+
     #[test]
     fn check_whole_2() {
         let mut d = MessageDeframer::new();
@@ -248,4 +485,48 @@ mod tests {
         pop_second(&mut d);
         assert_eq!(d.has_pending(), false);
     }
+
+    #[test]
+    fn check_mixed_types_in_one_read() {
+        let mut both = Vec::new();
+        both.extend_from_slice(FIRST_MESSAGE);
+        both.extend_from_slice(SECOND_MESSAGE);
+
+        let mut d = MessageDeframer::new();
+        assert_len(both.len(), input_bytes(&mut d, &both));
+        assert_eq!(d.desynced, false);
+        assert_eq!(d.frames.len(), 2);
+        pop_first(&mut d);
+        pop_second(&mut d);
+        assert_eq!(d.has_pending(), false);
+    }
+
+    #[test]
+    fn check_sslv2_client_hello() {
+        // An SSLv2-format ClientHello offering TLS1.0.
+        let mut hello = vec![ 0x80, 0x2e, 0x01, 0x03, 0x01, 0x00, 0x15, 0x00, 0x00, 0x00, 0x10 ];
+        hello.extend_from_slice(&[ 0x01; 21 ]);
+        hello.extend_from_slice(&[ 0x11; 16 ]);
+
+        let mut d = MessageDeframer::new();
+        assert_len(hello.len(), input_bytes(&mut d, &hello));
+        assert_eq!(d.desynced, true);
+        assert_eq!(d.sslv2_client_hello, true);
+        assert_eq!(d.frames.len(), 0);
+
+        // Mid-stream, that's just garbage.
+        let mut d = MessageDeframer::new();
+        assert_len(FIRST_MESSAGE.len(), input_bytes(&mut d, FIRST_MESSAGE));
+        assert_len(hello.len(), input_bytes(&mut d, &hello));
+        assert_eq!(d.desynced, true);
+        assert_eq!(d.sslv2_client_hello, false);
+    }
+
+    #[test]
+    fn check_other_garbage_is_not_sslv2() {
+        let mut d = MessageDeframer::new();
+        assert_len(8, input_bytes(&mut d, b"GET / HT"));
+        assert_eq!(d.desynced, true);
+        assert_eq!(d.sslv2_client_hello, false);
+    }
 }
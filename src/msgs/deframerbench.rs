@@ -0,0 +1,83 @@
+// This program benchmarks the deframer reading many small chunks,
+// through a trait object and through a concrete reader type.
+//
+// Note: we don't use any of the standard 'cargo bench', 'test::Bencher',
+// etc. because it's unstable at the time of writing.
+
+use std::time::{Duration, Instant};
+use std::io;
+
+use msgs::deframer::MessageDeframer;
+
+const MESSAGE: &'static [u8] = include_bytes!("deframer-test.2.bin");
+
+fn duration_nanos(d: Duration) -> u64 {
+    ((d.as_secs() as f64) * 1e9 + (d.subsec_nanos() as f64)) as u64
+}
+
+fn bench<Fsetup, Ftest, S>(count: usize, name: &'static str, f_setup: Fsetup, f_test: Ftest)
+    where Fsetup: Fn() -> S,
+          Ftest: Fn(S)
+{
+    let mut times = Vec::new();
+
+    for _ in 0..count {
+        let state = f_setup();
+        let start = Instant::now();
+        f_test(state);
+        times.push(duration_nanos(Instant::now().duration_since(start)));
+    }
+
+    println!("{}: min {:?}us",
+             name,
+             times.iter().min().unwrap() / 1000);
+}
+
+/// A reader which gives out at most `chunk` bytes per read.
+struct ChunkRead<'a> {
+    buf: &'a [u8],
+    chunk: usize,
+}
+
+impl<'a> io::Read for ChunkRead<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = *[ buf.len(), self.buf.len(), self.chunk ].iter().min().unwrap();
+        buf[..len].copy_from_slice(&self.buf[..len]);
+        self.buf = &self.buf[len..];
+        Ok(len)
+    }
+}
+
+const MESSAGES: usize = 1000;
+const CHUNK: usize = 3;
+
+fn many_messages() -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for _ in 0..MESSAGES {
+        bytes.extend_from_slice(MESSAGE);
+    }
+    bytes
+}
+
+#[test]
+fn bench_deframer_static_dispatch() {
+    let bytes = many_messages();
+    bench(20, "deframer read (static dispatch)",
+          || (MessageDeframer::new(), ChunkRead { buf: &bytes, chunk: CHUNK }),
+          |(mut d, mut rd)| {
+              while d.read(&mut rd).unwrap() > 0 {}
+              assert_eq!(d.frames.len(), MESSAGES);
+          });
+}
+
+#[test]
+fn bench_deframer_dynamic_dispatch() {
+    let bytes = many_messages();
+    bench(20, "deframer read (dynamic dispatch)",
+          || (MessageDeframer::new(), ChunkRead { buf: &bytes, chunk: CHUNK }),
+          |(mut d, mut rd)| {
+              let rd: &mut io::Read = &mut rd;
+              while d.read(rd).unwrap() > 0 {}
+              assert_eq!(d.frames.len(), MESSAGES);
+          });
+}
@@ -42,7 +42,8 @@ enum_builder! {@U8
         Anonymous => 0x00,
         RSA => 0x01,
         DSA => 0x02,
-        ECDSA => 0x03
+        ECDSA => 0x03,
+        ED25519 => 0x07
     }
 }
 
@@ -114,6 +115,7 @@ enum_builder! {@U8
         CertificateURL => 0x15,
         CertificateStatus => 0x16,
         KeyUpdate => 0x18,
+        CompressedCertificate => 0x19,
         MessageHash => 0xfe
     }
 }
@@ -206,8 +208,12 @@ enum_builder! {@U16
         UseSRTP => 0x000e,
         Heartbeat => 0x000f,
         ALProtocolNegotiation => 0x0010,
+        SCT => 0x0012,
         Padding => 0x0015,
         ExtendedMasterSecret => 0x0017,
+        CompressCertificate => 0x001b,
+        RecordSizeLimit => 0x001c,
+        DelegatedCredential => 0x0022,
         SessionTicket => 0x0023,
         KeyShare => 0x0028,
         PreSharedKey => 0x0029,
@@ -218,7 +224,10 @@ enum_builder! {@U16
         TicketEarlyDataInfo => 0x002e,
         CertificateAuthorities => 0x002f,
         OIDFilters => 0x0030,
+        PostHandshakeAuth => 0x0031,
+        SignatureAlgorithmsCert => 0x0032,
         NextProtocolNegotiation => 0x3374,
+        ApplicationSettings => 0x4469,
         ChannelId => 0x754f,
         RenegotiationInfo => 0xff01
     }
@@ -290,7 +299,8 @@ enum_builder! {@U16
         FFDHE3072 => 0x0101,
         FFDHE4096 => 0x0102,
         FFDHE6144 => 0x0103,
-        FFDHE8192 => 0x0104
+        FFDHE8192 => 0x0104,
+        X25519MLKEM768 => 0x11ec
     }
 }
 
@@ -763,3 +773,15 @@ enum_builder! {@U8
         OCSP => 0x01
     }
 }
+
+/// The `CertificateCompressionAlgorithm` TLS protocol enum.  Values in this enum are taken
+/// from the various RFCs covering TLS, and are listed by IANA.
+/// The `Unknown` item is used when processing unrecognised ordinals.
+enum_builder! {@U16
+    EnumName: CertificateCompressionAlgorithm;
+    EnumVal{
+        Zlib => 0x0001,
+        Brotli => 0x0002,
+        Zstd => 0x0003
+    }
+}
@@ -48,7 +48,7 @@ fn test_enum8<T: Codec>(first: T, last: T) {
 fn test_enums() {
     test_enum16::<ProtocolVersion>(ProtocolVersion::SSLv2, ProtocolVersion::TLSv1_3);
     test_enum8::<HashAlgorithm>(HashAlgorithm::NONE, HashAlgorithm::SHA512);
-    test_enum8::<SignatureAlgorithm>(SignatureAlgorithm::Anonymous, SignatureAlgorithm::ECDSA);
+    test_enum8::<SignatureAlgorithm>(SignatureAlgorithm::Anonymous, SignatureAlgorithm::ED25519);
     test_enum8::<ClientCertificateType>(ClientCertificateType::RSASign,
                                         ClientCertificateType::ECDSAFixedECDH);
     test_enum8::<Compression>(Compression::Null, Compression::LSZ);
@@ -75,4 +75,6 @@ fn test_enums() {
     test_enum8::<PSKKeyExchangeMode>(PSKKeyExchangeMode::PSK_KE, PSKKeyExchangeMode::PSK_DHE_KE);
     test_enum8::<KeyUpdateRequest>(KeyUpdateRequest::UpdateNotRequested,
                                    KeyUpdateRequest::UpdateRequested);
+    test_enum16::<CertificateCompressionAlgorithm>(CertificateCompressionAlgorithm::Zlib,
+                                                   CertificateCompressionAlgorithm::Zstd);
 }
@@ -20,6 +20,17 @@ impl MessageFragmenter {
         MessageFragmenter { max_frag: max_fragment_len }
     }
 
+    /// The maximum fragment size we produce.
+    pub fn get_max_fragment_len(&self) -> usize {
+        self.max_frag
+    }
+
+    /// Change the maximum fragment size to `max_fragment_len`.
+    pub fn set_max_fragment_len(&mut self, max_fragment_len: usize) {
+        debug_assert!(max_fragment_len <= MAX_FRAGMENT_LEN);
+        self.max_frag = max_fragment_len;
+    }
+
     /// Take the Message `msg` and re-fragment it into new
     /// messages whose fragment is no more than max_frag.
     /// The new messages are appended to the `out` deque.
@@ -5,7 +5,9 @@ use msgs::enums::{SignatureScheme, KeyUpdateRequest, NamedGroup};
 use msgs::enums::ClientCertificateType;
 use msgs::enums::ECCurveType;
 use msgs::enums::PSKKeyExchangeMode;
-use msgs::base::{Payload, PayloadU8, PayloadU16};
+use msgs::enums::CertificateCompressionAlgorithm;
+use msgs::enums::CertificateStatusType;
+use msgs::base::{Payload, PayloadU8, PayloadU16, PayloadU24};
 use msgs::codec;
 use msgs::codec::{Codec, Reader};
 use std::fmt;
@@ -45,18 +47,37 @@ macro_rules! declare_u16_vec(
   }
 );
 
+/// We refuse to parse more extensions than this in one message.
+/// Duplicates aren't allowed, so no legitimate peer comes close.
+pub const MAX_EXTENSIONS: usize = 128;
+
+macro_rules! declare_u16_extension_vec(
+  ($name:ident, $itemtype:ty) => {
+    pub type $name = Vec<$itemtype>;
+
+    impl Codec for $name {
+      fn encode(&self, bytes: &mut Vec<u8>) {
+        codec::encode_vec_u16(bytes, self);
+      }
+
+      fn read(r: &mut Reader) -> Option<$name> {
+        codec::read_vec_u16_max_items::<$itemtype>(r, MAX_EXTENSIONS)
+      }
+    }
+  }
+);
+
 #[derive(Debug)]
 pub struct Random([u8; 32]);
 
 impl Codec for Random {
     fn encode(&self, bytes: &mut Vec<u8>) {
-        bytes.extend_from_slice(&self.0);
+        codec::encode_fixed(&self.0, bytes);
     }
 
     fn read(r: &mut Reader) -> Option<Random> {
-        let bytes = try_ret!(r.take(32));
         let mut opaque = [0; 32];
-        opaque.clone_from_slice(bytes);
+        try_ret!(codec::read_fixed(r, &mut opaque));
 
         Some(Random(opaque))
     }
@@ -162,8 +183,8 @@ impl SessionID {
 
 #[derive(Debug)]
 pub struct UnknownExtension {
-    typ: ExtensionType,
-    payload: Payload,
+    pub typ: ExtensionType,
+    pub payload: Payload,
 }
 
 impl UnknownExtension {
@@ -225,6 +246,7 @@ impl DecomposedSignatureScheme for SignatureScheme {
             SignatureScheme::ECDSA_NISTP256_SHA256 |
                 SignatureScheme::ECDSA_NISTP384_SHA384 |
                 SignatureScheme::ECDSA_NISTP521_SHA512 => SignatureAlgorithm::ECDSA,
+            SignatureScheme::ED25519 => SignatureAlgorithm::ED25519,
             _ => SignatureAlgorithm::Unknown(0),
         }
     }
@@ -280,6 +302,8 @@ impl SupportedMandatedSignatureSchemes for SupportedSignatureSchemes {
     /// Supported signature verification algorithms in decreasing order of expected security.
     fn supported_verify() -> SupportedSignatureSchemes {
         vec![
+            SignatureScheme::ED25519,
+
             /* FIXME: ECDSA-P521-SHA512 */
             SignatureScheme::ECDSA_NISTP384_SHA384,
             SignatureScheme::ECDSA_NISTP256_SHA256,
@@ -298,6 +322,8 @@ impl SupportedMandatedSignatureSchemes for SupportedSignatureSchemes {
 
     fn supported_sign_tls13() -> SupportedSignatureSchemes {
         vec![
+            SignatureScheme::ED25519,
+
             SignatureScheme::ECDSA_NISTP384_SHA384,
             SignatureScheme::ECDSA_NISTP256_SHA256,
 
@@ -320,7 +346,10 @@ impl ServerNamePayload {
         let name = try_ret!(r.take(len));
         let hostname = String::from_utf8(name.to_vec());
 
+        // Names with embedded NULs or other control characters are
+        // never legitimate, and can fool naive comparisons.
         match hostname {
+            Ok(ref n) if n.chars().any(char::is_control) => None,
             Ok(n) => Some(ServerNamePayload::HostName(n)),
             _ => None,
         }
@@ -525,12 +554,14 @@ impl Codec for PresharedKeyOffer {
 declare_u8_vec!(PSKKeyExchangeModes, PSKKeyExchangeMode);
 declare_u16_vec!(KeyShareEntries, KeyShareEntry);
 declare_u8_vec!(ProtocolVersions, ProtocolVersion);
+declare_u8_vec!(CertificateCompressionAlgorithms, CertificateCompressionAlgorithm);
 
 #[derive(Debug)]
 pub enum ClientExtension {
     ECPointFormats(ECPointFormatList),
     NamedGroups(NamedGroups),
     SignatureAlgorithms(SupportedSignatureSchemes),
+    SignatureAlgorithmsCert(SupportedSignatureSchemes),
     Heartbeat(HeartbeatMode),
     ServerName(ServerNameRequest),
     SessionTicketRequest,
@@ -542,6 +573,13 @@ pub enum ClientExtension {
     PresharedKey(PresharedKeyOffer),
     Cookie(PayloadU16),
     ExtendedMasterSecretRequest,
+    PostHandshakeAuth,
+    CertificateCompressionAlgorithms(CertificateCompressionAlgorithms),
+    EarlyData,
+    ApplicationSettings(ProtocolNameList),
+    DelegatedCredential(SupportedSignatureSchemes),
+    RecordSizeLimit(u16),
+    RenegotiationInfo(PayloadU8),
     Unknown(UnknownExtension),
 }
 
@@ -551,6 +589,7 @@ impl ClientExtension {
             ClientExtension::ECPointFormats(_) => ExtensionType::ECPointFormats,
             ClientExtension::NamedGroups(_) => ExtensionType::EllipticCurves,
             ClientExtension::SignatureAlgorithms(_) => ExtensionType::SignatureAlgorithms,
+            ClientExtension::SignatureAlgorithmsCert(_) => ExtensionType::SignatureAlgorithmsCert,
             ClientExtension::Heartbeat(_) => ExtensionType::Heartbeat,
             ClientExtension::ServerName(_) => ExtensionType::ServerName,
             ClientExtension::SessionTicketRequest |
@@ -562,6 +601,15 @@ impl ClientExtension {
             ClientExtension::PresharedKey(_) => ExtensionType::PreSharedKey,
             ClientExtension::Cookie(_) => ExtensionType::Cookie,
             ClientExtension::ExtendedMasterSecretRequest => ExtensionType::ExtendedMasterSecret,
+            ClientExtension::PostHandshakeAuth => ExtensionType::PostHandshakeAuth,
+            ClientExtension::CertificateCompressionAlgorithms(_) => {
+                ExtensionType::CompressCertificate
+            }
+            ClientExtension::EarlyData => ExtensionType::EarlyData,
+            ClientExtension::ApplicationSettings(_) => ExtensionType::ApplicationSettings,
+            ClientExtension::DelegatedCredential(_) => ExtensionType::DelegatedCredential,
+            ClientExtension::RecordSizeLimit(_) => ExtensionType::RecordSizeLimit,
+            ClientExtension::RenegotiationInfo(_) => ExtensionType::RenegotiationInfo,
             ClientExtension::Unknown(ref r) => r.typ,
         }
     }
@@ -576,10 +624,13 @@ impl Codec for ClientExtension {
             ClientExtension::ECPointFormats(ref r) => r.encode(&mut sub),
             ClientExtension::NamedGroups(ref r) => r.encode(&mut sub),
             ClientExtension::SignatureAlgorithms(ref r) => r.encode(&mut sub),
+            ClientExtension::SignatureAlgorithmsCert(ref r) => r.encode(&mut sub),
             ClientExtension::Heartbeat(ref r) => r.encode(&mut sub),
             ClientExtension::ServerName(ref r) => r.encode(&mut sub),
             ClientExtension::SessionTicketRequest |
-                ClientExtension::ExtendedMasterSecretRequest => (),
+                ClientExtension::ExtendedMasterSecretRequest |
+                ClientExtension::PostHandshakeAuth |
+                ClientExtension::EarlyData => (),
             ClientExtension::SessionTicketOffer(ref r) => r.encode(&mut sub),
             ClientExtension::Protocols(ref r) => r.encode(&mut sub),
             ClientExtension::SupportedVersions(ref r) => r.encode(&mut sub),
@@ -587,6 +638,11 @@ impl Codec for ClientExtension {
             ClientExtension::PresharedKeyModes(ref r) => r.encode(&mut sub),
             ClientExtension::PresharedKey(ref r) => r.encode(&mut sub),
             ClientExtension::Cookie(ref r) => r.encode(&mut sub),
+            ClientExtension::CertificateCompressionAlgorithms(ref r) => r.encode(&mut sub),
+            ClientExtension::ApplicationSettings(ref r) => r.encode(&mut sub),
+            ClientExtension::DelegatedCredential(ref r) => r.encode(&mut sub),
+            ClientExtension::RecordSizeLimit(r) => codec::encode_u16(r, &mut sub),
+            ClientExtension::RenegotiationInfo(ref r) => r.encode(&mut sub),
             ClientExtension::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -610,6 +666,10 @@ impl Codec for ClientExtension {
                 let schemes = try_ret!(SupportedSignatureSchemes::read(&mut sub));
                 ClientExtension::SignatureAlgorithms(schemes)
             }
+            ExtensionType::SignatureAlgorithmsCert => {
+                let schemes = try_ret!(SupportedSignatureSchemes::read(&mut sub));
+                ClientExtension::SignatureAlgorithmsCert(schemes)
+            }
             ExtensionType::Heartbeat => {
                 ClientExtension::Heartbeat(try_ret!(HeartbeatMode::read(&mut sub)))
             }
@@ -642,6 +702,27 @@ impl Codec for ClientExtension {
             ExtensionType::ExtendedMasterSecret if !sub.any_left() => {
                 ClientExtension::ExtendedMasterSecretRequest
             }
+            ExtensionType::PostHandshakeAuth if !sub.any_left() => {
+                ClientExtension::PostHandshakeAuth
+            }
+            ExtensionType::CompressCertificate => {
+                let algs = try_ret!(CertificateCompressionAlgorithms::read(&mut sub));
+                ClientExtension::CertificateCompressionAlgorithms(algs)
+            }
+            ExtensionType::EarlyData if !sub.any_left() => ClientExtension::EarlyData,
+            ExtensionType::ApplicationSettings => {
+                ClientExtension::ApplicationSettings(try_ret!(ProtocolNameList::read(&mut sub)))
+            }
+            ExtensionType::DelegatedCredential => {
+                let schemes = try_ret!(SupportedSignatureSchemes::read(&mut sub));
+                ClientExtension::DelegatedCredential(schemes)
+            }
+            ExtensionType::RecordSizeLimit => {
+                ClientExtension::RecordSizeLimit(try_ret!(codec::read_u16(&mut sub)))
+            }
+            ExtensionType::RenegotiationInfo => {
+                ClientExtension::RenegotiationInfo(try_ret!(PayloadU8::read(&mut sub)))
+            }
             _ => ClientExtension::Unknown(try_ret!(UnknownExtension::read(typ, &mut sub))),
         })
     }
@@ -670,6 +751,10 @@ pub enum ServerExtension {
     KeyShare(KeyShareEntry),
     PresharedKey(u16),
     ExtendedMasterSecretAck,
+    EarlyData,
+    ApplicationSettings(Payload),
+    RecordSizeLimit(u16),
+    SupportedVersions(ProtocolVersion),
     Unknown(UnknownExtension),
 }
 
@@ -685,6 +770,10 @@ impl ServerExtension {
             ServerExtension::KeyShare(_) => ExtensionType::KeyShare,
             ServerExtension::PresharedKey(_) => ExtensionType::PreSharedKey,
             ServerExtension::ExtendedMasterSecretAck => ExtensionType::ExtendedMasterSecret,
+            ServerExtension::EarlyData => ExtensionType::EarlyData,
+            ServerExtension::ApplicationSettings(_) => ExtensionType::ApplicationSettings,
+            ServerExtension::RecordSizeLimit(_) => ExtensionType::RecordSizeLimit,
+            ServerExtension::SupportedVersions(_) => ExtensionType::SupportedVersions,
             ServerExtension::Unknown(ref r) => r.typ,
         }
     }
@@ -700,11 +789,15 @@ impl Codec for ServerExtension {
             ServerExtension::Heartbeat(ref r) => r.encode(&mut sub),
             ServerExtension::ServerNameAck |
                 ServerExtension::SessionTicketAck |
-                ServerExtension::ExtendedMasterSecretAck => (),
+                ServerExtension::ExtendedMasterSecretAck |
+                ServerExtension::EarlyData => (),
             ServerExtension::RenegotiationInfo(ref r) => r.encode(&mut sub),
             ServerExtension::Protocols(ref r) => r.encode(&mut sub),
             ServerExtension::KeyShare(ref r) => r.encode(&mut sub),
             ServerExtension::PresharedKey(r) => codec::encode_u16(r, &mut sub),
+            ServerExtension::ApplicationSettings(ref r) => r.encode(&mut sub),
+            ServerExtension::RecordSizeLimit(r) => codec::encode_u16(r, &mut sub),
+            ServerExtension::SupportedVersions(ref r) => r.encode(&mut sub),
             ServerExtension::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -739,6 +832,16 @@ impl Codec for ServerExtension {
                 ServerExtension::PresharedKey(try_ret!(codec::read_u16(&mut sub)))
             }
             ExtensionType::ExtendedMasterSecret => ServerExtension::ExtendedMasterSecretAck,
+            ExtensionType::EarlyData if !sub.any_left() => ServerExtension::EarlyData,
+            ExtensionType::ApplicationSettings => {
+                ServerExtension::ApplicationSettings(try_ret!(Payload::read(&mut sub)))
+            }
+            ExtensionType::RecordSizeLimit => {
+                ServerExtension::RecordSizeLimit(try_ret!(codec::read_u16(&mut sub)))
+            }
+            ExtensionType::SupportedVersions => {
+                ServerExtension::SupportedVersions(try_ret!(ProtocolVersion::read(&mut sub)))
+            }
             _ => ServerExtension::Unknown(try_ret!(UnknownExtension::read(typ, &mut sub))),
         })
     }
@@ -790,7 +893,8 @@ impl Codec for ClientHelloPayload {
         };
 
         if r.any_left() {
-            ret.extensions = try_ret!(codec::read_vec_u16::<ClientExtension>(r));
+            let exts = codec::read_vec_u16_max_items::<ClientExtension>(r, MAX_EXTENSIONS);
+            ret.extensions = try_ret!(exts);
         }
 
         Some(ret)
@@ -815,6 +919,16 @@ impl ClientHelloPayload {
         false
     }
 
+    pub fn get_unknown_extension(&self) -> Option<ExtensionType> {
+        self.extensions
+            .iter()
+            .find(|ext| match **ext {
+                ClientExtension::Unknown(_) => true,
+                _ => false,
+            })
+            .map(|ext| ext.get_type())
+    }
+
     pub fn find_extension(&self, ext: ExtensionType) -> Option<&ClientExtension> {
         self.extensions.iter().find(|x| x.get_type() == ext)
     }
@@ -835,6 +949,30 @@ impl ClientHelloPayload {
         }
     }
 
+    pub fn get_sigalgs_cert_extension(&self) -> Option<&SupportedSignatureSchemes> {
+        let ext = try_ret!(self.find_extension(ExtensionType::SignatureAlgorithmsCert));
+        match *ext {
+            ClientExtension::SignatureAlgorithmsCert(ref req) => Some(req),
+            _ => None,
+        }
+    }
+
+    pub fn get_delegated_credential_extension(&self) -> Option<&SupportedSignatureSchemes> {
+        let ext = try_ret!(self.find_extension(ExtensionType::DelegatedCredential));
+        match *ext {
+            ClientExtension::DelegatedCredential(ref req) => Some(req),
+            _ => None,
+        }
+    }
+
+    pub fn get_cert_compression_extension(&self) -> Option<&CertificateCompressionAlgorithms> {
+        let ext = try_ret!(self.find_extension(ExtensionType::CompressCertificate));
+        match *ext {
+            ClientExtension::CertificateCompressionAlgorithms(ref req) => Some(req),
+            _ => None,
+        }
+    }
+
     pub fn get_namedgroups_extension(&self) -> Option<&NamedGroups> {
         let ext = try_ret!(self.find_extension(ExtensionType::EllipticCurves));
         match *ext {
@@ -859,6 +997,14 @@ impl ClientHelloPayload {
         }
     }
 
+    pub fn get_application_settings_extension(&self) -> Option<&ProtocolNameList> {
+        let ext = try_ret!(self.find_extension(ExtensionType::ApplicationSettings));
+        match *ext {
+            ClientExtension::ApplicationSettings(ref req) => Some(req),
+            _ => None,
+        }
+    }
+
     pub fn get_ticket_extension(&self) -> Option<&ClientExtension> {
         self.find_extension(ExtensionType::SessionTicket)
     }
@@ -941,6 +1087,32 @@ impl ClientHelloPayload {
         self.find_extension(ExtensionType::ExtendedMasterSecret)
             .is_some()
     }
+
+    pub fn post_handshake_auth_offered(&self) -> bool {
+        self.find_extension(ExtensionType::PostHandshakeAuth)
+            .is_some()
+    }
+
+    pub fn early_data_extension_offered(&self) -> bool {
+        self.find_extension(ExtensionType::EarlyData)
+            .is_some()
+    }
+
+    pub fn get_record_size_limit(&self) -> Option<u16> {
+        let ext = try_ret!(self.find_extension(ExtensionType::RecordSizeLimit));
+        match *ext {
+            ClientExtension::RecordSizeLimit(limit) => Some(limit),
+            _ => None,
+        }
+    }
+
+    pub fn get_renegotiation_info(&self) -> Option<&PayloadU8> {
+        let ext = try_ret!(self.find_extension(ExtensionType::RenegotiationInfo));
+        match *ext {
+            ClientExtension::RenegotiationInfo(ref ri) => Some(ri),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -1005,9 +1177,12 @@ impl Codec for HelloRetryRequest {
     }
 
     fn read(r: &mut Reader) -> Option<HelloRetryRequest> {
+        let server_version = try_ret!(ProtocolVersion::read(r));
+        let exts = codec::read_vec_u16_max_items::<HelloRetryExtension>(r, MAX_EXTENSIONS);
+
         Some(HelloRetryRequest {
-            server_version: try_ret!(ProtocolVersion::read(r)),
-            extensions: try_ret!(codec::read_vec_u16::<HelloRetryExtension>(r)),
+            server_version: server_version,
+            extensions: try_ret!(exts),
         })
     }
 }
@@ -1114,7 +1289,8 @@ impl Codec for ServerHelloPayload {
         };
 
         if r.any_left() {
-            ret.extensions = try_ret!(codec::read_vec_u16::<ServerExtension>(r));
+            let exts = codec::read_vec_u16_max_items::<ServerExtension>(r, MAX_EXTENSIONS);
+            ret.extensions = try_ret!(exts);
         }
 
         Some(ret)
@@ -1156,6 +1332,30 @@ impl ServerHelloPayload {
         self.find_extension(ExtensionType::ExtendedMasterSecret)
             .is_some()
     }
+
+    pub fn get_supported_versions(&self) -> Option<ProtocolVersion> {
+        let ext = try_ret!(self.find_extension(ExtensionType::SupportedVersions));
+        match *ext {
+            ServerExtension::SupportedVersions(vers) => Some(vers),
+            _ => None,
+        }
+    }
+
+    pub fn get_renegotiation_info(&self) -> Option<&PayloadU8> {
+        let ext = try_ret!(self.find_extension(ExtensionType::RenegotiationInfo));
+        match *ext {
+            ServerExtension::RenegotiationInfo(ref ri) => Some(ri),
+            _ => None,
+        }
+    }
+
+    /// The version the server chose.  A TLS1.3 server may put this
+    /// in the supported_versions extension, leaving TLS1.2 in the
+    /// legacy version field.
+    pub fn get_selected_version(&self) -> ProtocolVersion {
+        self.get_supported_versions()
+            .unwrap_or(self.server_version)
+    }
 }
 
 pub type CertificatePayload = Vec<key::Certificate>;
@@ -1175,17 +1375,117 @@ impl Codec for CertificatePayload {
 // That's annoying. It means the parsing is not
 // context-free any more.
 
+/// A stapled OCSP response, as in RFC6066.
+#[derive(Debug)]
+pub struct CertificateStatus {
+    pub ocsp_response: PayloadU24,
+}
+
+impl Codec for CertificateStatus {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        CertificateStatusType::OCSP.encode(bytes);
+        self.ocsp_response.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<CertificateStatus> {
+        let typ = try_ret!(CertificateStatusType::read(r));
+
+        match typ {
+            CertificateStatusType::OCSP => {
+                Some(CertificateStatus { ocsp_response: try_ret!(PayloadU24::read(r)) })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl CertificateStatus {
+    pub fn new(ocsp: Vec<u8>) -> CertificateStatus {
+        CertificateStatus { ocsp_response: PayloadU24::new(ocsp) }
+    }
+}
+
+/// Signed certificate timestamps, as in RFC6962.  Each one is
+/// opaque to us.
+pub type SCTList = VecU16OfPayloadU16;
+declare_u16_vec!(VecU16OfPayloadU16, PayloadU16);
+
+/// The part of a delegated credential that is signed, as in RFC9345.
+#[derive(Clone, Debug)]
+pub struct Credential {
+    /// When the credential expires, in seconds from the start
+    /// of its certificate's validity period.
+    pub valid_time: u32,
+    pub dc_cert_verify_algorithm: SignatureScheme,
+    /// A DER-encoded SubjectPublicKeyInfo.
+    pub public_key: PayloadU24,
+}
+
+impl Codec for Credential {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        codec::encode_u32(self.valid_time, bytes);
+        self.dc_cert_verify_algorithm.encode(bytes);
+        self.public_key.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Credential> {
+        Some(Credential {
+            valid_time: try_ret!(codec::read_u32(r)),
+            dc_cert_verify_algorithm: try_ret!(SignatureScheme::read(r)),
+            public_key: try_ret!(PayloadU24::read(r)),
+        })
+    }
+}
+
+/// A delegated credential, as in RFC9345: a `Credential` signed
+/// by the key of the certificate it comes with.
+#[derive(Clone, Debug)]
+pub struct DelegatedCredentialPayload {
+    pub cred: Credential,
+    pub algorithm: SignatureScheme,
+    pub sig: PayloadU16,
+}
+
+impl Codec for DelegatedCredentialPayload {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.cred.encode(bytes);
+        self.algorithm.encode(bytes);
+        self.sig.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<DelegatedCredentialPayload> {
+        Some(DelegatedCredentialPayload {
+            cred: try_ret!(Credential::read(r)),
+            algorithm: try_ret!(SignatureScheme::read(r)),
+            sig: try_ret!(PayloadU16::read(r)),
+        })
+    }
+}
+
 #[derive(Debug)]
 pub enum CertificateExtension {
+    CertificateStatus(CertificateStatus),
+    SignedCertificateTimestamp(SCTList),
+    DelegatedCredential(DelegatedCredentialPayload),
     Unknown(UnknownExtension),
 }
 
 impl CertificateExtension {
     pub fn get_type(&self) -> ExtensionType {
         match *self {
+            CertificateExtension::CertificateStatus(_) => ExtensionType::StatusRequest,
+            CertificateExtension::SignedCertificateTimestamp(_) => ExtensionType::SCT,
+            CertificateExtension::DelegatedCredential(_) => ExtensionType::DelegatedCredential,
             CertificateExtension::Unknown(ref r) => r.typ,
         }
     }
+
+    pub fn get_delegated_credential(&self) -> Option<&DelegatedCredentialPayload> {
+        match *self {
+            CertificateExtension::DelegatedCredential(ref dc) => Some(dc),
+            _ => None,
+        }
+    }
 }
 
 impl Codec for CertificateExtension {
@@ -1194,6 +1494,9 @@ impl Codec for CertificateExtension {
 
         let mut sub: Vec<u8> = Vec::new();
         match *self {
+            CertificateExtension::CertificateStatus(ref r) => r.encode(&mut sub),
+            CertificateExtension::SignedCertificateTimestamp(ref r) => r.encode(&mut sub),
+            CertificateExtension::DelegatedCredential(ref r) => r.encode(&mut sub),
             CertificateExtension::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -1206,13 +1509,31 @@ impl Codec for CertificateExtension {
         let len = try_ret!(codec::read_u16(r)) as usize;
         let mut sub = try_ret!(r.sub(len));
 
-        Some(match typ {
+        let ext = match typ {
+            ExtensionType::StatusRequest => {
+                let st = try_ret!(CertificateStatus::read(&mut sub));
+                CertificateExtension::CertificateStatus(st)
+            }
+            ExtensionType::SCT => {
+                let scts = try_ret!(SCTList::read(&mut sub));
+                CertificateExtension::SignedCertificateTimestamp(scts)
+            }
+            ExtensionType::DelegatedCredential => {
+                let dc = try_ret!(DelegatedCredentialPayload::read(&mut sub));
+                CertificateExtension::DelegatedCredential(dc)
+            }
             _ => CertificateExtension::Unknown(try_ret!(UnknownExtension::read(typ, &mut sub))),
-        })
+        };
+
+        if sub.any_left() {
+            None
+        } else {
+            Some(ext)
+        }
     }
 }
 
-declare_u16_vec!(CertificateExtensions, CertificateExtension);
+declare_u16_extension_vec!(CertificateExtensions, CertificateExtension);
 
 #[derive(Debug)]
 pub struct CertificateEntry {
@@ -1258,7 +1579,19 @@ impl CertificateEntry {
     }
 
     pub fn has_unknown_extension(&self) -> bool {
-        !self.exts.is_empty()
+        self.exts
+            .iter()
+            .any(|ext| {
+                ext.get_type() != ExtensionType::StatusRequest &&
+                ext.get_type() != ExtensionType::SCT
+            })
+    }
+
+    pub fn get_delegated_credential(&self) -> Option<&DelegatedCredentialPayload> {
+        self.exts
+            .iter()
+            .find(|ext| ext.get_type() == ExtensionType::DelegatedCredential)
+            .and_then(|ext| ext.get_delegated_credential())
     }
 }
 
@@ -1282,6 +1615,30 @@ impl Codec for CertificatePayloadTLS13 {
     }
 }
 
+/// A TLS1.3 Certificate message, compressed under RFC8879.
+#[derive(Debug)]
+pub struct CompressedCertificatePayload {
+    pub alg: CertificateCompressionAlgorithm,
+    pub uncompressed_len: u32,
+    pub compressed: PayloadU24,
+}
+
+impl Codec for CompressedCertificatePayload {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.alg.encode(bytes);
+        codec::encode_u24(self.uncompressed_len, bytes);
+        self.compressed.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<CompressedCertificatePayload> {
+        Some(CompressedCertificatePayload {
+            alg: try_ret!(CertificateCompressionAlgorithm::read(r)),
+            uncompressed_len: try_ret!(codec::read_u24(r)),
+            compressed: try_ret!(PayloadU24::read(r)),
+        })
+    }
+}
+
 impl CertificatePayloadTLS13 {
     pub fn new() -> CertificatePayloadTLS13 {
         CertificatePayloadTLS13 {
@@ -1300,6 +1657,20 @@ impl CertificatePayloadTLS13 {
         false
     }
 
+    pub fn any_entry_has_extension(&self) -> bool {
+        self.list.iter().any(|ent| !ent.exts.is_empty())
+    }
+
+    /// Like `any_entry_has_extension`, but ignoring extensions in
+    /// `end_entity_exts` on the first entry.
+    pub fn any_entry_has_extension_except(&self, end_entity_exts: &[ExtensionType]) -> bool {
+        self.list.iter()
+            .enumerate()
+            .any(|(i, ent)| {
+                ent.exts.iter().any(|ext| i > 0 || !end_entity_exts.contains(&ext.get_type()))
+            })
+    }
+
     pub fn any_entry_has_unknown_extension(&self) -> bool {
         for ent in &self.list {
             if ent.has_unknown_extension() {
@@ -1310,6 +1681,11 @@ impl CertificatePayloadTLS13 {
         false
     }
 
+    pub fn get_end_entity_delegated_credential(&self) -> Option<&DelegatedCredentialPayload> {
+        self.list.first()
+            .and_then(|ent| ent.get_delegated_credential())
+    }
+
     pub fn convert(&self) -> CertificatePayload {
         let mut ret = Vec::new();
         for entry in &self.list {
@@ -1528,7 +1904,7 @@ impl ServerKeyExchangePayload {
 }
 
 // -- EncryptedExtensions (TLS1.3 only) --
-declare_u16_vec!(EncryptedExtensions, ServerExtension);
+declare_u16_extension_vec!(EncryptedExtensions, ServerExtension);
 
 pub trait HasServerExtensions {
     fn get_extensions(&self) -> &[ServerExtension];
@@ -1561,6 +1937,27 @@ pub trait HasServerExtensions {
             _ => None,
         }
     }
+
+    fn early_data_extension_offered(&self) -> bool {
+        self.find_extension(ExtensionType::EarlyData)
+            .is_some()
+    }
+
+    fn get_application_settings(&self) -> Option<&[u8]> {
+        let ext = try_ret!(self.find_extension(ExtensionType::ApplicationSettings));
+        match *ext {
+            ServerExtension::ApplicationSettings(ref settings) => Some(&settings.0),
+            _ => None,
+        }
+    }
+
+    fn get_record_size_limit(&self) -> Option<u16> {
+        let ext = try_ret!(self.find_extension(ExtensionType::RecordSizeLimit));
+        match *ext {
+            ServerExtension::RecordSizeLimit(limit) => Some(limit),
+            _ => None,
+        }
+    }
 }
 
 impl HasServerExtensions for EncryptedExtensions {
@@ -1668,12 +2065,14 @@ impl Codec for NewSessionTicketPayload {
 // -- NewSessionTicket electric boogaloo --
 #[derive(Debug)]
 pub enum NewSessionTicketExtension {
+    EarlyData(u32),
     Unknown(UnknownExtension),
 }
 
 impl NewSessionTicketExtension {
     pub fn get_type(&self) -> ExtensionType {
         match *self {
+            NewSessionTicketExtension::EarlyData(_) => ExtensionType::TicketEarlyDataInfo,
             NewSessionTicketExtension::Unknown(ref r) => r.typ,
         }
     }
@@ -1685,6 +2084,7 @@ impl Codec for NewSessionTicketExtension {
 
         let mut sub: Vec<u8> = Vec::new();
         match *self {
+            NewSessionTicketExtension::EarlyData(r) => codec::encode_u32(r, &mut sub),
             NewSessionTicketExtension::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -1698,6 +2098,9 @@ impl Codec for NewSessionTicketExtension {
         let mut sub = try_ret!(r.sub(len));
 
         Some(match typ {
+            ExtensionType::TicketEarlyDataInfo => {
+                NewSessionTicketExtension::EarlyData(try_ret!(codec::read_u32(&mut sub)))
+            }
             _ => {
                 NewSessionTicketExtension::Unknown(try_ret!(UnknownExtension::read(typ, &mut sub)))
             }
@@ -1705,7 +2108,7 @@ impl Codec for NewSessionTicketExtension {
     }
 }
 
-declare_u16_vec!(NewSessionTicketExtensions, NewSessionTicketExtension);
+declare_u16_extension_vec!(NewSessionTicketExtensions, NewSessionTicketExtension);
 
 #[derive(Debug)]
 pub struct NewSessionTicketPayloadTLS13 {
@@ -1724,6 +2127,16 @@ impl NewSessionTicketPayloadTLS13 {
             exts: vec![],
         }
     }
+
+    pub fn get_max_early_data_size(&self) -> Option<u32> {
+        self.exts
+            .iter()
+            .filter_map(|ext| match *ext {
+                NewSessionTicketExtension::EarlyData(sz) => Some(sz),
+                _ => None,
+            })
+            .next()
+    }
 }
 
 impl Codec for NewSessionTicketPayloadTLS13 {
@@ -1757,6 +2170,7 @@ pub enum HandshakePayload {
     HelloRetryRequest(HelloRetryRequest),
     Certificate(CertificatePayload),
     CertificateTLS13(CertificatePayloadTLS13),
+    CompressedCertificate(CompressedCertificatePayload),
     ServerKeyExchange(ServerKeyExchangePayload),
     CertificateRequest(CertificateRequestPayload),
     CertificateRequestTLS13(CertificateRequestPayloadTLS13),
@@ -1767,6 +2181,7 @@ pub enum HandshakePayload {
     NewSessionTicketTLS13(NewSessionTicketPayloadTLS13),
     EncryptedExtensions(EncryptedExtensions),
     KeyUpdate(KeyUpdateRequest),
+    EndOfEarlyData,
     Finished(Payload),
     Unknown(Payload),
 }
@@ -1775,12 +2190,14 @@ impl HandshakePayload {
     fn encode(&self, bytes: &mut Vec<u8>) {
         match *self {
             HandshakePayload::HelloRequest |
-                HandshakePayload::ServerHelloDone => {}
+                HandshakePayload::ServerHelloDone |
+                HandshakePayload::EndOfEarlyData => {}
             HandshakePayload::ClientHello(ref x) => x.encode(bytes),
             HandshakePayload::ServerHello(ref x) => x.encode(bytes),
             HandshakePayload::HelloRetryRequest(ref x) => x.encode(bytes),
             HandshakePayload::Certificate(ref x) => x.encode(bytes),
             HandshakePayload::CertificateTLS13(ref x) => x.encode(bytes),
+            HandshakePayload::CompressedCertificate(ref x) => x.encode(bytes),
             HandshakePayload::ServerKeyExchange(ref x) => x.encode(bytes),
             HandshakePayload::ClientKeyExchange(ref x) => x.encode(bytes),
             HandshakePayload::CertificateRequest(ref x) => x.encode(bytes),
@@ -1849,6 +2266,10 @@ impl HandshakeMessagePayload {
             HandshakeType::Certificate => {
                 HandshakePayload::Certificate(try_ret!(CertificatePayload::read(&mut sub)))
             }
+            HandshakeType::CompressedCertificate if vers == ProtocolVersion::TLSv1_3 => {
+                let p = try_ret!(CompressedCertificatePayload::read(&mut sub));
+                HandshakePayload::CompressedCertificate(p)
+            }
             HandshakeType::ServerKeyExchange => {
                 let p = try_ret!(ServerKeyExchangePayload::read(&mut sub));
                 HandshakePayload::ServerKeyExchange(p)
@@ -1887,6 +2308,12 @@ impl HandshakeMessagePayload {
             HandshakeType::KeyUpdate => {
                 HandshakePayload::KeyUpdate(try_ret!(KeyUpdateRequest::read(&mut sub)))
             }
+            HandshakeType::EndOfEarlyData if vers == ProtocolVersion::TLSv1_3 => {
+                if sub.any_left() {
+                    return None;
+                }
+                HandshakePayload::EndOfEarlyData
+            }
             HandshakeType::Finished => {
                 HandshakePayload::Finished(try_ret!(Payload::read(&mut sub)))
             }
@@ -0,0 +1,193 @@
+use super::codec::{Codec, Reader};
+use super::enums::{ProtocolVersion, HandshakeType, SignatureScheme, ExtensionType};
+use super::enums::CipherSuite;
+use super::handshake::*;
+
+/// A TLS1.3 Certificate message with one entry, [0x30, 0x00],
+/// carrying the single extension `ext`.  `ext` must be shorter
+/// than 128 bytes.
+fn certificate_with_ext(ext: &[u8]) -> Vec<u8> {
+    assert!(ext.len() < 0x80);
+    let entry_len = ext.len() as u8 + 7;
+    let mut bytes = vec![
+        0x0b, 0x00, 0x00, entry_len + 4,
+        0x00,
+        0x00, 0x00, entry_len,
+        0x00, 0x00, 0x02, 0x30, 0x00,
+        0x00, ext.len() as u8,
+    ];
+    bytes.extend_from_slice(ext);
+    bytes
+}
+
+const OCSP_EXT: &'static [u8] = &[
+    0x00, 0x05, 0x00, 0x08,
+    0x01, 0x00, 0x00, 0x04, b'o', b'c', b's', b'p',
+];
+
+const SCT_EXT: &'static [u8] = &[
+    0x00, 0x12, 0x00, 0x08,
+    0x00, 0x06, 0x00, 0x04, b's', b'c', b't', b'1',
+];
+
+const DELEGATED_CREDENTIAL_EXT: &'static [u8] = &[
+    0x00, 0x22, 0x00, 0x11,
+    0x00, 0x01, 0x51, 0x80,
+    0x08, 0x04,
+    0x00, 0x00, 0x02, 0x30, 0x00,
+    0x08, 0x05,
+    0x00, 0x02, 0xab, 0xcd,
+];
+
+fn decode_certificate(bytes: &[u8]) -> Option<CertificatePayloadTLS13> {
+    let mut rd = Reader::init(bytes);
+    let hmp = try_ret!(HandshakeMessagePayload::read_version(&mut rd,
+                                                             ProtocolVersion::TLSv1_3));
+    assert_eq!(hmp.typ, HandshakeType::Certificate);
+    assert!(!rd.any_left());
+
+    match hmp.payload {
+        HandshakePayload::CertificateTLS13(cert) => Some(cert),
+        _ => None,
+    }
+}
+
+#[test]
+fn decodes_leaf_ocsp_response() {
+    let bytes = certificate_with_ext(OCSP_EXT);
+    let cert = decode_certificate(&bytes).unwrap();
+
+    assert_eq!(cert.list.len(), 1);
+    assert_eq!(cert.list[0].cert.0, vec![ 0x30, 0x00 ]);
+    assert_eq!(cert.list[0].exts.len(), 1);
+    assert!(!cert.any_entry_has_unknown_extension());
+    assert!(cert.any_entry_has_extension());
+
+    match cert.list[0].exts[0] {
+        CertificateExtension::CertificateStatus(ref cs) => {
+            assert_eq!(cs.ocsp_response.0, b"ocsp".to_vec());
+        }
+        _ => panic!("status_request not decoded"),
+    }
+
+    assert_eq!(cert.get_encoding(), bytes[4..].to_vec());
+}
+
+#[test]
+fn decodes_leaf_scts() {
+    let bytes = certificate_with_ext(SCT_EXT);
+    let cert = decode_certificate(&bytes).unwrap();
+
+    assert_eq!(cert.list[0].exts.len(), 1);
+    assert!(!cert.any_entry_has_unknown_extension());
+
+    match cert.list[0].exts[0] {
+        CertificateExtension::SignedCertificateTimestamp(ref scts) => {
+            assert_eq!(scts.len(), 1);
+            assert_eq!(scts[0].0, b"sct1".to_vec());
+        }
+        _ => panic!("signed_certificate_timestamp not decoded"),
+    }
+
+    assert_eq!(cert.get_encoding(), bytes[4..].to_vec());
+}
+
+#[test]
+fn unknown_extensions_stay_opaque() {
+    let mut ext = OCSP_EXT.to_vec();
+    ext[1] = 0xee;
+    let cert = decode_certificate(&certificate_with_ext(&ext)).unwrap();
+
+    assert!(cert.any_entry_has_unknown_extension());
+    match cert.list[0].exts[0] {
+        CertificateExtension::Unknown(ref unk) => assert_eq!(unk.typ, ExtensionType::Unknown(0xee)),
+        _ => panic!("unknown extension decoded"),
+    }
+}
+
+#[test]
+fn rejects_unknown_status_type() {
+    let mut ext = OCSP_EXT.to_vec();
+    ext[4] = 0x02;
+    assert!(decode_certificate(&certificate_with_ext(&ext)).is_none());
+}
+
+#[test]
+fn rejects_trailing_data_in_status() {
+    let mut ext = OCSP_EXT.to_vec();
+    ext[7] = 0x03;
+    assert!(decode_certificate(&certificate_with_ext(&ext)).is_none());
+}
+
+#[test]
+fn decodes_leaf_delegated_credential() {
+    let bytes = certificate_with_ext(DELEGATED_CREDENTIAL_EXT);
+    let cert = decode_certificate(&bytes).unwrap();
+
+    let dc = cert.get_end_entity_delegated_credential().unwrap();
+    assert_eq!(dc.cred.valid_time, 86400);
+    assert_eq!(dc.cred.dc_cert_verify_algorithm, SignatureScheme::RSA_PSS_SHA256);
+    assert_eq!(dc.cred.public_key.0, vec![ 0x30, 0x00 ]);
+    assert_eq!(dc.algorithm, SignatureScheme::RSA_PSS_SHA384);
+    assert_eq!(dc.sig.0, vec![ 0xab, 0xcd ]);
+    assert!(cert.any_entry_has_extension());
+    assert!(!cert.any_entry_has_extension_except(&[ ExtensionType::DelegatedCredential ]));
+
+    assert_eq!(cert.get_encoding(), bytes[4..].to_vec());
+}
+
+#[test]
+fn rejects_truncated_delegated_credential() {
+    let mut ext = DELEGATED_CREDENTIAL_EXT.to_vec();
+    ext[3] -= 1;
+    ext.pop();
+    assert!(decode_certificate(&certificate_with_ext(&ext)).is_none());
+}
+
+/// A ServerHello with the legacy layout, choosing `suite`, with
+/// `exts` on the end.
+fn server_hello(version: [u8; 2], suite: [u8; 2], exts: &[u8]) -> Vec<u8> {
+    let body_len = 2 + 32 + 1 + 2 + 1 + 2 + exts.len();
+    let mut bytes = vec![ 0x02, 0x00, 0x00, body_len as u8 ];
+    bytes.extend_from_slice(&version);
+    bytes.extend_from_slice(&[ 0x11; 32 ]);
+    bytes.push(0x00);
+    bytes.extend_from_slice(&suite);
+    bytes.push(0x00);
+    bytes.extend_from_slice(&[ 0x00, exts.len() as u8 ]);
+    bytes.extend_from_slice(exts);
+    bytes
+}
+
+fn decode_server_hello(bytes: &[u8]) -> ServerHelloPayload {
+    let mut rd = Reader::init(bytes);
+    let hmp = HandshakeMessagePayload::read(&mut rd).unwrap();
+    assert!(!rd.any_left());
+
+    match hmp.payload {
+        HandshakePayload::ServerHello(sh) => sh,
+        _ => panic!("not a ServerHello"),
+    }
+}
+
+#[test]
+fn decodes_server_hello_selected_version() {
+    let bytes = server_hello([ 0x03, 0x03 ], [ 0x13, 0x01 ],
+                             &[ 0x00, 0x2b, 0x00, 0x02, 0x03, 0x04 ]);
+    let sh = decode_server_hello(&bytes);
+
+    assert_eq!(sh.server_version, ProtocolVersion::TLSv1_2);
+    assert_eq!(sh.cipher_suite, CipherSuite::TLS13_AES_128_GCM_SHA256);
+    assert_eq!(sh.get_supported_versions(), Some(ProtocolVersion::TLSv1_3));
+    assert_eq!(sh.get_selected_version(), ProtocolVersion::TLSv1_3);
+    assert_eq!(sh.get_encoding(), bytes[4..].to_vec());
+}
+
+#[test]
+fn server_hello_without_supported_versions_uses_legacy_version() {
+    let bytes = server_hello([ 0x03, 0x03 ], [ 0xc0, 0x2f ], &[ 0x00, 0x17, 0x00, 0x00 ]);
+    let sh = decode_server_hello(&bytes);
+
+    assert_eq!(sh.get_supported_versions(), None);
+    assert_eq!(sh.get_selected_version(), ProtocolVersion::TLSv1_2);
+}
@@ -1,13 +1,19 @@
 
 use std::collections::VecDeque;
+use std::cmp;
 
 use msgs::codec;
+use msgs::codec::Codec;
 use msgs::message::{Message, MessagePayload};
-use msgs::enums::{ContentType, ProtocolVersion};
+use msgs::enums::{ContentType, ProtocolVersion, HandshakeType};
 use msgs::handshake::HandshakeMessagePayload;
 
 const HEADER_SIZE: usize = 1 + 3;
 
+/// The largest handshake message we'll accept by default, including
+/// its header.  This allows for sizeable certificate chains.
+pub const DEFAULT_MAX_HANDSHAKE_SIZE: usize = 0x20000;
+
 /// This works to reconstruct TLS handshake messages
 /// from individual TLS messages.  It's guaranteed that
 /// TLS messages output from this layer contain precisely
@@ -18,6 +24,13 @@ pub struct HandshakeJoiner {
 
     /// The message payload we're currently accumulating.
     buf: Vec<u8>,
+
+    /// The largest message we'll accumulate, including its header.
+    max_size: usize,
+
+    /// The largest Certificate or CompressedCertificate message
+    /// we'll accumulate, including its header.
+    max_certificate_size: usize,
 }
 
 impl HandshakeJoiner {
@@ -26,9 +39,31 @@ impl HandshakeJoiner {
         HandshakeJoiner {
             frames: VecDeque::new(),
             buf: Vec::new(),
+            max_size: DEFAULT_MAX_HANDSHAKE_SIZE,
+            max_certificate_size: DEFAULT_MAX_HANDSHAKE_SIZE,
         }
     }
 
+    /// Refuse handshake messages longer than `max_size` bytes,
+    /// including their header.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    /// Refuse Certificate and CompressedCertificate messages longer
+    /// than `max_size` bytes, including their header.  Other limits
+    /// still apply.
+    pub fn set_max_certificate_size(&mut self, max_size: usize) {
+        self.max_certificate_size = max_size;
+    }
+
+    /// Discard any partial or complete messages, keeping
+    /// our allocations.
+    pub fn reset(&mut self) {
+        self.frames.clear();
+        self.buf.clear();
+    }
+
     /// Do we want to process this message?
     pub fn want_message(&self, msg: &Message) -> bool {
         msg.is_content_type(ContentType::Handshake)
@@ -43,7 +78,8 @@ impl HandshakeJoiner {
     /// Return the number of new messages added to the
     /// output deque as a result of this message.
     ///
-    /// Returns None if msg or a preceding message was corrupt.
+    /// Returns None if msg or a preceding message was corrupt,
+    /// or declared a length over our maximum.
     /// You cannot recover from this situation.  Otherwise returns
     /// a count of how many messages we queued.
     pub fn take_message(&mut self, mut msg: Message) -> Option<usize> {
@@ -54,7 +90,17 @@ impl HandshakeJoiner {
         self.buf.extend_from_slice(&payload.0[..]);
 
         let mut count = 0;
-        while self.buf_contains_message() {
+        loop {
+            // Give up as soon as we see an oversized header, rather
+            // than waiting for the whole message.
+            if self.buf_declares_oversized_message() {
+                return None;
+            }
+
+            if !self.buf_contains_message() {
+                break;
+            }
+
             if !self.deframe_one(msg.version) {
                 return None;
             }
@@ -65,6 +111,24 @@ impl HandshakeJoiner {
         Some(count)
     }
 
+    /// Does our `buf` start with a header declaring a message
+    /// longer than our maximum for its type?
+    fn buf_declares_oversized_message(&self) -> bool {
+        if self.buf.len() < HEADER_SIZE {
+            return false;
+        }
+
+        let max_size = match HandshakeType::read_bytes(&self.buf[..1]) {
+            Some(HandshakeType::Certificate) |
+                Some(HandshakeType::CompressedCertificate) => {
+                cmp::min(self.max_size, self.max_certificate_size)
+            }
+            _ => self.max_size,
+        };
+
+        (codec::decode_u24(&self.buf[1..4]).unwrap() as usize) + HEADER_SIZE > max_size
+    }
+
     /// Does our `buf` contain a full handshake payload?  It does if it is big
     /// enough to contain a header, and that header has a length which falls
     /// within `buf`.
@@ -237,4 +301,79 @@ mod tests {
 
         pop_eq(&expect, &mut hj);
     }
+
+    #[test]
+    fn message_at_max_size() {
+        // A 16 byte Finished is 20 bytes with its header.
+        let mut hj = HandshakeJoiner::new();
+        hj.set_max_size(20);
+
+        let mut msg = Message {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: MessagePayload::new_opaque(b"\x14\x00\x00\x10\x00\x01\x02\x03".to_vec()),
+        };
+        assert_eq!(hj.take_message(msg), Some(0));
+
+        msg = Message {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: MessagePayload::new_opaque(vec![ 0u8; 12 ]),
+        };
+        assert_eq!(hj.take_message(msg), Some(1));
+        assert_eq!(hj.is_empty(), true);
+    }
+
+    #[test]
+    fn message_over_max_size() {
+        // We reject the header alone, without waiting for the body.
+        let mut hj = HandshakeJoiner::new();
+        hj.set_max_size(19);
+
+        let msg = Message {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: MessagePayload::new_opaque(b"\x14\x00\x00\x10".to_vec()),
+        };
+        assert_eq!(hj.take_message(msg), None);
+    }
+
+    #[test]
+    fn second_message_over_max_size() {
+        let mut hj = HandshakeJoiner::new();
+        hj.set_max_size(8);
+
+        // A HelloRequest, then a header for a 16MB Finished.
+        let msg = Message {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: MessagePayload::new_opaque(b"\x00\x00\x00\x00\x14\xff\xff\xff".to_vec()),
+        };
+        assert_eq!(hj.take_message(msg), None);
+    }
+
+    #[test]
+    fn certificate_over_max_certificate_size() {
+        let mut hj = HandshakeJoiner::new();
+        hj.set_max_certificate_size(19);
+
+        // A 16 byte Finished is still fine.
+        let msg = Message {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: MessagePayload::new_opaque(b"\x14\x00\x00\x10".to_vec()),
+        };
+        assert_eq!(hj.take_message(msg), Some(0));
+
+        // But not a 16 byte Certificate.
+        let mut hj = HandshakeJoiner::new();
+        hj.set_max_certificate_size(19);
+
+        let msg = Message {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: MessagePayload::new_opaque(b"\x0b\x00\x00\x10".to_vec()),
+        };
+        assert_eq!(hj.take_message(msg), None);
+    }
 }
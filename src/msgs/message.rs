@@ -146,6 +146,14 @@ impl Message {
         }
     }
 
+    pub fn get_handshake_type(&self) -> Option<HandshakeType> {
+        if let MessagePayload::Handshake(ref hsp) = self.payload {
+            Some(hsp.typ)
+        } else {
+            None
+        }
+    }
+
     pub fn decode_payload(&mut self) -> bool {
         // Do we need a decode?
         if self.typ == ContentType::ApplicationData {
@@ -18,6 +18,12 @@ pub mod hsjoiner;
 #[cfg(test)]
 mod enums_test;
 
+#[cfg(test)]
+mod handshake_test;
+
+#[cfg(test)]
+mod deframerbench;
+
 #[cfg(test)]
 mod test {
     #[test]
@@ -58,6 +58,7 @@ pub struct ClientSessionValue {
     pub lifetime: u32,
     pub age_add: u32,
     pub extended_ms: bool,
+    pub max_early_data_size: u32,
 }
 
 impl Codec for ClientSessionValue {
@@ -71,6 +72,7 @@ impl Codec for ClientSessionValue {
         codec::encode_u32(self.lifetime, bytes);
         codec::encode_u32(self.age_add, bytes);
         codec::encode_u8(if self.extended_ms { 1u8 } else { 0u8 }, bytes);
+        codec::encode_u32(self.max_early_data_size, bytes);
     }
 
     fn read(r: &mut Reader) -> Option<ClientSessionValue> {
@@ -83,6 +85,7 @@ impl Codec for ClientSessionValue {
         let lifetime = try_ret!(codec::read_u32(r));
         let age_add = try_ret!(codec::read_u32(r));
         let extended_ms = try_ret!(codec::read_u8(r));
+        let max_early_data_size = try_ret!(codec::read_u32(r));
 
         Some(ClientSessionValue {
             version: v,
@@ -94,13 +97,43 @@ impl Codec for ClientSessionValue {
             lifetime: lifetime,
             age_add: age_add,
             extended_ms: extended_ms == 1u8,
+            max_early_data_size: max_early_data_size,
         })
     }
 }
 
 static MAX_TICKET_LIFETIME: u32 = 7 * 24 * 60 * 60;
 
+/// The version of the format written by `ClientSessionValue::to_bytes`.
+/// Bump this when the encoding changes, so entries written by an older
+/// version are discarded rather than misread.
+const CLIENT_SESSION_FORMAT_VERSION: u8 = 1;
+
 impl ClientSessionValue {
+    /// Serialise this value for storage, in a stable, versioned format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        codec::encode_u8(CLIENT_SESSION_FORMAT_VERSION, &mut bytes);
+        self.encode(&mut bytes);
+        bytes
+    }
+
+    /// Restore a value written by `to_bytes`.  Returns None if `bytes`
+    /// is malformed or was written with a different format version.
+    pub fn from_bytes(bytes: &[u8]) -> Option<ClientSessionValue> {
+        let mut rd = Reader::init(bytes);
+        if try_ret!(codec::read_u8(&mut rd)) != CLIENT_SESSION_FORMAT_VERSION {
+            return None;
+        }
+
+        let value = try_ret!(ClientSessionValue::read(&mut rd));
+        if rd.any_left() {
+            return None;
+        }
+
+        Some(value)
+    }
+
     pub fn new(v: ProtocolVersion,
                cs: CipherSuite,
                sessid: &SessionID,
@@ -117,6 +150,7 @@ impl ClientSessionValue {
             lifetime: 0,
             age_add: 0,
             extended_ms: false,
+            max_early_data_size: 0,
         }
     }
 
@@ -137,7 +171,7 @@ impl ClientSessionValue {
 
     pub fn get_obfuscated_ticket_age(&self, time_now: u64) -> u32 {
         let age_secs = time_now.saturating_sub(self.epoch);
-        let age_millis = age_secs as u32 * 1000;
+        let age_millis = (age_secs as u32).wrapping_mul(1000);
         age_millis.wrapping_sub(self.age_add)
     }
 
@@ -157,6 +191,10 @@ pub struct ServerSessionValue {
     pub cipher_suite: CipherSuite,
     pub master_secret: PayloadU8,
     pub extended_ms: bool,
+    pub creation_time: u64,
+    pub age_add: u32,
+    pub max_early_data_size: u32,
+    pub alpn_protocol: Option<PayloadU8>,
     pub client_cert_chain: Option<CertificatePayload>,
 }
 
@@ -166,8 +204,20 @@ impl Codec for ServerSessionValue {
         self.cipher_suite.encode(bytes);
         self.master_secret.encode(bytes);
         codec::encode_u8(if self.extended_ms { 1u8 } else { 0u8 }, bytes);
-        if self.client_cert_chain.is_some() {
-            self.client_cert_chain.as_ref().unwrap().encode(bytes);
+        codec::encode_u64(self.creation_time, bytes);
+        codec::encode_u32(self.age_add, bytes);
+        codec::encode_u32(self.max_early_data_size, bytes);
+        if let Some(ref proto) = self.alpn_protocol {
+            codec::encode_u8(1, bytes);
+            proto.encode(bytes);
+        } else {
+            codec::encode_u8(0, bytes);
+        }
+        if let Some(ref chain) = self.client_cert_chain {
+            codec::encode_u8(1, bytes);
+            chain.encode(bytes);
+        } else {
+            codec::encode_u8(0, bytes);
         }
     }
 
@@ -176,10 +226,18 @@ impl Codec for ServerSessionValue {
         let cs = try_ret!(CipherSuite::read(r));
         let ms = try_ret!(PayloadU8::read(r));
         let ems = try_ret!(codec::read_u8(r));
-        let ccert = if r.any_left() {
-            CertificatePayload::read(r)
-        } else {
-            None
+        let creation_time = try_ret!(codec::read_u64(r));
+        let age_add = try_ret!(codec::read_u32(r));
+        let max_early_data_size = try_ret!(codec::read_u32(r));
+        let alpn = match try_ret!(codec::read_u8(r)) {
+            0 => None,
+            1 => Some(try_ret!(PayloadU8::read(r))),
+            _ => return None,
+        };
+        let ccert = match try_ret!(codec::read_u8(r)) {
+            0 => None,
+            1 => Some(try_ret!(CertificatePayload::read(r))),
+            _ => return None,
         };
 
         Some(ServerSessionValue {
@@ -187,12 +245,49 @@ impl Codec for ServerSessionValue {
             cipher_suite: cs,
             master_secret: ms,
             extended_ms: ems == 1u8,
+            creation_time: creation_time,
+            age_add: age_add,
+            max_early_data_size: max_early_data_size,
+            alpn_protocol: alpn,
             client_cert_chain: ccert,
         })
     }
 }
 
+/// The version of the format written by `ServerSessionValue::to_bytes`.
+/// Bump this when the encoding changes, so entries written by an older
+/// version, perhaps on another server sharing our session store, are
+/// discarded rather than misread.
+///
+/// Version 2 added `max_early_data_size`, and version 3 `alpn_protocol`.
+const SERVER_SESSION_FORMAT_VERSION: u8 = 3;
+
 impl ServerSessionValue {
+    /// Serialise this value for storage or a ticket, in a stable,
+    /// versioned format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        codec::encode_u8(SERVER_SESSION_FORMAT_VERSION, &mut bytes);
+        self.encode(&mut bytes);
+        bytes
+    }
+
+    /// Restore a value written by `to_bytes`.  Returns None if `bytes`
+    /// is malformed or was written with a different format version.
+    pub fn from_bytes(bytes: &[u8]) -> Option<ServerSessionValue> {
+        let mut rd = Reader::init(bytes);
+        if try_ret!(codec::read_u8(&mut rd)) != SERVER_SESSION_FORMAT_VERSION {
+            return None;
+        }
+
+        let value = try_ret!(ServerSessionValue::read(&mut rd));
+        if rd.any_left() {
+            return None;
+        }
+
+        Some(value)
+    }
+
     pub fn new(v: ProtocolVersion,
                cs: CipherSuite,
                ms: Vec<u8>,
@@ -203,6 +298,10 @@ impl ServerSessionValue {
             cipher_suite: cs,
             master_secret: PayloadU8::new(ms),
             extended_ms: false,
+            creation_time: 0,
+            age_add: 0,
+            max_early_data_size: 0,
+            alpn_protocol: None,
             client_cert_chain: cert_chain.clone(),
         }
     }
@@ -210,4 +309,191 @@ impl ServerSessionValue {
     pub fn set_extended_ms_used(&mut self) {
         self.extended_ms = true;
     }
+
+    /// This is for a TLS1.3 ticket issued at `creation_time_secs`,
+    /// which obfuscates its age with `age_add`.
+    pub fn set_ticket_times(&mut self, creation_time_secs: u64, age_add: u32) {
+        self.creation_time = creation_time_secs;
+        self.age_add = age_add;
+    }
+
+    /// Returns how far, in milliseconds, the ticket age a client
+    /// claims with `obfuscated_age` is from its actual age at
+    /// `time_now`.
+    pub fn get_ticket_age_error(&self, obfuscated_age: u32, time_now: u64) -> u64 {
+        let claimed_millis = obfuscated_age.wrapping_sub(self.age_add) as i64;
+        let actual_millis = cmp::min(time_now.saturating_sub(self.creation_time)
+                                         .saturating_mul(1000),
+                                     i64::max_value() as u64) as i64;
+        (claimed_millis - actual_millis).abs() as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use msgs::codec::Codec;
+    use key;
+
+    fn make_value() -> ClientSessionValue {
+        let mut value = ClientSessionValue::new(ProtocolVersion::TLSv1_2,
+                                                CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                                                &SessionID::new(&[ 1u8; 32 ]),
+                                                vec![ 2u8; 16 ],
+                                                vec![ 3u8; 48 ]);
+        value.set_times(1000, 3600, 1234);
+        value.set_extended_ms_used();
+        value.max_early_data_size = 16384;
+        value
+    }
+
+    #[test]
+    fn client_session_value_roundtrip() {
+        let value = make_value();
+        let bytes = value.to_bytes();
+        let restored = ClientSessionValue::from_bytes(&bytes).unwrap();
+
+        assert_eq!(restored.version, value.version);
+        assert_eq!(restored.cipher_suite, value.cipher_suite);
+        assert_eq!(restored.session_id, value.session_id);
+        assert_eq!(restored.ticket.0, value.ticket.0);
+        assert_eq!(restored.master_secret.0, value.master_secret.0);
+        assert_eq!(restored.epoch, 1000);
+        assert_eq!(restored.lifetime, 3600);
+        assert_eq!(restored.age_add, 1234);
+        assert!(restored.extended_ms);
+        assert_eq!(restored.max_early_data_size, 16384);
+        assert_eq!(restored.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn client_session_value_rejects_other_versions() {
+        let mut bytes = make_value().to_bytes();
+        bytes[0] = CLIENT_SESSION_FORMAT_VERSION + 1;
+        assert!(ClientSessionValue::from_bytes(&bytes).is_none());
+
+        // An unversioned encoding, as written by older releases.
+        let bytes = make_value().get_encoding();
+        assert!(ClientSessionValue::from_bytes(&bytes).is_none());
+    }
+
+    #[test]
+    fn client_session_value_reads_version_1() {
+        // `make_value()`, as written by version 1.
+        let mut v1 = vec![ 0x01, 0x03, 0x03, 0xc0, 0x2f, 0x20 ];
+        v1.extend_from_slice(&[ 1u8; 32 ]);
+        v1.extend_from_slice(&[ 0x00, 0x10 ]);
+        v1.extend_from_slice(&[ 2u8; 16 ]);
+        v1.push(0x30);
+        v1.extend_from_slice(&[ 3u8; 48 ]);
+        v1.extend_from_slice(&[ 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xe8,
+                                0x00, 0x00, 0x0e, 0x10,
+                                0x00, 0x00, 0x04, 0xd2,
+                                0x01,
+                                0x00, 0x00, 0x40, 0x00 ]);
+
+        let restored = ClientSessionValue::from_bytes(&v1).unwrap();
+        assert_eq!(restored.epoch, 1000);
+        assert_eq!(restored.lifetime, 3600);
+        assert_eq!(restored.age_add, 1234);
+        assert!(restored.extended_ms);
+        assert_eq!(restored.max_early_data_size, 16384);
+        assert_eq!(make_value().to_bytes(), v1);
+    }
+
+    #[test]
+    fn client_session_value_rejects_malformed() {
+        let bytes = make_value().to_bytes();
+
+        assert!(ClientSessionValue::from_bytes(&[]).is_none());
+        for len in 1..bytes.len() {
+            assert!(ClientSessionValue::from_bytes(&bytes[..len]).is_none());
+        }
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(ClientSessionValue::from_bytes(&trailing).is_none());
+    }
+
+    fn make_server_value() -> ServerSessionValue {
+        let chain = vec![ key::Certificate(vec![ 4u8; 20 ]), key::Certificate(vec![ 5u8; 10 ]) ];
+        let mut value = ServerSessionValue::new(ProtocolVersion::TLSv1_2,
+                                                CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                                                vec![ 3u8; 48 ],
+                                                &Some(chain));
+        value.set_extended_ms_used();
+        value.set_ticket_times(1000, 1234);
+        value.max_early_data_size = 16384;
+        value.alpn_protocol = Some(PayloadU8::new(b"h2".to_vec()));
+        value
+    }
+
+    #[test]
+    fn server_session_value_roundtrip() {
+        let value = make_server_value();
+        let bytes = value.to_bytes();
+        let restored = ServerSessionValue::from_bytes(&bytes).unwrap();
+
+        assert_eq!(restored.version, value.version);
+        assert_eq!(restored.cipher_suite, value.cipher_suite);
+        assert_eq!(restored.master_secret.0, value.master_secret.0);
+        assert!(restored.extended_ms);
+        assert_eq!(restored.creation_time, 1000);
+        assert_eq!(restored.age_add, 1234);
+        assert_eq!(restored.max_early_data_size, 16384);
+        assert_eq!(restored.alpn_protocol.as_ref().unwrap().0, b"h2".to_vec());
+        assert_eq!(restored.client_cert_chain, value.client_cert_chain);
+        assert_eq!(restored.to_bytes(), bytes);
+
+        let mut value = make_server_value();
+        value.alpn_protocol = None;
+        value.client_cert_chain = None;
+        let restored = ServerSessionValue::from_bytes(&value.to_bytes()).unwrap();
+        assert!(restored.alpn_protocol.is_none());
+        assert_eq!(restored.client_cert_chain, None);
+    }
+
+    #[test]
+    fn server_session_value_rejects_other_versions() {
+        let mut bytes = make_server_value().to_bytes();
+        bytes[0] = SERVER_SESSION_FORMAT_VERSION + 1;
+        assert!(ServerSessionValue::from_bytes(&bytes).is_none());
+
+        // An unversioned encoding, as written by older releases.
+        let bytes = make_server_value().get_encoding();
+        assert!(ServerSessionValue::from_bytes(&bytes).is_none());
+    }
+
+    #[test]
+    fn server_session_value_rejects_malformed() {
+        let bytes = make_server_value().to_bytes();
+
+        assert!(ServerSessionValue::from_bytes(&[]).is_none());
+        for len in 1..bytes.len() {
+            assert!(ServerSessionValue::from_bytes(&bytes[..len]).is_none());
+        }
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(ServerSessionValue::from_bytes(&trailing).is_none());
+    }
+
+    #[test]
+    fn ticket_age_roundtrip() {
+        let client = make_value();
+        let mut server = ServerSessionValue::new(ProtocolVersion::TLSv1_3,
+                                                 CipherSuite::TLS13_AES_128_GCM_SHA256,
+                                                 vec![ 3u8; 32 ],
+                                                 &None);
+        server.set_ticket_times(1000, 1234);
+
+        let obfuscated = client.get_obfuscated_ticket_age(1010);
+        assert_eq!(server.get_ticket_age_error(obfuscated, 1010), 0);
+        assert_eq!(server.get_ticket_age_error(obfuscated, 1015), 5000);
+        assert_eq!(server.get_ticket_age_error(obfuscated, 1000), 10000);
+
+        let restored = ServerSessionValue::read_bytes(&server.get_encoding()).unwrap();
+        assert_eq!(restored.creation_time, 1000);
+        assert_eq!(restored.age_add, 1234);
+    }
 }
@@ -4,7 +4,11 @@ use key;
 
 /// Extract and decode all PEM sections from `rd`, which begin with `start_mark`
 /// and end with `end_mark`.  Apply the functor `f` to each decoded buffer,
-/// and return a Vec of `f`'s return values.
+/// and return a Vec of `f`'s return values, in the order the sections
+/// appear in `rd`.
+///
+/// Sections of other types are skipped.  A section which isn't
+/// terminated by `end_mark` is an error.
 fn extract<A>(rd: &mut io::BufRead,
               start_mark: &str,
               end_mark: &str,
@@ -21,11 +25,17 @@ fn extract<A>(rd: &mut io::BufRead,
             .map_err(|_| ())?;
 
         if len == 0 {
+            if take_base64 {
+                return Err(());
+            }
             return Ok(ders);
         }
         let line = String::from_utf8_lossy(&raw_line);
 
         if line.starts_with(start_mark) {
+            if take_base64 {
+                return Err(());
+            }
             take_base64 = true;
             continue;
         }
@@ -40,6 +50,9 @@ fn extract<A>(rd: &mut io::BufRead,
         }
 
         if take_base64 {
+            if line.starts_with("-----") {
+                return Err(());
+            }
             b64buf.push_str(line.trim());
         }
     }
@@ -47,7 +60,8 @@ fn extract<A>(rd: &mut io::BufRead,
 
 
 /// Extract all the certificates from rd, and return a vec of `key::Certificate`s
-/// containing the der-format contents.
+/// containing the der-format contents.  The certificates are in the same
+/// order as in rd, so a chain stays in leaf-first order.
 pub fn certs(rd: &mut io::BufRead) -> Result<Vec<key::Certificate>, ()> {
     extract(rd,
             "-----BEGIN CERTIFICATE-----",
@@ -3,20 +3,58 @@
 /// for our own use.  These functions never fail,
 /// they panic on error.
 
-use ring::rand::{SystemRandom, SecureRandom};
+use ring;
 use msgs::codec;
 
-/// Fill the whole slice with random material.
-pub fn fill_random(bytes: &mut [u8]) {
-    SystemRandom::new()
-        .fill(bytes)
+/// A source of random material for keys, handshake randoms and
+/// the like.  This must be cryptographically secure.
+///
+/// You might provide your own to use a particular entropy source,
+/// or a deterministic one for testing.
+pub trait SecureRandom : Send + Sync {
+    /// Fill the whole of `bytes` with random material.  Returns
+    /// Err if this isn't possible, in which case we panic.
+    fn fill(&self, bytes: &mut [u8]) -> Result<(), ()>;
+}
+
+/// A `SecureRandom` which uses the operating system's source.
+/// This is the default.
+pub struct SystemRandom {}
+
+impl SystemRandom {
+    /// Make a new `SystemRandom`.
+    pub fn new() -> SystemRandom {
+        SystemRandom {}
+    }
+}
+
+impl SecureRandom for SystemRandom {
+    fn fill(&self, bytes: &mut [u8]) -> Result<(), ()> {
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), bytes)
+            .map_err(|_| ())
+    }
+}
+
+/// Adapts a `SecureRandom` for use by ring.
+pub struct RingRandom<'a>(pub &'a SecureRandom);
+
+impl<'a> ring::rand::SecureRandom for RingRandom<'a> {
+    fn fill(&self, dest: &mut [u8]) -> Result<(), ring::error::Unspecified> {
+        self.0.fill(dest)
+            .map_err(|_| ring::error::Unspecified)
+    }
+}
+
+/// Fill the whole slice with random material from `rng`.
+pub fn fill_random(rng: &SecureRandom, bytes: &mut [u8]) {
+    rng.fill(bytes)
         .unwrap();
 }
 
-/// Return a uniformly random u32.
-pub fn random_u32() -> u32 {
+/// Return a uniformly random u32 from `rng`.
+pub fn random_u32(rng: &SecureRandom) -> u32 {
     let mut buf = [0u8; 4];
-    fill_random(&mut buf);
+    fill_random(rng, &mut buf);
     codec::decode_u32(&buf)
         .unwrap()
 }
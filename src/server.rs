@@ -1,32 +1,51 @@
 use session::{Session, SessionRandoms, SessionSecrets, SessionCommon};
+use session::{DEFAULT_MAX_WARNING_ALERTS, Resumption};
+#[cfg(feature = "dangerous_debug")]
+use session::ExtractedSecrets;
 use suites::{SupportedCipherSuite, ALL_CIPHERSUITES, KeyExchange};
-use msgs::enums::{ContentType, SignatureScheme};
+use msgs::enums::{ContentType, SignatureScheme, NamedGroup, CipherSuite};
 use msgs::enums::{AlertDescription, HandshakeType, ProtocolVersion};
+use msgs::enums::SignatureAlgorithm;
 use msgs::handshake::{SessionID, CertificatePayload};
+use msgs::handshake::{NamedGroups, SupportedGroups};
 use msgs::message::Message;
+use msgs::hsjoiner::DEFAULT_MAX_HANDSHAKE_SIZE;
 use msgs::codec::Codec;
-use hash_hs;
+use kem::KeyEncapsulation;
+use compress::CertCompressor;
+use delegated::DelegatedCredential;
+use hash_hs::{self, HandshakeMessageSummary};
 use server_hs;
 use error::TLSError;
-use rand;
+use rand::{self, SecureRandom, SystemRandom};
 use sign;
 use verify;
 use anchors;
 use key;
+use x509;
+use cipher::RecordPadding;
+
+use ring::digest;
 
 use std::collections;
 use std::sync::{Arc, Mutex};
 use std::io;
+use std::time::Duration;
 
 /// A trait for the ability to generate Session IDs, and store
 /// server session data. The keys and values are opaque.
 ///
+/// Values are in a stable, versioned format, so a store may be
+/// shared by several servers, and outlive them: a server ignores
+/// values written in a format it doesn't know.
+///
 /// Both the keys and values should be treated as
 /// **highly sensitive data**, containing enough key material
 /// to break all security of the corresponding session.
 pub trait StoresServerSessions : Send + Sync {
-    /// Generate a session ID.
-    fn generate(&self) -> SessionID;
+    /// Generate a session ID, using `rng` for any random material.
+    /// `rng` is the `ServerConfig`'s `SecureRandom`.
+    fn generate(&self, rng: &SecureRandom) -> SessionID;
 
     /// Store session secrets encoded in `value` against key `id`,
     /// overwrites any existing value against `id`.  Returns `true`
@@ -64,8 +83,9 @@ pub trait ProducesTickets : Send + Sync {
     /// Encrypt and authenticate `plain`, returning the resulting
     /// ticket.  Return None if `plain` cannot be encrypted for
     /// some reason: an empty ticket will be sent and the connection
-    /// will continue.
-    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>>;
+    /// will continue.  Any random material, such as keys or nonces,
+    /// comes from `rng`: the `ServerConfig`'s `SecureRandom`.
+    fn encrypt(&self, plain: &[u8], rng: &SecureRandom) -> Option<Vec<u8>>;
 
     /// Decrypt `cipher`, validating its authenticity protection
     /// and recovering the plaintext.  `cipher` is fully attacker
@@ -75,18 +95,85 @@ pub trait ProducesTickets : Send + Sync {
     fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>>;
 }
 
+/// What a server does with a client which doesn't send SNI.
+pub enum MissingSNIPolicy {
+    /// Ask `ServerConfig::cert_resolver` for a certificate as
+    /// usual.  Its `ClientHello::server_name()` is None.
+    Resolve,
+
+    /// Refuse the handshake with an `unrecognized_name` alert.
+    Reject,
+
+    /// Serve this certificate chain and key.
+    ServeDefault(sign::CertChainAndSigner),
+}
+
+/// A view on the ClientHello sent by a client, for use when
+/// choosing a certificate.
+pub struct ClientHello<'a> {
+    server_name: Option<&'a str>,
+    sigschemes: &'a [SignatureScheme],
+    cert_sigschemes: &'a [SignatureScheme],
+    alpn: Option<Vec<&'a [u8]>>,
+    cipher_suites: &'a [CipherSuite],
+}
+
+impl<'a> ClientHello<'a> {
+    /// Make a new `ClientHello` from its parts.
+    pub fn new(server_name: Option<&'a str>,
+               sigschemes: &'a [SignatureScheme],
+               cert_sigschemes: &'a [SignatureScheme],
+               alpn: Option<Vec<&'a [u8]>>,
+               cipher_suites: &'a [CipherSuite])
+               -> ClientHello<'a> {
+        ClientHello {
+            server_name: server_name,
+            sigschemes: sigschemes,
+            cert_sigschemes: cert_sigschemes,
+            alpn: alpn,
+            cipher_suites: cipher_suites,
+        }
+    }
+
+    /// The server DNS name the client asked for via SNI, if any.
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name
+    }
+
+    /// The signature schemes the client supports.
+    pub fn sigschemes(&self) -> &[SignatureScheme] {
+        self.sigschemes
+    }
+
+    /// The signature schemes the client accepts in our
+    /// certificate chain.  Unless the client said otherwise,
+    /// these are the same as `sigschemes`.
+    pub fn cert_sigschemes(&self) -> &[SignatureScheme] {
+        self.cert_sigschemes
+    }
+
+    /// The ALPN protocol names the client offered, or None if it
+    /// didn't send the ALPN extension.
+    pub fn alpn(&self) -> Option<&[&'a [u8]]> {
+        self.alpn.as_ref().map(|protos| protos.as_slice())
+    }
+
+    /// The cipher suites the client offered, in its preference order.
+    pub fn cipher_suites(&self) -> &[CipherSuite] {
+        self.cipher_suites
+    }
+}
+
 /// How to choose a certificate chain and signing key for use
 /// in server authentication.
 pub trait ResolvesServerCert : Send + Sync {
-    /// Choose a certificate chain and matching key given any server DNS
-    /// name provided via SNI, and signature schemes.
+    /// Choose a certificate chain and matching key given the
+    /// `client_hello`: for example, its SNI server name and
+    /// signature schemes.
     ///
     /// The certificate chain is returned as a vec of `Certificate`s,
     /// the key is inside a `Signer`.
-    fn resolve(&self,
-               server_name: Option<&str>,
-               sigschemes: &[SignatureScheme])
-               -> Option<sign::CertChainAndSigner>;
+    fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertChainAndSigner>;
 }
 
 /// Common configuration for a set of server sessions.
@@ -102,19 +189,53 @@ pub struct ServerConfig {
     /// which is supported by the client.
     pub ignore_client_order: bool,
 
+    /// Reject ClientHellos with extensions we don't understand,
+    /// with an `unsupported_extension` alert.  Note that this
+    /// includes GREASE extensions, and extensions we simply don't
+    /// implement.
+    ///
+    /// The default is false, which means unknown extensions are
+    /// ignored as the standard requires.
+    pub reject_unknown_extensions: bool,
+
     /// How to store client sessions.
     pub session_storage: Mutex<Box<StoresServerSessions + Send>>,
 
     /// How to produce tickets.
     pub ticketer: Box<ProducesTickets>,
 
+    /// The longest lifetime, in seconds, we tell clients our tickets
+    /// have.  We tell them the shorter of this and
+    /// `ticketer.get_lifetime()`.  Use `Ticketer::with_rotation`
+    /// to choose how long tickets are actually accepted.
+    ///
+    /// The default is None, meaning `ticketer.get_lifetime()`.
+    pub ticket_lifetime: Option<u32>,
+
+    /// How to detect replayed early data.  We only accept early
+    /// data on a ticket the first time this sees it.
+    ///
+    /// The default is a `ReplayCache` of 1024 tickets.
+    pub replay_protection: Mutex<Box<ReplayProtection + Send>>,
+
     /// How to choose a server cert and key.
     pub cert_resolver: Box<ResolvesServerCert>,
 
+    /// What to do with clients which don't send SNI.
+    ///
+    /// The default is `MissingSNIPolicy::Resolve`.
+    pub missing_sni_policy: MissingSNIPolicy,
+
     /// Protocol names we support, most preferred first.
     /// If empty we don't do ALPN at all.
     pub alpn_protocols: Vec<String>,
 
+    /// Our settings for each ALPN protocol supporting the
+    /// application_settings (ALPS) extension, which exchanges them
+    /// during a TLS1.3 handshake.  ALPS is negotiated for the agreed
+    /// ALPN protocol if both sides have settings for it.
+    pub application_settings: collections::HashMap<String, Vec<u8>>,
+
     /// List of client authentication root certificates.
     pub client_auth_roots: anchors::RootCertStore,
 
@@ -129,15 +250,136 @@ pub struct ServerConfig {
     /// The default is all supported versions.
     pub versions: Vec<ProtocolVersion>,
 
+    /// Key exchange groups, in preference order.  When the
+    /// client supports more than one of these, we choose the
+    /// earliest in this list.  The default is all supported
+    /// groups.
+    pub kx_groups: Vec<NamedGroup>,
+
+    /// A key encapsulation mechanism, for hybrid post-quantum
+    /// key exchange in TLS1.3.  Use `set_kem` to enable this.
+    ///
+    /// The default is None.
+    pub kem: Option<Box<KeyEncapsulation>>,
+
+    /// Certificate compression algorithms, in our order of preference.
+    /// If a TLS1.3 client supports one of these, we send our
+    /// certificate chain compressed with it.
+    ///
+    /// The default is empty, which means we never compress.
+    pub cert_compressors: Vec<Box<CertCompressor>>,
+
+    /// A delegated credential (RFC9345) issued by our end-entity
+    /// certificate.  If a TLS1.3 client accepts it, we send it with
+    /// that certificate and sign the handshake with its key.
+    ///
+    /// The default is None.
+    pub delegated_credential: Option<DelegatedCredential>,
+
+    /// The largest amount of early data we invite clients to send
+    /// when resuming with our TLS1.3 tickets.  Use
+    /// `set_max_early_data_size` to change this.
+    ///
+    /// If this is non-zero, we accept early data from resuming
+    /// clients, and it can be read before the handshake completes.
+    /// Early data can be replayed by an attacker: `replay_protection`
+    /// stops us accepting the same early data twice, but beware other
+    /// servers sharing our tickets may still accept it.
+    ///
+    /// The default is zero, which means no early data is invited
+    /// or accepted.
+    pub max_early_data_size: u32,
+
+    /// The largest handshake message we'll accept from the peer,
+    /// including its four byte header.  Longer messages are rejected
+    /// with a `decode_error` alert as soon as we see their header.
+    ///
+    /// The default is 128KB.
+    pub max_handshake_size: usize,
+
+    /// The largest Certificate message we'll accept from the peer,
+    /// including its four byte header.  Longer messages are rejected
+    /// with a `decode_error` alert as soon as we see their header,
+    /// before we allocate anything for the certificates themselves.
+    /// For a compressed certificate, this limits both the compressed
+    /// and uncompressed lengths.  `max_handshake_size` applies too.
+    ///
+    /// The default is 128KB.
+    pub max_certificate_size: usize,
+
+    /// How many warning alerts we accept from the peer in a row,
+    /// with no other messages between them.  Beyond this, we abort
+    /// with an `unexpected_message` alert.
+    ///
+    /// The default is 4.
+    pub max_warning_alerts: usize,
+
+    /// Whether to negotiate ciphersuites without forward secrecy,
+    /// such as those using static RSA key exchange.  When false
+    /// they're never chosen, even if they're in `ciphersuites`.
+    ///
+    /// The default is false.
+    pub allow_non_forward_secret: bool,
+
+    /// Whether to refuse the ChangeCipherSpecs TLS1.3 peers send for
+    /// middlebox compatibility, aborting with an `unexpected_message`
+    /// alert.  We don't send them either.  Some middleboxes break
+    /// TLS1.3 handshakes without them, so only set this where that
+    /// isn't a concern.
+    ///
+    /// The default is false.
+    pub reject_tls13_compat_ccs: bool,
+
+    /// The largest record we're willing to receive, which we tell
+    /// the peer using the record_size_limit extension (RFC8449).  For
+    /// TLS1.3 this counts the record's content type byte and padding
+    /// too.  If the peer advertises a limit as well, the records each
+    /// side sends fit within the smaller of the two.
+    ///
+    /// This must be at least 64, and at most 16385 for TLS1.3 or
+    /// 16384 for TLS1.2 (RFC8449 section 4): otherwise the handshake
+    /// fails.  The default is None, which means we only advertise a limit to clients which
+    /// advertise theirs, and then the largest the protocol allows.
+    /// We always obey a client's limit.
+    pub record_size_limit: Option<u16>,
+
+    /// Where we get random material, such as our handshake random
+    /// and ephemeral keys.
+    ///
+    /// The default is `SystemRandom`.
+    pub rng: Box<SecureRandom>,
+
     /// How to verify client certificates.
     verifier: Box<verify::ClientCertVerifier>,
+
+    /// Where we get the current time.
+    time: Arc<Box<verify::TimeProvider>>,
+
+    /// How far our clock may be from the client's.
+    clock_skew: Duration,
+
+    /// The smallest RSA key we accept in a certificate chain.
+    min_rsa_key_bits: usize,
+
+    /// How to pad the TLS1.3 records we send.
+    record_padding: Option<RecordPadding>,
+
+    /// Decides whether a client may resume.
+    resumption_policy: Option<Box<Fn(&ClientHello) -> bool + Send + Sync>>,
+
+    /// Whether clients may resume at all.
+    resumption: Resumption,
+
+    /// The certificate chains `cert_resolver` chooses between, if
+    /// they were set with `add_cert_chain` or `set_certs_with_signers`.
+    cert_chains: Vec<sign::CertChainAndSigner>,
 }
 
 /// Something which never stores sessions.
 struct NoSessionStorage {}
 
 impl StoresServerSessions for NoSessionStorage {
-    fn generate(&self) -> SessionID {
+    fn generate(&self, _rng: &SecureRandom) -> SessionID {
         SessionID::empty()
     }
     fn put(&mut self, _id: &SessionID, _sec: Vec<u8>) -> bool {
@@ -179,9 +421,9 @@ impl ServerSessionMemoryCache {
 }
 
 impl StoresServerSessions for ServerSessionMemoryCache {
-    fn generate(&self) -> SessionID {
+    fn generate(&self, rng: &SecureRandom) -> SessionID {
         let mut v = [0u8; 32];
-        rand::fill_random(&mut v);
+        rand::fill_random(rng, &mut v);
         SessionID::new(&v)
     }
 
@@ -200,6 +442,68 @@ impl StoresServerSessions for ServerSessionMemoryCache {
     }
 }
 
+/// Something which remembers the tickets clients have used to send
+/// TLS1.3 early data.  Early data isn't protected against replay by
+/// the protocol, so an attacker can capture a client's first flight
+/// and send it again: we must refuse early data on each ticket after
+/// the first time we see it.
+pub trait ReplayProtection : Send {
+    /// A client offers early data using the ticket `identity`.
+    /// Return true if we haven't seen `identity` do this before, and
+    /// remember it for at least `lifetime` seconds, after which the
+    /// ticket is no longer accepted.
+    ///
+    /// `now` is the UNIX time in seconds, from the `ServerConfig`'s
+    /// `TimeProvider`.
+    ///
+    /// If in doubt, return false.  The early data is then rejected,
+    /// and the client sends it again after the handshake.
+    fn check_and_record(&mut self, identity: &[u8], now: u64, lifetime: u32) -> bool;
+}
+
+/// An implementor of `ReplayProtection` that remembers ticket
+/// identities in memory until their tickets expire.  It enforces a
+/// limit on the number of identities to bound memory usage: when
+/// that many unexpired tickets have been used, early data is refused
+/// until some expire.
+pub struct ReplayCache {
+    seen: collections::HashMap<Vec<u8>, u64>,
+    max_entries: usize,
+}
+
+impl ReplayCache {
+    /// Make a new ReplayCache.  `size` is the maximum number of
+    /// ticket identities we remember.
+    pub fn new(size: usize) -> Box<ReplayCache> {
+        debug_assert!(size > 0);
+        Box::new(ReplayCache {
+            seen: collections::HashMap::new(),
+            max_entries: size,
+        })
+    }
+}
+
+impl ReplayProtection for ReplayCache {
+    fn check_and_record(&mut self, identity: &[u8], now: u64, lifetime: u32) -> bool {
+        // Tickets are long: we only need their hash.
+        let key = digest::digest(&digest::SHA256, identity).as_ref().to_vec();
+        if self.seen.contains_key(&key) {
+            return false;
+        }
+
+        if self.seen.len() >= self.max_entries {
+            self.seen.retain(|_, expiry| *expiry > now);
+        }
+
+        if self.seen.len() >= self.max_entries {
+            return false;
+        }
+
+        self.seen.insert(key, now + lifetime as u64);
+        true
+    }
+}
+
 /// Something which never produces tickets.
 struct NeverProducesTickets {}
 
@@ -210,7 +514,7 @@ impl ProducesTickets for NeverProducesTickets {
     fn get_lifetime(&self) -> u32 {
         0
     }
-    fn encrypt(&self, _bytes: &[u8]) -> Option<Vec<u8>> {
+    fn encrypt(&self, _bytes: &[u8], _rng: &SecureRandom) -> Option<Vec<u8>> {
         None
     }
     fn decrypt(&self, _bytes: &[u8]) -> Option<Vec<u8>> {
@@ -222,10 +526,7 @@ impl ProducesTickets for NeverProducesTickets {
 struct FailResolveChain {}
 
 impl ResolvesServerCert for FailResolveChain {
-    fn resolve(&self,
-               _server_name: Option<&str>,
-               _sigschemes: &[SignatureScheme])
-               -> Option<sign::CertChainAndSigner> {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<sign::CertChainAndSigner> {
         None
     }
 }
@@ -236,23 +537,69 @@ struct AlwaysResolvesChain {
     key: Arc<Box<sign::Signer>>,
 }
 
-impl AlwaysResolvesChain {
-    fn new_rsa(chain: Vec<key::Certificate>, priv_key: &key::PrivateKey) -> AlwaysResolvesChain {
-        let key = sign::RSASigner::new(priv_key)
-            .expect("Invalid RSA private key");
-        AlwaysResolvesChain {
-            chain: chain,
-            key: Arc::new(Box::new(key)),
+impl ResolvesServerCert for AlwaysResolvesChain {
+    fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertChainAndSigner> {
+        // We've nothing else to send, and the client may manage
+        // anyway (RFC8446 section 4.4.2.2), so we try.
+        if !chain_signed_acceptably(&self.chain, client_hello.cert_sigschemes()) {
+            warn!("certificate chain is signed with a scheme the client didn't offer");
         }
+
+        Some((self.chain.clone(), self.key.clone()))
     }
 }
 
-impl ResolvesServerCert for AlwaysResolvesChain {
-    fn resolve(&self,
-               _server_name: Option<&str>,
-               _sigschemes: &[SignatureScheme])
-               -> Option<sign::CertChainAndSigner> {
-        Some((self.chain.clone(), self.key.clone()))
+/// Whether all the signatures in `chain` use schemes in
+/// `cert_sigschemes`.  A self-signed root's signature doesn't count:
+/// nobody checks it.  Nor do signatures we can't identify.
+fn chain_signed_acceptably(chain: &[key::Certificate],
+                           cert_sigschemes: &[SignatureScheme]) -> bool {
+    chain.iter().all(|cert| {
+        match x509::CertificateInfo::parse(&cert.0) {
+            Some(ref info) if info.issuer != info.subject => {
+                x509::signature_scheme(info.signature_algorithm)
+                    .map_or(true, |scheme| cert_sigschemes.contains(&scheme))
+            }
+            _ => true,
+        }
+    })
+}
+
+/// Something which chooses the first of several certificate chains
+/// whose key the client can use, preferring those signed with schemes
+/// the client accepts in certificates.
+struct ResolvesChainByKeyType {
+    certs: Vec<sign::CertChainAndSigner>,
+}
+
+impl ResolvesChainByKeyType {
+    /// Can the client use `signer`?  It must offer a ciphersuite
+    /// compatible with the key type (all TLS1.3 suites are), and a
+    /// signature scheme the key can make.
+    fn client_can_use(client_hello: &ClientHello, signer: &sign::Signer) -> bool {
+        let alg = signer.algorithm();
+        let suite_ok = ALL_CIPHERSUITES.iter()
+            .filter(|scs| client_hello.cipher_suites().contains(&scs.suite))
+            .any(|scs| scs.sign == alg || scs.sign == SignatureAlgorithm::Anonymous);
+
+        suite_ok && signer.choose_scheme(client_hello.sigschemes()).is_some()
+    }
+}
+
+impl ResolvesServerCert for ResolvesChainByKeyType {
+    fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertChainAndSigner> {
+        let usable: Vec<&sign::CertChainAndSigner> = self.certs
+            .iter()
+            .filter(|cert| ResolvesChainByKeyType::client_can_use(&client_hello, &**cert.1))
+            .collect();
+
+        // If no chain is signed as the client would like, send one
+        // anyway, as RFC8446 section 4.4.2.2 suggests.
+        usable.iter()
+            .find(|cert| chain_signed_acceptably(&cert.0, client_hello.cert_sigschemes()))
+            .or(usable.first())
+            .cloned()
+            .cloned()
     }
 }
 
@@ -264,15 +611,39 @@ impl ServerConfig {
         ServerConfig {
             ciphersuites: ALL_CIPHERSUITES.to_vec(),
             ignore_client_order: false,
+            reject_unknown_extensions: false,
             session_storage: Mutex::new(Box::new(NoSessionStorage {})),
             ticketer: Box::new(NeverProducesTickets {}),
+            ticket_lifetime: None,
+            replay_protection: Mutex::new(ReplayCache::new(1024)),
             alpn_protocols: Vec::new(),
+            application_settings: collections::HashMap::new(),
             cert_resolver: Box::new(FailResolveChain {}),
+            missing_sni_policy: MissingSNIPolicy::Resolve,
             client_auth_roots: anchors::RootCertStore::empty(),
             client_auth_offer: false,
             client_auth_mandatory: false,
             versions: vec![ ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2 ],
-            verifier: Box::new(verify::WebPKIVerifier {}),
+            kx_groups: NamedGroups::supported(),
+            kem: None,
+            cert_compressors: Vec::new(),
+            delegated_credential: None,
+            max_early_data_size: 0,
+            max_handshake_size: DEFAULT_MAX_HANDSHAKE_SIZE,
+            max_certificate_size: DEFAULT_MAX_HANDSHAKE_SIZE,
+            max_warning_alerts: DEFAULT_MAX_WARNING_ALERTS,
+            allow_non_forward_secret: false,
+            reject_tls13_compat_ccs: false,
+            record_size_limit: None,
+            rng: Box::new(SystemRandom::new()),
+            verifier: Box::new(verify::WebPKIVerifier::new()),
+            time: Arc::new(Box::new(verify::SystemTimeProvider {})),
+            clock_skew: Duration::from_secs(verify::DEFAULT_CLOCK_SKEW_SECS),
+            min_rsa_key_bits: verify::DEFAULT_MIN_RSA_KEY_BITS,
+            record_padding: None,
+            resumption_policy: None,
+            resumption: Resumption::Enabled,
+            cert_chains: Vec::new(),
         }
     }
 
@@ -281,21 +652,99 @@ impl ServerConfig {
         self.verifier.as_ref()
     }
 
+    #[doc(hidden)]
+    pub fn get_time_provider(&self) -> &verify::TimeProvider {
+        &**self.time
+    }
+
+    #[doc(hidden)]
+    pub fn get_clock_skew(&self) -> Duration {
+        self.clock_skew
+    }
+
     /// Sets the session persistence layer to `persist`.
     pub fn set_persistence(&mut self, persist: Box<StoresServerSessions + Send>) {
         self.session_storage = Mutex::new(persist);
     }
 
+    /// Sets how we detect replayed early data to `protection`.
+    pub fn set_replay_protection(&mut self, protection: Box<ReplayProtection + Send>) {
+        self.replay_protection = Mutex::new(protection);
+    }
+
     /// Sets a single certificate chain and matching private key.  This
     /// certificate and key is used for all subsequent connections,
     /// irrespective of things like SNI hostname.
     ///
+    /// This replaces any chains set before.  Chains added afterwards
+    /// with `add_cert_chain` are chosen between along with this one,
+    /// which is tried first.
+    ///
     /// `cert_chain` is a vector of DER-encoded certificates.
     /// `key_der` is a DER-encoded RSA private key.
     pub fn set_single_cert(&mut self,
                            cert_chain: Vec<key::Certificate>,
                            key_der: key::PrivateKey) {
-        self.cert_resolver = Box::new(AlwaysResolvesChain::new_rsa(cert_chain, &key_der));
+        let signer = sign::RSASigner::new(&key_der)
+            .expect("Invalid RSA private key");
+        self.set_single_cert_with_signer(cert_chain, Box::new(signer));
+    }
+
+    /// Sets a single certificate chain, and a `Signer` for its
+    /// certified key.  Like `set_single_cert`, this is used for all
+    /// subsequent connections.
+    ///
+    /// Use this when the private key isn't available to us, for
+    /// example when it's held in an HSM or a remote key service.
+    pub fn set_single_cert_with_signer(&mut self,
+                                       cert_chain: Vec<key::Certificate>,
+                                       signer: Box<sign::Signer>) {
+        let key = Arc::new(signer);
+        self.cert_chains = vec![ (cert_chain.clone(), key.clone()) ];
+        self.cert_resolver = Box::new(AlwaysResolvesChain {
+            chain: cert_chain,
+            key: key,
+        });
+    }
+
+    /// Sets several certificate chains, each with a `Signer` for its
+    /// certified key: for example, one RSA and one ECDSA.  For each
+    /// connection we use the first chain whose key type is compatible
+    /// with the ciphersuites and signature schemes the client offered,
+    /// preferring one whose certificates are signed with schemes the
+    /// client accepts.  If there's none, the handshake fails.
+    pub fn set_certs_with_signers(&mut self, certs: Vec<sign::CertChainAndSigner>) {
+        self.cert_chains = certs;
+        self.rebuild_cert_resolver();
+    }
+
+    /// Adds a certificate chain and matching private key to those we
+    /// choose between, like `set_certs_with_signers`.  Chains are
+    /// tried in the order they were added, and more than one may have
+    /// the same key type.
+    ///
+    /// `cert_chain` is a vector of DER-encoded certificates.
+    /// `key_der` is a DER-encoded RSA private key.
+    pub fn add_cert_chain(&mut self,
+                          cert_chain: Vec<key::Certificate>,
+                          key_der: key::PrivateKey) {
+        let signer = sign::RSASigner::new(&key_der)
+            .expect("Invalid RSA private key");
+        self.add_cert_chain_with_signer(cert_chain, Box::new(signer));
+    }
+
+    /// Adds a certificate chain, and a `Signer` for its certified
+    /// key, to those we choose between.  See `add_cert_chain`.
+    pub fn add_cert_chain_with_signer(&mut self,
+                                      cert_chain: Vec<key::Certificate>,
+                                      signer: Box<sign::Signer>) {
+        self.cert_chains.push((cert_chain, Arc::new(signer)));
+        self.rebuild_cert_resolver();
+    }
+
+    fn rebuild_cert_resolver(&mut self) {
+        let certs = self.cert_chains.clone();
+        self.cert_resolver = Box::new(ResolvesChainByKeyType { certs: certs });
     }
 
     /// Set the ALPN protocol list to the given protocol names.
@@ -322,6 +771,126 @@ impl ServerConfig {
         self.client_auth_mandatory = mandatory;
     }
 
+    /// Only accept key exchange with `groups`, preferring the
+    /// earliest.  If the client supports none of them, the
+    /// handshake fails with a `handshake_failure` alert.
+    ///
+    /// This replaces `kx_groups`, so call `set_kem` afterwards
+    /// if you want hybrid key exchange.
+    pub fn set_groups(&mut self, groups: &[NamedGroup]) {
+        self.kx_groups = groups.to_vec();
+    }
+
+    /// Enables hybrid post-quantum key exchange, using `kem`
+    /// for the post-quantum half.  This makes `X25519MLKEM768`
+    /// our most preferred group.
+    pub fn set_kem(&mut self, kem: Box<KeyEncapsulation>) {
+        self.kem = Some(kem);
+        self.kx_groups.retain(|group| *group != NamedGroup::X25519MLKEM768);
+        self.kx_groups.insert(0, NamedGroup::X25519MLKEM768);
+    }
+
+    /// Pads each TLS1.3 record we send with the number of zero
+    /// bytes `padding` returns, to hide the length of its content.
+    /// `padding` is given the length of the record's content plus
+    /// one.  For example, to pad to a multiple of 256 bytes:
+    ///
+    /// ```
+    /// # let mut config = rustls::ServerConfig::new();
+    /// config.set_record_padding(|len| (256 - len % 256) % 256);
+    /// ```
+    ///
+    /// Padding is limited so no record exceeds the maximum size.
+    /// TLS1.2 records aren't padded.
+    pub fn set_record_padding<F>(&mut self, padding: F)
+        where F: Fn(usize) -> usize + Send + Sync + 'static
+    {
+        self.record_padding = Some(Arc::new(padding));
+    }
+
+    /// Sets where we get the current time when checking client
+    /// certificates have not expired, and the ages of TLS1.3
+    /// tickets.  The default is the system clock.
+    ///
+    /// This replaces any certificate verifier set through
+    /// `dangerous()`.
+    pub fn set_time_provider(&mut self, time: Box<verify::TimeProvider>) {
+        self.time = Arc::new(time);
+        self.rebuild_verifier();
+    }
+
+    /// Sets how far our clock may be from the client's, and from
+    /// those of certificate issuers.  We accept client certificates
+    /// which are valid at any time within `skew` of now, and
+    /// remember tickets' early data for `skew` beyond their
+    /// lifetime.  The default is five minutes.
+    ///
+    /// Like `set_time_provider`, this replaces any certificate
+    /// verifier set through `dangerous()`.
+    pub fn set_clock_skew(&mut self, skew: Duration) {
+        self.clock_skew = skew;
+        self.rebuild_verifier();
+    }
+
+    /// Sets the smallest RSA key, in bits, we accept in client certificate chains.
+    /// We never accept keys smaller than 2048 bits, so a smaller
+    /// `bits` has no effect.  The default is 2048.
+    ///
+    /// Like `set_time_provider`, this replaces any certificate
+    /// verifier set through `dangerous()`.
+    pub fn set_min_rsa_key_bits(&mut self, bits: usize) {
+        self.min_rsa_key_bits = bits;
+        self.rebuild_verifier();
+    }
+
+    fn rebuild_verifier(&mut self) {
+        let verifier = verify::WebPKIVerifier::with_min_rsa_key_bits(self.time.clone(),
+                                                                     self.clock_skew,
+                                                                     self.min_rsa_key_bits);
+        self.verifier = Box::new(verifier);
+    }
+
+    /// Sets the largest amount of early data, in bytes, that our
+    /// TLS1.3 tickets invite the client to send.  Zero means
+    /// tickets don't mention early data at all.
+    pub fn set_max_early_data_size(&mut self, max: u32) {
+        self.max_early_data_size = max;
+    }
+
+    /// Sets a function which decides, given the client's hello,
+    /// whether the client may resume a session.  When it returns
+    /// false we do a full handshake, even if the client presented
+    /// a valid ticket or session ID: for example, to make certain
+    /// clients authenticate afresh.
+    ///
+    /// By default, all clients may resume.
+    pub fn set_resumption_policy<F>(&mut self, policy: F)
+        where F: Fn(&ClientHello) -> bool + Send + Sync + 'static
+    {
+        self.resumption_policy = Some(Box::new(policy));
+    }
+
+    /// Sets whether clients may resume sessions.  With
+    /// `Resumption::Disabled` we never issue session IDs or tickets,
+    /// whatever `session_storage` and `ticketer` say, and always do
+    /// a full handshake.  The default is `Resumption::Enabled`.
+    pub fn set_resumption(&mut self, resumption: Resumption) {
+        self.resumption = resumption;
+    }
+
+    #[doc(hidden)]
+    pub fn resumption_enabled(&self) -> bool {
+        self.resumption == Resumption::Enabled
+    }
+
+    #[doc(hidden)]
+    pub fn should_resume(&self, hello: &ClientHello) -> bool {
+        self.resumption_enabled() && self.resumption_policy
+            .as_ref()
+            .map(|policy| policy(hello))
+            .unwrap_or(true)
+    }
+
     /// Access configuration options whose use is dangerous and requires
     /// extra care.
     #[cfg(feature = "dangerous_configuration")]
@@ -353,6 +922,7 @@ pub mod danger {
 
 pub struct ServerHandshakeData {
     pub server_cert_chain: Option<CertificatePayload>,
+    pub sent_server_cert_chain: bool,
     pub session_id: SessionID,
     pub randoms: SessionRandoms,
     pub transcript: hash_hs::HandshakeHash,
@@ -364,14 +934,29 @@ pub struct ServerHandshakeData {
     pub doing_client_auth: bool,
     pub done_retry: bool,
     pub valid_client_cert_chain: Option<Vec<key::Certificate>>,
+    pub client_sigschemes: Option<Vec<SignatureScheme>>,
+    pub client_versions: Option<Vec<ProtocolVersion>>,
+    pub doing_alps: bool,
+    pub peer_application_settings: Option<Vec<u8>>,
+    pub kx_group: Option<NamedGroup>,
+    pub server_sigscheme: Option<SignatureScheme>,
+    pub offered_alpn: Option<Vec<Vec<u8>>>,
+    pub post_handshake_auth_offered: bool,
+    pub transcript_at_client_fin: Option<hash_hs::HandshakeHash>,
+    pub post_handshake_auth_context: Option<Vec<u8>>,
+    pub post_handshake_cert_chain: Option<Vec<key::Certificate>>,
+    pub early_data_left: usize,
+    pub forced_alpn: Option<String>,
+    pub resumption_denied: bool,
 }
 
 impl ServerHandshakeData {
-    fn new() -> ServerHandshakeData {
+    fn new(rng: &SecureRandom) -> ServerHandshakeData {
         ServerHandshakeData {
             server_cert_chain: None,
+            sent_server_cert_chain: false,
             session_id: SessionID::empty(),
-            randoms: SessionRandoms::for_server(),
+            randoms: SessionRandoms::for_server(rng),
             transcript: hash_hs::HandshakeHash::new(),
             hash_at_server_fin: vec![],
             kx_data: None,
@@ -381,6 +966,20 @@ impl ServerHandshakeData {
             doing_client_auth: false,
             done_retry: false,
             valid_client_cert_chain: None,
+            client_sigschemes: None,
+            client_versions: None,
+            doing_alps: false,
+            peer_application_settings: None,
+            kx_group: None,
+            server_sigscheme: None,
+            offered_alpn: None,
+            post_handshake_auth_offered: false,
+            transcript_at_client_fin: None,
+            post_handshake_auth_context: None,
+            post_handshake_cert_chain: None,
+            early_data_left: 0,
+            forced_alpn: None,
+            resumption_denied: false,
         }
     }
 }
@@ -399,14 +998,19 @@ impl ServerSessionImpl {
     pub fn new(server_config: &Arc<ServerConfig>) -> ServerSessionImpl {
         let mut sess = ServerSessionImpl {
             config: server_config.clone(),
-            handshake_data: ServerHandshakeData::new(),
+            handshake_data: ServerHandshakeData::new(server_config.rng.as_ref()),
             secrets: None,
-            common: SessionCommon::new(None, false),
+            common: SessionCommon::new(None, server_config.record_padding.clone(), false),
             alpn_protocol: None,
             error: None,
             state: &server_hs::EXPECT_CLIENT_HELLO,
         };
 
+        sess.common.handshake_joiner.set_max_size(sess.config.max_handshake_size);
+        sess.common.handshake_joiner.set_max_certificate_size(sess.config.max_certificate_size);
+        sess.common.max_warning_alerts = sess.config.max_warning_alerts;
+        sess.common.reject_compat_ccs = sess.config.reject_tls13_compat_ccs;
+
         if sess.config.client_auth_offer {
             sess.handshake_data.transcript.set_client_auth_enabled();
         }
@@ -414,6 +1018,29 @@ impl ServerSessionImpl {
         sess
     }
 
+    pub fn reset(&mut self, server_config: &Arc<ServerConfig>) {
+        if let Some(ref mut secrets) = self.secrets {
+            secrets.wipe();
+        }
+
+        self.config = server_config.clone();
+        self.handshake_data = ServerHandshakeData::new(server_config.rng.as_ref());
+        self.secrets = None;
+        self.common.reset();
+        self.common.record_padding = self.config.record_padding.clone();
+        self.common.handshake_joiner.set_max_size(self.config.max_handshake_size);
+        self.common.handshake_joiner.set_max_certificate_size(self.config.max_certificate_size);
+        self.common.max_warning_alerts = self.config.max_warning_alerts;
+        self.common.reject_compat_ccs = self.config.reject_tls13_compat_ccs;
+        self.alpn_protocol = None;
+        self.error = None;
+        self.state = &server_hs::EXPECT_CLIENT_HELLO;
+
+        if self.config.client_auth_offer {
+            self.handshake_data.transcript.set_client_auth_enabled();
+        }
+    }
+
     pub fn wants_read(&self) -> bool {
         // We want to read more data all the time, except when we
         // have unprocessed plaintext.  This provides back-pressure
@@ -428,19 +1055,60 @@ impl ServerSessionImpl {
         !self.common.sendable_tls.is_empty()
     }
 
+    pub fn pending_write_len(&self) -> usize {
+        self.common.sendable_tls.len()
+    }
+
+    pub fn handshake_transcript(&self) -> Vec<HandshakeMessageSummary> {
+        self.handshake_data.transcript.get_summaries().to_vec()
+    }
+
+    #[cfg(feature = "dangerous_debug")]
+    pub fn dangerous_extract_secrets(&self) -> Option<ExtractedSecrets> {
+        self.common.extract_secrets(self.secrets.as_ref())
+    }
+
     pub fn is_handshaking(&self) -> bool {
         !self.common.traffic
     }
 
-    pub fn set_buffer_limit(&mut self, len: usize) {
+    pub fn set_buffer_limit(&mut self, len: Option<usize>) {
         self.common.set_buffer_limit(len)
     }
 
     pub fn process_msg(&mut self, mut msg: Message) -> Result<(), TLSError> {
-        // Decrypt if demanded by current state.
+        // Drop TLS1.3 middlebox compatibility ChangeCipherSpecs, unless
+        // we're refusing them.
+        if self.common.is_tls13_compat_ccs(&msg) {
+            if self.common.reject_compat_ccs {
+                self.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+                return Err(TLSError::PeerMisbehavedError("received TLS1.3 compatibility \
+                                                          ChangeCipherSpec"
+                    .to_string()));
+            }
+
+            trace!("Dropping compatibility ChangeCipherSpec");
+            return Ok(());
+        }
+
+        // Decrypt if demanded by current state.  Records we can't
+        // decrypt may be early data we rejected, which we skip.
         if self.common.peer_encrypting {
-            let dm = self.common.decrypt_incoming(msg)?;
-            msg = dm;
+            msg = match self.common.decrypt_incoming_or_skip(msg)? {
+                Some(dm) => dm,
+                None => return Ok(()),
+            };
+        } else if self.common.skip_early_data_before_retry(&msg) {
+            return Ok(());
+        }
+
+        // Handshake messages may be fragmented across records, but
+        // those records mustn't be interleaved with other types.
+        if !self.common.handshake_joiner.is_empty() &&
+           !self.common.handshake_joiner.want_message(&msg) {
+            self.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+            let why = "handshake message interleaved with other content";
+            return Err(TLSError::PeerMisbehavedError(why.to_string()));
         }
 
         // For handshake messages, we need to join them before parsing
@@ -477,6 +1145,9 @@ impl ServerSessionImpl {
     }
 
     pub fn process_main_protocol(&mut self, msg: Message) -> Result<(), TLSError> {
+        trace!("Received {:?} message {:?}", msg.typ, msg.get_handshake_type());
+        self.common.reset_warning_alerts();
+
         if self.common.traffic && !self.common.is_tls13() &&
            msg.is_handshake_type(HandshakeType::ClientHello) {
             self.common.send_warning_alert(AlertDescription::NoRenegotiation);
@@ -496,6 +1167,14 @@ impl ServerSessionImpl {
             return Err(err.clone());
         }
 
+        if self.common.message_deframer.sslv2_client_hello {
+            let err = TLSError::PeerIncompatibleError("client sent SSLv2-format ClientHello"
+                                                      .to_string());
+            self.common.send_fatal_alert(AlertDescription::ProtocolVersion);
+            self.error = Some(err.clone());
+            return Err(err);
+        }
+
         if self.common.message_deframer.desynced {
             return Err(TLSError::CorruptMessage);
         }
@@ -532,13 +1211,91 @@ impl ServerSessionImpl {
         Some(r)
     }
 
+    pub fn request_client_auth(&mut self) -> Result<(), TLSError> {
+        server_hs::start_post_handshake_auth(self)
+    }
+
+    pub fn is_authenticating_client(&self) -> bool {
+        self.handshake_data.post_handshake_auth_context.is_some()
+    }
+
+    pub fn get_sent_certificates(&self) -> Option<&[key::Certificate]> {
+        if !self.handshake_data.sent_server_cert_chain {
+            return None;
+        }
+
+        self.handshake_data.server_cert_chain
+            .as_ref()
+            .map(|chain| chain.as_slice())
+    }
+
     pub fn get_alpn_protocol(&self) -> Option<String> {
         self.alpn_protocol.clone()
     }
 
+    pub fn get_peer_application_settings(&self, proto: &str) -> Option<Vec<u8>> {
+        match self.alpn_protocol {
+            Some(ref agreed) if agreed == proto => {
+                self.handshake_data.peer_application_settings.clone()
+            }
+            _ => None,
+        }
+    }
+
+    pub fn set_alpn_protocol(&mut self, proto: &[u8]) -> Result<(), TLSError> {
+        if self.common.negotiated_version.is_some() {
+            return Err(TLSError::General("ALPN protocol must be set before \
+                                          the ClientHello arrives".to_string()));
+        }
+
+        let proto = String::from_utf8(proto.to_vec())
+            .map_err(|_| TLSError::General("ALPN protocol is not UTF-8".to_string()))?;
+        if proto.is_empty() {
+            return Err(TLSError::General("ALPN protocol is empty".to_string()));
+        }
+
+        self.handshake_data.forced_alpn = Some(proto);
+        Ok(())
+    }
+
     pub fn get_protocol_version(&self) -> Option<ProtocolVersion> {
         self.common.negotiated_version
     }
+
+    pub fn get_peer_signature_schemes(&self) -> Option<&[SignatureScheme]> {
+        self.handshake_data.client_sigschemes
+            .as_ref()
+            .map(|schemes| schemes.as_slice())
+    }
+
+    pub fn get_peer_supported_versions(&self) -> Option<&[ProtocolVersion]> {
+        self.handshake_data.client_versions
+            .as_ref()
+            .map(|versions| versions.as_slice())
+    }
+
+    pub fn get_offered_alpn(&self) -> Option<&[Vec<u8>]> {
+        self.handshake_data.offered_alpn
+            .as_ref()
+            .map(|protos| protos.as_slice())
+    }
+
+    pub fn get_key_exchange_group(&self) -> Option<NamedGroup> {
+        self.handshake_data.kx_group
+    }
+
+    pub fn get_server_signature_scheme(&self) -> Option<SignatureScheme> {
+        self.handshake_data.server_sigscheme
+    }
+
+    pub fn send_fatal_alert(&mut self, desc: AlertDescription) {
+        if self.error.is_some() {
+            return;
+        }
+
+        self.common.send_fatal_alert(desc);
+        self.error = Some(TLSError::General(format!("session aborted with {:?} alert", desc)));
+    }
 }
 
 /// This represents a single TLS server session.
@@ -556,6 +1313,69 @@ impl ServerSession {
     pub fn new(config: &Arc<ServerConfig>) -> ServerSession {
         ServerSession { imp: ServerSessionImpl::new(config) }
     }
+
+    /// Abandons the current connection and readies this session
+    /// for a new one, as if it were made by `ServerSession::new`
+    /// with `config`.  This reuses the session's buffers, which
+    /// saves allocations on busy servers.
+    ///
+    /// The secrets of the old connection are overwritten.  Any
+    /// buffered data, sent or received, is discarded.
+    pub fn reset(&mut self, config: &Arc<ServerConfig>) {
+        self.imp.reset(config)
+    }
+
+    /// Retrieves the certificate chain we sent to authenticate
+    /// ourselves to the client.
+    ///
+    /// This returns None until we've sent our certificate, and
+    /// for resumed sessions, where we don't send one.
+    pub fn get_sent_certificates(&self) -> Option<&[key::Certificate]> {
+        self.imp.get_sent_certificates()
+    }
+
+    /// Asks the client to authenticate with a certificate after
+    /// the handshake.  This is only possible in TLS1.3, with a
+    /// client that offered post-handshake authentication;
+    /// otherwise this returns an error and does nothing.
+    ///
+    /// The request goes out in the next `write_tls` call.  Once
+    /// `process_new_packets` has handled the client's answer,
+    /// `is_authenticating_client` returns false and
+    /// `get_peer_certificates` returns the client's new
+    /// certificate chain, if it sent one.
+    pub fn request_client_auth(&mut self) -> Result<(), TLSError> {
+        self.imp.request_client_auth()
+    }
+
+    /// Returns true between `request_client_auth` and the
+    /// client finishing its answer.
+    pub fn is_authenticating_client(&self) -> bool {
+        self.imp.is_authenticating_client()
+    }
+
+    /// Retrieves all the ALPN protocols the client offered, in
+    /// the client's order of preference.  Use `get_alpn_protocol`
+    /// for the one we chose.
+    ///
+    /// This returns None until we've received the ClientHello,
+    /// and if the client didn't offer ALPN.
+    pub fn get_offered_alpn(&self) -> Option<&[Vec<u8>]> {
+        self.imp.get_offered_alpn()
+    }
+
+    /// Forces the outcome of ALPN negotiation to be `proto`,
+    /// overriding `ServerConfig::alpn_protocols`.  This is for
+    /// transparent proxies, which must agree the same protocol as
+    /// the server behind them.
+    ///
+    /// This must be called before the ClientHello is processed,
+    /// and returns an error otherwise.  If the client doesn't offer
+    /// `proto`, the handshake fails with a `no_application_protocol`
+    /// alert.
+    pub fn set_alpn_protocol(&mut self, proto: &[u8]) -> Result<(), TLSError> {
+        self.imp.set_alpn_protocol(proto)
+    }
 }
 
 impl Session for ServerSession {
@@ -580,18 +1400,51 @@ impl Session for ServerSession {
         self.imp.wants_write()
     }
 
+    fn pending_write_len(&self) -> usize {
+        self.imp.pending_write_len()
+    }
+
+    fn handshake_transcript(&self) -> Vec<HandshakeMessageSummary> {
+        self.imp.handshake_transcript()
+    }
+
+    #[cfg(feature = "dangerous_debug")]
+    fn dangerous_extract_secrets(&self) -> Option<ExtractedSecrets> {
+        self.imp.dangerous_extract_secrets()
+    }
+
     fn is_handshaking(&self) -> bool {
         self.imp.is_handshaking()
     }
 
-    fn set_buffer_limit(&mut self, len: usize) {
+    fn set_buffer_limit(&mut self, len: Option<usize>) {
         self.imp.set_buffer_limit(len)
     }
 
+    fn flush_plaintext(&mut self) {
+        self.imp.common.flush_plaintext()
+    }
+
     fn send_close_notify(&mut self) {
         self.imp.common.send_close_notify()
     }
 
+    fn send_fatal_alert(&mut self, desc: AlertDescription) {
+        self.imp.send_fatal_alert(desc)
+    }
+
+    fn take_received_warnings(&mut self) -> Vec<AlertDescription> {
+        self.imp.common.take_received_warnings()
+    }
+
+    fn peek_plaintext(&self) -> &[u8] {
+        self.imp.common.peek_plaintext()
+    }
+
+    fn consume_plaintext(&mut self, amt: usize) {
+        self.imp.common.consume_plaintext(amt)
+    }
+
     fn get_peer_certificates(&self) -> Option<Vec<key::Certificate>> {
         self.imp.get_peer_certificates()
     }
@@ -600,9 +1453,45 @@ impl Session for ServerSession {
         self.imp.get_alpn_protocol()
     }
 
+    fn get_peer_application_settings(&self, proto: &str) -> Option<Vec<u8>> {
+        self.imp.get_peer_application_settings(proto)
+    }
+
     fn get_protocol_version(&self) -> Option<ProtocolVersion> {
         self.imp.get_protocol_version()
     }
+
+    fn negotiated_record_size_limit(&self) -> usize {
+        self.imp.common.get_record_size_limit()
+    }
+
+    fn has_forward_secrecy(&self) -> bool {
+        self.imp.common.has_forward_secrecy()
+    }
+
+    fn client_random(&self) -> Option<[u8; 32]> {
+        self.imp.handshake_data.randoms.get_client()
+    }
+
+    fn server_random(&self) -> Option<[u8; 32]> {
+        self.imp.handshake_data.randoms.get_server()
+    }
+
+    fn get_peer_signature_schemes(&self) -> Option<&[SignatureScheme]> {
+        self.imp.get_peer_signature_schemes()
+    }
+
+    fn get_peer_supported_versions(&self) -> Option<&[ProtocolVersion]> {
+        self.imp.get_peer_supported_versions()
+    }
+
+    fn get_key_exchange_group(&self) -> Option<NamedGroup> {
+        self.imp.get_key_exchange_group()
+    }
+
+    fn get_server_signature_scheme(&self) -> Option<SignatureScheme> {
+        self.imp.get_server_signature_scheme()
+    }
 }
 
 impl io::Read for ServerSession {
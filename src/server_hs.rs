@@ -8,7 +8,7 @@ use msgs::handshake::{HandshakePayload, SupportedSignatureSchemes};
 use msgs::handshake::{HandshakeMessagePayload, ServerHelloPayload, Random};
 use msgs::handshake::{ClientHelloPayload, ServerExtension, SessionID};
 use msgs::handshake::{ConvertProtocolNameList, ConvertServerNameList};
-use msgs::handshake::{NamedGroups, SupportedGroups, ClientExtension};
+use msgs::handshake::{ClientExtension, HasServerExtensions};
 use msgs::handshake::{ECPointFormatList, SupportedPointFormats};
 use msgs::handshake::{ServerECDHParams, DigitallySignedStruct};
 use msgs::handshake::{ServerKeyExchangePayload, ECDHEServerKeyExchange};
@@ -17,14 +17,20 @@ use msgs::handshake::{CertificateRequestPayloadTLS13, NewSessionTicketPayloadTLS
 use msgs::handshake::{HelloRetryRequest, HelloRetryExtension, KeyShareEntry};
 use msgs::handshake::{CertificatePayloadTLS13, CertificateEntry};
 use msgs::handshake::SupportedMandatedSignatureSchemes;
+use msgs::handshake::NewSessionTicketExtension;
+use msgs::handshake::CertificateCompressionAlgorithms;
+use msgs::handshake::{CertificateExtension, DelegatedCredentialPayload};
 use msgs::ccs::ChangeCipherSpecPayload;
 use msgs::codec::Codec;
 use msgs::persist;
 use session::SessionSecrets;
 use cipher;
-use server::ServerSessionImpl;
+use server::{ServerSessionImpl, ClientHello, MissingSNIPolicy};
 use key_schedule::{KeySchedule, SecretKind};
 use suites;
+use kem;
+use compress;
+use delegated;
 use hash_hs;
 use sign;
 use verify;
@@ -34,6 +40,8 @@ use error::TLSError;
 use handshake::Expectation;
 
 use std::sync::Arc;
+use std::cmp;
+use std::time::UNIX_EPOCH;
 
 use ring::constant_time;
 
@@ -60,13 +68,21 @@ pub struct State {
     pub handle: HandleFunction,
 }
 
+/// The ALPN protocol we choose from `their_protocols`, if any.
+fn choose_alpn_protocol(sess: &ServerSessionImpl, their_protocols: &[String]) -> Option<String> {
+    match sess.handshake_data.forced_alpn {
+        Some(ref forced) if their_protocols.contains(forced) => Some(forced.clone()),
+        Some(_) => None,
+        None => util::first_in_both(&sess.config.alpn_protocols, their_protocols),
+    }
+}
+
 fn process_extensions(sess: &mut ServerSessionImpl,
                       hello: &ClientHelloPayload)
                       -> Result<Vec<ServerExtension>, TLSError> {
     let mut ret = Vec::new();
 
     // ALPN
-    let our_protocols = &sess.config.alpn_protocols;
     let maybe_their_protocols = hello.get_alpn_extension();
     if let Some(their_protocols) = maybe_their_protocols {
         let their_proto_strings = their_protocols.to_strings();
@@ -76,21 +92,65 @@ fn process_extensions(sess: &mut ServerSessionImpl,
                 .to_string()));
         }
 
-        sess.alpn_protocol = util::first_in_both(our_protocols, &their_proto_strings);
+        sess.alpn_protocol = choose_alpn_protocol(sess, &their_proto_strings);
         if let Some(ref selected_protocol) = sess.alpn_protocol {
             info!("Chosen ALPN protocol {:?}", selected_protocol);
             ret.push(ServerExtension::make_alpn(selected_protocol.clone()));
         }
     }
 
+    if sess.handshake_data.forced_alpn.is_some() && sess.alpn_protocol.is_none() {
+        sess.common.send_fatal_alert(AlertDescription::NoApplicationProtocol);
+        return Err(TLSError::PeerIncompatibleError("client didn't offer the forced \
+                                                    ALPN protocol".to_string()));
+    }
+
+    // ALPS: if the client offered it for the agreed protocol, and we
+    // have settings for that protocol.
+    if let Some(ref proto) = sess.alpn_protocol {
+        let alps_offered = hello.get_application_settings_extension()
+            .map(|protos| protos.to_strings().contains(proto))
+            .unwrap_or(false);
+        let our_settings = sess.config.application_settings.get(proto);
+
+        match our_settings {
+            Some(settings) if alps_offered && sess.common.is_tls13() => {
+                ret.push(ServerExtension::ApplicationSettings(Payload::new(settings.clone())));
+                sess.handshake_data.doing_alps = true;
+            }
+            _ => {}
+        }
+    }
+
     // SNI
     if hello.get_sni_extension().is_some() {
         ret.push(ServerExtension::ServerNameAck);
     }
 
+    // Record size limit: if the client advertised one, we obey it
+    // whether or not we have one.  Without one, we advertise the
+    // largest record the protocol allows (RFC8449 section 4).
+    if let Some(theirs) = hello.get_record_size_limit() {
+        let largest = sess.common.largest_record_size_limit();
+        let ours = sess.config.record_size_limit.unwrap_or(largest);
+        sess.common.negotiate_record_size_limit(ours, theirs)?;
+        ret.push(ServerExtension::RecordSizeLimit(ours));
+    }
+
     if !sess.common.is_tls13() {
         // Renegotiation.
         // (We don't do reneg at all, but would support the secure version if we did.)
+        // Every handshake we do is the first on its connection, resumed or
+        // not, so the client's renegotiated_connection must be empty.
+        if let Some(ri) = hello.get_renegotiation_info() {
+            if !ri.0.is_empty() {
+                sess.common.send_fatal_alert(AlertDescription::HandshakeFailure);
+                return Err(TLSError::PeerMisbehavedError("client sent non-empty \
+                                                          renegotiation info"
+                    .to_string()));
+            }
+        }
+
         let secure_reneg_offered =
             hello.find_extension(ExtensionType::RenegotiationInfo).is_some() ||
             hello.cipher_suites.contains(&CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV);
@@ -103,7 +163,7 @@ fn process_extensions(sess: &mut ServerSessionImpl,
         // If we get any SessionTicket extension and have tickets enabled,
         // we send an ack.
         if hello.find_extension(ExtensionType::SessionTicket).is_some() &&
-           sess.config.ticketer.enabled() {
+           sess.config.ticketer.enabled() && sess.config.resumption_enabled() {
             sess.handshake_data.send_ticket = true;
             ret.push(ServerExtension::SessionTicketAck);
         }
@@ -112,6 +172,12 @@ fn process_extensions(sess: &mut ServerSessionImpl,
         if sess.handshake_data.using_ems {
             ret.push(ServerExtension::ExtendedMasterSecretAck);
         }
+
+        // Say which EC point formats we process, if asked.  All our
+        // TLS1.2 suites use ECDHE.
+        if hello.get_ecpoints_extension().is_some() {
+            ret.push(ServerExtension::ECPointFormats(ECPointFormatList::supported()));
+        }
     }
 
     Ok(ret)
@@ -122,12 +188,14 @@ fn emit_server_hello(sess: &mut ServerSessionImpl,
                      -> Result<(), TLSError> {
     let extensions = process_extensions(sess, hello)?;
 
-    if sess.handshake_data.session_id.is_empty() {
+    // Without resumption, an empty session ID says we won't cache
+    // this session.
+    if sess.handshake_data.session_id.is_empty() && sess.config.resumption_enabled() {
         let sessid = sess.config
             .session_storage
             .lock()
             .unwrap()
-            .generate();
+            .generate(sess.config.rng.as_ref());
         sess.handshake_data.session_id = sessid;
     }
 
@@ -167,6 +235,7 @@ fn emit_certificate(sess: &mut ServerSessionImpl) {
 
     sess.handshake_data.transcript.add_message(&c);
     sess.common.send_msg(c, false);
+    sess.handshake_data.sent_server_cert_chain = true;
 }
 
 fn emit_server_kx(sess: &mut ServerSessionImpl,
@@ -175,16 +244,14 @@ fn emit_server_kx(sess: &mut ServerSessionImpl,
                   signer: Arc<Box<sign::Signer>>)
                   -> Result<(), TLSError> {
     let kx = sess.common.get_suite()
-        .start_server_kx(*group)
+        .start_server_kx(*group, sess.config.rng.as_ref())
         .ok_or_else(|| TLSError::PeerMisbehavedError("key exchange failed".to_string()))?;
     let secdh = ServerECDHParams::new(group, &kx.pubkey);
 
-    let mut msg = Vec::new();
-    msg.extend(&sess.handshake_data.randoms.client);
-    msg.extend(&sess.handshake_data.randoms.server);
-    secdh.encode(&mut msg);
+    let msg = verify::construct_tls12_server_kx_message(&sess.handshake_data.randoms,
+                                                        &secdh.get_encoding());
 
-    let sig = signer.sign(sigscheme, &msg)
+    let sig = signer.sign(sigscheme, &msg, sess.config.rng.as_ref())
         .map_err(|_| TLSError::General("signing failed".to_string()))?;
 
     let skx = ServerKeyExchangePayload::ECDHE(ECDHEServerKeyExchange {
@@ -202,6 +269,8 @@ fn emit_server_kx(sess: &mut ServerSessionImpl,
     };
 
     sess.handshake_data.kx_data = Some(kx);
+    sess.handshake_data.kx_group = Some(*group);
+    sess.handshake_data.server_sigscheme = Some(sigscheme);
     sess.handshake_data.transcript.add_message(&m);
     sess.common.send_msg(m, false);
     Ok(())
@@ -250,6 +319,16 @@ fn emit_server_hello_done(sess: &mut ServerSessionImpl) {
     sess.common.send_msg(m, false);
 }
 
+/// Our key exchange groups which are usable in TLS1.3 (if `tls13`)
+/// or TLS1.2, most preferred first.
+fn usable_groups(sess: &ServerSessionImpl, tls13: bool) -> Vec<NamedGroup> {
+    sess.config.kx_groups
+        .iter()
+        .filter(|group| !kem::is_hybrid(**group) || (tls13 && sess.config.kem.is_some()))
+        .cloned()
+        .collect()
+}
+
 fn incompatible(sess: &mut ServerSessionImpl, why: &str) -> TLSError {
     sess.common.send_fatal_alert(AlertDescription::HandshakeFailure);
     TLSError::PeerIncompatibleError(why.to_string())
@@ -267,6 +346,11 @@ fn decode_error(sess: &mut ServerSessionImpl, why: &str) -> TLSError {
 
 fn can_resume(sess: &ServerSessionImpl,
               resumedata: &Option<persist::ServerSessionValue>) -> bool {
+    if sess.handshake_data.resumption_denied {
+        info!("Resumption denied by policy");
+        return false;
+    }
+
     // The RFCs underspecify what happens if we try to resume to
     // an unoffered/varying suite.  We merely don't resume in weird cases.
     if let Some(ref resume) = *resumedata {
@@ -278,6 +362,38 @@ fn can_resume(sess: &ServerSessionImpl,
     }
 }
 
+fn ticket_timebase(sess: &ServerSessionImpl) -> u64 {
+    sess.config.get_time_provider()
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// A TLS1.3 client tells us how old it thinks the ticket `resume`
+/// came from is, obfuscated as `obfuscated_age`.  Returns false if
+/// that's too far from its real age, which suggests the ClientHello
+/// is a replay.
+///
+/// Both ages are measured in whole seconds, so we allow one second
+/// beyond our clock skew.
+fn ticket_age_is_plausible(sess: &ServerSessionImpl,
+                           resume: &persist::ServerSessionValue,
+                           obfuscated_age: u32) -> bool {
+    let window_millis = sess.config.get_clock_skew()
+        .as_secs()
+        .saturating_add(1)
+        .saturating_mul(1000);
+    let error_millis = resume.get_ticket_age_error(obfuscated_age, ticket_timebase(sess));
+
+    if error_millis > window_millis {
+        warn!("Ticket age is {}ms out", error_millis);
+        false
+    } else {
+        true
+    }
+}
+
 fn start_resumption(sess: &mut ServerSessionImpl,
                     client_hello: &ClientHelloPayload,
                     id: &SessionID,
@@ -326,9 +442,15 @@ fn emit_server_hello_tls13(sess: &mut ServerSessionImpl,
     let mut extensions = Vec::new();
 
     // Do key exchange
-    let kxr = suites::KeyExchange::start_ecdhe(share.group)
-        .and_then(|kx| kx.complete(&share.payload.0))
-        .ok_or_else(|| TLSError::PeerMisbehavedError("key exchange failed".to_string()))?;
+    let maybe_kxr = if kem::is_hybrid(share.group) {
+        let kem = sess.config.kem.as_ref().unwrap();
+        kem::server_complete(share.group, &**kem, &share.payload.0, sess.config.rng.as_ref())
+    } else {
+        suites::KeyExchange::start_ecdhe(share.group, sess.config.rng.as_ref())
+            .and_then(|kx| kx.complete(&share.payload.0))
+    };
+    let kxr = maybe_kxr
+        .ok_or_else(|| illegal_param(sess, "client sent invalid key share"))?;
 
     let kse = KeyShareEntry::new(share.group, &kxr.pubkey);
     extensions.push(ServerExtension::KeyShare(kse));
@@ -358,6 +480,7 @@ fn emit_server_hello_tls13(sess: &mut ServerSessionImpl,
     debug!("sending server hello {:?}", sh);
     sess.handshake_data.transcript.add_message(&sh);
     sess.common.send_msg(sh, false);
+    sess.common.send_tls13_compat_ccs();
 
     // Start key schedule
     let suite = sess.common.get_suite();
@@ -401,12 +524,18 @@ fn emit_hello_retry_request(sess: &mut ServerSessionImpl, group: NamedGroup) {
     debug!("Requesting retry {:?}", m);
     sess.handshake_data.transcript.add_message(&m);
     sess.common.send_msg(m, false);
+    sess.common.send_tls13_compat_ccs();
 }
 
 fn emit_encrypted_extensions(sess: &mut ServerSessionImpl,
-                             hello: &ClientHelloPayload)
+                             hello: &ClientHelloPayload,
+                             accept_early_data: bool)
                              -> Result<(), TLSError> {
-    let encrypted_exts = process_extensions(sess, hello)?;
+    let mut encrypted_exts = process_extensions(sess, hello)?;
+    if accept_early_data {
+        encrypted_exts.push(ServerExtension::EarlyData);
+    }
+
     let ee = Message {
         typ: ContentType::Handshake,
         version: ProtocolVersion::TLSv1_3,
@@ -451,7 +580,9 @@ fn emit_certificate_req_tls13(sess: &mut ServerSessionImpl) {
     sess.handshake_data.doing_client_auth = true;
 }
 
-fn emit_certificate_tls13(sess: &mut ServerSessionImpl) {
+fn emit_certificate_tls13(sess: &mut ServerSessionImpl,
+                          compression_offered: Option<&CertificateCompressionAlgorithms>,
+                          delegated_credential: Option<&DelegatedCredentialPayload>) {
     let mut cert_body = CertificatePayloadTLS13::new();
 
     for cert in sess.handshake_data.server_cert_chain.as_ref().unwrap() {
@@ -463,33 +594,53 @@ fn emit_certificate_tls13(sess: &mut ServerSessionImpl) {
         cert_body.list.push(entry);
     }
 
+    // Any delegated credential goes with the end-entity certificate.
+    if let Some(dc) = delegated_credential {
+        cert_body.list[0].exts.push(CertificateExtension::DelegatedCredential(dc.clone()));
+    }
+
+    // If the client can't decompress any of our algorithms, or
+    // compression fails, we send the chain uncompressed.
+    let compressed = compression_offered
+        .and_then(|offered| compress::compress(&sess.config.cert_compressors,
+                                               offered,
+                                               &cert_body));
+
+    let payload = match compressed {
+        Some(compressed) => HandshakeMessagePayload {
+            typ: HandshakeType::CompressedCertificate,
+            payload: HandshakePayload::CompressedCertificate(compressed),
+        },
+        None => HandshakeMessagePayload {
+            typ: HandshakeType::Certificate,
+            payload: HandshakePayload::CertificateTLS13(cert_body),
+        },
+    };
+
     let c = Message {
         typ: ContentType::Handshake,
         version: ProtocolVersion::TLSv1_3,
-        payload: MessagePayload::Handshake(HandshakeMessagePayload {
-            typ: HandshakeType::Certificate,
-            payload: HandshakePayload::CertificateTLS13(cert_body),
-        }),
+        payload: MessagePayload::Handshake(payload),
     };
 
     debug!("sending certificate {:?}", c);
     sess.handshake_data.transcript.add_message(&c);
     sess.common.send_msg(c, true);
+    sess.handshake_data.sent_server_cert_chain = true;
 }
 
 fn emit_certificate_verify_tls13(sess: &mut ServerSessionImpl,
                                  schemes: &[SignatureScheme],
                                  signer: &Arc<Box<sign::Signer>>)
                                  -> Result<(), TLSError> {
-    let mut message = Vec::new();
-    message.resize(64, 0x20u8);
-    message.extend_from_slice(b"TLS 1.3, server CertificateVerify\x00");
-    message.extend_from_slice(&sess.handshake_data.transcript.get_current_hash());
+    let handshake_hash = sess.handshake_data.transcript.get_current_hash();
+    let message = verify::construct_tls13_verify_message(&handshake_hash,
+                                                         verify::SERVER_CERT_VERIFY_CONTEXT);
 
     let scheme = signer.choose_scheme(schemes)
-        .ok_or_else(|| TLSError::PeerIncompatibleError("no overlapping sigschemes".to_string()))?;
+        .ok_or_else(|| incompatible(sess, "no overlapping sigschemes"))?;
 
-    let sig = signer.sign(scheme, &message)
+    let sig = signer.sign(scheme, &message, sess.config.rng.as_ref())
         .map_err(|_| TLSError::General("cannot sign".to_string()))?;
 
     let cv = DigitallySignedStruct::new(scheme, sig);
@@ -504,6 +655,7 @@ fn emit_certificate_verify_tls13(sess: &mut ServerSessionImpl,
     };
 
     debug!("sending certificate-verify {:?}", m);
+    sess.handshake_data.server_sigscheme = Some(scheme);
     sess.handshake_data.transcript.add_message(&m);
     sess.common.send_msg(m, true);
     Ok(())
@@ -569,6 +721,41 @@ fn check_binder(sess: &mut ServerSessionImpl,
     constant_time::verify_slices_are_equal(&real_binder, binder).is_ok()
 }
 
+fn first_early_data_for_psk(sess: &ServerSessionImpl, client_hello: &ClientHelloPayload) -> bool {
+    let identity = &client_hello.get_psk().unwrap().identities[0].identity.0;
+    // We accept the ticket for as long as we said it lasts when we
+    // issued it, and beyond that a client whose clock is slow may
+    // still use it.
+    let skew = sess.config.get_clock_skew().as_secs();
+    let lifetime = get_ticket_lifetime(sess)
+        .saturating_add(cmp::min(skew, u32::max_value() as u64) as u32);
+    let now = ticket_timebase(sess);
+    let first = sess.config
+        .replay_protection
+        .lock()
+        .unwrap()
+        .check_and_record(identity, now, lifetime);
+
+    if !first {
+        warn!("Early data replayed");
+    }
+
+    first
+}
+
+/// How much rejected early data to skip.  The client may send as much
+/// as the ticket for its first PSK invited, which may be more than we
+/// invite now.  If we can't read that ticket, we go by our config.
+fn rejected_early_data_limit(sess: &ServerSessionImpl, client_hello: &ClientHelloPayload) -> usize {
+    let ticket_limit = client_hello.get_psk()
+        .and_then(|offer| offer.identities.first())
+        .and_then(|id| sess.config.ticketer.decrypt(&id.identity.0))
+        .and_then(|plain| persist::ServerSessionValue::from_bytes(&plain))
+        .map(|value| value.max_early_data_size);
+
+    ticket_limit.unwrap_or(sess.config.max_early_data_size) as usize
+}
+
 fn handle_client_hello_tls13(sess: &mut ServerSessionImpl,
                              chm: &Message,
                              signer: &Arc<Box<sign::Signer>>)
@@ -579,6 +766,8 @@ fn handle_client_hello_tls13(sess: &mut ServerSessionImpl,
         return Err(illegal_param(sess, "client offered wrong compressions"));
     }
 
+    sess.handshake_data.post_handshake_auth_offered = client_hello.post_handshake_auth_offered();
+
     let groups_ext = client_hello.get_namedgroups_extension()
         .ok_or_else(|| incompatible(sess, "client didn't describe groups"))?;
 
@@ -596,23 +785,36 @@ fn handle_client_hello_tls13(sess: &mut ServerSessionImpl,
         return Err(illegal_param(sess, "client sent duplicate keyshares"));
     }
 
-    let share_groups: Vec<NamedGroup> = shares_ext.iter()
-        .map(|share| share.group)
-        .collect();
+    if shares_ext.iter().any(|share| !groups_ext.contains(&share.group)) {
+        return Err(illegal_param(sess, "client sent key share for unsupported group"));
+    }
 
-    let chosen_group = util::first_in_both(&NamedGroups::supported(), &share_groups);
+    // Use the first key share the client sent which we can use.  Only
+    // if there's none do we ask for one, in our most preferred group
+    // out of those the client supports.
+    let our_groups = usable_groups(sess, true);
+    let chosen_group = shares_ext.iter()
+        .map(|share| share.group)
+        .find(|group| our_groups.contains(group));
     if chosen_group.is_none() {
         // We don't have a suitable key share.  Choose a suitable group and
         // send a HelloRetryRequest.
-        let retry_group_maybe = util::first_in_both(&NamedGroups::supported(), groups_ext);
+        let preferred_group = util::first_in_both(&our_groups, groups_ext);
         sess.handshake_data.transcript.add_message(chm);
 
-        if let Some(group) = retry_group_maybe {
+        if let Some(group) = preferred_group {
             if sess.handshake_data.done_retry {
                 return Err(illegal_param(sess, "did not follow retry request"));
             } else {
                 emit_hello_retry_request(sess, group);
                 sess.handshake_data.done_retry = true;
+
+                // Early data sent with this ClientHello arrives before
+                // the next one, and we skip it.
+                if client_hello.early_data_extension_offered() {
+                    let max_early_data_size = rejected_early_data_limit(sess, client_hello);
+                    sess.common.skip_rejected_early_data(max_early_data_size);
+                }
                 return Ok(&EXPECT_CLIENT_HELLO);
             }
         } else {
@@ -621,12 +823,16 @@ fn handle_client_hello_tls13(sess: &mut ServerSessionImpl,
     }
 
     let chosen_group = chosen_group.unwrap();
+    debug!("decided upon key exchange group {:?}", chosen_group);
+    sess.handshake_data.kx_group = Some(chosen_group);
     let chosen_share = shares_ext.iter()
         .find(|share| share.group == chosen_group)
         .unwrap();
 
     let mut chosen_psk_index = None;
     let mut resuming_psk = None;
+    let mut ticket_max_early_data_size = 0;
+    let mut ticket_alpn_protocol = None;
     if let Some(psk_offer) = client_hello.get_psk() {
         if !client_hello.check_psk_ext_is_last() {
             return Err(illegal_param(sess, "psk extension in wrong position"));
@@ -644,7 +850,7 @@ fn handle_client_hello_tls13(sess: &mut ServerSessionImpl,
             let maybe_resume = sess.config
                 .ticketer
                 .decrypt(&psk_id.identity.0)
-                .and_then(|plain| persist::ServerSessionValue::read_bytes(&plain));
+                .and_then(|plain| persist::ServerSessionValue::from_bytes(&plain));
 
             if !can_resume(sess, &maybe_resume) {
                 continue;
@@ -652,12 +858,18 @@ fn handle_client_hello_tls13(sess: &mut ServerSessionImpl,
 
             let resume = maybe_resume.unwrap();
 
+            if !ticket_age_is_plausible(sess, &resume, psk_id.obfuscated_ticket_age) {
+                continue;
+            }
+
             if !check_binder(sess, chm, &resume.master_secret.0, &psk_offer.binders[i].0) {
                 sess.common.send_fatal_alert(AlertDescription::DecryptError);
                 return Err(TLSError::PeerMisbehavedError("client sent wrong binder".to_string()));
             }
 
             chosen_psk_index = Some(i);
+            ticket_max_early_data_size = resume.max_early_data_size;
+            ticket_alpn_protocol = resume.alpn_protocol.map(|proto| proto.0);
             resuming_psk = Some(resume.master_secret.0);
             break;
         }
@@ -669,26 +881,106 @@ fn handle_client_hello_tls13(sess: &mut ServerSessionImpl,
         chosen_psk_index = None;
         resuming_psk = None;
     } else {
-        sess.handshake_data.send_ticket = true;
+        sess.handshake_data.send_ticket = sess.config.resumption_enabled();
     }
 
     let full_handshake = resuming_psk.is_none();
+
+    // A full handshake needs a CertificateVerify.  Make sure we can
+    // sign it with a scheme the client accepts before going on,
+    // rather than failing after our ServerHello.
+    let config = sess.config.clone();
+    let delegated_credential = if full_handshake {
+        let end_entity = sess.handshake_data.server_cert_chain.as_ref()
+            .and_then(|chain| chain.first());
+        delegated::choose(config.delegated_credential.as_ref(),
+                          end_entity,
+                          client_hello.get_delegated_credential_extension())
+    } else {
+        None
+    };
+
+    if full_handshake && delegated_credential.is_none() &&
+       signer.choose_scheme(&sigschemes_ext).is_none() {
+        return Err(incompatible(sess, "no overlapping sigschemes"));
+    }
+
     sess.handshake_data.transcript.add_message(chm);
+
+    // We accept early data if we invite it, and the client is resuming
+    // using its first PSK, whose ticket invited it too.  We accept as
+    // much as both allow.  The protocol we'd agree with ALPN must be
+    // the one the ticket was for (RFC8446 section 4.2.10).  Not after
+    // a retry, though: then the client can't send it.  Nor if we've
+    // seen that PSK's early data before: it's being replayed.
+    let early_data_offered = client_hello.early_data_extension_offered();
+    let max_early_data_size = cmp::min(ticket_max_early_data_size,
+                                       sess.config.max_early_data_size);
+    let alpn_protocol = client_hello.get_alpn_extension()
+        .and_then(|protos| choose_alpn_protocol(sess, &protos.to_strings()))
+        .map(|proto| proto.into_bytes());
+    let accept_early_data = early_data_offered &&
+                            chosen_psk_index == Some(0) &&
+                            max_early_data_size > 0 &&
+                            alpn_protocol == ticket_alpn_protocol &&
+                            !sess.handshake_data.done_retry &&
+                            first_early_data_for_psk(sess, client_hello);
+    let early_data_key = if accept_early_data {
+        let client_hello_hash = sess.handshake_data.transcript.get_current_hash();
+        let mut key_schedule = KeySchedule::new(sess.common.get_suite().get_hash());
+        key_schedule.input_secret(resuming_psk.as_ref().unwrap());
+        Some(key_schedule.derive(SecretKind::ClientEarlyTrafficSecret, &client_hello_hash))
+    } else {
+        None
+    };
+
     emit_server_hello_tls13(sess, chosen_share, chosen_psk_index, resuming_psk)?;
-    emit_encrypted_extensions(sess, client_hello)?;
+    emit_encrypted_extensions(sess, client_hello, accept_early_data)?;
+
+    // Until its EndOfEarlyData, the client's early data is encrypted
+    // with its early traffic keys.  If we rejected it, we skip it.
+    if let Some(read_key) = early_data_key {
+        info!("Accepting early data");
+        let suite = sess.common.get_suite();
+        sess.common.set_message_decrypter(cipher::new_tls13_read(suite, &read_key));
+        sess.handshake_data.early_data_left = max_early_data_size as usize;
+    } else if early_data_offered {
+        info!("Rejecting early data");
+        let max_early_data_size = rejected_early_data_limit(sess, client_hello);
+        sess.common.skip_rejected_early_data(max_early_data_size);
+    }
 
     if full_handshake {
         emit_certificate_req_tls13(sess);
-        emit_certificate_tls13(sess);
-        emit_certificate_verify_tls13(sess, &sigschemes_ext, signer)?;
+        emit_certificate_tls13(sess,
+                               client_hello.get_cert_compression_extension(),
+                               delegated_credential.map(delegated::get_payload));
+
+        if let Some(dc) = delegated_credential {
+            let (scheme, dc_signer) = delegated::get_signer(dc);
+            emit_certificate_verify_tls13(sess, &[ scheme ], dc_signer)?;
+        } else {
+            emit_certificate_verify_tls13(sess, &sigschemes_ext, signer)?;
+        }
     }
     check_aligned_handshake(sess)?;
     emit_finished_tls13(sess);
 
-    if sess.handshake_data.doing_client_auth && full_handshake {
-        Ok(&EXPECT_TLS13_CERTIFICATE)
+    if accept_early_data {
+        Ok(&EXPECT_TLS13_EARLY_DATA)
     } else {
-        Ok(&EXPECT_TLS13_FINISHED)
+        Ok(expect_client_flight_tls13(sess))
+    }
+}
+
+/// What the client's TLS1.3 flight starts with, after any early data.
+fn expect_client_flight_tls13(sess: &ServerSessionImpl) -> &'static State {
+    if sess.handshake_data.doing_alps {
+        &EXPECT_TLS13_ENCRYPTED_EXTENSIONS
+    } else if sess.handshake_data.doing_client_auth {
+        &EXPECT_TLS13_CERTIFICATE
+    } else {
+        &EXPECT_TLS13_FINISHED
     }
 }
 
@@ -709,12 +1001,28 @@ fn handle_client_hello(sess: &mut ServerSessionImpl, m: Message) -> StateResult
             .to_string()));
     }
 
+    // We never compress, and TLS-level compression is unsafe anyway (see
+    // CRIME), so a client offering anything else is confused.
+    if client_hello.compression_methods.iter().any(|c| *c != Compression::Null) {
+        return Err(illegal_param(sess, "client offered non-Null compression"));
+    }
+
     if client_hello.has_duplicate_extension() {
         return Err(decode_error(sess, "client sent duplicate extensions"));
     }
 
+    if sess.config.reject_unknown_extensions {
+        if let Some(typ) = client_hello.get_unknown_extension() {
+            warn!("rejecting ClientHello with unknown extension {:?}", typ);
+            sess.common.send_fatal_alert(AlertDescription::UnsupportedExtension);
+            return Err(TLSError::PeerMisbehavedError("client sent unknown extension"
+                .to_string()));
+        }
+    }
+
     // Are we doing TLS1.3?
     let maybe_versions_ext = client_hello.get_versions_extension();
+    sess.handshake_data.client_versions = maybe_versions_ext.cloned();
     if let Some(versions) = maybe_versions_ext {
         if versions.contains(&ProtocolVersion::Unknown(0x7f12)) && tls13_enabled {
             sess.common.negotiated_version = Some(ProtocolVersion::TLSv1_3);
@@ -730,20 +1038,50 @@ fn handle_client_hello(sess: &mut ServerSessionImpl, m: Message) -> StateResult
     if sess.common.negotiated_version == None {
         sess.common.negotiated_version = Some(ProtocolVersion::TLSv1_2);
     }
+    debug!("negotiated version {:?}", sess.common.negotiated_version);
 
     // Common to TLS1.2 and TLS1.3: ciphersuite and certificate selection.
     let default_sigschemes_ext = SupportedSignatureSchemes::default();
 
     let sni_ext = client_hello.get_sni_extension()
         .and_then(|sni| sni.get_hostname());
+    sess.handshake_data.client_sigschemes = client_hello.get_sigalgs_extension().cloned();
+    sess.handshake_data.offered_alpn = client_hello.get_alpn_extension()
+        .map(|protos| protos.iter().map(|proto| proto.0.clone()).collect());
     let sigschemes_ext = client_hello.get_sigalgs_extension()
         .unwrap_or(&default_sigschemes_ext);
+    let cert_sigschemes_ext = client_hello.get_sigalgs_cert_extension()
+        .unwrap_or(sigschemes_ext);
+
+    let alpn_protocols = client_hello.get_alpn_extension()
+        .map(|protos| protos.iter().map(|proto| proto.0.as_slice()).collect());
 
     debug!("sni {:?}", sni_ext);
     debug!("sig schemes {:?}", sigschemes_ext);
 
+    if sni_ext.is_none() {
+        if let MissingSNIPolicy::Reject = sess.config.missing_sni_policy {
+            sess.common.send_fatal_alert(AlertDescription::UnrecognisedName);
+            return Err(TLSError::PeerIncompatibleError("client did not send SNI".to_string()));
+        }
+    }
+
     // Choose a certificate.
-    let maybe_cert_key = sess.config.cert_resolver.resolve(sni_ext, sigschemes_ext);
+    let maybe_cert_key = {
+        let hello = ClientHello::new(sni_ext,
+                                     sigschemes_ext,
+                                     cert_sigschemes_ext,
+                                     alpn_protocols,
+                                     &client_hello.cipher_suites);
+        sess.handshake_data.resumption_denied = !sess.config.should_resume(&hello);
+
+        match sess.config.missing_sni_policy {
+            MissingSNIPolicy::ServeDefault(ref default) if sni_ext.is_none() => {
+                Some(default.clone())
+            }
+            _ => sess.config.cert_resolver.resolve(hello),
+        }
+    };
     if maybe_cert_key.is_none() {
         sess.common.send_fatal_alert(AlertDescription::AccessDenied);
         return Err(TLSError::General("no server certificate chain resolved".to_string()));
@@ -760,6 +1098,10 @@ fn handle_client_hello(sess: &mut ServerSessionImpl, m: Message) -> StateResult
     let protocol_version = sess.common.negotiated_version.unwrap();
     let suitable_suites = suites::reduce_given_version(&suitable_suites, protocol_version);
 
+    // And forward secrecy
+    let allow_static = sess.config.allow_non_forward_secret;
+    let suitable_suites = suites::reduce_given_forward_secrecy(&suitable_suites, allow_static);
+
     let maybe_ciphersuite = if sess.config.ignore_client_order {
         suites::choose_ciphersuite_preferring_server(&client_hello.cipher_suites, &suitable_suites)
     } else {
@@ -780,14 +1122,15 @@ fn handle_client_hello(sess: &mut ServerSessionImpl, m: Message) -> StateResult
             .to_string()));
     }
 
+    // Save their Random.  TLS1.3 doesn't use it, but it's reported.
+    sess.handshake_data.randoms.set_peer(&client_hello.random);
+
     if sess.common.is_tls13() {
         return handle_client_hello_tls13(sess, &m, &private_key);
     }
 
     // -- TLS1.2 only from hereon in --
     sess.handshake_data.transcript.add_message(&m);
-    // Save their Random.
-    client_hello.random.write_slice(&mut sess.handshake_data.randoms.client);
 
     if client_hello.ems_support_offered() {
         sess.handshake_data.using_ems = true;
@@ -829,7 +1172,7 @@ fn handle_client_hello(sess: &mut ServerSessionImpl, m: Message) -> StateResult
             let maybe_resume = sess.config
                 .ticketer
                 .decrypt(&ticket.0)
-                .and_then(|plain| persist::ServerSessionValue::read_bytes(&plain));
+                .and_then(|plain| persist::ServerSessionValue::from_bytes(&plain));
 
             if can_resume(sess, &maybe_resume) {
                 return start_resumption(sess,
@@ -849,7 +1192,7 @@ fn handle_client_hello(sess: &mut ServerSessionImpl, m: Message) -> StateResult
                 let persist = sess.config.session_storage.lock().unwrap();
                 persist.get(&client_hello.session_id)
             }
-            .and_then(|x| persist::ServerSessionValue::read_bytes(&x));
+            .and_then(|x| persist::ServerSessionValue::from_bytes(&x));
 
         if can_resume(sess, &maybe_resume) {
             return start_resumption(sess,
@@ -864,9 +1207,10 @@ fn handle_client_hello(sess: &mut ServerSessionImpl, m: Message) -> StateResult
         .resolve_sig_scheme(sigschemes_ext)
         .ok_or_else(|| incompatible(sess, "no supported sig scheme"))?;
 
-    let group = util::first_in_both(NamedGroups::supported().as_slice(),
+    let group = util::first_in_both(usable_groups(sess, false).as_slice(),
                                     groups_ext.as_slice())
         .ok_or_else(|| incompatible(sess, "no supported group"))?;
+    debug!("decided upon key exchange group {:?}", group);
 
     let ecpoint = util::first_in_both(ECPointFormatList::supported().as_slice(),
                                       ecpoints_ext.as_slice())
@@ -900,11 +1244,16 @@ fn handle_certificate_tls12(sess: &mut ServerSessionImpl, m: Message) -> StateRe
     sess.handshake_data.transcript.add_message(&m);
     let cert_chain = extract_handshake!(m, HandshakePayload::Certificate).unwrap();
 
-    if cert_chain.is_empty() && !sess.config.client_auth_mandatory {
-        info!("client auth requested but no certificate supplied");
-        sess.handshake_data.doing_client_auth = false;
-        sess.handshake_data.transcript.abandon_client_auth();
-        return Ok(&EXPECT_TLS12_CLIENT_KX);
+    if cert_chain.is_empty() {
+        if !sess.config.client_auth_mandatory {
+            info!("client auth requested but no certificate supplied");
+            sess.handshake_data.doing_client_auth = false;
+            sess.handshake_data.transcript.abandon_client_auth();
+            return Ok(&EXPECT_TLS12_CLIENT_KX);
+        }
+
+        sess.common.send_fatal_alert(AlertDescription::HandshakeFailure);
+        return Err(TLSError::NoCertificatesPresented);
     }
 
     debug!("certs {:?}", cert_chain);
@@ -948,7 +1297,11 @@ fn handle_certificate_tls13(sess: &mut ServerSessionImpl,
     }
 
     sess.config.get_verifier().verify_client_cert(&sess.config.client_auth_roots,
-                                                  &cert_chain)?;
+                                                  &cert_chain)
+        .or_else(|err| {
+                 sess.common.send_fatal_alert(AlertDescription::BadCertificate);
+                 Err(err)
+                 })?;
 
     sess.handshake_data.valid_client_cert_chain = Some(cert_chain);
     Ok(&EXPECT_TLS13_CERTIFICATE_VERIFY)
@@ -962,6 +1315,37 @@ static EXPECT_TLS13_CERTIFICATE: State = State {
     handle: handle_certificate_tls13,
 };
 
+/// The client sends its ALPS settings in its own EncryptedExtensions.
+fn handle_encrypted_extensions_tls13(sess: &mut ServerSessionImpl, m: Message) -> StateResult {
+    sess.handshake_data.transcript.add_message(&m);
+    let exts = extract_handshake!(m, HandshakePayload::EncryptedExtensions).unwrap();
+
+    let only_alps = exts.iter()
+        .all(|ext| ext.get_type() == ExtensionType::ApplicationSettings);
+    if exts.has_duplicate_extension() || !only_alps {
+        return Err(illegal_param(sess, "client sent unexpected encrypted extensions"));
+    }
+
+    let settings = exts.get_application_settings()
+        .map(|settings| settings.to_vec())
+        .ok_or_else(|| illegal_param(sess, "client didn't send application settings"))?;
+    sess.handshake_data.peer_application_settings = Some(settings);
+
+    if sess.handshake_data.doing_client_auth {
+        Ok(&EXPECT_TLS13_CERTIFICATE)
+    } else {
+        Ok(&EXPECT_TLS13_FINISHED)
+    }
+}
+
+static EXPECT_TLS13_ENCRYPTED_EXTENSIONS: State = State {
+    expect: Expectation {
+        content_types: &[ContentType::Handshake],
+        handshake_types: &[HandshakeType::EncryptedExtensions],
+    },
+    handle: handle_encrypted_extensions_tls13,
+};
+
 // --- Process client's KeyExchange ---
 fn handle_client_kx(sess: &mut ServerSessionImpl, m: Message) -> StateResult {
     let client_kx = extract_handshake!(m, HandshakePayload::ClientKeyExchange).unwrap();
@@ -976,8 +1360,7 @@ fn handle_client_kx(sess: &mut ServerSessionImpl, m: Message) -> StateResult {
     }
 
     let kxd = kx.server_complete(&client_kx.0)
-        .ok_or_else(|| TLSError::PeerMisbehavedError("key exchange completion failed"
-                                                     .to_string()))?;
+        .ok_or_else(|| illegal_param(sess, "client sent invalid key exchange public key"))?;
 
     let hashalg = sess.common.get_suite().get_hash();
     if sess.handshake_data.using_ems {
@@ -1050,7 +1433,7 @@ fn handle_certificate_verify_tls13(sess: &mut ServerSessionImpl,
         verify::verify_tls13(&certs[0],
                              sig,
                              &handshake_hash,
-                             b"TLS 1.3, client CertificateVerify\x00")
+                             verify::CLIENT_CERT_VERIFY_CONTEXT)
     };
 
     if rc.is_err() {
@@ -1097,6 +1480,14 @@ static EXPECT_TLS12_CCS: State = State {
 };
 
 // --- Process client's Finished ---
+/// The lifetime we tell the client its ticket has.
+fn get_ticket_lifetime(sess: &ServerSessionImpl) -> u32 {
+    let lifetime = sess.config.ticketer.get_lifetime();
+
+    sess.config.ticket_lifetime
+        .map_or(lifetime, |limit| cmp::min(limit, lifetime))
+}
+
 fn emit_ticket(sess: &mut ServerSessionImpl) {
     if !sess.handshake_data.send_ticket {
         return;
@@ -1104,12 +1495,12 @@ fn emit_ticket(sess: &mut ServerSessionImpl) {
 
     // If we can't produce a ticket for some reason, we can't
     // report an error. Send an empty one.
-    let plain = get_server_session_value(sess).get_encoding();
+    let plain = get_server_session_value(sess).to_bytes();
     let ticket = sess.config
         .ticketer
-        .encrypt(&plain)
+        .encrypt(&plain, sess.config.rng.as_ref())
         .unwrap_or_else(Vec::new);
-    let ticket_lifetime = sess.config.ticketer.get_lifetime();
+    let ticket_lifetime = get_ticket_lifetime(sess);
 
     let m = Message {
         typ: ContentType::Handshake,
@@ -1177,6 +1568,10 @@ fn get_server_session_value(sess: &ServerSessionImpl) -> persist::ServerSessionV
         v.set_extended_ms_used();
     }
 
+    v.alpn_protocol = sess.alpn_protocol
+        .as_ref()
+        .map(|proto| PayloadU8::new(proto.as_bytes().to_vec()));
+
     v
 }
 
@@ -1198,7 +1593,7 @@ fn handle_finished(sess: &mut ServerSessionImpl, m: Message) -> StateResult {
         let value = get_server_session_value(sess);
 
         let mut persist = sess.config.session_storage.lock().unwrap();
-        if persist.put(&sess.handshake_data.session_id, value.get_encoding()) {
+        if persist.put(&sess.handshake_data.session_id, value.to_bytes()) {
             info!("Session saved");
         } else {
             info!("Session not saved");
@@ -1231,19 +1626,28 @@ fn emit_ticket_tls13(sess: &mut ServerSessionImpl) {
         return;
     }
 
-    let plain = get_server_session_value(sess).get_encoding();
+    let age_add = rand::random_u32(sess.config.rng.as_ref());
+    let mut value = get_server_session_value(sess);
+    value.set_ticket_times(ticket_timebase(sess), age_add);
+    value.max_early_data_size = sess.config.max_early_data_size;
+
+    let plain = value.to_bytes();
     let maybe_ticket = sess.config
         .ticketer
-        .encrypt(&plain);
-    let ticket_lifetime = sess.config.ticketer.get_lifetime();
+        .encrypt(&plain, sess.config.rng.as_ref());
+    let ticket_lifetime = get_ticket_lifetime(sess);
 
     if maybe_ticket.is_none() {
         return;
     }
 
     let ticket = maybe_ticket.unwrap();
-    let age_add = rand::random_u32(); // nb, we don't do 0-RTT data, so whatever
-    let payload = NewSessionTicketPayloadTLS13::new(ticket_lifetime, age_add, ticket);
+    let mut payload = NewSessionTicketPayloadTLS13::new(ticket_lifetime, age_add, ticket);
+
+    if sess.config.max_early_data_size > 0 {
+        payload.exts.push(NewSessionTicketExtension::EarlyData(sess.config.max_early_data_size));
+    }
+
     let m = Message {
         typ: ContentType::Handshake,
         version: ProtocolVersion::TLSv1_3,
@@ -1258,6 +1662,38 @@ fn emit_ticket_tls13(sess: &mut ServerSessionImpl) {
     sess.common.send_msg(m, true);
 }
 
+fn handle_early_data_tls13(sess: &mut ServerSessionImpl, mut m: Message) -> StateResult {
+    if m.is_content_type(ContentType::ApplicationData) {
+        let payload = m.take_opaque_payload().unwrap();
+        if payload.0.len() > sess.handshake_data.early_data_left {
+            sess.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+            return Err(TLSError::PeerMisbehavedError("client sent too much early data"
+                                                     .to_string()));
+        }
+
+        sess.handshake_data.early_data_left -= payload.0.len();
+        sess.common.take_received_plaintext(payload);
+        return Ok(&EXPECT_TLS13_EARLY_DATA);
+    }
+
+    // EndOfEarlyData: the client now uses its handshake keys.
+    sess.handshake_data.transcript.add_message(&m);
+    check_aligned_handshake(sess)?;
+
+    let suite = sess.common.get_suite();
+    let read_key = sess.common.get_key_schedule().current_client_traffic_secret.clone();
+    sess.common.set_message_decrypter(cipher::new_tls13_read(suite, &read_key));
+    Ok(expect_client_flight_tls13(sess))
+}
+
+static EXPECT_TLS13_EARLY_DATA: State = State {
+    expect: Expectation {
+        content_types: &[ContentType::ApplicationData, ContentType::Handshake],
+        handshake_types: &[HandshakeType::EndOfEarlyData],
+    },
+    handle: handle_early_data_tls13,
+};
+
 fn handle_finished_tls13(sess: &mut ServerSessionImpl, m: Message) -> StateResult {
     let finished = extract_handshake!(m, HandshakePayload::Finished).unwrap();
 
@@ -1276,6 +1712,13 @@ fn handle_finished_tls13(sess: &mut ServerSessionImpl, m: Message) -> StateResul
     // nb. future derivations include Client Finished, but not the
     // main application data keying.
     sess.handshake_data.transcript.add_message(&m);
+    sess.handshake_data.transcript.stop_summarising();
+
+    // Post-handshake client auth continues from here.
+    if sess.handshake_data.post_handshake_auth_offered {
+        let transcript = sess.handshake_data.transcript.clone();
+        sess.handshake_data.transcript_at_client_fin = Some(transcript);
+    }
 
     // Now move to using application data keys for client traffic.
     // Server traffic is already done.
@@ -1344,3 +1787,179 @@ static EXPECT_TLS13_TRAFFIC: State = State {
     },
     handle: handle_traffic_tls13,
 };
+
+// --- Post-handshake client auth (TLS1.3) ---
+pub fn start_post_handshake_auth(sess: &mut ServerSessionImpl) -> Result<(), TLSError> {
+    if !sess.common.traffic || !sess.common.is_tls13() {
+        return Err(TLSError::General("post-handshake auth needs a completed TLS1.3 \
+                                      handshake".to_string()));
+    }
+
+    if !sess.handshake_data.post_handshake_auth_offered {
+        return Err(TLSError::PeerIncompatibleError("client didn't offer post-handshake \
+                                                    auth".to_string()));
+    }
+
+    if sess.handshake_data.post_handshake_auth_context.is_some() {
+        return Err(TLSError::General("post-handshake auth already underway".to_string()));
+    }
+
+    let mut context = vec![0u8; 16];
+    rand::fill_random(sess.config.rng.as_ref(), &mut context);
+
+    let cr = CertificateRequestPayloadTLS13 {
+        context: PayloadU8::new(context.clone()),
+        sigschemes: SupportedSignatureSchemes::supported_verify(),
+        canames: sess.config.client_auth_roots.get_subjects(),
+        extensions: Vec::new(),
+    };
+
+    let m = Message {
+        typ: ContentType::Handshake,
+        version: ProtocolVersion::TLSv1_3,
+        payload: MessagePayload::Handshake(HandshakeMessagePayload {
+            typ: HandshakeType::CertificateRequest,
+            payload: HandshakePayload::CertificateRequestTLS13(cr),
+        }),
+    };
+
+    // Each exchange's transcript continues from the client's Finished.
+    let transcript = sess.handshake_data.transcript_at_client_fin.as_ref().unwrap().clone();
+    sess.handshake_data.transcript = transcript;
+
+    debug!("Sending post-handshake CertificateRequest {:?}", m);
+    sess.handshake_data.transcript.add_message(&m);
+    sess.common.send_msg(m, true);
+    sess.handshake_data.post_handshake_auth_context = Some(context);
+    sess.state = &EXPECT_TLS13_POST_HANDSHAKE_CERTIFICATE;
+    Ok(())
+}
+
+fn handle_post_handshake_certificate(sess: &mut ServerSessionImpl,
+                                     m: Message)
+                                     -> StateResult {
+    if m.is_content_type(ContentType::ApplicationData) {
+        handle_traffic(sess, m)?;
+        return Ok(&EXPECT_TLS13_POST_HANDSHAKE_CERTIFICATE);
+    }
+
+    sess.handshake_data.transcript.add_message(&m);
+    let certp = extract_handshake!(m, HandshakePayload::CertificateTLS13).unwrap();
+
+    if Some(&certp.context.0) != sess.handshake_data.post_handshake_auth_context.as_ref() {
+        return Err(illegal_param(sess, "client sent wrong post-handshake certificate context"));
+    }
+
+    let cert_chain = certp.convert();
+
+    if cert_chain.is_empty() {
+        if !sess.config.client_auth_mandatory {
+            info!("post-handshake client auth requested but no certificate supplied");
+            return Ok(&EXPECT_TLS13_POST_HANDSHAKE_FINISHED);
+        }
+
+        sess.common.send_fatal_alert(AlertDescription::CertificateRequired);
+        return Err(TLSError::NoCertificatesPresented);
+    }
+
+    sess.config.get_verifier().verify_client_cert(&sess.config.client_auth_roots,
+                                                  &cert_chain)
+        .or_else(|err| {
+                 sess.common.send_fatal_alert(AlertDescription::BadCertificate);
+                 Err(err)
+                 })?;
+
+    sess.handshake_data.post_handshake_cert_chain = Some(cert_chain);
+    Ok(&EXPECT_TLS13_POST_HANDSHAKE_CERTIFICATE_VERIFY)
+}
+
+static EXPECT_TLS13_POST_HANDSHAKE_CERTIFICATE: State = State {
+    expect: Expectation {
+        content_types: &[ContentType::ApplicationData, ContentType::Handshake],
+        handshake_types: &[HandshakeType::Certificate],
+    },
+    handle: handle_post_handshake_certificate,
+};
+
+fn handle_post_handshake_certificate_verify(sess: &mut ServerSessionImpl,
+                                            m: Message)
+                                            -> StateResult {
+    if m.is_content_type(ContentType::ApplicationData) {
+        handle_traffic(sess, m)?;
+        return Ok(&EXPECT_TLS13_POST_HANDSHAKE_CERTIFICATE_VERIFY);
+    }
+
+    // The client must use one of the schemes we asked for in
+    // start_post_handshake_auth.
+    let scheme = extract_handshake!(m, HandshakePayload::CertificateVerify).unwrap().scheme;
+    if !SupportedSignatureSchemes::supported_verify().contains(&scheme) {
+        return Err(illegal_param(sess, "client used unrequested post-handshake sigscheme"));
+    }
+
+    let rc = {
+        let sig = extract_handshake!(m, HandshakePayload::CertificateVerify).unwrap();
+        let certs = sess.handshake_data.post_handshake_cert_chain.as_ref().unwrap();
+        let handshake_hash = sess.handshake_data.transcript.get_current_hash();
+
+        verify::verify_tls13(&certs[0],
+                             sig,
+                             &handshake_hash,
+                             verify::CLIENT_CERT_VERIFY_CONTEXT)
+    };
+
+    if rc.is_err() {
+        sess.common.send_fatal_alert(AlertDescription::AccessDenied);
+        return Err(rc.unwrap_err());
+    } else {
+        debug!("client post-handshake CertificateVerify OK");
+    }
+
+    sess.handshake_data.transcript.add_message(&m);
+    Ok(&EXPECT_TLS13_POST_HANDSHAKE_FINISHED)
+}
+
+static EXPECT_TLS13_POST_HANDSHAKE_CERTIFICATE_VERIFY: State = State {
+    expect: Expectation {
+        content_types: &[ContentType::ApplicationData, ContentType::Handshake],
+        handshake_types: &[HandshakeType::CertificateVerify],
+    },
+    handle: handle_post_handshake_certificate_verify,
+};
+
+fn handle_post_handshake_finished(sess: &mut ServerSessionImpl, m: Message) -> StateResult {
+    if m.is_content_type(ContentType::ApplicationData) {
+        handle_traffic(sess, m)?;
+        return Ok(&EXPECT_TLS13_POST_HANDSHAKE_FINISHED);
+    }
+
+    let finished = extract_handshake!(m, HandshakePayload::Finished).unwrap();
+
+    // This Finished is keyed with the client's current application
+    // traffic secret.
+    let handshake_hash = sess.handshake_data.transcript.get_current_hash();
+    let expect_verify_data = sess.common
+        .get_key_schedule()
+        .sign_finish(SecretKind::ClientApplicationTrafficSecret, &handshake_hash);
+
+    constant_time::verify_slices_are_equal(&expect_verify_data, &finished.0)
+        .map_err(|_| {
+                 sess.common.send_fatal_alert(AlertDescription::DecryptError);
+                 warn!("post-handshake Finished wrong");
+                 TLSError::DecryptError
+                 })?;
+
+    sess.handshake_data.post_handshake_auth_context = None;
+    if let Some(cert_chain) = sess.handshake_data.post_handshake_cert_chain.take() {
+        sess.handshake_data.valid_client_cert_chain = Some(cert_chain);
+    }
+
+    Ok(&EXPECT_TLS13_TRAFFIC)
+}
+
+static EXPECT_TLS13_POST_HANDSHAKE_FINISHED: State = State {
+    expect: Expectation {
+        content_types: &[ContentType::ApplicationData, ContentType::Handshake],
+        handshake_types: &[HandshakeType::Finished],
+    },
+    handle: handle_post_handshake_finished,
+};
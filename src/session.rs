@@ -5,20 +5,28 @@ use msgs::deframer::MessageDeframer;
 use msgs::fragmenter::{MessageFragmenter, MAX_FRAGMENT_LEN};
 use msgs::hsjoiner::HandshakeJoiner;
 use msgs::base::Payload;
+use msgs::ccs::ChangeCipherSpecPayload;
 use msgs::codec::Codec;
+use msgs::handshake::Random;
 use msgs::enums::{ContentType, ProtocolVersion, AlertDescription, AlertLevel};
-use msgs::enums::KeyUpdateRequest;
+use msgs::enums::{KeyUpdateRequest, SignatureScheme, NamedGroup};
 use error::TLSError;
 use suites::SupportedCipherSuite;
-use cipher::{MessageDecrypter, MessageEncrypter, self};
+use cipher::{MessageDecrypter, MessageEncrypter, RecordPadding, self};
 use vecbuf::ChunkVecBuffer;
 use key;
 use key_schedule::{SecretKind, KeySchedule};
+use hash_hs::HandshakeMessageSummary;
+use split::{self, SessionReader, SessionWriter};
 use prf;
-use rand;
+use rand::{self, SecureRandom};
+use util;
 
 use std::io;
+use std::cmp;
 use std::collections::VecDeque;
+use std::mem;
+use std::sync::Arc;
 
 /// Generalises `ClientSession` and `ServerSession`
 pub trait Session: Read + Write + Send {
@@ -45,6 +53,11 @@ pub trait Session: Read + Write + Send {
     /// are fatal to the session.  Future calls after an error will do
     /// no new work and will return the same error.
     ///
+    /// Only whole TLS records are processed: a partial record read
+    /// by `read_tls` stays buffered until the rest of it arrives.
+    /// If the peer sends something that isn't TLS, this returns
+    /// `TLSError::CorruptMessage`.
+    ///
     /// Success from this function can mean new plaintext is available:
     /// obtain it using `read`.
     fn process_new_packets(&mut self) -> Result<(), TLSError>;
@@ -57,24 +70,73 @@ pub trait Session: Read + Write + Send {
     /// as possible.
     fn wants_write(&self) -> bool;
 
-    /// Returns true if the session is currently perform the TLS
+    /// Returns how many bytes of TLS records are waiting to be
+    /// written by `write_tls`.  This doesn't include plaintext
+    /// buffered until the handshake completes.
+    fn pending_write_len(&self) -> usize;
+
+    /// Returns true if the session is currently performing the TLS
     /// handshake.  During this time plaintext written to the
     /// session is buffered in memory.
+    ///
+    /// This becomes false once we've verified the peer's Finished
+    /// message and sent our own, and then stays false.
     fn is_handshaking(&self) -> bool;
 
     /// Sets a limit on the internal buffers used to buffer
     /// unsent plaintext (prior to completing the TLS handshake)
     /// and unsent TLS records.
     ///
-    /// By default, there is no limit.  The limit can be set
+    /// By default, the limit is 64KB.  The limit can be set
     /// at any time, even if the current buffer use is higher.
-    fn set_buffer_limit(&mut self, limit: usize);
+    ///
+    /// `None` means no limit is applied: use this only if you
+    /// trust the peer to read as fast as you write.
+    fn set_buffer_limit(&mut self, limit: Option<usize>);
+
+    /// Encrypts any plaintext buffered by earlier calls to `write`,
+    /// so that a following `write_tls` sends it.  Plaintext written
+    /// during the handshake stays buffered until the handshake
+    /// completes.  This does nothing if no plaintext is buffered.
+    ///
+    /// `io::Write::flush` on a session does the same thing.
+    fn flush_plaintext(&mut self);
 
     /// Queues a close_notify fatal alert to be sent in the next
     /// `write_tls` call.  This informs the peer that the
     /// connection is being closed.
     fn send_close_notify(&mut self);
 
+    /// Queues a fatal alert with description `desc` to be sent in
+    /// the next `write_tls` call, and then abandons the session.
+    ///
+    /// Use this to reject a peer for reasons of your own (for
+    /// example, after inspecting its certificate).  Subsequent
+    /// calls to `process_new_packets` return an error and do no
+    /// new work.
+    fn send_fatal_alert(&mut self, desc: AlertDescription);
+
+    /// Returns the warning alerts received from the peer since the
+    /// last call, other than close_notify.  These don't affect the
+    /// session: this is just so they can be observed.
+    ///
+    /// Only the first few warnings between calls are kept.  TLS1.3
+    /// has no warning alerts, so for TLS1.3 sessions this is empty.
+    fn take_received_warnings(&mut self) -> Vec<AlertDescription>;
+
+    /// Returns plaintext received from the peer which hasn't been
+    /// read yet, without copying it.  This is an alternative to
+    /// `read`, like `io::BufRead::fill_buf`.
+    ///
+    /// This may be only some of the available plaintext: once you
+    /// `consume_plaintext` all of it, there may be more.  It's empty
+    /// if there's none.
+    fn peek_plaintext(&self) -> &[u8];
+
+    /// Marks the first `amt` bytes returned by `peek_plaintext` as
+    /// read, so they aren't returned again.
+    fn consume_plaintext(&mut self, amt: usize);
+
     /// Retrieves the certificate chain used by the peer to authenticate.
     ///
     /// For clients, this is the certificate chain of the server.
@@ -92,11 +154,104 @@ pub trait Session: Read + Write + Send {
     /// were offered or accepted by the peer).
     fn get_alpn_protocol(&self) -> Option<String>;
 
+    /// Retrieves the settings the peer sent for ALPN protocol `proto`
+    /// using the application_settings (ALPS) extension.
+    ///
+    /// This returns None if `proto` isn't the agreed ALPN protocol,
+    /// or ALPS wasn't negotiated for it.  ALPS needs TLS1.3, and both
+    /// sides to have settings for the protocol.
+    fn get_peer_application_settings(&self, proto: &str) -> Option<Vec<u8>>;
+
     /// Retrieves the protocol version agreed with the peer.
     ///
     /// This returns None until the version is agreed.
     fn get_protocol_version(&self) -> Option<ProtocolVersion>;
 
+    /// Retrieves the record size limit agreed with the peer using the
+    /// record_size_limit extension (RFC8449): the smaller of the two
+    /// limits the peers advertised.  The plaintext of every record we
+    /// send fits within it.
+    ///
+    /// If no limit was agreed -- because either peer didn't advertise
+    /// one, or the handshake hasn't got that far -- this is the most
+    /// plaintext we put in a record anyway.
+    fn negotiated_record_size_limit(&self) -> usize;
+
+    /// Returns true if the negotiated ciphersuite gives forward
+    /// secrecy: its key exchange is ephemeral, as for all TLS1.3
+    /// suites.  Stealing a long-term key later doesn't then reveal
+    /// this session's traffic.
+    ///
+    /// This is false until a ciphersuite is agreed.
+    fn has_forward_secrecy(&self) -> bool;
+
+    /// Returns the random from the client's hello, for matching
+    /// this session with packet captures and logs.  These values
+    /// are sent in the clear, so aren't secret.
+    ///
+    /// For servers, this is None until the client's hello arrives.
+    fn client_random(&self) -> Option<[u8; 32]>;
+
+    /// Returns the random from the server's hello, like
+    /// `client_random`.
+    ///
+    /// For clients, this is None until the server's hello arrives.
+    fn server_random(&self) -> Option<[u8; 32]>;
+
+    /// Retrieves the protocol versions the peer said it supports,
+    /// as they appeared on the wire.
+    ///
+    /// For servers, this is the client's `supported_versions`
+    /// extension, and None if the client didn't send one.  Servers
+    /// only tell us the version they chose, so for clients this is
+    /// that version alone.
+    ///
+    /// The return value is None until this value is available.
+    fn get_peer_supported_versions(&self) -> Option<&[ProtocolVersion]>;
+
+    /// Retrieves the signature schemes the peer told us it supports.
+    ///
+    /// For servers, this is the client's `signature_algorithms`
+    /// extension.  For clients, this is the list in the server's
+    /// CertificateRequest, if client authentication was requested.
+    ///
+    /// The return value is None until this value is available.
+    fn get_peer_signature_schemes(&self) -> Option<&[SignatureScheme]>;
+
+    /// Retrieves the key exchange group agreed with the peer.
+    ///
+    /// This returns None until the group is agreed, and for
+    /// resumed TLS1.2 sessions, which have no key exchange.
+    fn get_key_exchange_group(&self) -> Option<NamedGroup>;
+
+    /// Retrieves the signature scheme the server used to
+    /// authenticate itself: in its ServerKeyExchange for TLS1.2, or
+    /// its CertificateVerify for TLS1.3.
+    ///
+    /// This returns None until the server signs, and for resumed
+    /// sessions, where it doesn't.
+    fn get_server_signature_scheme(&self) -> Option<SignatureScheme>;
+
+    /// Summarises the handshake messages exchanged so far, in the
+    /// order they were sent, for audit logging.  Messages sent after
+    /// the handshake, such as tickets, key updates and post-handshake
+    /// client authentication, aren't included.
+    ///
+    /// The summaries never include secrets.
+    fn handshake_transcript(&self) -> Vec<HandshakeMessageSummary>;
+
+    /// Returns the secrets of the established session, so they can be
+    /// dumped for offline analysis of a traffic capture.
+    ///
+    /// This returns None while the session is handshaking.
+    ///
+    /// **This is for protocol research and debugging only.**  Anyone
+    /// holding these secrets can decrypt the session, so this must never
+    /// be used in production.  It's only available with the
+    /// `dangerous_debug` crate feature.
+    #[cfg(feature = "dangerous_debug")]
+    fn dangerous_extract_secrets(&self) -> Option<ExtractedSecrets>;
+
     /// This function uses `io` to complete any outstanding IO for
     /// this session.
     ///
@@ -147,6 +302,57 @@ pub trait Session: Read + Write + Send {
             };
         }
     }
+
+    /// Splits this session into a reader and a writer, so one
+    /// thread can read from the peer while another writes to it.
+    ///
+    /// The halves share the session, and take turns using it: each
+    /// call on either locks it while using it, but not while reading
+    /// or writing TLS.  They are joined again with
+    /// `SessionReader::reunite`.
+    fn split(self) -> (SessionReader<Self>, SessionWriter<Self>)
+        where Self: Sized
+    {
+        split::split(self)
+    }
+}
+
+/// Whether sessions may be resumed, for `ClientConfig::set_resumption`
+/// and `ServerConfig::set_resumption`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resumption {
+    /// Sessions may be resumed using session IDs or tickets.
+    Enabled,
+
+    /// Every handshake is a full one: we neither offer, issue
+    /// nor accept session IDs or tickets.
+    Disabled,
+}
+
+/// Secrets of an established session, from
+/// `Session::dangerous_extract_secrets`.
+#[cfg(feature = "dangerous_debug")]
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExtractedSecrets {
+    /// A TLS1.2 session's master secret, with the randoms which
+    /// identify the session.
+    TLS12 {
+        /// The ClientHello's random.
+        client_random: Vec<u8>,
+        /// The ServerHello's random.
+        server_random: Vec<u8>,
+        /// The master secret.
+        master_secret: Vec<u8>,
+    },
+
+    /// A TLS1.3 session's current application traffic secrets.
+    /// These change on each key update.
+    TLS13 {
+        /// The secret protecting data sent by the client.
+        client_traffic_secret: Vec<u8>,
+        /// The secret protecting data sent by the server.
+        server_traffic_secret: Vec<u8>,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -154,30 +360,63 @@ pub struct SessionRandoms {
     pub we_are_client: bool,
     pub client: [u8; 32],
     pub server: [u8; 32],
+
+    /// Have we had the peer's random yet?
+    pub have_peer: bool,
 }
 
 impl SessionRandoms {
-    pub fn for_server() -> SessionRandoms {
+    pub fn for_server(rng: &SecureRandom) -> SessionRandoms {
         let mut ret = SessionRandoms {
             we_are_client: false,
             client: [0u8; 32],
             server: [0u8; 32],
+            have_peer: false,
         };
 
-        rand::fill_random(&mut ret.server);
+        rand::fill_random(rng, &mut ret.server);
         ret
     }
 
-    pub fn for_client() -> SessionRandoms {
+    pub fn for_client(rng: &SecureRandom) -> SessionRandoms {
         let mut ret = SessionRandoms {
             we_are_client: true,
             client: [0u8; 32],
             server: [0u8; 32],
+            have_peer: false,
         };
 
-        rand::fill_random(&mut ret.client);
+        rand::fill_random(rng, &mut ret.client);
         ret
     }
+
+    /// Save the random from the peer's hello.
+    pub fn set_peer(&mut self, random: &Random) {
+        if self.we_are_client {
+            random.write_slice(&mut self.server);
+        } else {
+            random.write_slice(&mut self.client);
+        }
+        self.have_peer = true;
+    }
+
+    /// The client's random, if we have it yet.
+    pub fn get_client(&self) -> Option<[u8; 32]> {
+        if self.we_are_client || self.have_peer {
+            Some(self.client)
+        } else {
+            None
+        }
+    }
+
+    /// The server's random, if we have it yet.
+    pub fn get_server(&self) -> Option<[u8; 32]> {
+        if !self.we_are_client || self.have_peer {
+            Some(self.server)
+        } else {
+            None
+        }
+    }
 }
 
 fn join_randoms(first: &[u8], second: &[u8]) -> [u8; 64] {
@@ -194,6 +433,11 @@ pub struct SessionSecrets {
 }
 
 impl SessionSecrets {
+    /// Overwrite the master secret.
+    pub fn wipe(&mut self) {
+        util::zero(&mut self.master_secret);
+    }
+
     pub fn new(randoms: &SessionRandoms,
                hashalg: &'static ring::digest::Algorithm,
                pms: &[u8])
@@ -296,6 +540,19 @@ enum Limit {
     No
 }
 
+/// The default limit on buffered outgoing plaintext and TLS data.
+static DEFAULT_BUFFER_LIMIT: usize = 64 * 1024;
+
+/// How many received warning alerts we keep until they're taken.
+static MAX_RECEIVED_WARNINGS: usize = 16;
+
+/// By default, how many warning alerts we accept in a row before
+/// giving up on the peer.
+pub const DEFAULT_MAX_WARNING_ALERTS: usize = 4;
+
+/// The smallest record size limit a peer may advertise.
+pub const MIN_RECORD_SIZE_LIMIT: u16 = 64;
+
 pub struct SessionCommon {
     pub negotiated_version: Option<ProtocolVersion>,
     pub is_client: bool,
@@ -309,18 +566,31 @@ pub struct SessionCommon {
     pub peer_encrypting: bool,
     pub we_encrypting: bool,
     pub traffic: bool,
+    false_start: bool,
     pub want_write_key_update: bool,
+    sent_compat_ccs: bool,
+    early_data_to_skip: usize,
+    received_warnings: Vec<AlertDescription>,
+    warning_alerts: usize,
+    pub max_warning_alerts: usize,
+    pub reject_compat_ccs: bool,
+    pub record_padding: Option<RecordPadding>,
     pub message_deframer: MessageDeframer,
     pub handshake_joiner: HandshakeJoiner,
     pub message_fragmenter: MessageFragmenter,
+    max_fragment_len: usize,
+    record_size_limit: Option<usize>,
     received_plaintext: ChunkVecBuffer,
     sendable_plaintext: ChunkVecBuffer,
     pub sendable_tls: ChunkVecBuffer,
 }
 
 impl SessionCommon {
-    pub fn new(mtu: Option<usize>, client: bool) -> SessionCommon {
-        SessionCommon {
+    pub fn new(mtu: Option<usize>,
+               record_padding: Option<RecordPadding>,
+               client: bool) -> SessionCommon {
+        let max_fragment_len = mtu.unwrap_or(MAX_FRAGMENT_LEN);
+        let mut common = SessionCommon {
             negotiated_version: None,
             is_client: client,
             suite: None,
@@ -333,14 +603,62 @@ impl SessionCommon {
             peer_encrypting: false,
             we_encrypting: false,
             traffic: false,
+            false_start: false,
             want_write_key_update: false,
+            sent_compat_ccs: false,
+            early_data_to_skip: 0,
+            received_warnings: Vec::new(),
+            warning_alerts: 0,
+            max_warning_alerts: DEFAULT_MAX_WARNING_ALERTS,
+            reject_compat_ccs: false,
+            record_padding: record_padding,
             message_deframer: MessageDeframer::new(),
             handshake_joiner: HandshakeJoiner::new(),
-            message_fragmenter: MessageFragmenter::new(mtu.unwrap_or(MAX_FRAGMENT_LEN)),
+            message_fragmenter: MessageFragmenter::new(max_fragment_len),
+            max_fragment_len: max_fragment_len,
+            record_size_limit: None,
             received_plaintext: ChunkVecBuffer::new(),
             sendable_plaintext: ChunkVecBuffer::new(),
             sendable_tls: ChunkVecBuffer::new(),
+        };
+
+        common.set_buffer_limit(Some(DEFAULT_BUFFER_LIMIT));
+        common
+    }
+
+    /// Forget everything about the current connection, so we
+    /// can start another.  Secrets we hold are overwritten, and
+    /// our buffers keep their allocations.
+    pub fn reset(&mut self) {
+        if let Some(ref mut ks) = self.key_schedule {
+            ks.wipe();
         }
+
+        self.negotiated_version = None;
+        self.message_encrypter = MessageEncrypter::invalid();
+        self.message_decrypter = MessageDecrypter::invalid();
+        self.key_schedule = None;
+        self.suite = None;
+        self.write_seq = 0;
+        self.read_seq = 0;
+        self.peer_eof = false;
+        self.peer_encrypting = false;
+        self.we_encrypting = false;
+        self.traffic = false;
+        self.false_start = false;
+        self.want_write_key_update = false;
+        self.sent_compat_ccs = false;
+        self.early_data_to_skip = 0;
+        self.received_warnings.clear();
+        self.warning_alerts = 0;
+        self.message_deframer.reset();
+        self.handshake_joiner.reset();
+        self.message_fragmenter.set_max_fragment_len(self.max_fragment_len);
+        self.record_size_limit = None;
+        self.received_plaintext.clear();
+        self.sendable_plaintext.clear();
+        self.sendable_tls.clear();
+        self.set_buffer_limit(Some(DEFAULT_BUFFER_LIMIT));
     }
 
     pub fn is_tls13(&self) -> bool {
@@ -354,6 +672,15 @@ impl SessionCommon {
         self.suite.as_ref().unwrap()
     }
 
+    /// Does our ciphersuite, if we have one, use an ephemeral key
+    /// exchange?  In TLS1.3 we only do (EC)DHE, whatever the suite.
+    pub fn has_forward_secrecy(&self) -> bool {
+        match self.suite {
+            Some(suite) => self.is_tls13() || suite.is_forward_secret(),
+            None => false,
+        }
+    }
+
     pub fn set_suite(&mut self, suite: &'static SupportedCipherSuite) {
         self.suite = Some(suite);
     }
@@ -370,13 +697,97 @@ impl SessionCommon {
         self.key_schedule = Some(ks);
     }
 
+    /// Pull out the session's secrets; `secrets` are the TLS1.2
+    /// session secrets, if we have them.
+    #[cfg(feature = "dangerous_debug")]
+    pub fn extract_secrets(&self, secrets: Option<&SessionSecrets>) -> Option<ExtractedSecrets> {
+        if !self.traffic {
+            return None;
+        }
+
+        if self.is_tls13() {
+            let ks = self.get_key_schedule();
+            return Some(ExtractedSecrets::TLS13 {
+                client_traffic_secret: ks.current_client_traffic_secret.clone(),
+                server_traffic_secret: ks.current_server_traffic_secret.clone(),
+            });
+        }
+
+        secrets.map(|secrets| ExtractedSecrets::TLS12 {
+            client_random: secrets.randoms.client.to_vec(),
+            server_random: secrets.randoms.server.to_vec(),
+            master_secret: secrets.get_master_secret(),
+        })
+    }
+
     pub fn set_message_encrypter(&mut self,
-                                 cipher: Box<MessageEncrypter>) {
+                                 mut cipher: Box<MessageEncrypter>) {
+        cipher.set_padding(self.record_padding.clone());
         self.message_encrypter = cipher;
         self.write_seq = 0;
         self.we_encrypting = true;
     }
 
+    /// The largest record size limit the negotiated version allows
+    /// (RFC8449 section 4).
+    pub fn largest_record_size_limit(&self) -> u16 {
+        if self.is_tls13() {
+            MAX_FRAGMENT_LEN as u16 + 1
+        } else {
+            MAX_FRAGMENT_LEN as u16
+        }
+    }
+
+    /// Agree a record size limit with the peer, given the limits we
+    /// (`ours`) and they (`theirs`) advertised.  From now on, the
+    /// records we send fit within the smaller of the two.
+    pub fn negotiate_record_size_limit(&mut self,
+                                       ours: u16,
+                                       theirs: u16) -> Result<(), TLSError> {
+        if ours < MIN_RECORD_SIZE_LIMIT || ours > self.largest_record_size_limit() {
+            self.send_fatal_alert(AlertDescription::InternalError);
+            return Err(TLSError::General(format!("record_size_limit of {} is out of range",
+                                                 ours)));
+        }
+
+        if theirs < MIN_RECORD_SIZE_LIMIT {
+            self.send_fatal_alert(AlertDescription::IllegalParameter);
+            return Err(TLSError::PeerMisbehavedError("peer sent too small record size limit"
+                                                     .to_string()));
+        }
+
+        let limit = cmp::min(ours, theirs) as usize;
+        self.record_size_limit = Some(limit);
+
+        // In TLS1.3, the limit covers the content type byte and any
+        // padding too.
+        let content_len = if self.is_tls13() { limit - 1 } else { limit };
+        let max_frag = cmp::min(self.max_fragment_len, content_len);
+        self.message_fragmenter.set_max_fragment_len(max_frag);
+
+        if let Some(padding) = self.record_padding.take() {
+            let capped: RecordPadding = Arc::new(move |len| {
+                cmp::min(padding(len), limit.saturating_sub(len))
+            });
+            self.record_padding = Some(capped);
+            self.message_encrypter.set_padding(self.record_padding.clone());
+        }
+
+        Ok(())
+    }
+
+    pub fn get_record_size_limit(&self) -> usize {
+        self.record_size_limit
+            .unwrap_or_else(|| self.message_fragmenter.get_max_fragment_len())
+    }
+
+    /// Stop encrypting what we send, and forget our keys.
+    pub fn clear_message_encrypter(&mut self) {
+        self.message_encrypter = MessageEncrypter::invalid();
+        self.write_seq = 0;
+        self.we_encrypting = false;
+    }
+
     pub fn set_message_decrypter(&mut self,
                                  cipher: Box<MessageDecrypter>) {
         self.message_decrypter = cipher;
@@ -388,7 +799,7 @@ impl SessionCommon {
         !self.received_plaintext.is_empty()
     }
 
-    pub fn set_buffer_limit(&mut self, limit: usize) {
+    pub fn set_buffer_limit(&mut self, limit: Option<usize>) {
         self.sendable_plaintext.set_limit(limit);
         self.sendable_tls.set_limit(limit);
     }
@@ -399,7 +810,20 @@ impl SessionCommon {
         self.message_encrypter.encrypt(plain, seq).unwrap()
     }
 
+    /// Decrypt and authenticate `encr`.  If it fails authentication,
+    /// we send a `bad_record_mac` alert and return `DecryptError`:
+    /// no part of the record's plaintext is ever returned.
     pub fn decrypt_incoming(&mut self, encr: Message) -> Result<Message, TLSError> {
+        let result = self.decrypt_record(encr);
+
+        if let Err(TLSError::DecryptError) = result {
+            self.send_fatal_alert(AlertDescription::BadRecordMac);
+        }
+
+        result
+    }
+
+    fn decrypt_record(&mut self, encr: Message) -> Result<Message, TLSError> {
         // Perhaps if we send an alert well before their counter wraps, a
         // buggy peer won't make a terrible mistake here?
         // Note that there's no reason to refuse to decrypt: the security
@@ -410,7 +834,63 @@ impl SessionCommon {
 
         let seq = self.read_seq;
         self.read_seq += 1;
-        self.message_decrypter.decrypt(encr, seq)
+        let result = self.message_decrypter.decrypt(encr, seq);
+
+        // The decrypter rejects records with no valid content type.
+        if let Err(TLSError::InappropriateMessage { .. }) = result {
+            self.send_fatal_alert(AlertDescription::UnexpectedMessage);
+        }
+
+        result
+    }
+
+    /// We rejected the peer's early data: its records won't decrypt
+    /// with our keys, so we discard up to `max` bytes of records which
+    /// fail to decrypt until one succeeds.
+    pub fn skip_rejected_early_data(&mut self, max: usize) {
+        self.early_data_to_skip = max;
+    }
+
+    /// Like `decrypt_incoming`, but returns None if `encr` is
+    /// rejected early data which we're skipping.
+    pub fn decrypt_incoming_or_skip(&mut self, encr: Message) -> Result<Option<Message>, TLSError> {
+        if self.early_data_to_skip == 0 {
+            return self.decrypt_incoming(encr).map(Some);
+        }
+
+        let len = encr.payload.length();
+        match self.decrypt_record(encr) {
+            Ok(msg) => {
+                self.early_data_to_skip = 0;
+                Ok(Some(msg))
+            }
+            Err(TLSError::DecryptError) if len <= self.early_data_to_skip => {
+                trace!("Skipping {} bytes of rejected early data", len);
+                self.early_data_to_skip -= len;
+                self.read_seq -= 1;
+                Ok(None)
+            }
+            Err(TLSError::DecryptError) => {
+                self.send_fatal_alert(AlertDescription::BadRecordMac);
+                Err(TLSError::DecryptError)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Before the peer starts encrypting, application data can only
+    /// be early data sent with a ClientHello we answered with a
+    /// HelloRetryRequest.  Returns true if `msg` is such a record,
+    /// which we're skipping.
+    pub fn skip_early_data_before_retry(&mut self, msg: &Message) -> bool {
+        let len = msg.payload.length();
+        if msg.typ != ContentType::ApplicationData || len > self.early_data_to_skip {
+            return false;
+        }
+
+        trace!("Skipping {} bytes of early data sent before retry", len);
+        self.early_data_to_skip -= len;
+        true
     }
 
     pub fn process_alert(&mut self, msg: Message) -> Result<(), TLSError> {
@@ -428,6 +908,19 @@ impl SessionCommon {
                     self.send_fatal_alert(AlertDescription::DecodeError);
                 } else {
                     warn!("TLS alert warning received: {:#?}", msg);
+
+                    // Don't let the peer keep us busy with nothing but
+                    // warnings.
+                    self.warning_alerts += 1;
+                    if self.warning_alerts > self.max_warning_alerts {
+                        self.send_fatal_alert(AlertDescription::UnexpectedMessage);
+                        let why = "too many warning alerts in a row";
+                        return Err(TLSError::PeerMisbehavedError(why.to_string()));
+                    }
+
+                    if self.received_warnings.len() < MAX_RECEIVED_WARNINGS {
+                        self.received_warnings.push(alert.description);
+                    }
                     return Ok(());
                 }
             }
@@ -439,6 +932,15 @@ impl SessionCommon {
         }
     }
 
+    /// Note that the peer sent something other than an alert.
+    pub fn reset_warning_alerts(&mut self) {
+        self.warning_alerts = 0;
+    }
+
+    pub fn take_received_warnings(&mut self) -> Vec<AlertDescription> {
+        mem::replace(&mut self.received_warnings, Vec::new())
+    }
+
     fn do_write_key_update(&mut self) {
         // TLS1.3 putting key update triggering here breaks layering
         // between the handshake and record layer.
@@ -552,9 +1054,30 @@ impl SessionCommon {
         self.send_plain(data, Limit::Yes)
     }
 
+    /// Send TLS1.3 early data.  Unlike other plaintext sent during
+    /// the handshake, this goes out straight away, encrypted with the
+    /// early traffic keys we're currently using.
+    pub fn send_early_plaintext(&mut self, data: &[u8]) -> usize {
+        debug_assert!(self.we_encrypting);
+
+        if data.is_empty() {
+            return 0;
+        }
+
+        self.send_appdata_encrypt(data, Limit::Yes)
+    }
+
+    /// Early data we sent was rejected: send `data` once the
+    /// handshake completes instead, before any other plaintext
+    /// written during the handshake.
+    pub fn resend_early_plaintext(&mut self, data: Vec<u8>) {
+        debug_assert!(!self.traffic);
+        self.sendable_plaintext.prepend(data);
+    }
+
 
     fn send_plain(&mut self, data: &[u8], limit: Limit) -> io::Result<usize> {
-        if !self.traffic {
+        if !self.traffic && !self.false_start {
             // If we haven't completed handshaking, buffer
             // plaintext to send once we do.
             let len = match limit {
@@ -579,10 +1102,18 @@ impl SessionCommon {
         self.flush_plaintext();
     }
 
+    /// Start sending plaintext before the handshake completes
+    /// (TLS1.2 False Start).  We must already be encrypting.
+    pub fn start_false_start(&mut self) {
+        debug_assert!(self.we_encrypting);
+        self.false_start = true;
+        self.flush_plaintext();
+    }
+
     /// Send any buffered plaintext.  Plaintext is buffered if
     /// written during handshake.
     pub fn flush_plaintext(&mut self) {
-        if !self.traffic {
+        if !self.traffic && !self.false_start {
             return;
         }
 
@@ -600,6 +1131,8 @@ impl SessionCommon {
 
     /// Send a raw TLS message, fragmenting it if needed.
     pub fn send_msg(&mut self, m: Message, must_encrypt: bool) {
+        trace!("Sending {:?} message {:?}", m.typ, m.get_handshake_type());
+
         if !must_encrypt {
             let mut to_send = VecDeque::new();
             self.message_fragmenter.fragment(m, &mut to_send);
@@ -611,10 +1144,52 @@ impl SessionCommon {
         }
     }
 
+    /// Send a ChangeCipherSpec for middlebox compatibility in TLS1.3.
+    /// These are never encrypted, and we only send one.  We send none
+    /// if we're refusing the peer's.
+    pub fn send_tls13_compat_ccs(&mut self) {
+        if self.sent_compat_ccs || self.reject_compat_ccs {
+            return;
+        }
+
+        let ccs = Message {
+            typ: ContentType::ChangeCipherSpec,
+            version: ProtocolVersion::TLSv1_2,
+            payload: MessagePayload::ChangeCipherSpec(ChangeCipherSpecPayload {}),
+        };
+
+        trace!("Sending compatibility ChangeCipherSpec");
+        self.queue_tls_message(ccs);
+        self.sent_compat_ccs = true;
+    }
+
+    /// Is `msg` a middlebox compatibility ChangeCipherSpec which
+    /// should be silently dropped?  These may arrive any time during
+    /// a TLS1.3 handshake, but elsewhere a ChangeCipherSpec is an error.
+    pub fn is_tls13_compat_ccs(&self, msg: &Message) -> bool {
+        if !msg.is_content_type(ContentType::ChangeCipherSpec) ||
+           !self.is_tls13() || self.traffic || !self.handshake_joiner.is_empty() {
+            return false;
+        }
+
+        match msg.payload {
+            MessagePayload::Opaque(ref p) => p.0 == [0x01],
+            _ => false,
+        }
+    }
+
     pub fn take_received_plaintext(&mut self, bytes: Payload) {
         self.received_plaintext.append(bytes.0);
     }
 
+    pub fn peek_plaintext(&self) -> &[u8] {
+        self.received_plaintext.peek()
+    }
+
+    pub fn consume_plaintext(&mut self, amt: usize) {
+        self.received_plaintext.consume(amt)
+    }
+
     pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let len = self.received_plaintext.read(buf)?;
 
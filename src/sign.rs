@@ -1,19 +1,29 @@
 use msgs::enums::{SignatureAlgorithm, SignatureScheme};
 use util;
 use untrusted;
-use ring;
 use ring::signature;
 use ring::signature::RSAKeyPair;
 use std::sync::Arc;
 use key;
+use x509;
+use rand::{SecureRandom, RingRandom};
 
 /// A thing that can sign a message.
+///
+/// Implement this yourself if the private key lives elsewhere,
+/// such as in an HSM or a remote key service.
 pub trait Signer : Send + Sync {
     /// Choose a SignatureScheme from those offered.
     fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<SignatureScheme>;
 
-    /// Signs `message` using `scheme`.
-    fn sign(&self, scheme: SignatureScheme, message: &[u8]) -> Result<Vec<u8>, ()>;
+    /// Signs `message` using `scheme`.  Schemes which need random
+    /// material, such as RSA-PSS, take it from `rng`: this is the
+    /// session's configured `SecureRandom`.
+    fn sign(&self,
+            scheme: SignatureScheme,
+            message: &[u8],
+            rng: &SecureRandom)
+            -> Result<Vec<u8>, ()>;
 
     /// What kind of key we have.
     fn algorithm(&self) -> SignatureAlgorithm;
@@ -58,7 +68,11 @@ impl Signer for RSASigner {
         util::first_in_both(self.schemes, offered)
     }
 
-    fn sign(&self, scheme: SignatureScheme, message: &[u8]) -> Result<Vec<u8>, ()> {
+    fn sign(&self,
+            scheme: SignatureScheme,
+            message: &[u8],
+            rng: &SecureRandom)
+            -> Result<Vec<u8>, ()> {
         let mut sig = vec![0; self.key.public_modulus_len()];
 
         let encoding: &signature::RSAEncoding = match scheme {
@@ -71,11 +85,10 @@ impl Signer for RSASigner {
             _ => return Err(()),
         };
 
-        let rng = ring::rand::SystemRandom::new();
         let mut signer = signature::RSASigningState::new(self.key.clone())
             .map_err(|_| ())?;
 
-        signer.sign(encoding, &rng, message, &mut sig)
+        signer.sign(encoding, &RingRandom(rng), message, &mut sig)
             .map(|_| sig)
             .map_err(|_| ())
     }
@@ -84,3 +97,53 @@ impl Signer for RSASigner {
         SignatureAlgorithm::RSA
     }
 }
+
+/// A Signer for Ed25519.  This signs the whole message: Ed25519
+/// has no separate hash function.  No TLS1.2 ciphersuite uses
+/// Ed25519 keys, so servers only use it for TLS1.3.
+pub struct Ed25519Signer {
+    key: signature::Ed25519KeyPair,
+}
+
+impl Ed25519Signer {
+    /// Make a new Ed25519Signer from a PKCS#8 encoding of the
+    /// private key, as RFC8410 describes, and the end-entity
+    /// certificate `cert` for it.  We check the key matches
+    /// the one `cert` certifies.
+    pub fn new(der: &key::PrivateKey, cert: &key::Certificate) -> Result<Ed25519Signer, ()> {
+        let seed = x509::ed25519_private_key(&der.0).ok_or(())?;
+        let info = x509::CertificateInfo::parse(&cert.0).ok_or(())?;
+        let public_key = x509::ed25519_public_key(info.spki).ok_or(())?;
+
+        signature::Ed25519KeyPair::from_seed_and_public_key(untrusted::Input::from(seed),
+                                                            untrusted::Input::from(public_key))
+            .map(|key| Ed25519Signer { key: key })
+            .map_err(|_| ())
+    }
+}
+
+impl Signer for Ed25519Signer {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<SignatureScheme> {
+        if offered.contains(&SignatureScheme::ED25519) {
+            Some(SignatureScheme::ED25519)
+        } else {
+            None
+        }
+    }
+
+    fn sign(&self,
+            scheme: SignatureScheme,
+            message: &[u8],
+            _rng: &SecureRandom)
+            -> Result<Vec<u8>, ()> {
+        if scheme != SignatureScheme::ED25519 {
+            return Err(());
+        }
+
+        Ok(self.key.sign(message).as_ref().to_vec())
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::ED25519
+    }
+}
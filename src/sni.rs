@@ -0,0 +1,52 @@
+// Peeking at the server name a client asks for, for routing
+// connections before terminating them.
+
+use msgs::deframer::MessageDeframer;
+use msgs::hsjoiner::HandshakeJoiner;
+use msgs::message::MessagePayload;
+use msgs::handshake::{HandshakePayload, ConvertServerNameList};
+
+/// Find the name a client asked for with SNI, given
+/// `client_hello`: the first bytes it sent on a connection.
+/// These must contain at least the whole ClientHello, which can
+/// span several TLS records.  Anything after it is ignored.
+///
+/// Returns `Ok(None)` if the ClientHello doesn't include SNI, and
+/// `Err(())` if `client_hello` doesn't start with a complete and
+/// valid ClientHello.
+pub fn read_sni(client_hello: &[u8]) -> Result<Option<String>, ()> {
+    let mut rd = client_hello;
+    let mut deframer = MessageDeframer::new();
+    let mut joiner = HandshakeJoiner::new();
+
+    loop {
+        if let Some(msg) = joiner.frames.pop_front() {
+            return match msg.payload {
+                MessagePayload::Handshake(ref hs) => match hs.payload {
+                    HandshakePayload::ClientHello(ref hello) => {
+                        Ok(hello.get_sni_extension()
+                           .and_then(|sni| sni.get_hostname())
+                           .map(|name| name.to_string()))
+                    }
+                    _ => Err(()),
+                },
+                _ => Err(()),
+            };
+        }
+
+        if let Some(msg) = deframer.frames.pop_front() {
+            if !joiner.want_message(&msg) {
+                return Err(());
+            }
+
+            joiner.take_message(msg).ok_or(())?;
+            continue;
+        }
+
+        if deframer.desynced || rd.is_empty() {
+            return Err(());
+        }
+
+        deframer.read(&mut rd).map_err(|_| ())?;
+    }
+}
@@ -0,0 +1,175 @@
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use session::Session;
+use error::TLSError;
+
+/// How much TLS `SessionReader::read_tls` reads at once.
+const READ_SIZE: usize = 16384;
+
+/// The reading half of a `Session`, from `Session::split`.
+///
+/// This reads TLS from the peer, and the plaintext it carries.
+/// It shares the session with a `SessionWriter`, which may be
+/// used at the same time on another thread: each call locks the
+/// session while using it, but never while doing IO, so one
+/// half blocking on the network doesn't hold up the other.
+pub struct SessionReader<S: Session> {
+    sess: Arc<Mutex<S>>,
+
+    /// TLS we've read, but the session hasn't taken yet.
+    received: Vec<u8>,
+}
+
+/// The writing half of a `Session`, from `Session::split`.
+///
+/// This writes plaintext, and the TLS which carries it to the
+/// peer.  It shares the session with a `SessionReader`.
+pub struct SessionWriter<S: Session> {
+    sess: Arc<Mutex<S>>,
+
+    /// TLS taken from the session, but not yet written.
+    sendable: Vec<u8>,
+}
+
+pub fn split<S: Session>(sess: S) -> (SessionReader<S>, SessionWriter<S>) {
+    let sess = Arc::new(Mutex::new(sess));
+    let reader = SessionReader { sess: sess.clone(), received: Vec::new() };
+    let writer = SessionWriter { sess: sess, sendable: Vec::new() };
+    (reader, writer)
+}
+
+fn lock<S: Session>(sess: &Arc<Mutex<S>>) -> MutexGuard<S> {
+    sess.lock().unwrap()
+}
+
+impl<S: Session> SessionReader<S> {
+    /// Like `Session::read_tls`.  `rd` is read without the session
+    /// locked, and anything the session can't take yet is kept for
+    /// the next call, which then doesn't read from `rd`.
+    ///
+    /// This returns how many bytes the session took.
+    pub fn read_tls(&mut self, rd: &mut Read) -> io::Result<usize> {
+        if self.received.is_empty() {
+            self.received.resize(READ_SIZE, 0u8);
+            let new_bytes = match rd.read(&mut self.received) {
+                Ok(new_bytes) => new_bytes,
+                Err(e) => {
+                    self.received.clear();
+                    return Err(e);
+                }
+            };
+            self.received.truncate(new_bytes);
+
+            if new_bytes == 0 {
+                return Ok(0);
+            }
+        }
+
+        let taken = {
+            let mut received: &[u8] = &self.received;
+            lock(&self.sess).read_tls(&mut received)?
+        };
+        self.received.drain(..taken);
+        Ok(taken)
+    }
+
+    /// Like `Session::process_new_packets`.  Any handshake or
+    /// alert messages we need to send in reply are written by the
+    /// `SessionWriter`.
+    pub fn process_new_packets(&mut self) -> Result<(), TLSError> {
+        lock(&self.sess).process_new_packets()
+    }
+
+    /// Like `Session::wants_read`.
+    pub fn wants_read(&self) -> bool {
+        lock(&self.sess).wants_read()
+    }
+
+    /// Calls `f` with the whole session, for anything not covered
+    /// by this type.
+    pub fn with_session<F, R>(&self, f: F) -> R
+        where F: FnOnce(&mut S) -> R
+    {
+        f(&mut lock(&self.sess))
+    }
+
+    /// Joins this with `writer`, the other half of the same session,
+    /// returning the session.  Any TLS this has read but the session
+    /// hasn't taken is given to it now.
+    ///
+    /// This panics if `writer` is the other half of a different
+    /// session, or if it still has TLS to write: call its
+    /// `write_tls` until `wants_write` is false first.
+    pub fn reunite(mut self, writer: SessionWriter<S>) -> S {
+        assert!(Arc::ptr_eq(&self.sess, &writer.sess),
+                "reunite needs halves of the same session");
+        assert!(writer.sendable.is_empty(),
+                "reunite needs the writer's TLS to be written");
+        drop(writer);
+
+        while !self.received.is_empty() {
+            if self.read_tls(&mut io::empty()).unwrap() == 0 {
+                break;
+            }
+        }
+
+        match Arc::try_unwrap(self.sess) {
+            Ok(sess) => sess.into_inner().unwrap(),
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+impl<S: Session> Read for SessionReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        lock(&self.sess).read(buf)
+    }
+}
+
+impl<S: Session> SessionWriter<S> {
+    /// Like `Session::write_tls`.  All the TLS the session has to
+    /// send is taken from it first, so `wr` is written without the
+    /// session locked.  What `wr` doesn't take is kept for the next
+    /// call.
+    pub fn write_tls(&mut self, wr: &mut Write) -> io::Result<usize> {
+        if self.sendable.is_empty() {
+            let mut sess = lock(&self.sess);
+            while sess.wants_write() {
+                sess.write_tls(&mut self.sendable)?;
+            }
+        }
+
+        let written = wr.write(&self.sendable)?;
+        self.sendable.drain(..written);
+        Ok(written)
+    }
+
+    /// Like `Session::wants_write`.  This includes TLS taken from
+    /// the session by `write_tls`, but not yet written.
+    pub fn wants_write(&self) -> bool {
+        !self.sendable.is_empty() || lock(&self.sess).wants_write()
+    }
+
+    /// Like `Session::send_close_notify`.
+    pub fn send_close_notify(&mut self) {
+        lock(&self.sess).send_close_notify()
+    }
+
+    /// Calls `f` with the whole session, for anything not covered
+    /// by this type.
+    pub fn with_session<F, R>(&self, f: F) -> R
+        where F: FnOnce(&mut S) -> R
+    {
+        f(&mut lock(&self.sess))
+    }
+}
+
+impl<S: Session> Write for SessionWriter<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        lock(&self.sess).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        lock(&self.sess).flush()
+    }
+}
@@ -60,7 +60,7 @@ impl<'a, S, T> Write for Stream<'a, S, T> where S: 'a + Session, T: 'a + Read +
     fn flush(&mut self) -> Result<()> {
         self.complete_prior_io()?;
 
-        self.sess.flush()?;
+        self.sess.flush_plaintext();
         if self.sess.wants_write() {
             self.sess.complete_io(self.sock)?;
         }
@@ -5,6 +5,7 @@ use msgs::handshake::DecomposedSignatureScheme;
 use msgs::handshake::{ClientECDHParams, ServerECDHParams};
 use msgs::codec::{Reader, Codec};
 use util;
+use rand::{SecureRandom, RingRandom};
 
 use ring;
 use untrusted;
@@ -18,14 +19,22 @@ pub enum BulkAlgorithm {
 }
 
 /// The result of a key exchange.  This has our public key,
-/// and the agreed premaster secret.
+/// and the agreed premaster secret, which is overwritten when
+/// this is dropped.
 pub struct KeyExchangeResult {
     pub pubkey: Vec<u8>,
     pub premaster_secret: Vec<u8>,
 }
 
+impl Drop for KeyExchangeResult {
+    fn drop(&mut self) {
+        util::zero(&mut self.premaster_secret);
+    }
+}
+
 /// An in-progress key exchange.  This has the algorithm,
-/// our private key, and our public key.
+/// our private key, and our public key.  ring owns the private
+/// key, so we can't overwrite it; ring consumes it on completion.
 pub struct KeyExchange {
     pub group: NamedGroup,
     alg: &'static ring::agreement::Algorithm,
@@ -44,18 +53,17 @@ impl KeyExchange {
         }
     }
 
-    pub fn client_ecdhe(kx_params: &[u8]) -> Option<KeyExchangeResult> {
+    pub fn client_ecdhe(kx_params: &[u8], rng: &SecureRandom) -> Option<KeyExchangeResult> {
         let mut rd = Reader::init(kx_params);
         let ecdh_params = try_ret!(ServerECDHParams::read(&mut rd));
 
-        try_ret!(KeyExchange::start_ecdhe(ecdh_params.curve_params.named_group))
+        try_ret!(KeyExchange::start_ecdhe(ecdh_params.curve_params.named_group, rng))
             .complete(&ecdh_params.public.0)
     }
 
-    pub fn start_ecdhe(named_group: NamedGroup) -> Option<KeyExchange> {
+    pub fn start_ecdhe(named_group: NamedGroup, rng: &SecureRandom) -> Option<KeyExchange> {
         let alg = try_ret!(KeyExchange::named_group_to_ecdh_alg(named_group));
-        let rng = ring::rand::SystemRandom::new();
-        let ours = ring::agreement::EphemeralPrivateKey::generate(alg, &rng).unwrap();
+        let ours = ring::agreement::EphemeralPrivateKey::generate(alg, &RingRandom(rng)).unwrap();
 
         let mut pubkey = Vec::new();
         pubkey.resize(ours.public_key_len(), 0u8);
@@ -88,7 +96,23 @@ impl KeyExchange {
             .and_then(|ecdh| self.complete(&ecdh.public.0))
     }
 
+    /// Is `peer` plausibly a public key in `group`?  It must be the
+    /// right length and, for the NIST curves, an uncompressed point.
+    /// ring checks the point is on the curve when we use it.
+    pub fn check_peer_public_key(group: NamedGroup, peer: &[u8]) -> bool {
+        match group {
+            NamedGroup::X25519 => peer.len() == 32 && peer.iter().any(|b| *b != 0),
+            NamedGroup::secp256r1 => peer.len() == 65 && peer[0] == 0x04,
+            NamedGroup::secp384r1 => peer.len() == 97 && peer[0] == 0x04,
+            _ => false,
+        }
+    }
+
     pub fn complete(self, peer: &[u8]) -> Option<KeyExchangeResult> {
+        if !KeyExchange::check_peer_public_key(self.group, peer) {
+            return None;
+        }
+
         let secret = ring::agreement::agree_ephemeral(self.privkey,
                                                       self.alg,
                                                       untrusted::Input::from(peer),
@@ -103,9 +127,15 @@ impl KeyExchange {
             return None;
         }
 
+        // A low-order X25519 point gives an all-zero secret.
+        let secret = secret.unwrap();
+        if secret.iter().all(|b| *b == 0) {
+            return None;
+        }
+
         Some(KeyExchangeResult {
             pubkey: self.pubkey,
-            premaster_secret: secret.unwrap(),
+            premaster_secret: secret,
         })
     }
 }
@@ -168,18 +198,24 @@ impl SupportedCipherSuite {
     /// We have parameters and a verified public key in `kx_params`.
     /// Generate an ephemeral key, generate the shared secret, and
     /// return it and the public half in a `KeyExchangeResult`.
-    pub fn do_client_kx(&self, kx_params: &[u8]) -> Option<KeyExchangeResult> {
+    pub fn do_client_kx(&self,
+                        kx_params: &[u8],
+                        rng: &SecureRandom)
+                        -> Option<KeyExchangeResult> {
         match self.kx {
-            KeyExchangeAlgorithm::ECDHE => KeyExchange::client_ecdhe(kx_params),
+            KeyExchangeAlgorithm::ECDHE => KeyExchange::client_ecdhe(kx_params, rng),
             _ => None,
         }
     }
 
     /// Start the KX process with the given group.  This generates
     /// the server's share, but we don't yet have the client's share.
-    pub fn start_server_kx(&self, named_group: NamedGroup) -> Option<KeyExchange> {
+    pub fn start_server_kx(&self,
+                           named_group: NamedGroup,
+                           rng: &SecureRandom)
+                           -> Option<KeyExchange> {
         match self.kx {
-            KeyExchangeAlgorithm::ECDHE => KeyExchange::start_ecdhe(named_group),
+            KeyExchangeAlgorithm::ECDHE => KeyExchange::start_ecdhe(named_group, rng),
             _ => None,
         }
     }
@@ -236,6 +272,15 @@ impl SupportedCipherSuite {
         }
     }
 
+    /// Does this suite use an ephemeral key exchange?  TLS1.3 suites
+    /// always do.
+    pub fn is_forward_secret(&self) -> bool {
+        match self.kx {
+            KeyExchangeAlgorithm::DHE | KeyExchangeAlgorithm::ECDHE => true,
+            _ => self.usable_for_version(ProtocolVersion::TLSv1_3),
+        }
+    }
+
     /// Can a session using suite self resume using suite new_suite?
     pub fn can_resume_to(&self, new_suite: &SupportedCipherSuite) -> bool {
         if self.usable_for_version(ProtocolVersion::TLSv1_3) &&
@@ -416,9 +461,58 @@ pub fn reduce_given_version(all: &[&'static SupportedCipherSuite],
         .collect()
 }
 
+/// Return a list of the ciphersuites in `all` with the suites
+/// lacking forward secrecy removed, unless `allow_static` is set.
+pub fn reduce_given_forward_secrecy(all: &[&'static SupportedCipherSuite],
+                                    allow_static: bool)
+                                    -> Vec<&'static SupportedCipherSuite> {
+    all.iter()
+        .filter(|&&suite| allow_static || suite.is_forward_secret())
+        .cloned()
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
-    use msgs::enums::CipherSuite;
+    use msgs::enums::{CipherSuite, NamedGroup};
+    use msgs::enums::{HashAlgorithm, SignatureAlgorithm};
+    use msgs::handshake::KeyExchangeAlgorithm;
+    use super::{KeyExchange, SupportedCipherSuite, BulkAlgorithm};
+
+    // We don't implement static RSA, but need a suite to filter.
+    static TLS_RSA_WITH_AES_128_GCM_SHA256: SupportedCipherSuite = SupportedCipherSuite {
+        suite: CipherSuite::TLS_RSA_WITH_AES_128_GCM_SHA256,
+        kx: KeyExchangeAlgorithm::RSA,
+        sign: SignatureAlgorithm::RSA,
+        bulk: BulkAlgorithm::AES_128_GCM,
+        hash: HashAlgorithm::SHA256,
+        enc_key_len: 16,
+        fixed_iv_len: 4,
+        explicit_nonce_len: 8,
+    };
+
+    #[test]
+    fn test_forward_secrecy() {
+        assert!(!TLS_RSA_WITH_AES_128_GCM_SHA256.is_forward_secret());
+
+        for suite in super::ALL_CIPHERSUITES.iter() {
+            assert!(suite.is_forward_secret());
+        }
+    }
+
+    #[test]
+    fn test_reduce_given_forward_secrecy() {
+        let all = vec![&TLS_RSA_WITH_AES_128_GCM_SHA256,
+                       &super::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                       &super::TLS13_AES_128_GCM_SHA256];
+
+        let reduced = super::reduce_given_forward_secrecy(&all, false);
+        assert_eq!(reduced, vec![&super::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                                 &super::TLS13_AES_128_GCM_SHA256]);
+
+        let reduced = super::reduce_given_forward_secrecy(&all, true);
+        assert_eq!(reduced, all);
+    }
 
     #[test]
     fn test_client_pref() {
@@ -443,4 +537,20 @@ mod test {
         assert_eq!(chosen.unwrap(),
                    &super::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384);
     }
+
+    #[test]
+    fn test_check_peer_public_key() {
+        let mut p256 = vec![ 0x04 ];
+        p256.extend_from_slice(&[ 0x55; 64 ]);
+        assert!(KeyExchange::check_peer_public_key(NamedGroup::secp256r1, &p256));
+        assert!(!KeyExchange::check_peer_public_key(NamedGroup::secp384r1, &p256));
+        assert!(!KeyExchange::check_peer_public_key(NamedGroup::secp256r1, &p256[..64]));
+        p256[0] = 0x03;
+        assert!(!KeyExchange::check_peer_public_key(NamedGroup::secp256r1, &p256));
+
+        assert!(KeyExchange::check_peer_public_key(NamedGroup::X25519, &[ 0x55; 32 ]));
+        assert!(!KeyExchange::check_peer_public_key(NamedGroup::X25519, &[ 0; 32 ]));
+        assert!(!KeyExchange::check_peer_public_key(NamedGroup::X25519, &[]));
+        assert!(!KeyExchange::check_peer_public_key(NamedGroup::FFDHE2048, &[ 0x55; 32 ]));
+    }
 }
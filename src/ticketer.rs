@@ -1,9 +1,9 @@
 
 use server::ProducesTickets;
-use rand;
+use rand::{self, SecureRandom};
 
 use time;
-use std::mem;
+use std::collections::VecDeque;
 use std::sync::Mutex;
 use ring::aead;
 
@@ -35,10 +35,11 @@ impl AEADTicketer {
         }
     }
 
-    /// Make a ticketer with recommended configuration and a random key.
-    pub fn new() -> AEADTicketer {
+    /// Make a ticketer with recommended configuration and a key
+    /// from `rng`.
+    pub fn new(rng: &SecureRandom) -> AEADTicketer {
         let mut key = [0u8; 32];
-        rand::fill_random(&mut key);
+        rand::fill_random(rng, &mut key);
         AEADTicketer::new_custom(&aead::CHACHA20_POLY1305, &key, 60 * 60 * 12)
     }
 }
@@ -52,10 +53,10 @@ impl ProducesTickets for AEADTicketer {
     }
 
     /// Encrypt `message` and return the ciphertext.
-    fn encrypt(&self, message: &[u8]) -> Option<Vec<u8>> {
+    fn encrypt(&self, message: &[u8], rng: &SecureRandom) -> Option<Vec<u8>> {
         // Random nonce, because a counter is a privacy leak.
         let mut nonce = [0u8; 12];
-        rand::fill_random(&mut nonce);
+        rand::fill_random(rng, &mut nonce);
 
         let mut out = Vec::new();
         out.extend_from_slice(&nonce);
@@ -94,17 +95,21 @@ impl ProducesTickets for AEADTicketer {
 }
 
 struct TicketSwitcherState {
-    current: Box<ProducesTickets>,
-    previous: Option<Box<ProducesTickets>>,
+    /// The current ticketer first, then previous ones, newest first.
+    epochs: VecDeque<Box<ProducesTickets>>,
     next_switch_time: i64,
 }
 
-/// A ticketer that has a 'current' sub-ticketer and a single
-/// 'previous' ticketer.  It creates a new ticketer every so
-/// often, demoting the current ticketer.
+/// A ticketer that has a 'current' sub-ticketer and some number
+/// of 'previous' ticketers.  It creates a new ticketer every so
+/// often, demoting the current ticketer and erasing the oldest.
+///
+/// New ticketers get their keys from the `SecureRandom` passed
+/// to `encrypt`, so the first one is made by the first `encrypt`.
 pub struct TicketSwitcher {
-    generator: fn() -> Box<ProducesTickets>,
+    generator: fn(&SecureRandom) -> Box<ProducesTickets>,
     lifetime: u32,
+    previous: usize,
     state: Mutex<TicketSwitcherState>,
 }
 
@@ -114,73 +119,100 @@ impl TicketSwitcher {
     /// longer than twice this duration.  `generator` produces a new
     /// `ProducesTickets` implementation.
     pub fn new(lifetime: u32,
-               generator: fn() -> Box<ProducesTickets>)
+               generator: fn(&SecureRandom) -> Box<ProducesTickets>)
                -> TicketSwitcher {
+        TicketSwitcher::with_previous(lifetime, 1, generator)
+    }
+
+    /// Like `new`, but keeps `previous` demoted ticketers rather
+    /// than one.  Tickets are accepted for no longer than
+    /// `previous + 1` times `lifetime`.
+    pub fn with_previous(lifetime: u32,
+                         previous: usize,
+                         generator: fn(&SecureRandom) -> Box<ProducesTickets>)
+                         -> TicketSwitcher {
         TicketSwitcher {
             generator: generator,
             lifetime: lifetime,
+            previous: previous,
             state: Mutex::new(TicketSwitcherState {
-                current: generator(),
-                previous: None,
+                epochs: VecDeque::new(),
                 next_switch_time: time::get_time().sec + lifetime as i64,
             }),
         }
     }
 
-    /// If it's time, demote the `current` ticketer to `previous` (so it
-    /// does no new encryptions but can do decryptions) and make a fresh
-    /// `current` ticketer.
+    /// If it's time, demote the current ticketer (so it does no new
+    /// encryptions but can do decryptions), make a fresh one, and
+    /// erase the oldest if we have too many.  The fresh one's key
+    /// comes from `rng`.
     ///
     /// Calling this regularly will ensure timely key erasure.  Otherwise,
-    /// key erasure will be delayed until the next encrypt/decrypt call.
-    pub fn maybe_roll(&self) {
+    /// the oldest key is erased at the next decrypt call, and the fresh
+    /// ticketer is made at the next encrypt call.
+    pub fn maybe_roll(&self, rng: &SecureRandom) {
+        // Check and roll under one lock, so concurrent callers can't
+        // both see it's time and roll twice.
         let mut state = self.state.lock().unwrap();
-        let now = time::get_time().sec;
+        self.maybe_roll_locked(&mut state, rng);
+    }
+
+    /// Demote the current ticketer now, whatever the time.  This
+    /// starts a new epoch, whose key comes from `rng`.
+    pub fn roll(&self, rng: &SecureRandom) {
+        let mut state = self.state.lock().unwrap();
+        self.roll_locked(&mut state, time::get_time().sec, rng);
+    }
 
-        if now > state.next_switch_time {
-            state.previous = Some(mem::replace(&mut state.current, (self.generator)()));
-            state.next_switch_time = now + self.lifetime as i64;
+    fn maybe_roll_locked(&self, state: &mut TicketSwitcherState, rng: &SecureRandom) {
+        let now = time::get_time().sec;
+        if state.epochs.is_empty() || now > state.next_switch_time {
+            self.roll_locked(state, now, rng);
         }
     }
+
+    fn roll_locked(&self, state: &mut TicketSwitcherState, now: i64, rng: &SecureRandom) {
+        state.epochs.push_front((self.generator)(rng));
+        state.epochs.truncate(self.previous + 1);
+        state.next_switch_time = now + self.lifetime as i64;
+    }
 }
 
 impl ProducesTickets for TicketSwitcher {
     fn get_lifetime(&self) -> u32 {
-        self.lifetime * 2
+        self.lifetime.saturating_mul(self.previous as u32 + 1)
     }
     fn enabled(&self) -> bool {
         true
     }
 
-    fn encrypt(&self, message: &[u8]) -> Option<Vec<u8>> {
-        self.maybe_roll();
-
-        self.state
-            .lock()
-            .unwrap()
-            .current
-            .encrypt(message)
+    fn encrypt(&self, message: &[u8], rng: &SecureRandom) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        self.maybe_roll_locked(&mut state, rng);
+        state.epochs[0].encrypt(message, rng)
     }
 
     fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
-        self.maybe_roll();
-
-        let state = self.state.lock().unwrap();
-        let rc = state.current.decrypt(ciphertext);
+        let mut state = self.state.lock().unwrap();
 
-        if rc.is_none() && state.previous.is_some() {
-            state.previous.as_ref().unwrap().decrypt(ciphertext)
-        } else {
-            rc
+        // We can't make a fresh ticketer without an rng, but we can
+        // still erase the key a roll would have dropped.
+        if time::get_time().sec > state.next_switch_time {
+            state.epochs.truncate(self.previous);
         }
+
+        state.epochs
+            .iter()
+            .filter_map(|ticketer| ticketer.decrypt(ciphertext))
+            .next()
     }
 }
 
 /// A concrete, safe ticket creation mechanism.
 pub struct Ticketer {}
 
-fn generate_inner() -> Box<ProducesTickets> {
-    Box::new(AEADTicketer::new())
+fn generate_inner(rng: &SecureRandom) -> Box<ProducesTickets> {
+    Box::new(AEADTicketer::new(rng))
 }
 
 impl Ticketer {
@@ -191,4 +223,134 @@ impl Ticketer {
     pub fn new() -> Box<ProducesTickets> {
         Box::new(TicketSwitcher::new(6 * 60 * 60, generate_inner))
     }
+
+    /// Make a Ticketer which changes key every `lifetime` seconds,
+    /// and keeps the keys of `previous` earlier epochs so their
+    /// tickets are still accepted.  Tickets are therefore valid
+    /// for `previous + 1` times `lifetime`.
+    pub fn with_rotation(lifetime: u32, previous: usize) -> Box<ProducesTickets> {
+        Box::new(TicketSwitcher::with_previous(lifetime, previous, generate_inner))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SystemRandom;
+
+    fn switcher(previous: usize) -> TicketSwitcher {
+        TicketSwitcher::with_previous(60 * 60, previous, generate_inner)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let rng = SystemRandom::new();
+        let t = switcher(1);
+        let ticket = t.encrypt(b"hello", &rng).unwrap();
+        assert_eq!(t.decrypt(&ticket), Some(b"hello".to_vec()));
+        assert_eq!(t.decrypt(&ticket[1..]), None);
+        assert_eq!(t.get_lifetime(), 2 * 60 * 60);
+    }
+
+    #[test]
+    fn test_previous_epoch_decrypts() {
+        let rng = SystemRandom::new();
+        let t = switcher(1);
+        let old = t.encrypt(b"old", &rng).unwrap();
+        t.roll(&rng);
+        let new = t.encrypt(b"new", &rng).unwrap();
+
+        assert_eq!(t.decrypt(&old), Some(b"old".to_vec()));
+        assert_eq!(t.decrypt(&new), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_expired_epoch_fails() {
+        let rng = SystemRandom::new();
+        let t = switcher(1);
+        let old = t.encrypt(b"old", &rng).unwrap();
+        t.roll(&rng);
+        let middle = t.encrypt(b"middle", &rng).unwrap();
+        t.roll(&rng);
+
+        assert_eq!(t.decrypt(&old), None);
+        assert_eq!(t.decrypt(&middle), Some(b"middle".to_vec()));
+    }
+
+    #[test]
+    fn test_more_epochs() {
+        let rng = SystemRandom::new();
+        let t = switcher(3);
+        assert_eq!(t.get_lifetime(), 4 * 60 * 60);
+
+        let tickets: Vec<Vec<u8>> = (0..5)
+            .map(|i| {
+                let ticket = t.encrypt(&[ i ], &rng).unwrap();
+                t.roll(&rng);
+                ticket
+            })
+            .collect();
+
+        // We've rolled five times, so the current epoch has no tickets,
+        // and we keep the three before it.
+        assert_eq!(t.decrypt(&tickets[0]), None);
+        assert_eq!(t.decrypt(&tickets[1]), None);
+        assert_eq!(t.decrypt(&tickets[2]), Some(vec![ 2 ]));
+        assert_eq!(t.decrypt(&tickets[4]), Some(vec![ 4 ]));
+    }
+
+    #[test]
+    fn test_due_roll_happens_once() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let t = Arc::new(switcher(1));
+        let old = t.encrypt(b"old", &SystemRandom::new()).unwrap();
+        t.state.lock().unwrap().next_switch_time = 0;
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let t = t.clone();
+                thread::spawn(move || t.maybe_roll(&SystemRandom::new()))
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Rolling twice would have lost the old epoch.
+        assert_eq!(t.decrypt(&old), Some(b"old".to_vec()));
+    }
+
+    #[test]
+    fn test_due_decrypt_erases_oldest_epoch() {
+        let rng = SystemRandom::new();
+        let t = switcher(1);
+        let old = t.encrypt(b"old", &rng).unwrap();
+        t.roll(&rng);
+        let new = t.encrypt(b"new", &rng).unwrap();
+        t.state.lock().unwrap().next_switch_time = 0;
+
+        assert_eq!(t.decrypt(&old), None);
+        assert_eq!(t.decrypt(&new), Some(b"new".to_vec()));
+    }
+
+    struct FixedRandom(u8);
+
+    impl SecureRandom for FixedRandom {
+        fn fill(&self, bytes: &mut [u8]) -> Result<(), ()> {
+            for b in bytes.iter_mut() {
+                *b = self.0;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_keys_and_nonces_come_from_given_rng() {
+        let a = switcher(1).encrypt(b"hello", &FixedRandom(0x55)).unwrap();
+        let b = switcher(1).encrypt(b"hello", &FixedRandom(0x55)).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(&a[..12], &[ 0x55; 12 ]);
+    }
 }
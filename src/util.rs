@@ -1,3 +1,6 @@
+use std::ptr;
+use std::sync::atomic;
+
 /// Return the first member of `prefs` that appears in `avail`.
 pub fn first_in_both<T: Clone + PartialEq>(prefs: &[T], avail: &[T]) -> Option<T> {
     for p in prefs {
@@ -8,3 +11,13 @@ pub fn first_in_both<T: Clone + PartialEq>(prefs: &[T], avail: &[T]) -> Option<T
 
     None
 }
+
+/// Overwrite `buf` with zeroes.  The writes are volatile, so they
+/// happen even if `buf` is never read again.
+#[allow(unsafe_code)]
+pub fn zero(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        unsafe { ptr::write_volatile(b, 0u8) };
+    }
+    atomic::compiler_fence(atomic::Ordering::SeqCst);
+}
@@ -9,12 +9,12 @@ use std::collections::VecDeque;
 /// more complexity when reading out.
 pub struct ChunkVecBuffer {
     chunks: VecDeque<Vec<u8>>,
-    limit: usize,
+    limit: Option<usize>,
 }
 
 impl ChunkVecBuffer {
     pub fn new() -> ChunkVecBuffer {
-        ChunkVecBuffer { chunks: VecDeque::new(), limit: 0 }
+        ChunkVecBuffer { chunks: VecDeque::new(), limit: None }
     }
 
     /// Sets the upper limit on how many bytes this
@@ -23,11 +23,16 @@ impl ChunkVecBuffer {
     /// Setting a lower limit than the currently stored
     /// data is not an error.
     ///
-    /// A zero limit is interpreted as no limit.
-    pub fn set_limit(&mut self, new_limit: usize) {
+    /// A limit of `None` is interpreted as no limit.
+    pub fn set_limit(&mut self, new_limit: Option<usize>) {
         self.limit = new_limit;
     }
 
+    /// Discard everything we're storing.
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+    }
+
     /// If we're empty
     pub fn is_empty(&self) -> bool {
         self.chunks.is_empty()
@@ -46,11 +51,11 @@ impl ChunkVecBuffer {
     /// bytes should we actually append to adhere to the
     /// currently set `limit`?
     pub fn apply_limit(&self, len: usize) -> usize {
-        if self.limit == 0 {
-            len
-        } else {
-            let space =self.limit.saturating_sub(self.len());
+        if let Some(limit) = self.limit {
+            let space = limit.saturating_sub(self.len());
             cmp::min(len, space)
+        } else {
+            len
         }
     }
 
@@ -73,6 +78,18 @@ impl ChunkVecBuffer {
         len
     }
 
+    /// Take the given `bytes`, and put them before everything
+    /// we're already storing.
+    pub fn prepend(&mut self, bytes: Vec<u8>) -> usize {
+        let len = bytes.len();
+
+        if !bytes.is_empty() {
+            self.chunks.push_front(bytes);
+        }
+
+        len
+    }
+
     /// Take one of the chunks from this object.  This
     /// function panics if the object `is_empty`.
     pub fn take_one(&mut self) -> Vec<u8> {
@@ -99,6 +116,27 @@ impl ChunkVecBuffer {
         Ok(offs)
     }
 
+    /// The first of our chunks, or an empty slice if we're empty.
+    /// Use `consume` to move on to the rest.
+    pub fn peek(&self) -> &[u8] {
+        self.chunks.front()
+            .map(|ch| ch.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Discard the first `used` bytes we're storing, or everything
+    /// if we have fewer.
+    pub fn consume(&mut self, mut used: usize) {
+        while used > 0 && !self.is_empty() {
+            if used >= self.chunks[0].len() {
+                used -= self.take_one().len();
+            } else {
+                self.chunks[0] = self.chunks[0].split_off(used);
+                used = 0;
+            }
+        }
+    }
+
     /// Read data of this object, passing it `wr`
     pub fn write_to(&mut self, wr: &mut io::Write) -> io::Result<usize> {
         // would desperately like writev support here!
@@ -126,7 +164,7 @@ mod test {
     fn short_append_copy_with_limit()
     {
         let mut cvb = ChunkVecBuffer::new();
-        cvb.set_limit(12);
+        cvb.set_limit(Some(12));
         assert_eq!(cvb.append_limited_copy(b"hello"), 5);
         assert_eq!(cvb.append_limited_copy(b"world"), 5);
         assert_eq!(cvb.append_limited_copy(b"hello"), 2);
@@ -137,4 +175,44 @@ mod test {
         assert_eq!(buf.to_vec(),
                    b"helloworldhe".to_vec());
     }
+
+    #[test]
+    fn peek_and_consume()
+    {
+        let mut cvb = ChunkVecBuffer::new();
+        assert_eq!(cvb.peek(), b"");
+        cvb.append(b"hello".to_vec());
+        cvb.append(b"world".to_vec());
+
+        assert_eq!(cvb.peek(), b"hello");
+        cvb.consume(2);
+        assert_eq!(cvb.peek(), b"llo");
+        cvb.consume(3);
+        assert_eq!(cvb.peek(), b"world");
+        cvb.consume(0);
+        assert_eq!(cvb.peek(), b"world");
+        cvb.consume(100);
+        assert_eq!(cvb.peek(), b"");
+        assert!(cvb.is_empty());
+
+        cvb.append(b"hello".to_vec());
+        cvb.append(b"world".to_vec());
+        cvb.consume(7);
+        assert_eq!(cvb.peek(), b"rld");
+        assert_eq!(cvb.len(), 3);
+    }
+
+    #[test]
+    fn prepend_goes_first()
+    {
+        let mut cvb = ChunkVecBuffer::new();
+        cvb.append(b"world".to_vec());
+        assert_eq!(cvb.prepend(b"hello".to_vec()), 5);
+        assert_eq!(cvb.prepend(Vec::new()), 0);
+
+        let mut buf = [0u8; 10];
+        assert_eq!(cvb.read(&mut buf).unwrap(), 10);
+        assert_eq!(buf.to_vec(),
+                   b"helloworld".to_vec());
+    }
 }
@@ -1,12 +1,18 @@
 use webpki;
+use ring::digest;
+use ring::signature;
 use time;
 use untrusted;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
 
 use key::Certificate;
 use msgs::handshake::DigitallySignedStruct;
 use msgs::enums::SignatureScheme;
 use error::TLSError;
 use anchors::RootCertStore;
+use session::SessionRandoms;
+use x509;
 
 type SignatureAlgorithms = &'static [&'static webpki::SignatureAlgorithm];
 
@@ -45,17 +51,42 @@ pub trait ClientCertVerifier : Send + Sync {
                           presented_certs: &[Certificate]) -> Result<(), TLSError>;
 }
 
-pub struct WebPKIVerifier {}
+/// A source of the current time, used to check certificates
+/// are valid.
+pub trait TimeProvider : Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// The default `TimeProvider`, which uses the system clock.
+pub struct SystemTimeProvider {}
+
+impl TimeProvider for SystemTimeProvider {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// How far, in seconds, we allow our clock to be from those of our
+/// peers and certificate issuers by default: five minutes.
+pub const DEFAULT_CLOCK_SKEW_SECS: u64 = 5 * 60;
+
+/// The smallest RSA key, in bits, we accept in a certificate chain by
+/// default.
+pub const DEFAULT_MIN_RSA_KEY_BITS: usize = 2048;
+
+pub struct WebPKIVerifier {
+    time: Arc<Box<TimeProvider>>,
+    clock_skew: Duration,
+    min_rsa_key_bits: usize,
+}
 
 impl ServerCertVerifier for WebPKIVerifier {
     fn verify_server_cert(&self,
                           roots: &RootCertStore,
                           presented_certs: &[Certificate],
                           dns_name: &str) -> Result<(), TLSError> {
-        let cert = self.verify_common_cert(roots, presented_certs)?;
-
-        cert.verify_is_valid_for_dns_name(untrusted::Input::from(dns_name.as_bytes()))
-            .map_err(TLSError::WebPKIError)
+        self.verify_server_cert_at(roots, presented_certs, dns_name, to_timespec(self.time.now()))
     }
 }
 
@@ -63,17 +94,64 @@ impl ClientCertVerifier for WebPKIVerifier {
     fn verify_client_cert(&self,
                           roots: &RootCertStore,
                           presented_certs: &[Certificate]) -> Result<(), TLSError> {
-        self.verify_common_cert(roots, presented_certs).map(|_| ())
+        self.verify_common_cert(roots, presented_certs, to_timespec(self.time.now())).map(|_| ())
     }
 }
 
 impl WebPKIVerifier {
-    /// Check `presented_certs` is non-empty and rooted in `roots`.
-    /// Return the `webpki::EndEntityCert` for the top certificate
-    /// in `presented_certs`.
+    pub fn new() -> WebPKIVerifier {
+        WebPKIVerifier::with_time_provider(Arc::new(Box::new(SystemTimeProvider {})))
+    }
+
+    pub fn with_time_provider(time: Arc<Box<TimeProvider>>) -> WebPKIVerifier {
+        WebPKIVerifier::with_clock_skew(time, Duration::from_secs(DEFAULT_CLOCK_SKEW_SECS))
+    }
+
+    /// Like `with_time_provider`, but certificates are accepted if
+    /// valid at any time within `clock_skew` of now.
+    pub fn with_clock_skew(time: Arc<Box<TimeProvider>>, clock_skew: Duration) -> WebPKIVerifier {
+        WebPKIVerifier::with_min_rsa_key_bits(time, clock_skew, DEFAULT_MIN_RSA_KEY_BITS)
+    }
+
+    /// Like `with_clock_skew`, but certificates with RSA keys
+    /// smaller than `min_rsa_key_bits` are refused.
+    pub fn with_min_rsa_key_bits(time: Arc<Box<TimeProvider>>,
+                                 clock_skew: Duration,
+                                 min_rsa_key_bits: usize) -> WebPKIVerifier {
+        WebPKIVerifier {
+            time: time,
+            clock_skew: clock_skew,
+            min_rsa_key_bits: min_rsa_key_bits,
+        }
+    }
+
+    /// Check `presented_certs` is valid at `now`, rooted in `roots`,
+    /// and that its top certificate quotes `dns_name`.
+    fn verify_server_cert_at(&self,
+                             roots: &RootCertStore,
+                             presented_certs: &[Certificate],
+                             dns_name: &str,
+                             now: time::Timespec) -> Result<(), TLSError> {
+        let cert = self.verify_common_cert(roots, presented_certs, now)?;
+
+        // No certificate is valid for a name with embedded NULs or
+        // other control characters.
+        if dns_name.chars().any(char::is_control) {
+            return Err(TLSError::WebPKIError(webpki::Error::CertNotValidForName));
+        }
+
+        cert.verify_is_valid_for_dns_name(untrusted::Input::from(dns_name.as_bytes()))
+            .map_err(TLSError::WebPKIError)
+    }
+
+    /// Check `presented_certs` is non-empty and rooted in `roots`,
+    /// valid at `now`, give or take our clock skew, and has no RSA
+    /// keys which are too small.  Return the `webpki::EndEntityCert`
+    /// for the top certificate in `presented_certs`.
     fn verify_common_cert<'a>(&self,
                               roots: &RootCertStore,
-                              presented_certs: &'a [Certificate])
+                              presented_certs: &'a [Certificate],
+                              now: time::Timespec)
                               -> Result<webpki::EndEntityCert<'a>, TLSError> {
         if presented_certs.is_empty() {
             return Err(TLSError::NoCertificatesPresented);
@@ -94,12 +172,197 @@ impl WebPKIVerifier {
             .map(|x| x.to_trust_anchor())
             .collect();
 
-        cert.verify_is_valid_tls_server_cert(SUPPORTED_SIG_ALGS, &trustroots, &chain, time::get_time())
-            .map_err(TLSError::WebPKIError)
-            .map(|_| cert)
+        let skew = time::Duration::seconds(self.clock_skew.as_secs() as i64);
+        let result = {
+            let verify_at = |t| cert.verify_is_valid_tls_server_cert(SUPPORTED_SIG_ALGS,
+                                                                     &trustroots, &chain, t);
+
+            // A certificate which isn't valid now may be if our clock
+            // is a little fast or slow.
+            match verify_at(now) {
+                Err(webpki::Error::CertExpired) => verify_at(now - skew),
+                Err(webpki::Error::CertNotValidYet) => verify_at(now + skew),
+                result => result,
+            }
+        };
+
+        result.map_err(TLSError::WebPKIError)?;
+        self.check_rsa_key_sizes(presented_certs)?;
+        Ok(cert)
+    }
+
+    /// Check no certificate in `certs` has an RSA key smaller than
+    /// our minimum.
+    fn check_rsa_key_sizes(&self, certs: &[Certificate]) -> Result<(), TLSError> {
+        for cert in certs {
+            let info = x509::CertificateInfo::parse(&cert.0)
+                .ok_or(TLSError::WebPKIError(webpki::Error::BadDER))?;
+
+            match x509::rsa_key_bits(info.spki) {
+                Some(bits) if bits < self.min_rsa_key_bits => {
+                    let msg = format!("certificate has {}-bit RSA key, we need at least {} bits",
+                                      bits, self.min_rsa_key_bits);
+                    return Err(TLSError::PeerIncompatibleError(msg));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn to_timespec(t: SystemTime) -> time::Timespec {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(after) => time::Timespec::new(after.as_secs() as i64, after.subsec_nanos() as i32),
+        Err(before) => time::Timespec::new(-(before.duration().as_secs() as i64), 0),
+    }
+}
+
+/// Verify `chain` as a server certificate chain for `dns_name`,
+/// rooted in `roots`, as if the time were `now`.  This makes the
+/// same checks as a `ClientSession` does during a handshake.
+///
+/// `chain` is DER-encoded certificates, end-entity first.
+pub fn verify_chain(roots: &RootCertStore,
+                    chain: &[Certificate],
+                    dns_name: &str,
+                    now: SystemTime) -> Result<(), TLSError> {
+    WebPKIVerifier::new().verify_server_cert_at(roots, chain, dns_name, to_timespec(now))
+}
+
+/// The ring algorithms which might verify a certificate signature
+/// using `scheme`.  Certificates name ECDSA algorithms by their hash
+/// alone, so those could be for either curve.
+fn cert_signature_algs(scheme: SignatureScheme) -> Vec<&'static signature::VerificationAlgorithm> {
+    match scheme {
+        SignatureScheme::RSA_PKCS1_SHA1 => vec![ &signature::RSA_PKCS1_2048_8192_SHA1 ],
+        SignatureScheme::RSA_PKCS1_SHA256 => vec![ &signature::RSA_PKCS1_2048_8192_SHA256 ],
+        SignatureScheme::RSA_PKCS1_SHA384 => vec![ &signature::RSA_PKCS1_2048_8192_SHA384 ],
+        SignatureScheme::RSA_PKCS1_SHA512 => vec![ &signature::RSA_PKCS1_2048_8192_SHA512 ],
+        SignatureScheme::ECDSA_NISTP256_SHA256 => {
+            vec![ &signature::ECDSA_P256_SHA256_ASN1, &signature::ECDSA_P384_SHA256_ASN1 ]
+        }
+        SignatureScheme::ECDSA_NISTP384_SHA384 => {
+            vec![ &signature::ECDSA_P256_SHA384_ASN1, &signature::ECDSA_P384_SHA384_ASN1 ]
+        }
+        SignatureScheme::ED25519 => vec![ &signature::ED25519 ],
+        _ => Vec::new(),
+    }
+}
+
+/// Whether the key in DER-encoded SubjectPublicKeyInfo `spki` made
+/// the signature on the certificate `subject`.
+fn issued_by(spki: &[u8], subject: &x509::CertificateInfo) -> bool {
+    let key = match x509::parse_spki(spki) {
+        Some((_, key)) => key,
+        None => return false,
+    };
+
+    let scheme = match x509::signature_scheme(subject.signature_algorithm) {
+        Some(scheme) => scheme,
+        None => return false,
+    };
+
+    cert_signature_algs(scheme).iter().any(|alg| {
+        signature::verify(*alg,
+                          untrusted::Input::from(key),
+                          untrusted::Input::from(subject.signed_data),
+                          untrusted::Input::from(subject.signature))
+            .is_ok()
+    })
+}
+
+/// Find the SubjectPublicKeyInfos of the certification path from
+/// the end-entity certificate `presented_certs[0]` to `roots`:
+/// the end-entity certificate, the intermediates that issued it,
+/// and the trust anchor.  Certificates in `presented_certs` which
+/// aren't part of the path are ignored.
+///
+/// webpki doesn't tell us which path it validated, so we rebuild it
+/// the same way: each link must match by name, and each intermediate
+/// and the trust anchor must have signed the certificate below it.
+/// The SubjectPublicKeyInfos are DER-encoded, as pinned by RFC7469.
+fn validated_path_spkis(roots: &RootCertStore,
+                        presented_certs: &[Certificate])
+                        -> Result<Vec<Vec<u8>>, TLSError> {
+    let bad_der = TLSError::WebPKIError(webpki::Error::BadDER);
+    let mut infos = Vec::new();
+    for cert in presented_certs {
+        infos.push(x509::CertificateInfo::parse(&cert.0).ok_or(bad_der.clone())?);
+    }
+
+    if infos.is_empty() {
+        return Err(TLSError::NoCertificatesPresented);
+    }
+
+    let mut path = vec![ infos[0].spki.to_vec() ];
+    let mut used = vec![ false; infos.len() ];
+    let mut current = 0;
+    used[0] = true;
+
+    loop {
+        // Trust anchors keep their SubjectPublicKeyInfo without its
+        // outer SEQUENCE.
+        let anchor = roots.roots
+            .iter()
+            .map(|ota| ota.to_trust_anchor())
+            .filter(|ta| ta.subject == infos[current].issuer)
+            .map(|ta| {
+                let mut spki = ta.spki.to_vec();
+                x509::wrap_in_sequence(&mut spki);
+                spki
+            })
+            .find(|spki| issued_by(spki, &infos[current]));
+
+        if let Some(spki) = anchor {
+            path.push(spki);
+            return Ok(path);
+        }
+
+        let next = (0..infos.len()).find(|&i| {
+            !used[i] && infos[i].subject == infos[current].issuer &&
+                issued_by(infos[i].spki, &infos[current])
+        });
+
+        match next {
+            Some(i) => {
+                path.push(infos[i].spki.to_vec());
+                used[i] = true;
+                current = i;
+            }
+            None => return Err(TLSError::WebPKIError(webpki::Error::UnknownIssuer)),
+        }
     }
 }
 
+/// Check some certificate in the path from `presented_certs` to
+/// `roots` has a key whose SHA-256 SubjectPublicKeyInfo hash is
+/// in `pins`.  If `pins` is empty, we don't pin keys, so this is
+/// always true.  `presented_certs` must already have been verified.
+///
+/// Only the end-entity certificate, the intermediates which issued
+/// it and the trust anchor count: a pinned certificate tacked on to
+/// the end of some other valid chain does not.
+pub fn verify_spki_pins(roots: &RootCertStore,
+                        presented_certs: &[Certificate],
+                        pins: &[[u8; 32]]) -> Result<(), TLSError> {
+    if pins.is_empty() {
+        return Ok(());
+    }
+
+    for spki in validated_path_spkis(roots, presented_certs)? {
+        let hash = digest::digest(&digest::SHA256, &spki);
+
+        if pins.iter().any(|pin| &pin[..] == hash.as_ref()) {
+            return Ok(());
+        }
+    }
+
+    Err(TLSError::PeerIncompatibleError("server certificate chain matches no pinned key"
+                                        .to_string()))
+}
+
 static ECDSA_SHA256: SignatureAlgorithms = &[&webpki::ECDSA_P256_SHA256,
                                              &webpki::ECDSA_P384_SHA256];
 static ECDSA_SHA384: SignatureAlgorithms = &[&webpki::ECDSA_P256_SHA384,
@@ -154,6 +417,17 @@ fn verify_sig_using_any_alg(cert: &webpki::EndEntityCert,
     Err(webpki::Error::UnsupportedSignatureAlgorithmForPublicKey)
 }
 
+/// Build the message a TLS1.2 ServerKeyExchange signs: the client's
+/// random, then the server's, then the encoded key exchange `params`.
+/// Covering both randoms binds the signature to this handshake.
+pub fn construct_tls12_server_kx_message(randoms: &SessionRandoms, params: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::new();
+    msg.extend_from_slice(&randoms.client);
+    msg.extend_from_slice(&randoms.server);
+    msg.extend_from_slice(params);
+    msg
+}
+
 /// Verify the signed `message` using the public key quoted in
 /// `cert` and algorithm and signature in `dss`.
 ///
@@ -164,6 +438,10 @@ pub fn verify_signed_struct(message: &[u8],
                             dss: &DigitallySignedStruct)
                             -> Result<(), TLSError> {
 
+    if dss.scheme == SignatureScheme::ED25519 {
+        return verify_ed25519(cert, message, &dss.sig.0);
+    }
+
     let possible_algs = convert_scheme(dss.scheme)?;
     let cert_in = untrusted::Input::from(&cert.0);
     let cert = webpki::EndEntityCert::from(cert_in)
@@ -190,24 +468,102 @@ fn convert_alg_tls13(scheme: SignatureScheme)
     }
 }
 
+/// Check `sig` is an Ed25519 signature over `msg` by the key `cert`
+/// certifies.  webpki doesn't do Ed25519, so we do this ourselves.
+/// Ed25519 signs the whole message: there's no hash to choose.
+fn verify_ed25519(cert: &Certificate, msg: &[u8], sig: &[u8]) -> Result<(), TLSError> {
+    let info = x509::CertificateInfo::parse(&cert.0)
+        .ok_or(TLSError::WebPKIError(webpki::Error::BadDER))?;
+    let key = x509::ed25519_public_key(info.spki)
+        .ok_or(TLSError::WebPKIError(webpki::Error::UnsupportedSignatureAlgorithmForPublicKey))?;
+
+    signature::verify(&signature::ED25519,
+                      untrusted::Input::from(key),
+                      untrusted::Input::from(msg),
+                      untrusted::Input::from(sig))
+        .map_err(|_| TLSError::WebPKIError(webpki::Error::InvalidSignatureForPublicKey))
+}
+
+/// The context string a server's TLS1.3 CertificateVerify signs,
+/// with its terminating NUL.
+pub static SERVER_CERT_VERIFY_CONTEXT: &'static [u8] = b"TLS 1.3, server CertificateVerify\x00";
+
+/// The context string a client's TLS1.3 CertificateVerify signs,
+/// with its terminating NUL.
+pub static CLIENT_CERT_VERIFY_CONTEXT: &'static [u8] = b"TLS 1.3, client CertificateVerify\x00";
+
+/// Build the message a TLS1.3 CertificateVerify signs: 64 spaces,
+/// then `context_string_with_0`, then `handshake_hash`.
+pub fn construct_tls13_verify_message(handshake_hash: &[u8],
+                                      context_string_with_0: &[u8])
+                                      -> Vec<u8> {
+    let mut msg = Vec::new();
+    msg.resize(64, 0x20u8);
+    msg.extend_from_slice(context_string_with_0);
+    msg.extend_from_slice(handshake_hash);
+    msg
+}
+
 pub fn verify_tls13(cert: &Certificate,
                     dss: &DigitallySignedStruct,
                     handshake_hash: &[u8],
                     context_string_with_0: &[u8])
                     -> Result<(), TLSError> {
-    let alg = convert_alg_tls13(dss.scheme)?;
+    let msg = construct_tls13_verify_message(handshake_hash, context_string_with_0);
+    verify_signed_message_tls13(cert, dss, &msg)
+}
 
-    let mut msg = Vec::new();
-    msg.resize(64, 0x20u8);
-    msg.extend_from_slice(context_string_with_0);
-    msg.extend_from_slice(handshake_hash);
+/// Check `dss` is `cert`'s signature over `msg`, using a scheme
+/// allowed in TLS1.3.
+pub fn verify_signed_message_tls13(cert: &Certificate,
+                                   dss: &DigitallySignedStruct,
+                                   msg: &[u8])
+                                   -> Result<(), TLSError> {
+    if dss.scheme == SignatureScheme::ED25519 {
+        return verify_ed25519(cert, msg, &dss.sig.0);
+    }
+
+    let alg = convert_alg_tls13(dss.scheme)?;
 
     let cert_in = untrusted::Input::from(&cert.0);
     let cert = webpki::EndEntityCert::from(cert_in)
         .map_err(TLSError::WebPKIError)?;
 
     cert.verify_signature(alg,
-                          untrusted::Input::from(&msg),
+                          untrusted::Input::from(msg),
                           untrusted::Input::from(&dss.sig.0))
     .map_err(TLSError::WebPKIError)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tls13_verify_message() {
+        let hash = [ 0xaa; 32 ];
+        let msg = construct_tls13_verify_message(&hash, SERVER_CERT_VERIFY_CONTEXT);
+        assert_eq!(msg.len(), 64 + 34 + 32);
+        assert!(msg[..64].iter().all(|b| *b == 0x20));
+        assert_eq!(&msg[64..98], b"TLS 1.3, server CertificateVerify\x00");
+        assert_eq!(&msg[98..], &hash);
+
+        let msg = construct_tls13_verify_message(&hash, CLIENT_CERT_VERIFY_CONTEXT);
+        assert_eq!(&msg[64..98], b"TLS 1.3, client CertificateVerify\x00");
+    }
+
+    #[test]
+    fn test_tls12_server_kx_message() {
+        let randoms = SessionRandoms {
+            we_are_client: true,
+            client: [ 0x11; 32 ],
+            server: [ 0x22; 32 ],
+            have_peer: true,
+        };
+        let msg = construct_tls12_server_kx_message(&randoms, &[ 0x03, 0x00, 0x1d ]);
+        assert_eq!(msg.len(), 67);
+        assert_eq!(&msg[..32], &[ 0x11; 32 ]);
+        assert_eq!(&msg[32..64], &[ 0x22; 32 ]);
+        assert_eq!(&msg[64..], &[ 0x03, 0x00, 0x1d ]);
+    }
+}
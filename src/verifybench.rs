@@ -35,8 +35,6 @@ fn bench<Fsetup, Ftest, S>(count: usize, name: &'static str, f_setup: Fsetup, f_
              times.iter().min().unwrap() / 1000);
 }
 
-static V: &'static verify::WebPKIVerifier = &verify::WebPKIVerifier {};
-
 #[test]
 fn test_reddit_cert() {
     let cert0 = key::Certificate(include_bytes!("testdata/cert-reddit.0.der").to_vec());
@@ -44,9 +42,10 @@ fn test_reddit_cert() {
     let chain = [ cert0, cert1 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_trust_anchors(&webpki_roots::ROOTS);
+    let v = verify::WebPKIVerifier::new();
     bench(100, "verify_server_cert(reddit)", 
           || (),
-          |_| V.verify_server_cert(&anchors, &chain[..], "reddit.com").unwrap());
+          |_| v.verify_server_cert(&anchors, &chain[..], "reddit.com").unwrap());
 }
 
 #[test]
@@ -56,9 +55,10 @@ fn test_github_cert() {
     let chain = [ cert0, cert1 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_trust_anchors(&webpki_roots::ROOTS);
+    let v = verify::WebPKIVerifier::new();
     bench(100, "verify_server_cert(github)", 
           || (),
-          |_| V.verify_server_cert(&anchors, &chain[..], "github.com").unwrap());
+          |_| v.verify_server_cert(&anchors, &chain[..], "github.com").unwrap());
 }
 
 #[test]
@@ -69,9 +69,10 @@ fn test_arstechnica_cert() {
     let chain = [ cert0, cert1, cert2 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_trust_anchors(&webpki_roots::ROOTS);
+    let v = verify::WebPKIVerifier::new();
     bench(100, "verify_server_cert(arstechnica)", 
           || (),
-          |_| V.verify_server_cert(&anchors, &chain[..], "arstechnica.com").unwrap());
+          |_| v.verify_server_cert(&anchors, &chain[..], "arstechnica.com").unwrap());
 }
 
 #[test]
@@ -82,9 +83,10 @@ fn test_servo_cert() {
     let chain = [ cert0, cert1, cert2 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_trust_anchors(&webpki_roots::ROOTS);
+    let v = verify::WebPKIVerifier::new();
     bench(100, "verify_server_cert(servo)", 
           || (),
-          |_| V.verify_server_cert(&anchors, &chain[..], "servo.org").unwrap());
+          |_| v.verify_server_cert(&anchors, &chain[..], "servo.org").unwrap());
 }
 
 #[test]
@@ -94,9 +96,10 @@ fn test_twitter_cert() {
     let chain = [ cert0, cert1 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_trust_anchors(&webpki_roots::ROOTS);
+    let v = verify::WebPKIVerifier::new();
     bench(100, "verify_server_cert(twitter)", 
           || (),
-          |_| V.verify_server_cert(&anchors, &chain[..], "twitter.com").unwrap());
+          |_| v.verify_server_cert(&anchors, &chain[..], "twitter.com").unwrap());
 }
 
 #[test]
@@ -106,9 +109,10 @@ fn test_wikipedia_cert() {
     let chain = [ cert0, cert1 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_trust_anchors(&webpki_roots::ROOTS);
+    let v = verify::WebPKIVerifier::new();
     bench(100, "verify_server_cert(wikipedia)", 
           || (),
-          |_| V.verify_server_cert(&anchors, &chain[..], "wikipedia.org").unwrap());
+          |_| v.verify_server_cert(&anchors, &chain[..], "wikipedia.org").unwrap());
 }
 
 #[test]
@@ -119,9 +123,10 @@ fn test_google_cert() {
     let chain = [ cert0, cert1, cert2 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_trust_anchors(&webpki_roots::ROOTS);
+    let v = verify::WebPKIVerifier::new();
     bench(100, "verify_server_cert(google)", 
           || (),
-          |_| V.verify_server_cert(&anchors, &chain[..], "www.google.com").unwrap());
+          |_| v.verify_server_cert(&anchors, &chain[..], "www.google.com").unwrap());
 }
 
 #[test]
@@ -132,9 +137,10 @@ fn test_hn_cert() {
     let chain = [ cert0, cert1, cert2 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_trust_anchors(&webpki_roots::ROOTS);
+    let v = verify::WebPKIVerifier::new();
     bench(100, "verify_server_cert(hn)", 
           || (),
-          |_| V.verify_server_cert(&anchors, &chain[..], "news.ycombinator.com").unwrap());
+          |_| v.verify_server_cert(&anchors, &chain[..], "news.ycombinator.com").unwrap());
 }
 
 #[test]
@@ -144,9 +150,10 @@ fn test_stackoverflow_cert() {
     let chain = [ cert0, cert1 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_trust_anchors(&webpki_roots::ROOTS);
+    let v = verify::WebPKIVerifier::new();
     bench(100, "verify_server_cert(stackoverflow)", 
           || (),
-          |_| V.verify_server_cert(&anchors, &chain[..], "stackoverflow.com").unwrap());
+          |_| v.verify_server_cert(&anchors, &chain[..], "stackoverflow.com").unwrap());
 }
 
 #[test]
@@ -156,9 +163,10 @@ fn test_duckduckgo_cert() {
     let chain = [ cert0, cert1 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_trust_anchors(&webpki_roots::ROOTS);
+    let v = verify::WebPKIVerifier::new();
     bench(100, "verify_server_cert(duckduckgo)", 
           || (),
-          |_| V.verify_server_cert(&anchors, &chain[..], "duckduckgo.com").unwrap());
+          |_| v.verify_server_cert(&anchors, &chain[..], "duckduckgo.com").unwrap());
 }
 
 #[test]
@@ -169,9 +177,10 @@ fn test_rustlang_cert() {
     let chain = [ cert0, cert1, cert2 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_trust_anchors(&webpki_roots::ROOTS);
+    let v = verify::WebPKIVerifier::new();
     bench(100, "verify_server_cert(rustlang)", 
           || (),
-          |_| V.verify_server_cert(&anchors, &chain[..], "www.rust-lang.org").unwrap());
+          |_| v.verify_server_cert(&anchors, &chain[..], "www.rust-lang.org").unwrap());
 }
 
 #[test]
@@ -182,8 +191,9 @@ fn test_wapo_cert() {
     let chain = [ cert0, cert1, cert2 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_trust_anchors(&webpki_roots::ROOTS);
+    let v = verify::WebPKIVerifier::new();
     bench(100, "verify_server_cert(wapo)", 
           || (),
-          |_| V.verify_server_cert(&anchors, &chain[..], "www.washingtonpost.com").unwrap());
+          |_| v.verify_server_cert(&anchors, &chain[..], "www.washingtonpost.com").unwrap());
 }
 
@@ -2,6 +2,8 @@
 
 use ring::der;
 
+use msgs::enums::SignatureScheme;
+
 fn wrap_in_asn1_len(bytes: &mut Vec<u8>) {
     let len = bytes.len();
 
@@ -22,3 +24,426 @@ pub fn wrap_in_sequence(bytes: &mut Vec<u8>) {
     wrap_in_asn1_len(bytes);
     bytes.insert(0, der::Tag::Sequence as u8);
 }
+
+/// Split `der` into the tag and contents of its first element,
+/// and whatever follows that element.
+fn read_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *try_ret!(der.get(0));
+    let first = *try_ret!(der.get(1)) as usize;
+
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let len_bytes = first & 0x7f;
+        if len_bytes == 0 || len_bytes > 3 || der.len() < 2 + len_bytes {
+            return None;
+        }
+
+        let len = der[2..2 + len_bytes]
+            .iter()
+            .fold(0, |acc, b| (acc << 8) | (*b as usize));
+        (len, 2 + len_bytes)
+    };
+
+    if der.len() - header < len {
+        return None;
+    }
+
+    Some((tag, &der[header..header + len], &der[header + len..]))
+}
+
+/// Like `read_element`, but the element must have tag `tag`.
+/// Returns its contents and whatever follows it.
+fn expect_element(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (got_tag, contents, rest) = try_ret!(read_element(der));
+
+    if got_tag == tag {
+        Some((contents, rest))
+    } else {
+        None
+    }
+}
+
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_EXPLICIT_VERSION: u8 = 0xa0;
+const TAG_EXPLICIT_EXTENSIONS: u8 = 0xa3;
+
+/// The parts of an X.509 certificate we need which webpki
+/// doesn't give us.
+pub struct CertificateInfo<'a> {
+    /// The start of the certificate's validity period, in seconds
+    /// since the UNIX epoch.
+    pub not_before: u64,
+
+    /// The contents of the certificate's issuer and subject Names,
+    /// without their outer SEQUENCE, as a `TrustAnchor` holds them.
+    pub issuer: &'a [u8],
+    pub subject: &'a [u8],
+
+    /// The certificate's DER-encoded SubjectPublicKeyInfo.
+    pub spki: &'a [u8],
+
+    /// The DER-encoded TBSCertificate, which is what the issuer
+    /// signed, and the signature over it.
+    pub signed_data: &'a [u8],
+    pub signature: &'a [u8],
+
+    /// The contents of the AlgorithmIdentifier for the issuer's
+    /// signature.
+    pub signature_algorithm: &'a [u8],
+
+    /// The certificate's extensions, as (OID, value) pairs.
+    pub extensions: Vec<(&'a [u8], &'a [u8])>,
+}
+
+impl<'a> CertificateInfo<'a> {
+    /// Parse a DER-encoded certificate.  This does no verification:
+    /// use webpki for that.
+    pub fn parse(cert: &'a [u8]) -> Option<CertificateInfo<'a>> {
+        let (cert, _) = try_ret!(expect_element(cert, TAG_SEQUENCE));
+        let (tbs, after_tbs) = try_ret!(expect_element(cert, TAG_SEQUENCE));
+        let signed_data = &cert[..cert.len() - after_tbs.len()];
+
+        // signatureAlgorithm, then signatureValue, whose first
+        // byte is the count of unused bits.
+        let (signature_algorithm, after_alg) = try_ret!(expect_element(after_tbs,
+                                                                       TAG_SEQUENCE));
+        let (signature, _) = try_ret!(expect_element(after_alg, TAG_BIT_STRING));
+        if signature.first() != Some(&0) {
+            return None;
+        }
+
+        // version, serialNumber, signature, issuer
+        let mut rest = tbs;
+        if rest.first() == Some(&TAG_EXPLICIT_VERSION) {
+            rest = try_ret!(read_element(rest)).2;
+        }
+        for _ in 0..2 {
+            rest = try_ret!(read_element(rest)).2;
+        }
+        let (issuer, rest) = try_ret!(expect_element(rest, TAG_SEQUENCE));
+
+        let (validity, rest) = try_ret!(expect_element(rest, TAG_SEQUENCE));
+        let (not_before_tag, not_before, _) = try_ret!(read_element(validity));
+        let not_before = try_ret!(parse_time(not_before_tag, not_before));
+
+        // subject, subjectPublicKeyInfo, then optional
+        // issuerUniqueID, subjectUniqueID and extensions.
+        let (subject, spki_onwards) = try_ret!(expect_element(rest, TAG_SEQUENCE));
+        let mut rest = try_ret!(read_element(spki_onwards)).2;
+        let spki = &spki_onwards[..spki_onwards.len() - rest.len()];
+
+        let mut extensions = Vec::new();
+        while !rest.is_empty() {
+            let (tag, contents, next) = try_ret!(read_element(rest));
+            rest = next;
+
+            if tag != TAG_EXPLICIT_EXTENSIONS {
+                continue;
+            }
+
+            let (mut exts, _) = try_ret!(expect_element(contents, TAG_SEQUENCE));
+            while !exts.is_empty() {
+                let (ext, next) = try_ret!(expect_element(exts, TAG_SEQUENCE));
+                exts = next;
+
+                let (oid, ext) = try_ret!(expect_element(ext, TAG_OID));
+                let ext = match expect_element(ext, TAG_BOOLEAN) {
+                    Some((_, after_critical)) => after_critical,
+                    None => ext,
+                };
+                let (value, _) = try_ret!(expect_element(ext, TAG_OCTET_STRING));
+                extensions.push((oid, value));
+            }
+        }
+
+        Some(CertificateInfo {
+            not_before: not_before,
+            issuer: issuer,
+            subject: subject,
+            spki: spki,
+            signed_data: signed_data,
+            signature: &signature[1..],
+            signature_algorithm: signature_algorithm,
+            extensions: extensions,
+        })
+    }
+
+    /// Whether the certificate has an extension with DER-encoded
+    /// OID `oid`.
+    pub fn has_extension(&self, oid: &[u8]) -> bool {
+        self.extensions.iter().any(|&(ext_oid, _)| ext_oid == oid)
+    }
+}
+
+/// Parse a UTCTime or GeneralizedTime, which must be in UTC and
+/// include seconds, as certificates' times are.  Returns seconds
+/// since the UNIX epoch.
+fn parse_time(tag: u8, value: &[u8]) -> Option<u64> {
+    let year_digits = match tag {
+        TAG_UTC_TIME => 2,
+        TAG_GENERALIZED_TIME => 4,
+        _ => return None,
+    };
+
+    if value.len() != year_digits + 11 || value[value.len() - 1] != b'Z' {
+        return None;
+    }
+
+    let digits = &value[..value.len() - 1];
+    if !digits.iter().all(|b| *b >= b'0' && *b <= b'9') {
+        return None;
+    }
+
+    let num = |from: usize, len: usize| {
+        digits[from..from + len]
+            .iter()
+            .fold(0u64, |acc, b| acc * 10 + (*b - b'0') as u64)
+    };
+
+    let mut year = num(0, year_digits);
+    if year_digits == 2 {
+        // RFC5280: two-digit years from 50 are in the 20th century.
+        year += if year >= 50 { 1900 } else { 2000 };
+    }
+
+    let (month, day) = (num(year_digits, 2), num(year_digits + 2, 2));
+    let (hour, minute, second) = (num(year_digits + 4, 2),
+                                  num(year_digits + 6, 2),
+                                  num(year_digits + 8, 2));
+
+    if year < 1970 || month < 1 || month > 12 || day < 1 || day > 31 ||
+        hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    Some(days_since_epoch(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// How many days there are between 1970-01-01 and the given
+/// date, which mustn't be before it.
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // Count from March, so any leap day is at the end of the year.
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era_days = year * 365 + year / 4 - year / 100 + year / 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+
+    // 719468 is the number of days from 0000-03-01 to 1970-01-01.
+    era_days + day_of_year - 719468
+}
+
+/// Split a DER-encoded SubjectPublicKeyInfo into the contents of
+/// its AlgorithmIdentifier, and the public key.
+pub fn parse_spki(spki: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (spki, rest) = try_ret!(expect_element(spki, TAG_SEQUENCE));
+    if !rest.is_empty() {
+        return None;
+    }
+
+    let (alg_id, rest) = try_ret!(expect_element(spki, TAG_SEQUENCE));
+    let (key, rest) = try_ret!(expect_element(rest, TAG_BIT_STRING));
+
+    // The key must be a whole number of bytes.
+    if !rest.is_empty() || key.first() != Some(&0) {
+        return None;
+    }
+
+    Some((alg_id, &key[1..]))
+}
+
+/// The DER-encoded OID of rsaEncryption.
+const RSA_ENCRYPTION: &'static [u8] = &[ 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d,
+                                          0x01, 0x01, 0x01 ];
+
+/// If DER-encoded SubjectPublicKeyInfo `spki` is for an RSA key,
+/// return the size of its modulus in bits.
+pub fn rsa_key_bits(spki: &[u8]) -> Option<usize> {
+    let (alg_id, key) = try_ret!(parse_spki(spki));
+    if !alg_id.starts_with(RSA_ENCRYPTION) {
+        return None;
+    }
+
+    let (key, _) = try_ret!(expect_element(key, TAG_SEQUENCE));
+    let (modulus, _) = try_ret!(expect_element(key, TAG_INTEGER));
+    let modulus = match modulus.iter().position(|b| *b != 0) {
+        Some(first) => &modulus[first..],
+        None => return Some(0),
+    };
+
+    Some(modulus.len() * 8 - modulus[0].leading_zeros() as usize)
+}
+
+/// The DER-encoded OIDs of the certificate signature algorithms
+/// we know, and the `SignatureScheme` each corresponds to.
+static SIGNATURE_ALGORITHMS: &'static [(&'static [u8], SignatureScheme)] = &[
+    (&[ 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x05 ],
+     SignatureScheme::RSA_PKCS1_SHA1),
+    (&[ 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b ],
+     SignatureScheme::RSA_PKCS1_SHA256),
+    (&[ 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c ],
+     SignatureScheme::RSA_PKCS1_SHA384),
+    (&[ 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d ],
+     SignatureScheme::RSA_PKCS1_SHA512),
+    (&[ 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02 ],
+     SignatureScheme::ECDSA_NISTP256_SHA256),
+    (&[ 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03 ],
+     SignatureScheme::ECDSA_NISTP384_SHA384),
+    (&[ 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x04 ],
+     SignatureScheme::ECDSA_NISTP521_SHA512),
+    (ED25519, SignatureScheme::ED25519),
+];
+
+/// The `SignatureScheme` a certificate was signed with, given its
+/// AlgorithmIdentifier.  None if we don't know the algorithm, as for
+/// RSASSA-PSS, whose hash is in its parameters.
+///
+/// ECDSA schemes are named by their hash alone in certificates, so
+/// we report the scheme with the same hash, whatever the curve.
+pub fn signature_scheme(alg_id: &[u8]) -> Option<SignatureScheme> {
+    SIGNATURE_ALGORITHMS.iter()
+        .find(|&&(oid, _)| alg_id.starts_with(oid))
+        .map(|&(_, scheme)| scheme)
+}
+
+/// The DER-encoded OID of id-Ed25519, from RFC8410.  This is
+/// the whole AlgorithmIdentifier: it has no parameters.
+const ED25519: &'static [u8] = &[ 0x06, 0x03, 0x2b, 0x65, 0x70 ];
+
+/// If DER-encoded SubjectPublicKeyInfo `spki` is for an Ed25519
+/// key, return the key.
+pub fn ed25519_public_key(spki: &[u8]) -> Option<&[u8]> {
+    let (alg_id, key) = try_ret!(parse_spki(spki));
+
+    if alg_id == ED25519 && key.len() == 32 {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+/// If DER-encoded PKCS#8 `der` is an Ed25519 private key, return
+/// its 32 byte seed.
+pub fn ed25519_private_key(der: &[u8]) -> Option<&[u8]> {
+    let (key, _) = try_ret!(expect_element(der, TAG_SEQUENCE));
+    let (_version, rest) = try_ret!(expect_element(key, TAG_INTEGER));
+    let (alg_id, rest) = try_ret!(expect_element(rest, TAG_SEQUENCE));
+    if alg_id != ED25519 {
+        return None;
+    }
+
+    // The private key is an OCTET STRING holding an OCTET STRING.
+    let (private_key, _) = try_ret!(expect_element(rest, TAG_OCTET_STRING));
+    let (seed, _) = try_ret!(expect_element(private_key, TAG_OCTET_STRING));
+
+    if seed.len() == 32 {
+        Some(seed)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// DelegationUsage, from RFC9345.
+    const DELEGATION_USAGE: &'static [u8] = &[ 0x2b, 0x06, 0x01, 0x04, 0x01,
+                                                0x82, 0xda, 0x4b, 0x2c ];
+
+    #[test]
+    fn test_parse_certificate() {
+        let cert = include_bytes!("testdata/cert-delegation.der");
+        let info = CertificateInfo::parse(cert).unwrap();
+        assert_eq!(info.not_before, 1481391753);
+        assert!(info.has_extension(DELEGATION_USAGE));
+
+        // Key usage.
+        assert!(info.has_extension(&[ 0x55, 0x1d, 0x0f ]));
+
+        let cert = include_bytes!("../test-ca/rsa/ca.der");
+        let info = CertificateInfo::parse(cert).unwrap();
+        assert_eq!(info.not_before, 1481391753);
+        assert!(!info.has_extension(DELEGATION_USAGE));
+
+        // The CA is self-signed.
+        assert_eq!(info.issuer, info.subject);
+        assert!(!info.signed_data.is_empty());
+        assert_eq!(info.signature.len(), 1024);
+    }
+
+    #[test]
+    fn test_parse_certificate_rejects_garbage() {
+        let cert = include_bytes!("testdata/cert-delegation.der");
+        assert!(CertificateInfo::parse(&cert[..cert.len() / 2]).is_none());
+        assert!(CertificateInfo::parse(&[]).is_none());
+        assert!(CertificateInfo::parse(&[ 0x30, 0x84, 0xff, 0xff, 0xff, 0xff ]).is_none());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time(TAG_UTC_TIME, b"700101000000Z"), Some(0));
+        assert_eq!(parse_time(TAG_UTC_TIME, b"161210174233Z"), Some(1481391753));
+        assert_eq!(parse_time(TAG_GENERALIZED_TIME, b"20161210174233Z"), Some(1481391753));
+        assert_eq!(parse_time(TAG_GENERALIZED_TIME, b"20000301000000Z"), Some(951868800));
+        assert_eq!(parse_time(TAG_UTC_TIME, b"491231235959Z"), Some(2524607999));
+        assert_eq!(parse_time(TAG_UTC_TIME, b"161210174233"), None);
+        assert_eq!(parse_time(TAG_UTC_TIME, b"161310174233Z"), None);
+        assert_eq!(parse_time(TAG_GENERALIZED_TIME, b"161210174233Z"), None);
+    }
+
+    #[test]
+    fn test_parse_spki() {
+        let spki = include_bytes!("../test-ca/rsa/dc.spki");
+        let (alg_id, key) = parse_spki(spki).unwrap();
+        assert_eq!(alg_id, &[ 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d,
+                              0x01, 0x01, 0x01, 0x05, 0x00 ]);
+        assert_eq!(key.len(), 270);
+
+        assert!(parse_spki(&spki[..spki.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn test_rsa_key_bits() {
+        let spki = include_bytes!("../test-ca/rsa/dc.spki");
+        assert_eq!(rsa_key_bits(spki), Some(2048));
+        assert_eq!(rsa_key_bits(&spki[..spki.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_signature_scheme() {
+        let cert = include_bytes!("../test-ca/rsa/ca.der");
+        let info = CertificateInfo::parse(cert).unwrap();
+        assert_eq!(signature_scheme(info.signature_algorithm),
+                   Some(SignatureScheme::RSA_PKCS1_SHA256));
+
+        // RSASSA-PSS.
+        assert_eq!(signature_scheme(&[ 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d,
+                                       0x01, 0x01, 0x0a ]),
+                   None);
+        assert_eq!(signature_scheme(&[]), None);
+    }
+
+    #[test]
+    fn test_ed25519_keys() {
+        let cert = include_bytes!("testdata/cert-ed25519.der");
+        let info = CertificateInfo::parse(cert).unwrap();
+        let key = ed25519_public_key(info.spki).unwrap();
+        assert_eq!(key.len(), 32);
+        assert_eq!(ed25519_public_key(&[]), None);
+
+        let spki = include_bytes!("../test-ca/rsa/dc.spki");
+        assert_eq!(ed25519_public_key(spki), None);
+
+        let der = include_bytes!("testdata/key-ed25519.der");
+        assert_eq!(ed25519_private_key(der).map(|seed| seed.len()), Some(32));
+        assert_eq!(ed25519_private_key(&der[..der.len() - 1]), None);
+        assert_eq!(ed25519_private_key(spki), None);
+    }
+}
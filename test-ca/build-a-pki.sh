@@ -127,3 +127,89 @@ for kt in rsa ecdsa ; do
 
   openssl asn1parse -in $kt/ca.cert -out $kt/ca.der > /dev/null
 done
+
+# a server certificate allowing delegated credentials, and
+# a key to delegate to.
+openssl x509 -req \
+          -in rsa/end.req \
+          -out rsa/end-dc.cert \
+          -CA rsa/inter.cert \
+          -CAkey rsa/inter.key \
+          -sha256 \
+          -days 2000 \
+          -set_serial 457 \
+          -extensions v3_end_dc -extfile openssl.cnf
+
+cat rsa/end-dc.cert rsa/inter.cert rsa/ca.cert > rsa/end-dc.fullchain
+
+openssl genrsa -traditional -out rsa/dc.rsa 2048
+openssl rsa -in rsa/dc.rsa -pubout -outform DER -out rsa/dc.spki
+
+# a server certificate with a key too small to accept.
+openssl req -nodes \
+          -newkey rsa:1024 \
+          -keyout rsa/end-1024.key \
+          -out rsa/end-1024.req \
+          -sha256 \
+          -batch \
+          -subj "/CN=testserver.com"
+
+openssl x509 -req \
+          -in rsa/end-1024.req \
+          -out rsa/end-1024.cert \
+          -CA rsa/inter.cert \
+          -CAkey rsa/inter.key \
+          -sha256 \
+          -days 2000 \
+          -set_serial 458 \
+          -extensions v3_end -extfile openssl.cnf
+
+cat rsa/end-1024.cert rsa/inter.cert rsa/ca.cert > rsa/end-1024.fullchain
+
+# a server certificate with an Ed25519 key.
+openssl genpkey -algorithm ed25519 -out rsa/end-ed25519.key
+
+openssl req -new \
+          -key rsa/end-ed25519.key \
+          -out rsa/end-ed25519.req \
+          -batch \
+          -subj "/CN=testserver.com"
+
+openssl x509 -req \
+          -in rsa/end-ed25519.req \
+          -out rsa/end-ed25519.cert \
+          -CA rsa/inter.cert \
+          -CAkey rsa/inter.key \
+          -sha256 \
+          -not_before 20161210174233Z \
+          -not_after 20220602174233Z \
+          -set_serial 459 \
+          -extensions v3_end -extfile openssl.cnf
+
+cat rsa/end-ed25519.cert rsa/inter.cert rsa/ca.cert > rsa/end-ed25519.fullchain
+
+# the usual server certificate, but with an SHA-1 signature.
+openssl x509 -req \
+          -in rsa/end.req \
+          -out rsa/end-sha1.cert \
+          -CA rsa/inter.cert \
+          -CAkey rsa/inter.key \
+          -sha1 \
+          -not_before 20161210174233Z \
+          -not_after 20220602174233Z \
+          -set_serial 460 \
+          -extensions v3_end -extfile openssl.cnf
+
+cat rsa/end-sha1.cert rsa/inter.cert rsa/ca.cert > rsa/end-sha1.fullchain
+
+# a CA with the same name as rsa/ca.cert, but another key.
+openssl req -nodes \
+          -x509 \
+          -not_before 20161210174233Z \
+          -not_after 20261208174233Z \
+          -newkey rsa:2048 \
+          -keyout rsa/ca-impostor.key \
+          -out rsa/ca-impostor.cert \
+          -sha256 \
+          -batch \
+          -subj "/CN=ponytown RSA CA"
@@ -1,19 +1,47 @@
 // Assorted public API tests.
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic;
 use std::fs;
 use std::io::{self, Write, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::thread;
+use std::net;
 
 extern crate rustls;
+extern crate webpki;
+#[cfg(feature = "dangerous_debug")]
+extern crate ring;
 use rustls::{ClientConfig, ClientSession, ResolvesClientCert};
-use rustls::{ServerConfig, ServerSession, ResolvesServerCert};
-use rustls::Session;
+use rustls::{StoresClientSessions, ClientSessionMemoryCache};
+use rustls::{ServerConfig, ServerSession, ResolvesServerCert, ClientHello};
+use rustls::MissingSNIPolicy;
+use rustls::{ServerSessionMemoryCache, Ticketer};
+use rustls::ReplayProtection;
+use rustls::{Session, Resumption};
 use rustls::Stream;
-use rustls::{ProtocolVersion, SignatureScheme};
+use rustls::{ProtocolVersion, SignatureScheme, NamedGroup};
+use rustls::{AlertDescription, CipherSuite};
+use rustls::KeyEncapsulation;
+use rustls::{CertCompressor, CertificateCompressionAlgorithm};
+use rustls::DelegatedCredential;
 use rustls::TLSError;
+use rustls::{RootCertStore, verify_chain, TimeProvider};
+use rustls::{SecureRandom, SystemRandom};
+use rustls::read_sni;
+#[cfg(feature = "dangerous_debug")]
+use rustls::ExtractedSecrets;
 use rustls::sign;
+use rustls::sign::Signer;
 use rustls::{Certificate, PrivateKey};
 use rustls::internal::pemfile;
+use rustls::internal::msgs::handshake::{ClientHelloPayload, ClientExtension, UnknownExtension};
+use rustls::internal::msgs::handshake::KeyShareEntry;
+use rustls::internal::msgs::handshake::SupportedSignatureSchemes;
+use rustls::internal::msgs::handshake::SupportedMandatedSignatureSchemes;
+use rustls::internal::msgs::enums::{ExtensionType, ContentType, Compression};
+use rustls::internal::msgs::enums::HandshakeType;
+use rustls::internal::msgs::enums::ECPointFormat;
+use rustls::internal::msgs::base::{Payload, PayloadU16};
 
 fn transfer(left: &mut Session, right: &mut Session) {
     let mut buf = [0u8; 262144];
@@ -46,6 +74,43 @@ fn get_key() -> PrivateKey {
         .clone()
 }
 
+fn read_certs(filename: &str) -> Vec<Certificate> {
+    pemfile::certs(&mut io::BufReader::new(fs::File::open(filename).unwrap()))
+        .unwrap()
+}
+
+#[test]
+fn pemfile_certs_preserves_order() {
+    let chain = get_chain();
+    assert_eq!(chain.len(), 3);
+    assert_eq!(chain[0], read_certs("test-ca/rsa/end.cert")[0]);
+    assert_eq!(chain[1], read_certs("test-ca/rsa/inter.cert")[0]);
+    assert_eq!(chain[2], read_certs("test-ca/rsa/ca.cert")[0]);
+}
+
+#[test]
+fn pemfile_certs_skips_other_sections() {
+    let mut pem = Vec::new();
+    for filename in &["test-ca/rsa/end.cert", "test-ca/rsa/end.rsa", "test-ca/rsa/ca.cert"] {
+        fs::File::open(filename).unwrap().read_to_end(&mut pem).unwrap();
+    }
+
+    let certs = pemfile::certs(&mut pem.as_slice()).unwrap();
+    assert_eq!(certs,
+               vec![ read_certs("test-ca/rsa/end.cert")[0].clone(),
+                     read_certs("test-ca/rsa/ca.cert")[0].clone() ]);
+}
+
+#[test]
+fn pemfile_certs_rejects_unterminated_section() {
+    let mut pem = Vec::new();
+    fs::File::open("test-ca/rsa/end.cert").unwrap().read_to_end(&mut pem).unwrap();
+    let truncated = pem.len() - b"-----END CERTIFICATE-----\n".len();
+    pem.truncate(truncated);
+
+    assert!(pemfile::certs(&mut pem.as_slice()).is_err());
+}
+
 fn make_server_config() -> ServerConfig {
     let mut cfg = ServerConfig::new();
     cfg.set_single_cert(get_chain(), get_key());
@@ -125,6 +190,169 @@ fn alpn() {
     alpn_test(vec!["PROTO".to_string()], vec!["proto".to_string()], None);
 }
 
+fn make_alps_configs(client_settings: Option<&[u8]>,
+                     server_settings: Option<&[u8]>) -> (ClientConfig, ServerConfig) {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    client_config.alpn_protocols = vec!["h2".to_string(), "http/1.1".to_string()];
+    server_config.alpn_protocols = vec!["h2".to_string()];
+
+    if let Some(settings) = client_settings {
+        client_config.application_settings.insert("h2".to_string(), settings.to_vec());
+    }
+    if let Some(settings) = server_settings {
+        server_config.application_settings.insert("h2".to_string(), settings.to_vec());
+    }
+
+    (client_config, server_config)
+}
+
+#[test]
+fn alps_exchanges_settings() {
+    let (client_config, server_config) = make_alps_configs(Some(&b"client-settings"[..]),
+                                                           Some(&b"server-settings"[..]));
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_alpn_protocol(), Some("h2".to_string()));
+    assert_eq!(client.get_peer_application_settings("h2"), Some(b"server-settings".to_vec()));
+    assert_eq!(server.get_peer_application_settings("h2"), Some(b"client-settings".to_vec()));
+    assert_eq!(client.get_peer_application_settings("http/1.1"), None);
+    assert_eq!(server.get_peer_application_settings("http/1.1"), None);
+
+    // The client's settings are part of the handshake transcript.
+    let transcript = server.handshake_transcript();
+    let last_two = transcript.iter()
+        .rev()
+        .take(2)
+        .map(|msg| msg.typ)
+        .collect::<Vec<HandshakeType>>();
+    assert_eq!(last_two, vec![ HandshakeType::Finished, HandshakeType::EncryptedExtensions ]);
+
+    client.write(b"hello").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+}
+
+#[test]
+fn alps_exchanges_empty_settings() {
+    let (client_config, server_config) = make_alps_configs(Some(&b""[..]), Some(&b""[..]));
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_peer_application_settings("h2"), Some(vec![]));
+    assert_eq!(server.get_peer_application_settings("h2"), Some(vec![]));
+}
+
+#[test]
+fn alps_not_negotiated() {
+    let cases = [ (None, Some(&b"server-settings"[..])),
+                  (Some(&b"client-settings"[..]), None),
+                  (None, None) ];
+
+    for &(client_settings, server_settings) in cases.iter() {
+        let (client_config, server_config) = make_alps_configs(client_settings,
+                                                               server_settings);
+        let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+        let mut server = ServerSession::new(&Arc::new(server_config));
+
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.get_alpn_protocol(), Some("h2".to_string()));
+        assert_eq!(client.get_peer_application_settings("h2"), None);
+        assert_eq!(server.get_peer_application_settings("h2"), None);
+    }
+}
+
+#[test]
+fn alps_needs_tls13() {
+    let (mut client_config, server_config) = make_alps_configs(Some(&b"client-settings"[..]),
+                                                               Some(&b"server-settings"[..]));
+    client_config.versions = vec![ ProtocolVersion::TLSv1_2 ];
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_alpn_protocol(), Some("h2".to_string()));
+    assert_eq!(client.get_peer_application_settings("h2"), None);
+    assert_eq!(server.get_peer_application_settings("h2"), None);
+}
+
+#[test]
+fn server_gets_offered_alpn() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    client_config.alpn_protocols = vec!["h2".to_string(), "http/1.1".to_string()];
+    server_config.alpn_protocols = vec!["http/1.1".to_string()];
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    assert_eq!(server.get_offered_alpn(), None);
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_offered_alpn(),
+               Some(&[ b"h2".to_vec(), b"http/1.1".to_vec() ][..]));
+    assert_eq!(server.get_alpn_protocol(), Some("http/1.1".to_string()));
+}
+
+#[test]
+fn server_gets_no_offered_alpn() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_offered_alpn(), None);
+}
+
+#[test]
+fn server_forces_alpn_protocol() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    client_config.alpn_protocols = vec!["http/1.1".to_string(), "h2".to_string()];
+    server_config.alpn_protocols = vec!["http/1.1".to_string()];
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    assert_eq!(server.set_alpn_protocol(b"h2"), Ok(()));
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_alpn_protocol(), Some("h2".to_string()));
+    assert_eq!(client.get_alpn_protocol(), Some("h2".to_string()));
+
+    assert!(server.set_alpn_protocol(b"http/1.1").is_err());
+}
+
+#[test]
+fn server_forces_alpn_protocol_client_did_not_offer() {
+    let mut client_config = make_client_config();
+    client_config.alpn_protocols = vec!["http/1.1".to_string()];
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    assert_eq!(server.set_alpn_protocol(b"h2"), Ok(()));
+
+    assert_eq!(do_handshake_until_error(&mut client, &mut server),
+               Err(TLSError::PeerIncompatibleError("client didn't offer the forced \
+                                                    ALPN protocol".to_string())));
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::NoApplicationProtocol)));
+}
+
+#[test]
+fn server_forces_alpn_protocol_client_offered_none() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    assert_eq!(server.set_alpn_protocol(b"h2"), Ok(()));
+
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+}
+
 fn version_test(client_versions: Vec<ProtocolVersion>,
                 server_versions: Vec<ProtocolVersion>,
                 result: Option<ProtocolVersion>) {
@@ -195,545 +423,5244 @@ fn versions() {
                  Some(ProtocolVersion::TLSv1_2));
 }
 
-fn check_read(reader: &mut io::Read, bytes: &[u8]) {
-    let mut buf = Vec::new();
-    assert_eq!(bytes.len(), reader.read_to_end(&mut buf).unwrap());
-    assert_eq!(bytes.to_vec(), buf);
-}
+fn peer_versions_test(client_versions: Vec<ProtocolVersion>,
+                      expect_server_sees: Vec<ProtocolVersion>,
+                      expect_client_sees: Vec<ProtocolVersion>) {
+    let mut client_config = make_client_config();
+    client_config.versions = client_versions;
 
-#[test]
-fn buffered_client_data_sent() {
-    let client_config = make_client_config();
-    let server_config = make_server_config();
     let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
-    let mut server = ServerSession::new(&Arc::new(server_config));
-
-    assert_eq!(5, client.write(b"hello").unwrap());
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    assert_eq!(client.get_peer_supported_versions(), None);
+    assert_eq!(server.get_peer_supported_versions(), None);
 
     do_handshake(&mut client, &mut server);
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-
-    check_read(&mut server, b"hello");
+    assert_eq!(server.get_peer_supported_versions(), Some(&expect_server_sees[..]));
+    assert_eq!(client.get_peer_supported_versions(), Some(&expect_client_sees[..]));
 }
 
 #[test]
-fn buffered_server_data_sent() {
-    let client_config = make_client_config();
-    let server_config = make_server_config();
-    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
-    let mut server = ServerSession::new(&Arc::new(server_config));
+fn peer_supported_versions() {
+    // The client offers the TLS1.3 draft we implement.
+    let tls13_draft = ProtocolVersion::Unknown(0x7f12);
+
+    peer_versions_test(vec![ ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2 ],
+                       vec![ tls13_draft, ProtocolVersion::TLSv1_2 ],
+                       vec![ tls13_draft ]);
+    peer_versions_test(vec![ ProtocolVersion::TLSv1_3 ],
+                       vec![ tls13_draft ],
+                       vec![ tls13_draft ]);
+    peer_versions_test(vec![ ProtocolVersion::TLSv1_2 ],
+                       vec![ ProtocolVersion::TLSv1_2 ],
+                       vec![ ProtocolVersion::TLSv1_2 ]);
+}
 
-    assert_eq!(5, server.write(b"hello").unwrap());
+fn check_transcript(sess: &Session, expect: &[HandshakeType]) {
+    let transcript = sess.handshake_transcript();
+    let types: Vec<HandshakeType> = transcript.iter()
+        .map(|msg| msg.typ)
+        .collect();
+    assert_eq!(types, expect);
+    assert!(transcript.iter().all(|msg| msg.length >= 4));
+}
 
+const TLS13_TRANSCRIPT: &'static [HandshakeType] = &[
+    HandshakeType::ClientHello, HandshakeType::ServerHello,
+    HandshakeType::EncryptedExtensions, HandshakeType::Certificate,
+    HandshakeType::CertificateVerify, HandshakeType::Finished, HandshakeType::Finished
+];
+
+#[test]
+fn handshake_transcript_tls13() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    assert!(client.handshake_transcript().is_empty());
     do_handshake(&mut client, &mut server);
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
 
-    check_read(&mut client, b"hello");
+    check_transcript(&client, TLS13_TRANSCRIPT);
+    check_transcript(&server, TLS13_TRANSCRIPT);
+
+    let transcript = client.handshake_transcript();
+    assert_eq!(transcript[0].version, Some(ProtocolVersion::TLSv1_2));
+    assert_eq!(transcript[1].version, Some(ProtocolVersion::Unknown(0x7f12)));
+    assert!(transcript[1].cipher_suite.is_some());
+    assert_eq!(transcript, server.handshake_transcript());
 }
 
 #[test]
-fn buffered_both_data_sent() {
-    let client_config = make_client_config();
-    let server_config = make_server_config();
+fn handshake_transcript_excludes_post_handshake_messages() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+    server_config.ticketer = Ticketer::new();
+    server_config.set_client_auth_roots(get_chain(), true);
+    server_config.client_auth_offer = false;
+    client_config.set_single_client_cert(get_chain(), get_key());
+    client_config.enable_post_handshake_auth = true;
+
     let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
     let mut server = ServerSession::new(&Arc::new(server_config));
-
-    assert_eq!(12, server.write(b"from-server!").unwrap());
-    assert_eq!(12, client.write(b"from-client!").unwrap());
-
     do_handshake(&mut client, &mut server);
 
+    // A ticket, then post-handshake client auth.
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    server.request_client_auth().unwrap();
     transfer(&mut server, &mut client);
     client.process_new_packets().unwrap();
     transfer(&mut client, &mut server);
     server.process_new_packets().unwrap();
+    assert_eq!(server.get_peer_certificates(), Some(get_chain()));
 
-    check_read(&mut client, b"from-server!");
-    check_read(&mut server, b"from-client!");
+    check_transcript(&client, TLS13_TRANSCRIPT);
+    check_transcript(&server, TLS13_TRANSCRIPT);
 }
 
 #[test]
-fn client_can_get_server_cert() {
-    let client_config = make_client_config();
-    let server_config = make_server_config();
-    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
-    let mut server = ServerSession::new(&Arc::new(server_config));
-
+fn handshake_transcript_tls12() {
+    let mut client = ClientSession::new(&Arc::new(make_tls12_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
     do_handshake(&mut client, &mut server);
 
-    let certs = client.get_peer_certificates();
-    assert_eq!(certs, Some(get_chain()));
+    let expect = [ HandshakeType::ClientHello, HandshakeType::ServerHello,
+                   HandshakeType::Certificate, HandshakeType::ServerKeyExchange,
+                   HandshakeType::ServerHelloDone, HandshakeType::ClientKeyExchange,
+                   HandshakeType::Finished, HandshakeType::Finished ];
+    check_transcript(&client, &expect);
+    check_transcript(&server, &expect);
+
+    let transcript = server.handshake_transcript();
+    assert_eq!(transcript[1].version, Some(ProtocolVersion::TLSv1_2));
+}
+
+/// Remembers the most recent key exchange hint the client
+/// stores, so we can see which group was negotiated.
+struct ClientRecordsKxHint {
+    hint: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl StoresClientSessions for ClientRecordsKxHint {
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        if key.starts_with(b"kx-hint") {
+            *self.hint.lock().unwrap() = Some(value);
+        }
+        true
+    }
+
+    fn get(&mut self, _key: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
 }
 
 #[test]
-fn server_can_get_client_cert() {
+fn server_chooses_preferred_group() {
     let mut client_config = make_client_config();
     let mut server_config = make_server_config();
 
-    server_config.set_client_auth_roots(get_chain(), true);
-    client_config.set_single_client_cert(get_chain(), get_key());
+    // The client's X25519 share is no good, so the server asks for
+    // a share in the group it prefers.
+    let hint = Arc::new(Mutex::new(None));
+    client_config.set_persistence(Box::new(ClientRecordsKxHint { hint: hint.clone() }));
+    server_config.kx_groups = vec![ NamedGroup::secp256r1, NamedGroup::secp384r1 ];
 
     let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
     let mut server = ServerSession::new(&Arc::new(server_config));
 
     do_handshake(&mut client, &mut server);
-
-    let certs = server.get_peer_certificates();
-    assert_eq!(certs, Some(get_chain()));
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+    assert_eq!(*hint.lock().unwrap(), Some(vec![ 0x00, 0x17 ]));
 }
 
-fn check_read_and_close(reader: &mut io::Read, expect: &[u8]) {
-    let mut buf = Vec::new();
-    buf.resize(expect.len(), 0u8);
-    assert_eq!(expect.len(), reader.read(&mut buf).unwrap());
-    assert_eq!(expect.to_vec(), buf);
-
-    let err = reader.read(&mut buf);
-    assert!(err.is_err());
-    assert_eq!(err.err().unwrap().kind(), io::ErrorKind::ConnectionAborted);
+/// Put a key share for a group we can't use ahead of the real one.
+/// If `in_groups`, also list its group in supported_groups.
+fn offer_unusable_key_share(hello: &mut ClientHelloPayload, in_groups: bool) {
+    for ext in hello.extensions.iter_mut() {
+        match *ext {
+            ClientExtension::KeyShare(ref mut shares) => {
+                shares.insert(0, KeyShareEntry::new(NamedGroup::FFDHE2048, &[ 1, 2, 3 ]));
+            }
+            ClientExtension::NamedGroups(ref mut groups) if in_groups => {
+                groups.insert(0, NamedGroup::FFDHE2048);
+            }
+            _ => {}
+        }
+    }
 }
 
 #[test]
-fn server_close_notify() {
+fn server_uses_first_usable_key_share() {
     let mut client_config = make_client_config();
-    let mut server_config = make_server_config();
+    client_config.set_client_hello_callback(|hello| offer_unusable_key_share(hello, true));
 
-    server_config.set_client_auth_roots(get_chain(), true);
-    client_config.set_single_client_cert(get_chain(), get_key());
+    // This server would rather have secp256r1, but takes the X25519
+    // share it was given rather than asking for another.
+    let mut server_config = make_server_config();
+    server_config.kx_groups = vec![ NamedGroup::secp256r1, NamedGroup::X25519 ];
 
     let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
     let mut server = ServerSession::new(&Arc::new(server_config));
 
     do_handshake(&mut client, &mut server);
-
-    // check that alerts don't overtake appdata
-    assert_eq!(12, server.write(b"from-server!").unwrap());
-    assert_eq!(12, client.write(b"from-client!").unwrap());
-    server.send_close_notify();
-
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
-    check_read_and_close(&mut client, b"from-server!");
-
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    check_read(&mut server, b"from-client!");
+    assert_eq!(server.get_key_exchange_group(), Some(NamedGroup::X25519));
+    assert_eq!(client.get_key_exchange_group(), Some(NamedGroup::X25519));
 }
 
 #[test]
-fn client_close_notify() {
+fn server_rejects_key_share_for_unsupported_group() {
     let mut client_config = make_client_config();
-    let mut server_config = make_server_config();
-
-    server_config.set_client_auth_roots(get_chain(), true);
-    client_config.set_single_client_cert(get_chain(), get_key());
+    client_config.set_client_hello_callback(|hello| offer_unusable_key_share(hello, false));
 
     let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
-    let mut server = ServerSession::new(&Arc::new(server_config));
-
-    do_handshake(&mut client, &mut server);
-
-    // check that alerts don't overtake appdata
-    assert_eq!(12, server.write(b"from-server!").unwrap());
-    assert_eq!(12, client.write(b"from-client!").unwrap());
-    client.send_close_notify();
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
 
     transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    check_read_and_close(&mut server, b"from-client!");
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("client sent key share for unsupported group"
+                                                 .to_string())));
 
     transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
-    check_read(&mut client, b"from-server!");
-}
-
-struct ServerCheckCertResolve {
-    expected: String
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::IllegalParameter)));
 }
 
-impl ServerCheckCertResolve {
-    fn new(expect: &str) -> ServerCheckCertResolve {
-        ServerCheckCertResolve {
-            expected: expect.to_string()
+/// Replace the client's key shares with one in `group` with the
+/// given public key.
+fn replace_key_share(hello: &mut ClientHelloPayload, group: NamedGroup, public: &[u8]) {
+    for ext in hello.extensions.iter_mut() {
+        if let ClientExtension::KeyShare(ref mut shares) = *ext {
+            *shares = vec![ KeyShareEntry::new(group, public) ];
         }
     }
 }
 
-impl ResolvesServerCert for ServerCheckCertResolve {
-    fn resolve(&self,
-               server_name: Option<&str>,
-               sigschemes: &[SignatureScheme])
-        -> Option<sign::CertChainAndSigner> {
-        if let Some(got_dns_name) = server_name {
-            if got_dns_name != self.expected {
-                panic!("unexpected dns name (wanted '{}' got '{}')", &self.expected, got_dns_name);
-            }
-        } else {
-            panic!("dns name not provided (wanted '{}')", &self.expected);
-        }
+fn check_server_rejects_key_share(group: NamedGroup, public: Vec<u8>) {
+    let mut client_config = make_client_config();
+    client_config.set_client_hello_callback(move |hello| {
+        replace_key_share(hello, group, &public)
+    });
 
-        if sigschemes.len() == 0 {
-            panic!("no signature schemes shared by client");
-        }
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
 
-        None
-    }
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("client sent invalid key share".to_string())));
+
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::IllegalParameter)));
 }
 
 #[test]
-fn server_cert_resolve_with_sni() {
-    let client_config = make_client_config();
+fn server_rejects_empty_key_share() {
+    check_server_rejects_key_share(NamedGroup::X25519, vec![]);
+}
+
+#[test]
+fn server_rejects_wrong_length_key_share() {
+    check_server_rejects_key_share(NamedGroup::X25519, vec![ 0x55; 31 ]);
+    check_server_rejects_key_share(NamedGroup::secp256r1, vec![ 0x04; 64 ]);
+}
+
+#[test]
+fn server_rejects_zero_key_share() {
+    check_server_rejects_key_share(NamedGroup::X25519, vec![ 0; 32 ]);
+}
+
+#[test]
+fn server_rejects_compressed_key_share() {
+    let mut public = vec![ 0x02 ];
+    public.extend_from_slice(&[ 0x55; 64 ]);
+    check_server_rejects_key_share(NamedGroup::secp256r1, public);
+}
+
+#[test]
+fn server_rejects_off_curve_key_share() {
+    let mut public = vec![ 0x04 ];
+    public.extend_from_slice(&[ 0x01; 64 ]);
+    check_server_rejects_key_share(NamedGroup::secp256r1, public);
+}
+
+#[test]
+fn server_fails_with_no_common_group() {
+    let client_config = make_client_config();
     let mut server_config = make_server_config();
 
-    server_config.cert_resolver = Box::new(ServerCheckCertResolve::new("the-value-from-sni"));
+    server_config.kx_groups = vec![ NamedGroup::FFDHE2048 ];
 
-    let mut client = ClientSession::new(&Arc::new(client_config), "the-value-from-sni");
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
     let mut server = ServerSession::new(&Arc::new(server_config));
 
     let err = do_handshake_until_error(&mut client, &mut server);
     assert_eq!(err.is_err(), true);
 }
 
-struct ClientCheckCertResolve {
-    query_count: atomic::AtomicUsize,
-    expect_queries: usize
+fn check_single_group(mut client_config: ClientConfig) {
+    let mut server_config = make_server_config();
+    client_config.set_groups(&[ NamedGroup::secp256r1 ]);
+    server_config.set_groups(&[ NamedGroup::secp256r1 ]);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_key_exchange_group(), Some(NamedGroup::secp256r1));
+    assert_eq!(server.get_key_exchange_group(), Some(NamedGroup::secp256r1));
 }
 
-impl ClientCheckCertResolve {
-    fn new(expect_queries: usize) -> ClientCheckCertResolve {
-        ClientCheckCertResolve {
-            query_count: atomic::AtomicUsize::new(0),
-            expect_queries: expect_queries
-        }
-    }
+#[test]
+fn single_group_succeeds_tls13() {
+    check_single_group(make_client_config());
 }
 
-impl Drop for ClientCheckCertResolve {
-    fn drop(&mut self) {
-        let count = self.query_count.load(atomic::Ordering::SeqCst);
-        assert_eq!(count, self.expect_queries);
-    }
+#[test]
+fn single_group_succeeds_tls12() {
+    check_single_group(make_tls12_client_config());
 }
 
-impl ResolvesClientCert for ClientCheckCertResolve {
-    fn resolve(&self,
-               acceptable_issuers: &[&[u8]],
-               sigschemes: &[SignatureScheme])
-        -> Option<sign::CertChainAndSigner> {
-        self.query_count.fetch_add(1, atomic::Ordering::SeqCst);
+fn check_incompatible_single_group(mut client_config: ClientConfig, why: &str) {
+    let mut server_config = make_server_config();
+    client_config.set_groups(&[ NamedGroup::secp256r1 ]);
+    server_config.set_groups(&[ NamedGroup::X25519 ]);
 
-        if acceptable_issuers.len() == 0 {
-            panic!("no issuers offered by server");
-        }
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    assert_eq!(do_handshake_until_error(&mut client, &mut server),
+               Err(TLSError::PeerIncompatibleError(why.to_string())));
 
-        if sigschemes.len() == 0 {
-            panic!("no signature schemes shared by server");
-        }
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::HandshakeFailure)));
+}
 
-        None
+#[test]
+fn incompatible_single_group_fails_tls13() {
+    check_incompatible_single_group(make_client_config(), "no kx group overlap with client");
+}
+
+#[test]
+fn incompatible_single_group_fails_tls12() {
+    check_incompatible_single_group(make_tls12_client_config(), "no supported group");
+}
+
+/// An insecure stand-in for ML-KEM-768.  The encapsulation key
+/// is the decapsulation key, and the shared secret is the key.
+struct MockKEM {}
+
+impl KeyEncapsulation for MockKEM {
+    fn encapsulation_key_len(&self) -> usize { 8 }
+    fn ciphertext_len(&self) -> usize { 8 }
+
+    fn generate(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let key = b"mock-kem".to_vec();
+        Some((key.clone(), key))
     }
 
-    fn has_certs(&self) -> bool {
-        true
+    fn encapsulate(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        Some((key.to_vec(), key.to_vec()))
+    }
+
+    fn decapsulate(&self, key: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        if key == ciphertext { Some(key.to_vec()) } else { None }
     }
 }
 
-#[test]
-fn client_cert_resolve() {
+fn hybrid_kx_test(client_kem: bool, server_kem: bool, expect_group: Vec<u8>) {
     let mut client_config = make_client_config();
     let mut server_config = make_server_config();
 
-    client_config.client_auth_cert_resolver = Box::new(ClientCheckCertResolve::new(1));
-    server_config.set_client_auth_roots(get_chain(), true);
+    let hint = Arc::new(Mutex::new(None));
+    client_config.set_persistence(Box::new(ClientRecordsKxHint { hint: hint.clone() }));
+
+    if client_kem {
+        client_config.set_kem(Box::new(MockKEM {}));
+    }
+
+    if server_kem {
+        server_config.set_kem(Box::new(MockKEM {}));
+    }
 
     let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
     let mut server = ServerSession::new(&Arc::new(server_config));
 
-    let err = do_handshake_until_error(&mut client, &mut server);
-    assert_eq!(err.is_err(), true);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(*hint.lock().unwrap(), Some(expect_group));
 }
 
 #[test]
-fn client_error_is_sticky() {
-    let client_config = make_client_config();
-    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
-    client.read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref()).unwrap();
-    let mut err = client.process_new_packets();
-    assert_eq!(err.is_err(), true);
-    err = client.process_new_packets();
-    assert_eq!(err.is_err(), true);
+fn hybrid_kx() {
+    hybrid_kx_test(true, true, vec![ 0x11, 0xec ]);
+    hybrid_kx_test(true, false, vec![ 0x00, 0x1d ]);
+    hybrid_kx_test(false, true, vec![ 0x00, 0x1d ]);
 }
 
-#[test]
-fn server_error_is_sticky() {
-    let server_config = make_server_config();
-    let mut server = ServerSession::new(&Arc::new(server_config));
-    server.read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref()).unwrap();
-    let mut err = server.process_new_packets();
-    assert_eq!(err.is_err(), true);
-    err = server.process_new_packets();
-    assert_eq!(err.is_err(), true);
+fn check_read(reader: &mut io::Read, bytes: &[u8]) {
+    let mut buf = Vec::new();
+    assert_eq!(bytes.len(), reader.read_to_end(&mut buf).unwrap());
+    assert_eq!(bytes.to_vec(), buf);
 }
 
 #[test]
-fn server_is_send() {
+fn buffered_client_data_sent() {
+    let client_config = make_client_config();
     let server_config = make_server_config();
-    let server = ServerSession::new(&Arc::new(server_config));
-    &server as &Send;
-}
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
 
-#[test]
-fn client_is_send() {
-    let client_config = make_client_config();
-    let client = ClientSession::new(&Arc::new(client_config), "localhost");
-    &client as &Send;
+    assert_eq!(5, client.write(b"hello").unwrap());
+
+    do_handshake(&mut client, &mut server);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    check_read(&mut server, b"hello");
 }
 
 #[test]
-fn server_respects_buffer_limit_pre_handshake() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
-
-    server.set_buffer_limit(32);
+fn buffered_server_data_sent() {
+    let client_config = make_client_config();
+    let server_config = make_server_config();
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
 
-    assert_eq!(server.write(b"01234567890123456789").unwrap(), 20);
-    assert_eq!(server.write(b"01234567890123456789").unwrap(), 12);
+    assert_eq!(5, server.write(b"hello").unwrap());
 
     do_handshake(&mut client, &mut server);
     transfer(&mut server, &mut client);
     client.process_new_packets().unwrap();
 
-    check_read(&mut client, b"01234567890123456789012345678901");
+    check_read(&mut client, b"hello");
 }
 
 #[test]
-fn server_respects_buffer_limit_post_handshake() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+fn buffered_both_data_sent() {
+    let client_config = make_client_config();
+    let server_config = make_server_config();
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
 
-    // this test will vary in behaviour depending on the default suites
-    do_handshake(&mut client, &mut server);
-    server.set_buffer_limit(48);
+    assert_eq!(12, server.write(b"from-server!").unwrap());
+    assert_eq!(12, client.write(b"from-client!").unwrap());
 
-    assert_eq!(server.write(b"01234567890123456789").unwrap(), 20);
-    assert_eq!(server.write(b"01234567890123456789").unwrap(), 6);
+    do_handshake(&mut client, &mut server);
 
     transfer(&mut server, &mut client);
     client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-    check_read(&mut client, b"01234567890123456789012345");
+    check_read(&mut client, b"from-server!");
+    check_read(&mut server, b"from-client!");
 }
 
 #[test]
-fn client_respects_buffer_limit_pre_handshake() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
-
-    client.set_buffer_limit(32);
-
-    assert_eq!(client.write(b"01234567890123456789").unwrap(), 20);
-    assert_eq!(client.write(b"01234567890123456789").unwrap(), 12);
+fn client_can_get_server_cert() {
+    let client_config = make_client_config();
+    let server_config = make_server_config();
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
 
     do_handshake(&mut client, &mut server);
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
 
-    check_read(&mut server, b"01234567890123456789012345678901");
+    let certs = client.get_peer_certificates();
+    assert_eq!(certs, Some(get_chain()));
 }
 
-#[test]
-fn client_respects_buffer_limit_post_handshake() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+fn make_resuming_configs() -> (Arc<ClientConfig>, Arc<ServerConfig>) {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ ProtocolVersion::TLSv1_2 ];
+    client_config.set_persistence(ClientSessionMemoryCache::new(8));
 
-    do_handshake(&mut client, &mut server);
-    client.set_buffer_limit(48);
+    let mut server_config = make_server_config();
+    server_config.set_persistence(ServerSessionMemoryCache::new(8));
 
-    assert_eq!(client.write(b"01234567890123456789").unwrap(), 20);
-    assert_eq!(client.write(b"01234567890123456789").unwrap(), 6);
+    (Arc::new(client_config), Arc::new(server_config))
+}
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+fn resumption_denied_test(version: ProtocolVersion) {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ version ];
+    client_config.set_persistence(ClientSessionMemoryCache::new(8));
+    let client_config = Arc::new(client_config);
 
-    check_read(&mut server, b"01234567890123456789012345");
+    let mut server_config = make_server_config();
+    server_config.set_persistence(ServerSessionMemoryCache::new(8));
+    server_config.ticketer = Ticketer::new();
+    server_config.set_resumption_policy(|hello| hello.server_name() != Some("localhost"));
+    let server_config = Arc::new(server_config);
+
+    for _ in 0..2 {
+        let mut client = ClientSession::new(&client_config, "localhost");
+        let mut server = ServerSession::new(&server_config);
+        do_handshake(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+
+        assert!(client.get_peer_certificates().is_some());
+        assert!(server.get_sent_certificates().is_some());
+    }
 }
 
-struct OtherSession<'a> {
-    sess: &'a mut Session,
-    pub reads: usize,
-    pub writes: usize,
+#[test]
+fn server_resumption_policy_forces_full_handshake() {
+    resumption_denied_test(ProtocolVersion::TLSv1_3);
 }
 
-impl<'a> OtherSession<'a> {
-    fn new(sess: &'a mut Session) -> OtherSession<'a> {
-        OtherSession { sess, reads: 0, writes: 0 }
-    }
+#[test]
+fn server_resumption_policy_forces_full_handshake_tls12() {
+    resumption_denied_test(ProtocolVersion::TLSv1_2);
 }
 
-impl<'a> io::Read for OtherSession<'a> {
-    fn read(&mut self, mut b: &mut [u8]) -> io::Result<usize> {
-        self.reads += 1;
-        self.sess.write_tls(b.by_ref())
-    }
+fn no_resumption_offered(hello: &mut ClientHelloPayload) {
+    assert!(hello.find_extension(ExtensionType::SessionTicket).is_none());
+    assert!(hello.find_extension(ExtensionType::PreSharedKey).is_none());
+    assert!(hello.find_extension(ExtensionType::PSKKeyExchangeModes).is_none());
 }
 
-impl<'a> io::Write for OtherSession<'a> {
-    fn write(&mut self, mut b: &[u8]) -> io::Result<usize> {
-        self.writes += 1;
-        let l = self.sess.read_tls(b.by_ref())?;
-        self.sess.process_new_packets().unwrap();
-        Ok(l)
-    }
+fn check_resumption_disabled(client_config: ClientConfig, server_config: ServerConfig) {
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(server_config);
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+    for _ in 0..3 {
+        let mut client = ClientSession::new(&client_config, "localhost");
+        let mut server = ServerSession::new(&server_config);
+        do_handshake(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+
+        assert!(client.get_peer_certificates().is_some());
+        assert!(server.get_sent_certificates().is_some());
     }
 }
 
-#[test]
-fn client_complete_io_for_handshake() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+fn make_resumable_configs(version: ProtocolVersion) -> (ClientConfig, ServerConfig) {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ version ];
+    client_config.set_persistence(ClientSessionMemoryCache::new(8));
 
-    assert_eq!(true, client.is_handshaking());
-    let (rdlen, wrlen) = client.complete_io(&mut OtherSession::new(&mut server)).unwrap();
-    assert!(rdlen > 0 && wrlen > 0);
-    assert_eq!(false, client.is_handshaking());
+    let mut server_config = make_server_config();
+    server_config.set_persistence(ServerSessionMemoryCache::new(8));
+    server_config.ticketer = Ticketer::new();
+
+    (client_config, server_config)
 }
 
-#[test]
-fn client_complete_io_for_write() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+/// Records the sessions a client saves, and never resumes.
+struct RecordsSessions(Arc<Mutex<Vec<Vec<u8>>>>);
 
-    do_handshake(&mut client, &mut server);
+impl StoresClientSessions for RecordsSessions {
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        if key.starts_with(b"session") {
+            self.0.lock().unwrap().push(value);
+        }
+        true
+    }
 
-    client.write(b"01234567890123456789").unwrap();
-    client.write(b"01234567890123456789").unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
-        assert!(rdlen == 0 && wrlen > 0);
-        assert_eq!(pipe.writes, 2);
+    fn get(&mut self, _key: &[u8]) -> Option<Vec<u8>> {
+        None
     }
-    check_read(&mut server, b"0123456789012345678901234567890123456789");
 }
 
-#[test]
-fn client_complete_io_for_read() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+/// Shares a `ClientSessionMemoryCache` with the test.
+struct SharedSessionCache(Arc<Mutex<Box<ClientSessionMemoryCache>>>);
 
-    do_handshake(&mut client, &mut server);
+impl StoresClientSessions for SharedSessionCache {
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.0.lock().unwrap().put(key, value)
+    }
 
-    server.write(b"01234567890123456789").unwrap();
-    {
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.lock().unwrap().get(key)
+    }
+
+    fn take(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.lock().unwrap().take(key)
+    }
+}
+
+#[test]
+fn client_keeps_newest_tickets_per_server() {
+    let (mut client_config, server_config) = make_resumable_configs(ProtocolVersion::TLSv1_3);
+    let server_config = Arc::new(server_config);
+
+    // Get three tickets, and save the sessions made with them.
+    let sessions = Arc::new(Mutex::new(Vec::new()));
+    client_config.set_persistence(Box::new(RecordsSessions(sessions.clone())));
+    let recording_config = Arc::new(client_config);
+    for _ in 0..3 {
+        let mut client = ClientSession::new(&recording_config, "localhost");
+        let mut server = ServerSession::new(&server_config);
+        do_handshake(&mut client, &mut server);
+    }
+
+    let sessions = sessions.lock().unwrap().clone();
+    assert_eq!(sessions.len(), 3);
+
+    // With room for two tickets, the oldest is forgotten.
+    let mut cache = ClientSessionMemoryCache::new(8);
+    cache.set_max_tickets_per_server(2);
+    let cache = Arc::new(Mutex::new(cache));
+
+    let mut key = b"session".to_vec();
+    key.push(b"localhost".len() as u8);
+    key.extend_from_slice(b"localhost");
+    for session in &sessions {
+        cache.lock().unwrap().put(key.clone(), session.clone());
+    }
+
+    // We resume using the newest, and it's used up.  The server gives
+    // us a new ticket instead.
+    let (mut client_config, _) = make_resumable_configs(ProtocolVersion::TLSv1_3);
+    client_config.set_persistence(Box::new(SharedSessionCache(cache.clone())));
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(server.get_sent_certificates().is_none());
+
+    let mut cache = cache.lock().unwrap();
+    let newest = cache.take(&key).unwrap();
+    assert!(!sessions.contains(&newest));
+    assert_eq!(cache.take(&key), Some(sessions[1].clone()));
+    assert_eq!(cache.take(&key), None);
+}
+
+#[test]
+fn new_ticket_callback_sees_each_ticket() {
+    for version in &[ ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2 ] {
+        let (mut client_config, server_config) = make_resumable_configs(*version);
+        let tickets = Arc::new(Mutex::new(Vec::new()));
+        let seen = tickets.clone();
+        client_config.set_new_ticket_callback(move |name, ticket| {
+            seen.lock().unwrap().push((name.to_string(), ticket.to_vec()));
+        });
+
+        let client_config = Arc::new(client_config);
+        let server_config = Arc::new(server_config);
+
+        // The second handshake resumes, and gets a new ticket too.
+        for count in 1..3 {
+            let mut client = ClientSession::new(&client_config, "localhost");
+            let mut server = ServerSession::new(&server_config);
+            do_handshake(&mut client, &mut server);
+
+            let tickets = tickets.lock().unwrap();
+            assert_eq!(tickets.len(), count);
+            assert_eq!(tickets[count - 1].0, "localhost");
+            assert!(!tickets[count - 1].1.is_empty());
+        }
+
+        let tickets = tickets.lock().unwrap();
+        assert!(tickets[0].1 != tickets[1].1);
+    }
+}
+
+#[test]
+fn client_can_disable_resumption() {
+    for version in &[ ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2 ] {
+        let (mut client_config, server_config) = make_resumable_configs(*version);
+        client_config.set_resumption(Resumption::Disabled);
+        client_config.set_client_hello_callback(no_resumption_offered);
+        check_resumption_disabled(client_config, server_config);
+    }
+}
+
+#[test]
+fn server_can_disable_resumption() {
+    for version in &[ ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2 ] {
+        let (client_config, mut server_config) = make_resumable_configs(*version);
+        server_config.set_resumption(Resumption::Disabled);
+        check_resumption_disabled(client_config, server_config);
+    }
+}
+
+#[test]
+fn resumption_works_unless_disabled() {
+    let (client_config, server_config) = make_resumable_configs(ProtocolVersion::TLSv1_3);
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(server_config);
+
+    for expect_resumed in &[ false, true ] {
+        let mut client = ClientSession::new(&client_config, "localhost");
+        let mut server = ServerSession::new(&server_config);
+        do_handshake(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        assert_eq!(server.get_sent_certificates().is_none(), *expect_resumed);
+    }
+}
+
+/// Get a TLS1.3 ticket, then try to resume with it when the server
+/// thinks `ticket_age` seconds have passed.  Returns true if we
+/// resumed.
+fn resumes_with_ticket_age(ticket_age: u64) -> bool {
+    let mut client_config = make_client_config();
+    client_config.set_persistence(ClientSessionMemoryCache::new(8));
+    let client_config = Arc::new(client_config);
+
+    let issued = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let mut server_config = make_server_config();
+    server_config.ticketer = Ticketer::new();
+    server_config.set_time_provider(Box::new(FixedTime(issued)));
+    let mut server_config = Arc::new(server_config);
+
+    {
+        let mut client = ClientSession::new(&client_config, "localhost");
+        let mut server = ServerSession::new(&server_config);
+        do_handshake(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+    }
+
+    Arc::get_mut(&mut server_config)
+        .unwrap()
+        .set_time_provider(Box::new(FixedTime(issued + ticket_age)));
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    server.get_sent_certificates().is_none()
+}
+
+#[test]
+fn server_accepts_plausible_ticket_age() {
+    assert!(resumes_with_ticket_age(0));
+    assert!(resumes_with_ticket_age(60));
+}
+
+#[test]
+fn server_refuses_ticket_with_wrong_age() {
+    // The client says the ticket is new, but the server knows it
+    // isn't: so this may be a replay, and we do a full handshake.
+    assert!(!resumes_with_ticket_age(60 * 60));
+}
+
+fn get_max_early_data_size(server_max: u32) -> Option<u32> {
+    let mut server_config = make_server_config();
+    server_config.ticketer = Ticketer::new();
+    server_config.set_max_early_data_size(server_max);
+
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+
+    // The ticket follows the handshake.
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    client.get_max_early_data_size()
+}
+
+#[test]
+fn client_learns_max_early_data_size() {
+    assert_eq!(get_max_early_data_size(16384), Some(16384));
+    assert_eq!(get_max_early_data_size(1), Some(1));
+}
+
+#[test]
+fn tickets_invite_no_early_data_by_default() {
+    assert_eq!(get_max_early_data_size(0), None);
+}
+
+fn make_early_data_configs(server_max: u32) -> (Arc<ClientConfig>, Arc<ServerConfig>) {
+    let mut client_config = make_client_config();
+    client_config.enable_early_data = true;
+    client_config.set_persistence(ClientSessionMemoryCache::new(8));
+
+    let mut server_config = make_server_config();
+    server_config.ticketer = Ticketer::new();
+    server_config.set_max_early_data_size(server_max);
+
+    (Arc::new(client_config), Arc::new(server_config))
+}
+
+/// Make a TLS1.3 session using `client_config`, so the next one can resume
+/// and send early data.
+fn get_early_data_ticket(client_config: &Arc<ClientConfig>, server_config: &Arc<ServerConfig>) {
+    let mut client = ClientSession::new(client_config, "localhost");
+    let mut server = ServerSession::new(server_config);
+    assert!(client.write_early_data(b"hello").is_err());
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert!(!client.is_early_data_accepted());
+}
+
+#[test]
+fn server_accepts_early_data() {
+    let (client_config, server_config) = make_early_data_configs(16384);
+    get_early_data_ticket(&client_config, &server_config);
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    assert_eq!(client.write_early_data(b"hello").unwrap(), 5);
+
+    // The server reads early data before the handshake completes.
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert!(server.is_handshaking());
+    check_read(&mut server, b"hello");
+
+    // It's too late once we've heard from the server.
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert!(client.is_early_data_accepted());
+    assert!(client.write_early_data(b"hello").is_err());
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+
+    // Accepted early data isn't sent again.
+    assert_eq!(client.write(b"world").unwrap(), 5);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"world");
+}
+
+#[test]
+fn server_rejects_replayed_early_data() {
+    let (client_config, server_config) = make_early_data_configs(16384);
+    get_early_data_ticket(&client_config, &server_config);
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    assert_eq!(client.write_early_data(b"hello").unwrap(), 5);
+    let mut flight = Vec::new();
+    while client.wants_write() {
+        client.write_tls(&mut flight).unwrap();
+    }
+
+    let mut server = ServerSession::new(&server_config);
+    server.read_tls(&mut flight.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+
+    // An attacker replays the client's first flight.
+    let mut server = ServerSession::new(&server_config);
+    server.read_tls(&mut flight.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"");
+
+    // Another client with a fresh ticket is unaffected.
+    let (client_config, _) = make_early_data_configs(16384);
+    get_early_data_ticket(&client_config, &server_config);
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    assert_eq!(client.write_early_data(b"hello").unwrap(), 5);
+    do_handshake(&mut client, &mut server);
+    assert!(client.is_early_data_accepted());
+    check_read(&mut server, b"hello");
+}
+
+/// Records what it's asked to check, and accepts everything.
+struct RecordingReplayProtection(Arc<Mutex<Vec<(u64, u32)>>>);
+
+impl ReplayProtection for RecordingReplayProtection {
+    fn check_and_record(&mut self, _identity: &[u8], now: u64, lifetime: u32) -> bool {
+        self.0.lock().unwrap().push((now, lifetime));
+        true
+    }
+}
+
+#[test]
+fn server_remembers_early_data_for_ticket_lifetime() {
+    let checks = Arc::new(Mutex::new(Vec::new()));
+    let (client_config, mut server_config) = make_early_data_configs(16384);
+    {
+        let server_config = Arc::get_mut(&mut server_config).unwrap();
+        server_config.ticket_lifetime = Some(60);
+        server_config.set_time_provider(Box::new(FixedTime(WHILE_VALID)));
+        server_config.set_replay_protection(Box::new(RecordingReplayProtection(checks.clone())));
+    }
+    get_early_data_ticket(&client_config, &server_config);
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    assert_eq!(client.write_early_data(b"hello").unwrap(), 5);
+    do_handshake(&mut client, &mut server);
+    assert!(client.is_early_data_accepted());
+
+    // The configured lifetime, plus the clock skew, by the config's clock.
+    assert_eq!(*checks.lock().unwrap(), vec![ (WHILE_VALID, 60 + DEFAULT_SKEW as u32) ]);
+}
+
+#[test]
+fn server_rejects_too_much_early_data() {
+    let (client_config, mut server_config) = make_early_data_configs(16384);
+    get_early_data_ticket(&client_config, &server_config);
+
+    // The ticket invites more early data than the server now accepts.
+    Arc::get_mut(&mut server_config).unwrap().set_max_early_data_size(4);
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    assert_eq!(client.write_early_data(b"hello").unwrap(), 5);
+
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("client sent too much early data"
+                                                 .to_string())));
+
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::UnexpectedMessage)));
+}
+
+#[test]
+fn server_rejects_early_data_for_another_alpn_protocol() {
+    let (mut client_config, mut server_config) = make_early_data_configs(16384);
+    Arc::get_mut(&mut client_config).unwrap()
+        .set_protocols(&[ "foo".to_string(), "bar".to_string() ]);
+    Arc::get_mut(&mut server_config).unwrap().set_protocols(&[ "foo".to_string() ]);
+    get_early_data_ticket(&client_config, &server_config);
+
+    // The ticket was for "foo", but we'd now agree "bar".
+    Arc::get_mut(&mut server_config).unwrap().set_protocols(&[ "bar".to_string() ]);
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    assert_eq!(client.write_early_data(b"hello").unwrap(), 5);
+
+    do_handshake(&mut client, &mut server);
+    assert!(!client.is_early_data_accepted());
+    assert_eq!(server.get_alpn_protocol(), Some("bar".to_string()));
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+}
+
+#[test]
+fn client_sends_no_more_early_data_than_invited() {
+    let (client_config, server_config) = make_early_data_configs(4);
+    get_early_data_ticket(&client_config, &server_config);
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    assert_eq!(client.write_early_data(b"hello").unwrap(), 4);
+    assert_eq!(client.write_early_data(b"o").unwrap(), 0);
+
+    do_handshake(&mut client, &mut server);
+    assert!(client.is_early_data_accepted());
+    check_read(&mut server, b"hell");
+}
+
+#[test]
+fn rejected_early_data_is_resent() {
+    let (client_config, server_config) = make_early_data_configs(16384);
+    get_early_data_ticket(&client_config, &server_config);
+
+    // This server can't decrypt the ticket, so doesn't resume, so
+    // rejects the early data.
+    let (_, other_server_config) = make_early_data_configs(16384);
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&other_server_config);
+    assert_eq!(client.write_early_data(b"hello").unwrap(), 5);
+    assert_eq!(client.write(b" world").unwrap(), 6);
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"");
+
+    do_handshake(&mut client, &mut server);
+    assert!(!client.is_early_data_accepted());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello world");
+}
+
+#[test]
+fn rejected_early_data_is_skipped_up_to_ticket_limit() {
+    let (client_config, mut server_config) = make_early_data_configs(16384);
+    get_early_data_ticket(&client_config, &server_config);
+
+    // The server no longer invites early data, so it rejects this,
+    // but must skip as much as the ticket invited.
+    Arc::get_mut(&mut server_config).unwrap().set_max_early_data_size(0);
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    let data = vec![ 0x41u8; 1000 ];
+    assert_eq!(client.write_early_data(&data).unwrap(), 1000);
+
+    do_handshake(&mut client, &mut server);
+    assert!(!client.is_early_data_accepted());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, &data);
+}
+
+#[test]
+fn early_data_is_abandoned_after_retry_request() {
+    let (client_config, mut server_config) = make_early_data_configs(16384);
+    get_early_data_ticket(&client_config, &server_config);
+
+    // The client's key share is for X25519, so this makes the server
+    // send a HelloRetryRequest.
+    Arc::get_mut(&mut server_config).unwrap().set_groups(&[ NamedGroup::secp384r1 ]);
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    assert_eq!(client.write_early_data(b"hello").unwrap(), 5);
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert!(client.write_early_data(b"more").is_err());
+
+    do_handshake(&mut client, &mut server);
+    assert!(!client.is_early_data_accepted());
+    assert_eq!(server.get_key_exchange_group(), Some(NamedGroup::secp384r1));
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+}
+
+#[test]
+fn client_offers_no_early_data_by_default() {
+    let (_, server_config) = make_early_data_configs(16384);
+
+    let mut client_config = make_client_config();
+    client_config.set_persistence(ClientSessionMemoryCache::new(8));
+    let client_config = Arc::new(client_config);
+    get_early_data_ticket(&client_config, &server_config);
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    assert!(client.write_early_data(b"hello").is_err());
+}
+
+#[test]
+fn client_resumes_given_echoed_session_id() {
+    let (client_config, server_config) = make_resuming_configs();
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(client.get_peer_certificates().is_some());
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(client.get_peer_certificates().is_none());
+    assert!(server.get_sent_certificates().is_none());
+}
+
+#[test]
+fn client_full_handshake_given_new_session_id() {
+    let (client_config, server_config) = make_resuming_configs();
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+
+    // This server doesn't know the session, so issues a new ID.
+    let (_, other_server_config) = make_resuming_configs();
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&other_server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(client.get_peer_certificates().is_some());
+    assert!(server.get_sent_certificates().is_some());
+}
+
+/// Add an empty extension of type `typ` to the ServerHello at the
+/// start of the server's next flight, and give the flight to `client`.
+fn send_server_hello_with_extension(client: &mut ClientSession,
+                                    server: &mut ServerSession,
+                                    typ: u16) {
+    let mut flight = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut flight).unwrap();
+    }
+
+    let tls13 = server.get_protocol_version() == Some(ProtocolVersion::TLSv1_3);
+    let exts_at = server_hello_extensions_at(&flight, tls13);
+    let record_end = 5 + read_u16_at(&flight, 3);
+    grow_hello(&mut flight, 4, Some(exts_at));
+
+    let ext = [ (typ >> 8) as u8, typ as u8, 0x00, 0x00 ];
+    for (i, byte) in ext.iter().enumerate() {
+        flight.insert(record_end + i, *byte);
+    }
+
+    client.read_tls(&mut flight.as_slice()).unwrap();
+}
+
+/// Rewrite the TLS1.3 ServerHello at the start of `flight` in the
+/// newer format: TLS1.2 in the legacy version field, an empty session
+/// ID, null compression and `version` in a supported_versions
+/// extension.  Returns just that record.
+fn legacy_format_server_hello(flight: &[u8], version: u16) -> Vec<u8> {
+    let push_u16 = |buf: &mut Vec<u8>, val: usize| {
+        buf.push((val >> 8) as u8);
+        buf.push(val as u8);
+    };
+
+    let suite_at = HELLO_SESSION_ID_AT;
+    let record_end = 5 + read_u16_at(flight, 3);
+    let exts_len = read_u16_at(flight, suite_at + 2);
+
+    let mut body = vec![ 0x03, 0x03 ];
+    body.extend_from_slice(&flight[HELLO_RANDOM_AT..suite_at]);
+    body.push(0x00);
+    body.extend_from_slice(&flight[suite_at..suite_at + 2]);
+    body.push(0x00);
+    push_u16(&mut body, exts_len + 6);
+    body.extend_from_slice(&flight[suite_at + 4..record_end]);
+    body.extend_from_slice(&[ 0x00, 0x2b, 0x00, 0x02, (version >> 8) as u8, version as u8 ]);
+
+    let mut record = flight[..3].to_vec();
+    push_u16(&mut record, body.len() + 4);
+    record.extend_from_slice(&[ 0x02, 0x00 ]);
+    push_u16(&mut record, body.len());
+    record.extend_from_slice(&body);
+    record
+}
+
+fn client_reads_legacy_format_server_hello(version: u16) -> (ClientSession, Result<(), TLSError>) {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert_eq!(server.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+
+    let mut flight = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut flight).unwrap();
+    }
+
+    let hello = legacy_format_server_hello(&flight, version);
+    client.read_tls(&mut hello.as_slice()).unwrap();
+    let rc = client.process_new_packets();
+    (client, rc)
+}
+
+#[test]
+fn client_takes_version_from_server_hello_extension() {
+    let (client, rc) = client_reads_legacy_format_server_hello(0x0304);
+    assert_eq!(rc, Ok(()));
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+}
+
+#[test]
+fn client_rejects_tls12_in_server_hello_extension() {
+    let (_, rc) = client_reads_legacy_format_server_hello(0x0303);
+    assert_eq!(rc,
+               Err(TLSError::PeerMisbehavedError("server sent bad supported_versions"
+                                                 .to_string())));
+}
+
+fn check_client_rejects_unsolicited_extension(version: ProtocolVersion, typ: u16) {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ version ];
+    client_config.enable_tickets = false;
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    send_server_hello_with_extension(&mut client, &mut server, typ);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("server sent unsolicited extension"
+                                                 .to_string())));
+
+    // The client's unsupported_extension alert is in plaintext, which a
+    // TLS1.3 server can't read: so we check it on the wire.
+    let mut alert = Vec::new();
+    client.write_tls(&mut alert).unwrap();
+    assert_eq!(alert, vec![ 0x15, 0x03, 0x03, 0x00, 0x02,
+                            0x02, AlertDescription::UnsupportedExtension.get_u8() ]);
+}
+
+#[test]
+fn client_rejects_unsolicited_server_hello_extension_tls13() {
+    check_client_rejects_unsolicited_extension(ProtocolVersion::TLSv1_3, 0xe000);
+}
+
+#[test]
+fn client_rejects_unsolicited_server_hello_extension_tls12() {
+    check_client_rejects_unsolicited_extension(ProtocolVersion::TLSv1_2, 0xe000);
+
+    // We didn't offer tickets, so the server mustn't acknowledge them.
+    check_client_rejects_unsolicited_extension(ProtocolVersion::TLSv1_2, 0x0023);
+}
+
+#[test]
+fn client_rejects_oversized_session_id() {
+    let (client_config, server_config) = make_resuming_configs();
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let mut flight = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut flight).unwrap();
+    }
+
+    // The ServerHello comes first.
+    let grow = 33 - flight[HELLO_SESSION_ID_AT] as usize;
+    flight[HELLO_SESSION_ID_AT] = 33;
+    for _ in 0..grow {
+        flight.insert(HELLO_SESSION_ID_AT + 1, 0x5a);
+    }
+    grow_hello(&mut flight, grow, None);
+
+    client.read_tls(&mut flight.as_slice()).unwrap();
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::CorruptMessagePayload(ContentType::Handshake)));
+}
+
+#[test]
+fn client_rejects_non_offered_ciphersuite() {
+    let mut client_config = make_client_config();
+    client_config.ciphersuites = rustls::ALL_CIPHERSUITES.iter()
+        .filter(|suite| suite.suite == CipherSuite::TLS13_CHACHA20_POLY1305_SHA256)
+        .cloned()
+        .collect();
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let mut flight = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut flight).unwrap();
+    }
+
+    // The suite follows the ServerHello's session ID.  Swap it for
+    // one the client didn't offer.
+    let suite_offset = HELLO_SESSION_ID_AT + 1 + flight[HELLO_SESSION_ID_AT] as usize;
+    assert_eq!(&flight[suite_offset..suite_offset + 2], &[ 0x13, 0x03 ]);
+    flight[suite_offset + 1] = 0x01;
+
+    client.read_tls(&mut flight.as_slice()).unwrap();
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("server chose non-offered ciphersuite"
+                                                 .to_string())));
+
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::IllegalParameter)));
+}
+
+const COMPAT_CCS: &'static [u8] = &[ 0x14, 0x03, 0x03, 0x00, 0x01, 0x01 ];
+
+#[test]
+fn tls13_sends_compat_ccs() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let mut server_flight = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut server_flight).unwrap();
+    }
+    assert!(contains_bytes(&server_flight, COMPAT_CCS));
+
+    client.read_tls(&mut server_flight.as_slice()).unwrap();
+    client.process_new_packets().unwrap();
+
+    let mut client_flight = Vec::new();
+    while client.wants_write() {
+        client.write_tls(&mut client_flight).unwrap();
+    }
+    assert!(contains_bytes(&client_flight, COMPAT_CCS));
+
+    server.read_tls(&mut client_flight.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+    assert!(!client.is_handshaking());
+    assert!(!server.is_handshaking());
+}
+
+#[test]
+fn tls13_ignores_extra_compat_ccs() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    transfer(&mut client, &mut server);
+    server.read_tls(&mut COMPAT_CCS.as_ref()).unwrap();
+    server.process_new_packets().unwrap();
+
+    // Put another one straight after the ServerHello.
+    let mut flight = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut flight).unwrap();
+    }
+    let sh_len = 5 + read_u16_at(&flight, 3);
+    let mut tail = flight.split_off(sh_len);
+    flight.extend_from_slice(COMPAT_CCS);
+    flight.append(&mut tail);
+
+    client.read_tls(&mut flight.as_slice()).unwrap();
+    client.process_new_packets().unwrap();
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+}
+
+fn strict_ccs_handshake(client_strict: bool, server_strict: bool) -> Result<(), TLSError> {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+    client_config.reject_tls13_compat_ccs = client_strict;
+    server_config.reject_tls13_compat_ccs = server_strict;
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake_until_error(&mut client, &mut server)
+}
+
+fn received_compat_ccs_error() -> TLSError {
+    TLSError::PeerMisbehavedError("received TLS1.3 compatibility ChangeCipherSpec".to_string())
+}
+
+#[test]
+fn tls13_strict_server_rejects_compat_ccs() {
+    assert_eq!(strict_ccs_handshake(false, true), Err(received_compat_ccs_error()));
+}
+
+#[test]
+fn tls13_strict_client_rejects_compat_ccs() {
+    assert_eq!(strict_ccs_handshake(true, false), Err(received_compat_ccs_error()));
+}
+
+#[test]
+fn tls13_strict_peers_send_no_compat_ccs() {
+    assert_eq!(strict_ccs_handshake(true, true), Ok(()));
+
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+    client_config.reject_tls13_compat_ccs = true;
+    server_config.reject_tls13_compat_ccs = true;
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let mut server_flight = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut server_flight).unwrap();
+    }
+    assert!(!contains_bytes(&server_flight, COMPAT_CCS));
+
+    client.read_tls(&mut server_flight.as_slice()).unwrap();
+    client.process_new_packets().unwrap();
+
+    let mut client_flight = Vec::new();
+    while client.wants_write() {
+        client.write_tls(&mut client_flight).unwrap();
+    }
+    assert!(!contains_bytes(&client_flight, COMPAT_CCS));
+}
+
+#[test]
+fn tls13_tolerant_by_default() {
+    assert_eq!(strict_ccs_handshake(false, false), Ok(()));
+}
+
+#[test]
+fn tls13_rejects_misplaced_ccs() {
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    server.read_tls(&mut COMPAT_CCS.as_ref()).unwrap();
+    assert!(server.process_new_packets().is_err());
+
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    server.read_tls(&mut COMPAT_CCS.as_ref()).unwrap();
+    assert!(server.process_new_packets().is_err());
+}
+
+#[test]
+fn tls13_rejects_unprotected_records_after_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    assert!(!server.wants_write());
+
+    server.read_tls(&mut COMPAT_CCS.as_ref()).unwrap();
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::InappropriateMessage {
+                   expect_types: vec![ ContentType::ApplicationData ],
+                   got_type: ContentType::ChangeCipherSpec,
+               }));
+
+    // The alert is encrypted, so just check one is sent.
+    assert!(server.wants_write());
+}
+
+const PLAINTEXT_APPDATA: &'static [u8] = &[ 0x17, 0x03, 0x03, 0x00, 0x02, 0x68, 0x69 ];
+const UNEXPECTED_MESSAGE_ALERT: &'static [u8] = &[ 0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x0a ];
+
+fn check_rejects_appdata(sess: &mut Session, expect: ContentType) {
+    sess.read_tls(&mut PLAINTEXT_APPDATA.as_ref()).unwrap();
+    assert_eq!(sess.process_new_packets(),
+               Err(TLSError::InappropriateMessage {
+                   expect_types: vec![ expect ],
+                   got_type: ContentType::ApplicationData,
+               }));
+}
+
+fn check_sends_unexpected_message(sess: &mut Session) {
+    let mut alert = Vec::new();
+    while sess.wants_write() {
+        sess.write_tls(&mut alert).unwrap();
+    }
+    assert!(alert.ends_with(UNEXPECTED_MESSAGE_ALERT));
+}
+
+const EC_POINTS_UNCOMPRESSED: &'static [u8] = &[ 0x00, 0x0b, 0x00, 0x02, 0x01, 0x00 ];
+const ILLEGAL_PARAMETER_ALERT: &'static [u8] = &[ 0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x2f ];
+const HANDSHAKE_FAILURE_ALERT: &'static [u8] = &[ 0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28 ];
+
+fn make_tls12_client_config() -> ClientConfig {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ ProtocolVersion::TLSv1_2 ];
+    client_config
+}
+
+#[test]
+fn sessions_report_forward_secrecy() {
+    for version in &[ ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3 ] {
+        let mut client_config = make_client_config();
+        client_config.versions = vec![ *version ];
+        let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+        let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+        // No suite yet.
+        assert!(!client.has_forward_secrecy());
+        assert!(!server.has_forward_secrecy());
+
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.get_protocol_version(), Some(*version));
+        assert!(client.has_forward_secrecy());
+        assert!(server.has_forward_secrecy());
+    }
+}
+
+#[test]
+fn client_random_matches_client_hello() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let random = client.client_random().unwrap();
+    assert_eq!(client.server_random(), None);
+
+    let hello = take_flight(&mut client);
+    assert_eq!(&hello[HELLO_RANDOM_AT..HELLO_SESSION_ID_AT], &random[..]);
+}
+
+#[test]
+fn sessions_agree_on_randoms() {
+    for version in &[ ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3 ] {
+        let mut client_config = make_client_config();
+        client_config.versions = vec![ *version ];
+        let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+        let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+        assert!(server.server_random().is_some());
+        assert_eq!(server.client_random(), None);
+
+        do_handshake(&mut client, &mut server);
+        assert!(client.server_random().is_some());
+        assert_eq!(client.client_random(), server.client_random());
+        assert_eq!(client.server_random(), server.server_random());
+        assert!(client.client_random() != client.server_random());
+    }
+}
+
+fn offered_suites(allow_non_forward_secret: bool) -> Vec<CipherSuite> {
+    let offered = Arc::new(Mutex::new(Vec::new()));
+    let mut client_config = make_client_config();
+    client_config.allow_non_forward_secret = allow_non_forward_secret;
+    {
+        let offered = offered.clone();
+        client_config.set_client_hello_callback(move |hello| {
+            *offered.lock().unwrap() = hello.cipher_suites.clone();
+        });
+    }
+
+    ClientSession::new(&Arc::new(client_config), "localhost");
+    let offered = offered.lock().unwrap();
+    offered.clone()
+}
+
+#[test]
+fn non_forward_secret_suites_rejected_by_default() {
+    assert!(!make_client_config().allow_non_forward_secret);
+    assert!(!make_server_config().allow_non_forward_secret);
+
+    // All our suites are forward secret, so none are left out.
+    let mut expected: Vec<CipherSuite> = rustls::ALL_CIPHERSUITES.iter()
+        .map(|scs| scs.suite)
+        .collect();
+    expected.push(CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV);
+    assert!(rustls::ALL_CIPHERSUITES.iter().all(|scs| scs.is_forward_secret()));
+    assert_eq!(offered_suites(false), expected);
+}
+
+#[test]
+fn allowing_non_forward_secret_suites() {
+    assert_eq!(offered_suites(true), offered_suites(false));
+
+    for version in &[ ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3 ] {
+        let mut client_config = make_client_config();
+        client_config.versions = vec![ *version ];
+        client_config.allow_non_forward_secret = true;
+        let mut server_config = make_server_config();
+        server_config.allow_non_forward_secret = true;
+
+        let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        do_handshake(&mut client, &mut server);
+        assert!(client.has_forward_secrecy());
+        assert!(server.has_forward_secrecy());
+    }
+}
+
+/// Do a TLS1.2 handshake up to the server's reading of our Finished,
+/// having written some data first.  Returns whether we thought False
+/// Start was safe, and what the server read.
+fn false_start_handshake(enable: bool, alpn: bool) -> (bool, Vec<u8>) {
+    let mut client_config = make_tls12_client_config();
+    let mut server_config = make_server_config();
+    client_config.enable_false_start = enable;
+    if alpn {
+        client_config.alpn_protocols = vec!["h2".to_string()];
+        server_config.alpn_protocols = vec!["h2".to_string()];
+    }
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    assert_eq!(5, client.write(b"hello").unwrap());
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert!(client.is_handshaking());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let mut buf = Vec::new();
+    server.read_to_end(&mut buf).unwrap();
+    (client.is_false_start_safe(), buf)
+}
+
+#[test]
+fn false_start_sends_data_early() {
+    assert_eq!((true, b"hello".to_vec()), false_start_handshake(true, true));
+}
+
+#[test]
+fn false_start_is_off_by_default() {
+    assert_eq!((true, Vec::new()), false_start_handshake(false, true));
+}
+
+#[test]
+fn false_start_needs_alpn() {
+    assert_eq!((false, Vec::new()), false_start_handshake(true, false));
+}
+
+const EMPTY_RENEGOTIATION_INFO: &'static [u8] = &[ 0xff, 0x01, 0x00, 0x01, 0x00 ];
+
+fn read_u16_at(buf: &[u8], at: usize) -> usize {
+    ((buf[at] as usize) << 8) | buf[at + 1] as usize
+}
+
+fn write_u16_at(buf: &mut [u8], at: usize, val: usize) {
+    buf[at] = (val >> 8) as u8;
+    buf[at + 1] = val as u8;
+}
+
+/// Where the random starts in a hello record: after the record and
+/// handshake headers and the version.
+const HELLO_RANDOM_AT: usize = 5 + 4 + 2;
+
+/// Where the field after the random starts in a hello record: the
+/// session ID, or the suite in a TLS1.3 ServerHello.
+const HELLO_SESSION_ID_AT: usize = HELLO_RANDOM_AT + 32;
+
+/// Where the extensions start in the ClientHello record `hello`,
+/// past the session ID, suites and compression methods.
+fn client_hello_extensions_at(hello: &[u8]) -> usize {
+    let mut at = HELLO_SESSION_ID_AT;
+    at += 1 + hello[at] as usize;
+    at += 2 + read_u16_at(hello, at);
+    at + 1 + hello[at] as usize
+}
+
+/// Where the extensions start in the ServerHello record `hello`.
+/// TLS1.3 ServerHellos have only the suite after the random; TLS1.2
+/// ones have the session ID, suite and compression method.
+fn server_hello_extensions_at(hello: &[u8], tls13: bool) -> usize {
+    let at = HELLO_SESSION_ID_AT;
+    if tls13 {
+        at + 2
+    } else {
+        at + 1 + hello[at] as usize + 2 + 1
+    }
+}
+
+/// Add `grow` to the record and handshake lengths of the hello record
+/// `hello`, and to its extensions length at `exts_at` if given.
+fn grow_hello(hello: &mut [u8], grow: usize, exts_at: Option<usize>) {
+    let mut ats = vec![ 3, 7 ];
+    ats.extend(exts_at);
+    for at in ats {
+        let len = read_u16_at(hello, at);
+        write_u16_at(hello, at, len + grow);
+    }
+}
+
+fn take_flight(sess: &mut Session) -> Vec<u8> {
+    let mut flight = Vec::new();
+    while sess.wants_write() {
+        sess.write_tls(&mut flight).unwrap();
+    }
+    flight
+}
+
+/// Where the empty renegotiation_info extension is in the first
+/// record of `flight`, if anywhere.
+fn find_empty_renegotiation_info(flight: &[u8]) -> Option<usize> {
+    let record_end = 5 + read_u16_at(flight, 3);
+    (5..record_end).find(|i| flight[*i..].starts_with(EMPTY_RENEGOTIATION_INFO))
+}
+
+#[test]
+fn tls12_empty_renegotiation_info_on_full_and_resumed_handshakes() {
+    let (client_config, server_config) = make_resumable_configs(ProtocolVersion::TLSv1_2);
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(server_config);
+
+    let mut resumed = Vec::new();
+    for _ in 0..2 {
+        let mut client = ClientSession::new(&client_config, "localhost");
+        let mut server = ServerSession::new(&server_config);
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+
+        let flight = take_flight(&mut server);
+        assert!(find_empty_renegotiation_info(&flight).is_some());
+        client.read_tls(&mut flight.as_slice()).unwrap();
+        client.process_new_packets().unwrap();
+
+        do_handshake(&mut client, &mut server);
+        resumed.push(server.get_sent_certificates().is_none());
+    }
+
+    assert_eq!(resumed, vec![ false, true ]);
+}
+
+#[test]
+fn client_rejects_non_empty_renegotiation_info() {
+    let mut client = ClientSession::new(&Arc::new(make_tls12_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    // Claim a previous handshake, with one byte of verify_data.
+    let mut flight = take_flight(&mut server);
+    let ri_at = find_empty_renegotiation_info(&flight).unwrap();
+    write_u16_at(&mut flight, ri_at + 2, 2);
+    flight[ri_at + 4] = 0x01;
+    flight.insert(ri_at + 5, 0xaa);
+
+    let exts_at = server_hello_extensions_at(&flight, false);
+    grow_hello(&mut flight, 1, Some(exts_at));
+
+    client.read_tls(&mut flight.as_slice()).unwrap();
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("server sent non-empty renegotiation info"
+                                                 .to_string())));
+}
+
+/// A TLS1.2 ClientHello record with a renegotiation_info extension
+/// carrying `renegotiated_connection`.
+fn client_hello_with_renegotiation_info(renegotiated_connection: &[u8]) -> Vec<u8> {
+    let mut client = ClientSession::new(&Arc::new(make_tls12_client_config()), "localhost");
+    let mut hello = take_flight(&mut client);
+
+    let exts_at = client_hello_extensions_at(&hello);
+
+    let body_len = 1 + renegotiated_connection.len();
+    hello.extend_from_slice(&[ 0xff, 0x01, 0x00, body_len as u8 ]);
+    hello.push(renegotiated_connection.len() as u8);
+    hello.extend_from_slice(renegotiated_connection);
+
+    grow_hello(&mut hello, 4 + body_len, Some(exts_at));
+    hello
+}
+
+#[test]
+fn server_accepts_empty_renegotiation_info() {
+    let hello = client_hello_with_renegotiation_info(&[]);
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    server.read_tls(&mut hello.as_slice()).unwrap();
+    assert_eq!(server.process_new_packets(), Ok(()));
+    assert!(find_empty_renegotiation_info(&take_flight(&mut server)).is_some());
+}
+
+#[test]
+fn server_rejects_non_empty_renegotiation_info() {
+    let hello = client_hello_with_renegotiation_info(&[ 0xaa ]);
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    server.read_tls(&mut hello.as_slice()).unwrap();
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("client sent non-empty renegotiation info"
+                                                 .to_string())));
+}
+
+#[test]
+fn false_start_data_arrives_once() {
+    let mut client_config = make_tls12_client_config();
+    let mut server_config = make_server_config();
+    client_config.enable_false_start = true;
+    client_config.alpn_protocols = vec!["h2".to_string()];
+    server_config.alpn_protocols = vec!["h2".to_string()];
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    client.write(b"hello").unwrap();
+    do_handshake(&mut client, &mut server);
+    client.write(b"world").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"helloworld");
+}
+
+#[test]
+fn tls12_ec_point_formats_exchanged() {
+    let mut client = ClientSession::new(&Arc::new(make_tls12_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    assert!(contains_bytes(&hello, EC_POINTS_UNCOMPRESSED));
+    server.read_tls(&mut hello.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+
+    let mut flight = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut flight).unwrap();
+    }
+    assert!(contains_bytes(&flight, EC_POINTS_UNCOMPRESSED));
+    client.read_tls(&mut flight.as_slice()).unwrap();
+    client.process_new_packets().unwrap();
+
+    do_handshake(&mut client, &mut server);
+}
+
+#[test]
+fn client_rejects_server_with_only_compressed_points() {
+    let mut client = ClientSession::new(&Arc::new(make_tls12_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let mut flight = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut flight).unwrap();
+    }
+
+    // Swap uncompressed for ansiX962_compressed_prime.
+    let offs = flight.windows(EC_POINTS_UNCOMPRESSED.len())
+        .position(|w| w == EC_POINTS_UNCOMPRESSED)
+        .unwrap();
+    flight[offs + EC_POINTS_UNCOMPRESSED.len() - 1] = 0x01;
+
+    client.read_tls(&mut flight.as_slice()).unwrap();
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("server does not support uncompressed points"
+                                                 .to_string())));
+
+    let mut alert = Vec::new();
+    while client.wants_write() {
+        client.write_tls(&mut alert).unwrap();
+    }
+    assert!(alert.ends_with(ILLEGAL_PARAMETER_ALERT));
+}
+
+#[test]
+fn server_rejects_client_with_only_compressed_points() {
+    let mut client_config = make_tls12_client_config();
+    client_config.set_client_hello_callback(|hello| {
+        for ext in hello.extensions.iter_mut() {
+            if let ClientExtension::ECPointFormats(ref mut formats) = *ext {
+                *formats = vec![ ECPointFormat::ANSIX962CompressedPrime ];
+            }
+        }
+    });
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerIncompatibleError("client didn't support uncompressed ec \
+                                                    points"
+                                                   .to_string())));
+
+    let mut alert = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut alert).unwrap();
+    }
+    assert_eq!(alert, ILLEGAL_PARAMETER_ALERT.to_vec());
+}
+
+fn offer_only_ed25519(client_config: ClientConfig, expect: &str) {
+    let mut client_config = client_config;
+    client_config.set_client_hello_callback(|hello| {
+        for ext in hello.extensions.iter_mut() {
+            if let ClientExtension::SignatureAlgorithms(ref mut schemes) = *ext {
+                *schemes = vec![ SignatureScheme::ED25519 ];
+            }
+        }
+    });
+
+    // Our server only has an RSA key.
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerIncompatibleError(expect.to_string())));
+
+    // We don't get as far as a ServerHello.
+    assert_eq!(take_flight(&mut server), HANDSHAKE_FAILURE_ALERT.to_vec());
+}
+
+#[test]
+fn server_rejects_client_with_no_usable_sigscheme() {
+    offer_only_ed25519(make_client_config(), "no overlapping sigschemes");
+}
+
+#[test]
+fn server_rejects_client_with_no_usable_sigscheme_tls12() {
+    offer_only_ed25519(make_tls12_client_config(), "no supported sig scheme");
+}
+
+#[test]
+fn tls12_rejects_appdata_during_handshake() {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ ProtocolVersion::TLSv1_2 ];
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config());
+
+    // Server, while waiting for the client's second flight.
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_rejects_appdata(&mut server, ContentType::Handshake);
+    check_sends_unexpected_message(&mut server);
+
+    // Client, while waiting for the server's ChangeCipherSpec.
+    // Its alert is encrypted, so we can't look for it.
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_rejects_appdata(&mut client, ContentType::ChangeCipherSpec);
+}
+
+#[test]
+fn tls13_rejects_appdata_during_handshake() {
+    let client_config = Arc::new(make_client_config());
+
+    // Client, while waiting for the ServerHello.
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    transfer(&mut client, &mut server);
+    check_rejects_appdata(&mut client, ContentType::Handshake);
+    check_sends_unexpected_message(&mut client);
+
+    // Server, while waiting for a ClientHello after a retry request.
+    let mut server_config = make_server_config();
+    server_config.kx_groups = vec![ NamedGroup::secp256r1 ];
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert_eq!(server.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+    assert!(server.wants_write());
+    transfer(&mut server, &mut client);
+    check_rejects_appdata(&mut server, ContentType::Handshake);
+    check_sends_unexpected_message(&mut server);
+}
+
+#[test]
+fn is_handshaking_tls13() {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    assert_eq!((client.is_handshaking(), server.is_handshaking()), (true, true));
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert_eq!((client.is_handshaking(), server.is_handshaking()), (true, true));
+
+    // Client verifies server Finished and sends its own.
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert_eq!((client.is_handshaking(), server.is_handshaking()), (false, true));
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert_eq!((client.is_handshaking(), server.is_handshaking()), (false, false));
+}
+
+#[test]
+fn is_handshaking_tls12() {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ ProtocolVersion::TLSv1_2 ];
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    assert_eq!((client.is_handshaking(), server.is_handshaking()), (true, true));
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert_eq!((client.is_handshaking(), server.is_handshaking()), (true, true));
+
+    // Server verifies client Finished and sends its own.
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert_eq!((client.is_handshaking(), server.is_handshaking()), (true, false));
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert_eq!((client.is_handshaking(), server.is_handshaking()), (false, false));
+
+    // And it stays that way.
+    client.write(b"hello").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert_eq!((client.is_handshaking(), server.is_handshaking()), (false, false));
+}
+
+static OFFERED_SIGSCHEMES: &'static [SignatureScheme] = &[
+    SignatureScheme::RSA_PSS_SHA256,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::RSA_PKCS1_SHA256,
+];
+
+fn offer_few_sigschemes(hello: &mut ClientHelloPayload) {
+    for ext in hello.extensions.iter_mut() {
+        if let ClientExtension::SignatureAlgorithms(ref mut schemes) = *ext {
+            *schemes = OFFERED_SIGSCHEMES.to_vec();
+        }
+    }
+}
+
+#[test]
+fn server_gets_peer_signature_schemes() {
+    let mut client_config = make_client_config();
+    client_config.set_client_hello_callback(offer_few_sigschemes);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    assert_eq!(server.get_peer_signature_schemes(), None);
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_peer_signature_schemes(), Some(OFFERED_SIGSCHEMES));
+    assert_eq!(client.get_peer_signature_schemes(), None);
+}
+
+#[test]
+fn client_gets_peer_signature_schemes() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    server_config.set_client_auth_roots(get_chain(), true);
+    client_config.set_single_client_cert(get_chain(), get_key());
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_peer_signature_schemes(),
+               Some(SupportedSignatureSchemes::supported_verify().as_slice()));
+}
+
+#[test]
+fn server_session_reset() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    server_config.set_client_auth_roots(get_chain(), true);
+    server_config.alpn_protocols = vec![ "proto".to_string() ];
+    client_config.set_single_client_cert(get_chain(), get_key());
+    client_config.alpn_protocols = vec![ "proto".to_string() ];
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_peer_certificates(), Some(get_chain()));
+    assert_eq!(server.get_alpn_protocol(), Some("proto".to_string()));
+
+    // Leave data in both directions.
+    server.write(b"unsent").unwrap();
+    client.write(b"unread").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    server.reset(&Arc::new(make_server_config()));
+    assert!(server.is_handshaking());
+    assert!(!server.wants_write());
+    assert_eq!(server.get_protocol_version(), None);
+    assert_eq!(server.get_alpn_protocol(), None);
+    assert_eq!(server.get_peer_certificates(), None);
+    assert_eq!(server.get_peer_signature_schemes(), None);
+    assert_eq!(server.get_sent_certificates(), None);
+
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_peer_certificates(), None);
+    assert_eq!(server.get_alpn_protocol(), None);
+
+    client.write(b"hello").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+
+    server.write(b"world").unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client, b"world");
+}
+
+#[test]
+fn server_can_get_client_cert() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    server_config.set_client_auth_roots(get_chain(), true);
+    client_config.set_single_client_cert(get_chain(), get_key());
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    do_handshake(&mut client, &mut server);
+
+    let certs = server.get_peer_certificates();
+    assert_eq!(certs, Some(get_chain()));
+}
+
+#[test]
+fn server_post_handshake_client_auth() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    // Authenticate the client only after the handshake.
+    server_config.set_client_auth_roots(get_chain(), true);
+    server_config.client_auth_offer = false;
+    client_config.set_single_client_cert(get_chain(), get_key());
+    client_config.enable_post_handshake_auth = true;
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_peer_certificates(), None);
+
+    server.request_client_auth().unwrap();
+    assert_eq!(server.is_authenticating_client(), true);
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    assert_eq!(server.is_authenticating_client(), false);
+    assert_eq!(server.get_peer_certificates(), Some(get_chain()));
+
+    client.write(b"hello").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+}
+
+/// Signs with our RSA key, but claims a scheme nobody asks for.
+struct WrongSchemeSigner(sign::RSASigner);
+
+impl sign::Signer for WrongSchemeSigner {
+    fn choose_scheme(&self, _offered: &[SignatureScheme]) -> Option<SignatureScheme> {
+        Some(SignatureScheme::ECDSA_NISTP521_SHA512)
+    }
+
+    fn sign(&self,
+            _scheme: SignatureScheme,
+            message: &[u8],
+            rng: &SecureRandom)
+            -> Result<Vec<u8>, ()> {
+        self.0.sign(SignatureScheme::RSA_PSS_SHA256, message, rng)
+    }
+
+    fn algorithm(&self) -> rustls::internal::msgs::enums::SignatureAlgorithm {
+        self.0.algorithm()
+    }
+}
+
+#[test]
+fn server_post_handshake_client_auth_rejects_unrequested_scheme() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    server_config.set_client_auth_roots(get_chain(), true);
+    server_config.client_auth_offer = false;
+    let signer = WrongSchemeSigner(sign::RSASigner::new(&get_key()).unwrap());
+    client_config.client_auth_cert_resolver =
+        Box::new(ResolvesWithSigner { signer: Arc::new(Box::new(signer)) });
+    client_config.enable_post_handshake_auth = true;
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+
+    server.request_client_auth().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("client used unrequested \
+                                                  post-handshake sigscheme".to_string())));
+
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::IllegalParameter)));
+}
+
+#[test]
+fn server_post_handshake_client_auth_needs_client_support() {
+    let client_config = make_client_config();
+    let mut server_config = make_server_config();
+    server_config.set_client_auth_roots(get_chain(), true);
+    server_config.client_auth_offer = false;
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    assert!(server.request_client_auth().is_err());
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(server.request_client_auth(),
+               Err(TLSError::PeerIncompatibleError("client didn't offer post-handshake \
+                                                    auth".to_string())));
+    assert_eq!(server.is_authenticating_client(), false);
+}
+
+fn check_read_and_close(reader: &mut io::Read, expect: &[u8]) {
+    let mut buf = Vec::new();
+    buf.resize(expect.len(), 0u8);
+    assert_eq!(expect.len(), reader.read(&mut buf).unwrap());
+    assert_eq!(expect.to_vec(), buf);
+
+    let err = reader.read(&mut buf);
+    assert!(err.is_err());
+    assert_eq!(err.err().unwrap().kind(), io::ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn pending_write_len_counts_queued_records() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    // The ClientHello is queued as soon as the session is made.
+    let hello_len = client.pending_write_len();
+    assert!(hello_len > 0);
+    let mut buf = Vec::new();
+    while client.wants_write() {
+        client.write_tls(&mut buf).unwrap();
+    }
+    assert_eq!(buf.len(), hello_len);
+    assert_eq!(client.pending_write_len(), 0);
+    server.read_tls(&mut buf.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.pending_write_len(), 0);
+
+    client.write(b"hello").unwrap();
+    let after_one = client.pending_write_len();
+    assert!(after_one > 5);
+
+    client.write(b"world").unwrap();
+    let after_two = client.pending_write_len();
+    assert!(after_two > after_one);
+
+    let mut buf = Vec::new();
+    while client.wants_write() {
+        client.write_tls(&mut buf).unwrap();
+    }
+    assert_eq!(buf.len(), after_two);
+    assert_eq!(client.pending_write_len(), 0);
+}
+
+/// The secrets both sides of a handshake with `client_config` give
+/// out, with the client's ClientHello record and the first
+/// application data records the client and server then send.
+#[cfg(feature = "dangerous_debug")]
+fn extract_secrets_test(mut client_config: ClientConfig,
+                        suite: CipherSuite) -> (ExtractedSecrets, Vec<u8>, Vec<u8>, Vec<u8>) {
+    client_config.ciphersuites.retain(|scs| scs.suite == suite);
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    assert_eq!(client.dangerous_extract_secrets(), None);
+    assert_eq!(server.dangerous_extract_secrets(), None);
+
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    server.read_tls(&mut hello.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+    do_handshake(&mut client, &mut server);
+
+    let secrets = client.dangerous_extract_secrets().unwrap();
+    assert_eq!(server.dangerous_extract_secrets(), Some(secrets.clone()));
+
+    client.write(b"hello").unwrap();
+    server.write(b"world").unwrap();
+    let client_record = take_flight(&mut client);
+    let server_record = take_flight(&mut server);
+    (secrets, hello, client_record, server_record)
+}
+
+/// Open the AES-128-GCM protected `record`, checking it is the only
+/// record there.
+#[cfg(feature = "dangerous_debug")]
+fn open_aes128gcm_record(key: &[u8], nonce: &[u8], aad: &[u8], record: &[u8],
+                         explicit_nonce_len: usize) -> Vec<u8> {
+    use ring::aead;
+
+    assert_eq!(record.len(), 5 + read_u16_at(record, 3));
+    let key = aead::OpeningKey::new(&aead::AES_128_GCM, key).unwrap();
+    let mut buf = record[5 + explicit_nonce_len..].to_vec();
+    let plain_len = aead::open_in_place(&key, nonce, aad, 0, &mut buf).unwrap().len();
+    buf.truncate(plain_len);
+    buf
+}
+
+/// TLS1.3 HKDF-Expand-Label with SHA256 and an empty context.
+#[cfg(feature = "dangerous_debug")]
+fn tls13_expand_label(secret: &[u8], label: &[u8], len: usize) -> Vec<u8> {
+    use ring::{digest, hkdf, hmac};
+
+    let full_label = [ &b"TLS 1.3, "[..], label ].concat();
+    let mut info = vec![ (len >> 8) as u8, len as u8, full_label.len() as u8 ];
+    info.extend_from_slice(&full_label);
+    info.push(0);
+
+    let mut out = vec![ 0u8; len ];
+    hkdf::expand(&hmac::SigningKey::new(&digest::SHA256, secret), &info, &mut out);
+    out
+}
+
+/// Open the first TLS1.3 application data `record` sent under
+/// `traffic_secret`.
+#[cfg(feature = "dangerous_debug")]
+fn open_first_tls13_record(traffic_secret: &[u8], record: &[u8]) -> Vec<u8> {
+    let key = tls13_expand_label(traffic_secret, b"key", 16);
+    let iv = tls13_expand_label(traffic_secret, b"iv", 12);
+
+    // Sequence number zero leaves the iv as the nonce.
+    let mut plain = open_aes128gcm_record(&key, &iv, &[], record, 0);
+    assert_eq!(plain.pop(), Some(0x17));
+    plain
+}
+
+#[cfg(feature = "dangerous_debug")]
+#[test]
+fn dangerous_extract_secrets_tls13() {
+    let (secrets, _, client_record, server_record) =
+        extract_secrets_test(make_client_config(), CipherSuite::TLS13_AES_128_GCM_SHA256);
+    match secrets {
+        ExtractedSecrets::TLS13 { client_traffic_secret, server_traffic_secret } => {
+            assert_eq!(open_first_tls13_record(&client_traffic_secret, &client_record),
+                       b"hello".to_vec());
+            assert_eq!(open_first_tls13_record(&server_traffic_secret, &server_record),
+                       b"world".to_vec());
+        }
+        _ => panic!("expected TLS1.3 secrets"),
+    }
+}
+
+#[cfg(feature = "dangerous_debug")]
+#[test]
+fn dangerous_extract_secrets_tls12() {
+    use ring::{digest, hmac};
+
+    let (secrets, hello, client_record, server_record) =
+        extract_secrets_test(make_tls12_client_config(),
+                             CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256);
+    let (client_random, server_random, master_secret) = match secrets {
+        ExtractedSecrets::TLS12 { client_random, server_random, master_secret } => {
+            (client_random, server_random, master_secret)
+        }
+        _ => panic!("expected TLS1.2 secrets"),
+    };
+    assert_eq!(&client_random[..], &hello[HELLO_RANDOM_AT..HELLO_SESSION_ID_AT]);
+
+    // The key block, by the TLS1.2 PRF with SHA256: two keys, then
+    // two implicit nonces.
+    let seed = [ &b"key expansion"[..], &server_random[..], &client_random[..] ].concat();
+    let hmac_key = hmac::SigningKey::new(&digest::SHA256, &master_secret);
+    let mut key_block = Vec::new();
+    let mut a = hmac::sign(&hmac_key, &seed);
+    while key_block.len() < 2 * 16 + 2 * 4 {
+        let term = hmac::sign(&hmac_key, &[ a.as_ref(), &seed[..] ].concat());
+        key_block.extend_from_slice(term.as_ref());
+        a = hmac::sign(&hmac_key, a.as_ref());
+    }
+
+    // Each side's Finished went first, so these have sequence number one.
+    let open = |key: &[u8], salt: &[u8], record: &[u8]| {
+        let nonce = [ salt, &record[5..5 + 8] ].concat();
+        let aad = [ &[ 0u8, 0, 0, 0, 0, 0, 0, 1 ][..], &record[..3], &[ 0u8, 5 ][..] ].concat();
+        open_aes128gcm_record(key, &nonce, &aad, record, 8)
+    };
+    assert_eq!(open(&key_block[0..16], &key_block[32..36], &client_record),
+               b"hello".to_vec());
+    assert_eq!(open(&key_block[16..32], &key_block[36..40], &server_record),
+               b"world".to_vec());
+}
+
+fn client_auth_error_test(client_config: ClientConfig,
+                          server_config: ServerConfig,
+                          server_err: TLSError,
+                          client_err: TLSError) {
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    assert_eq!(do_handshake_until_error(&mut client, &mut server), Err(server_err));
+
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(), Err(client_err));
+}
+
+#[test]
+fn server_requires_client_cert() {
+    let mut server_config = make_server_config();
+    server_config.set_client_auth_roots(get_chain(), true);
+
+    client_auth_error_test(make_client_config(),
+                           server_config,
+                           TLSError::NoCertificatesPresented,
+                           TLSError::AlertReceived(AlertDescription::CertificateRequired));
+}
+
+#[test]
+fn server_requires_client_cert_tls12() {
+    let mut server_config = make_server_config();
+    server_config.set_client_auth_roots(get_chain(), true);
+
+    client_auth_error_test(make_tls12_client_config(),
+                           server_config,
+                           TLSError::NoCertificatesPresented,
+                           TLSError::AlertReceived(AlertDescription::HandshakeFailure));
+}
+
+#[test]
+fn server_rejects_untrusted_client_cert() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+    client_config.set_single_client_cert(get_chain(), get_key());
+    server_config.set_client_auth_roots(read_certs("test-ca/ecdsa/ca.cert"), true);
+
+    client_auth_error_test(client_config,
+                           server_config,
+                           TLSError::WebPKIError(webpki::Error::UnknownIssuer),
+                           TLSError::AlertReceived(AlertDescription::BadCertificate));
+}
+
+#[test]
+fn server_rejects_untrusted_client_cert_tls12() {
+    let mut client_config = make_tls12_client_config();
+    let mut server_config = make_server_config();
+    client_config.set_single_client_cert(get_chain(), get_key());
+    server_config.set_client_auth_roots(read_certs("test-ca/ecdsa/ca.cert"), true);
+
+    client_auth_error_test(client_config,
+                           server_config,
+                           TLSError::WebPKIError(webpki::Error::UnknownIssuer),
+                           TLSError::AlertReceived(AlertDescription::HandshakeFailure));
+}
+
+#[test]
+fn server_close_notify() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    server_config.set_client_auth_roots(get_chain(), true);
+    client_config.set_single_client_cert(get_chain(), get_key());
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    do_handshake(&mut client, &mut server);
+
+    // check that alerts don't overtake appdata
+    assert_eq!(12, server.write(b"from-server!").unwrap());
+    assert_eq!(12, client.write(b"from-client!").unwrap());
+    server.send_close_notify();
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_read_and_close(&mut client, b"from-server!");
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"from-client!");
+}
+
+#[test]
+fn client_close_notify() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    server_config.set_client_auth_roots(get_chain(), true);
+    client_config.set_single_client_cert(get_chain(), get_key());
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    do_handshake(&mut client, &mut server);
+
+    // check that alerts don't overtake appdata
+    assert_eq!(12, server.write(b"from-server!").unwrap());
+    assert_eq!(12, client.write(b"from-client!").unwrap());
+    client.send_close_notify();
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read_and_close(&mut server, b"from-client!");
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client, b"from-server!");
+}
+
+#[test]
+fn server_sends_fatal_alert() {
+    let client_config = make_client_config();
+    let server_config = make_server_config();
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    do_handshake(&mut client, &mut server);
+
+    server.send_fatal_alert(AlertDescription::AccessDenied);
+    assert!(server.process_new_packets().is_err());
+
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::AccessDenied)));
+
+    // neither side does any further work.
+    assert_eq!(5, client.write(b"hello").unwrap());
+    transfer(&mut client, &mut server);
+    assert!(server.process_new_packets().is_err());
+    assert!(client.process_new_packets().is_err());
+}
+
+#[test]
+fn server_observes_warning_alerts() {
+    let client_config = make_tls12_client_config();
+    let server_config = make_server_config();
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    // A plaintext user_canceled warning, ahead of the handshake.
+    let warning = [ 0x15, 0x03, 0x03, 0x00, 0x02, 0x01, 0x5a ];
+    server.read_tls(&mut &warning[..]).unwrap();
+    server.process_new_packets().unwrap();
+
+    assert_eq!(server.take_received_warnings(), vec![ AlertDescription::UserCanceled ]);
+    assert_eq!(server.take_received_warnings(), vec![]);
+
+    // The session carries on regardless.
+    do_handshake(&mut client, &mut server);
+    client.write(b"hello").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+    assert_eq!(client.take_received_warnings(), vec![]);
+}
+
+#[test]
+fn server_rejects_warning_alert_flood() {
+    let mut server_config = make_server_config();
+    server_config.max_warning_alerts = 3;
+    let server_config = Arc::new(server_config);
+    let warning = [ 0x15, 0x03, 0x03, 0x00, 0x02, 0x01, 0x5a ];
+
+    // A handshake message between warnings resets the count.
+    let mut client = ClientSession::new(&Arc::new(make_tls12_client_config()), "localhost");
+    let mut server = ServerSession::new(&server_config);
+    for _ in 0..3 {
+        server.read_tls(&mut &warning[..]).unwrap();
+        server.process_new_packets().unwrap();
+    }
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    for _ in 0..3 {
+        server.read_tls(&mut &warning[..]).unwrap();
+        server.process_new_packets().unwrap();
+    }
+
+    // But one too many in a row is fatal.
+    let mut server = ServerSession::new(&server_config);
+    for _ in 0..3 {
+        server.read_tls(&mut &warning[..]).unwrap();
+        server.process_new_packets().unwrap();
+    }
+    server.read_tls(&mut &warning[..]).unwrap();
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("too many warning alerts in a row"
+                                                 .to_string())));
+
+    let mut client = ClientSession::new(&Arc::new(make_tls12_client_config()), "localhost");
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::UnexpectedMessage)));
+}
+
+#[test]
+fn server_processes_only_whole_records() {
+    let client_config = make_client_config();
+    let server_config = make_server_config();
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    let (first, second) = hello.split_at(hello.len() / 2);
+
+    assert_eq!(first.len(), server.read_tls(&mut first.as_ref()).unwrap());
+    server.process_new_packets().unwrap();
+    assert_eq!(server.wants_write(), false);
+
+    assert_eq!(second.len(), server.read_tls(&mut second.as_ref()).unwrap());
+    server.process_new_packets().unwrap();
+    assert_eq!(server.wants_write(), true);
+}
+
+/// Split the single TLS record in `record` into two records,
+/// the first carrying `first` bytes of the payload.
+fn split_record(record: &[u8], first: usize) -> (Vec<u8>, Vec<u8>) {
+    let (header, body) = record.split_at(5);
+    assert_eq!(body.len(), read_u16_at(header, 3));
+
+    let make = |payload: &[u8]| {
+        let mut r = header[..3].to_vec();
+        r.push((payload.len() >> 8) as u8);
+        r.push(payload.len() as u8);
+        r.extend_from_slice(payload);
+        r
+    };
+
+    (make(&body[..first]), make(&body[first..]))
+}
+
+#[test]
+fn server_joins_fragmented_client_hello() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    let (first, second) = split_record(&hello, 32);
+
+    server.read_tls(&mut first.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+    assert_eq!(server.wants_write(), false);
+
+    server.read_tls(&mut second.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+    assert_eq!(server.wants_write(), true);
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+}
+
+#[test]
+fn server_rejects_interleaved_client_hello() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    let (first, second) = split_record(&hello, 32);
+    let ccs = [ 0x14, 0x03, 0x03, 0x00, 0x01, 0x01 ];
+
+    server.read_tls(&mut first.as_slice()).unwrap();
+    server.read_tls(&mut &ccs[..]).unwrap();
+    server.read_tls(&mut second.as_slice()).unwrap();
+
+    let err = server.process_new_packets();
+    assert_eq!(err,
+               Err(TLSError::PeerMisbehavedError("handshake message interleaved with other \
+                                                  content".to_string())));
+}
+
+#[test]
+fn server_rejects_non_tls() {
+    let server_config = make_server_config();
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let junk = b"GET / HTTP/1.0\r\n\r\n";
+    server.read_tls(&mut junk.as_ref()).unwrap();
+    assert_eq!(server.process_new_packets(), Err(TLSError::CorruptMessage));
+    assert_eq!(server.process_new_packets(), Err(TLSError::CorruptMessage));
+}
+
+fn contains_bytes(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn add_custom_extension(hello: &mut ClientHelloPayload) {
+    hello.extensions.insert(0, ClientExtension::Unknown(UnknownExtension {
+        typ: ExtensionType::Unknown(0xfeed),
+        payload: Payload::new(b"abc".to_vec()),
+    }));
+}
+
+#[test]
+fn client_hello_callback_adds_extension() {
+    let mut client_config = make_client_config();
+    let server_config = make_server_config();
+
+    client_config.set_client_hello_callback(add_custom_extension);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    assert!(contains_bytes(&hello, &[ 0xfe, 0xed, 0x00, 0x03, b'a', b'b', b'c' ]));
+
+    server.read_tls(&mut hello.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+    do_handshake(&mut client, &mut server);
+}
+
+#[test]
+fn server_ignores_unknown_extensions_by_default() {
+    let mut client_config = make_client_config();
+    client_config.set_client_hello_callback(add_custom_extension);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+}
+
+fn offer_deflate(hello: &mut ClientHelloPayload) {
+    hello.compression_methods.push(Compression::Deflate);
+}
+
+fn server_rejects_compression_test(mut client_config: ClientConfig) {
+    client_config.set_client_hello_callback(offer_deflate);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("client offered non-Null compression"
+                                                 .to_string())));
+
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::IllegalParameter)));
+}
+
+#[test]
+fn server_rejects_compression() {
+    server_rejects_compression_test(make_client_config());
+}
+
+#[test]
+fn server_rejects_compression_tls12() {
+    server_rejects_compression_test(make_tls12_client_config());
+}
+
+fn client_hello_for(client_config: ClientConfig, dns_name: &str) -> Vec<u8> {
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name);
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    hello
+}
+
+fn remove_sni(hello: &mut ClientHelloPayload) {
+    hello.extensions.retain(|ext| ext.get_type() != ExtensionType::ServerName);
+}
+
+fn missing_sni_config(policy: MissingSNIPolicy) -> (ClientConfig, ServerConfig) {
+    let mut client_config = make_client_config();
+    client_config.set_client_hello_callback(remove_sni);
+    let mut server_config = make_server_config();
+    server_config.missing_sni_policy = policy;
+    (client_config, server_config)
+}
+
+#[test]
+fn server_rejects_missing_sni() {
+    let (client_config, server_config) = missing_sni_config(MissingSNIPolicy::Reject);
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerIncompatibleError("client did not send SNI".to_string())));
+
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::UnrecognisedName)));
+}
+
+#[test]
+fn server_serves_default_cert_for_missing_sni() {
+    // Leave out the root, so we can tell this chain from the usual one.
+    let mut chain = get_chain();
+    chain.truncate(2);
+    let signer = sign::RSASigner::new(&get_key()).unwrap();
+    let default = (chain.clone(), Arc::new(Box::new(signer) as Box<sign::Signer>));
+
+    let policy = MissingSNIPolicy::ServeDefault(default);
+    let (client_config, server_config) = missing_sni_config(policy);
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_peer_certificates(), Some(chain));
+
+    // Clients sending SNI are unaffected by the policy.
+    let mut server_config = make_server_config();
+    server_config.missing_sni_policy = MissingSNIPolicy::Reject;
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_peer_certificates(), Some(get_chain()));
+}
+
+#[test]
+fn read_sni_from_single_record() {
+    let hello = client_hello_for(make_client_config(), "the-sni-name");
+    assert_eq!(read_sni(&hello), Ok(Some("the-sni-name".to_string())));
+}
+
+#[test]
+fn read_sni_from_split_records() {
+    let hello = client_hello_for(make_client_config(), "the-sni-name");
+
+    // Split inside the handshake header, and well within the body.
+    for first_len in &[ 2, 40, hello.len() - 6 ] {
+        let (mut split, second) = split_record(&hello, *first_len);
+        split.extend(second);
+        assert_eq!(read_sni(&split), Ok(Some("the-sni-name".to_string())));
+    }
+}
+
+#[test]
+fn read_sni_without_sni() {
+    let mut client_config = make_client_config();
+    client_config.set_client_hello_callback(remove_sni);
+
+    let hello = client_hello_for(client_config, "the-sni-name");
+    assert_eq!(read_sni(&hello), Ok(None));
+}
+
+#[test]
+fn read_sni_needs_whole_client_hello() {
+    let hello = client_hello_for(make_client_config(), "the-sni-name");
+    assert_eq!(read_sni(&hello[..hello.len() - 1]), Err(()));
+    assert_eq!(read_sni(&[]), Err(()));
+    assert_eq!(read_sni(b"GET / HTTP/1.1\r\n\r\n"), Err(()));
+}
+
+#[test]
+fn server_can_reject_unknown_extensions() {
+    let mut server_config = make_server_config();
+    server_config.reject_unknown_extensions = true;
+    let server_config = Arc::new(server_config);
+
+    // Our own ClientHellos have only known extensions.
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+
+    let mut client_config = make_client_config();
+    client_config.set_client_hello_callback(add_custom_extension);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&server_config);
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("client sent unknown extension".to_string())));
+
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::UnsupportedExtension)));
+}
+
+#[test]
+fn client_hello_callback_cannot_break_hello() {
+    let mut client_config = make_client_config();
+    let server_config = make_server_config();
+
+    client_config.set_client_hello_callback(|hello| {
+        add_custom_extension(hello);
+        add_custom_extension(hello);
+    });
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    assert!(!contains_bytes(&hello, &[ 0xfe, 0xed, 0x00, 0x03, b'a', b'b', b'c' ]));
+
+    server.read_tls(&mut hello.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+    do_handshake(&mut client, &mut server);
+}
+
+/// Sprinkle GREASE values (RFC 8701) through `hello`, as
+/// Chrome does.
+fn add_grease(hello: &mut ClientHelloPayload) {
+    hello.cipher_suites.insert(0, CipherSuite::Unknown(0x0a0a));
+
+    for ext in hello.extensions.iter_mut() {
+        match *ext {
+            ClientExtension::NamedGroups(ref mut groups) => {
+                groups.insert(0, NamedGroup::Unknown(0x1a1a));
+            }
+            ClientExtension::SignatureAlgorithms(ref mut schemes) => {
+                schemes.insert(0, SignatureScheme::Unknown(0x2a2a));
+            }
+            ClientExtension::SupportedVersions(ref mut versions) => {
+                versions.insert(0, ProtocolVersion::Unknown(0x3a3a));
+            }
+            ClientExtension::KeyShare(ref mut shares) => {
+                shares.insert(0, KeyShareEntry {
+                    group: NamedGroup::Unknown(0x1a1a),
+                    payload: PayloadU16::new(vec![ 0 ]),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    hello.extensions.insert(0, ClientExtension::Unknown(UnknownExtension {
+        typ: ExtensionType::Unknown(0x4a4a),
+        payload: Payload::new(Vec::new()),
+    }));
+}
+
+#[test]
+fn server_ignores_grease() {
+    let mut client_config = make_client_config();
+    let server_config = make_server_config();
+
+    client_config.set_client_hello_callback(add_grease);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    assert!(contains_bytes(&hello, &[ 0x4a, 0x4a, 0x00, 0x00 ]));
+
+    server.read_tls(&mut hello.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(server.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+    client.write(b"hello").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+}
+
+struct ServerCheckCertResolve {
+    expected: String
+}
+
+impl ServerCheckCertResolve {
+    fn new(expect: &str) -> ServerCheckCertResolve {
+        ServerCheckCertResolve {
+            expected: expect.to_string()
+        }
+    }
+}
+
+impl ResolvesServerCert for ServerCheckCertResolve {
+    fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertChainAndSigner> {
+        if let Some(got_dns_name) = client_hello.server_name() {
+            if got_dns_name != self.expected {
+                panic!("unexpected dns name (wanted '{}' got '{}')", &self.expected, got_dns_name);
+            }
+        } else {
+            panic!("dns name not provided (wanted '{}')", &self.expected);
+        }
+
+        if client_hello.sigschemes().len() == 0 {
+            panic!("no signature schemes shared by client");
+        }
+
+        None
+    }
+}
+
+#[test]
+fn server_cert_resolve_with_sni() {
+    let client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    server_config.cert_resolver = Box::new(ServerCheckCertResolve::new("the-value-from-sni"));
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "the-value-from-sni");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err.is_err(), true);
+}
+
+#[test]
+fn server_rejects_sni_with_nul() {
+    let client_config = make_client_config();
+    let server_config = make_server_config();
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "a.com\0b.com");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::CorruptMessagePayload(ContentType::Handshake)));
+}
+
+/// Signs using a real key, but records what it was asked to do,
+/// like a signer for a key held elsewhere might.
+struct RecordingSigner {
+    key: sign::RSASigner,
+    signed: Arc<Mutex<Vec<(SignatureScheme, Vec<u8>)>>>,
+}
+
+impl sign::Signer for RecordingSigner {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<SignatureScheme> {
+        self.key.choose_scheme(offered)
+    }
+
+    fn sign(&self,
+            scheme: SignatureScheme,
+            message: &[u8],
+            rng: &SecureRandom)
+            -> Result<Vec<u8>, ()> {
+        self.signed.lock().unwrap().push((scheme, message.to_vec()));
+        self.key.sign(scheme, message, rng)
+    }
+
+    fn algorithm(&self) -> rustls::internal::msgs::enums::SignatureAlgorithm {
+        self.key.algorithm()
+    }
+}
+
+fn external_signer_test(version: ProtocolVersion) {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ version ];
+
+    let signed = Arc::new(Mutex::new(Vec::new()));
+    let signer = RecordingSigner {
+        key: sign::RSASigner::new(&get_key()).unwrap(),
+        signed: signed.clone(),
+    };
+
+    let mut server_config = ServerConfig::new();
+    server_config.set_single_cert_with_signer(get_chain(), Box::new(signer));
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    do_handshake(&mut client, &mut server);
+
+    let signed = signed.lock().unwrap();
+    assert_eq!(signed.len(), 1);
+    let (scheme, ref message) = signed[0];
+
+    if version == ProtocolVersion::TLSv1_3 {
+        assert_eq!(scheme, SignatureScheme::RSA_PSS_SHA512);
+        assert!(contains_bytes(message, b"TLS 1.3, server CertificateVerify\x00"));
+    } else {
+        // The suite decides the hash.
+        let rsa_schemes = [ SignatureScheme::RSA_PKCS1_SHA256,
+                            SignatureScheme::RSA_PKCS1_SHA384,
+                            SignatureScheme::RSA_PKCS1_SHA512 ];
+        assert!(rsa_schemes.contains(&scheme));
+
+        // Client random, server random, then the ECDHE parameters.
+        assert!(message.len() > 64);
+    }
+}
+
+#[test]
+fn server_uses_external_signer() {
+    external_signer_test(ProtocolVersion::TLSv1_3);
+    external_signer_test(ProtocolVersion::TLSv1_2);
+}
+
+/// Signs using a real key, but alters each message first.
+struct TamperingSigner {
+    key: sign::RSASigner,
+    tamper: fn(&mut Vec<u8>),
+}
+
+impl sign::Signer for TamperingSigner {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<SignatureScheme> {
+        self.key.choose_scheme(offered)
+    }
+
+    fn sign(&self,
+            scheme: SignatureScheme,
+            message: &[u8],
+            rng: &SecureRandom)
+            -> Result<Vec<u8>, ()> {
+        let mut message = message.to_vec();
+        (self.tamper)(&mut message);
+        self.key.sign(scheme, &message, rng)
+    }
+
+    fn algorithm(&self) -> rustls::internal::msgs::enums::SignatureAlgorithm {
+        self.key.algorithm()
+    }
+}
+
+fn tampering_signer(tamper: fn(&mut Vec<u8>)) -> Arc<Box<sign::Signer>> {
+    let signer = TamperingSigner {
+        key: sign::RSASigner::new(&get_key()).unwrap(),
+        tamper: tamper,
+    };
+    Arc::new(Box::new(signer) as Box<sign::Signer>)
+}
+
+/// Sign a TLS1.3 CertificateVerify as if we were the other peer.
+fn swap_context_role(message: &mut Vec<u8>) {
+    // The role follows 64 spaces and "TLS 1.3, ".
+    let role = &mut message[73..79];
+    let swapped = if role == &b"server"[..] { b"client" } else { b"server" };
+    role.copy_from_slice(swapped);
+}
+
+fn wrong_context_signer() -> Arc<Box<sign::Signer>> {
+    tampering_signer(swap_context_role)
+}
+
+#[test]
+fn client_rejects_certificate_verify_with_wrong_context() {
+    let mut server_config = ServerConfig::new();
+    server_config.cert_resolver = Box::new(ResolvesWithSigner { signer: wrong_context_signer() });
+
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err,
+               Err(TLSError::WebPKIError(webpki::Error::InvalidSignatureForPublicKey)));
+}
+
+#[test]
+fn server_rejects_certificate_verify_with_wrong_context() {
+    let mut client_config = make_client_config();
+    client_config.client_auth_cert_resolver =
+        Box::new(ResolvesWithSigner { signer: wrong_context_signer() });
+    let mut server_config = make_server_config();
+    server_config.set_client_auth_roots(get_chain(), true);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err,
+               Err(TLSError::WebPKIError(webpki::Error::InvalidSignatureForPublicKey)));
+}
+
+fn server_kx_signature_test(tamper: fn(&mut Vec<u8>)) -> Result<(), TLSError> {
+    let mut server_config = ServerConfig::new();
+    let signer = tampering_signer(tamper);
+    server_config.cert_resolver = Box::new(ResolvesWithSigner { signer: signer });
+
+    let mut client = ClientSession::new(&Arc::new(make_tls12_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake_until_error(&mut client, &mut server)
+}
+
+fn no_tampering(_message: &mut Vec<u8>) {}
+
+/// The ServerKeyExchange signs both randoms, then the parameters.
+fn drop_client_random(message: &mut Vec<u8>) {
+    message.drain(..32);
+}
+
+fn swap_randoms(message: &mut Vec<u8>) {
+    let client_random: Vec<u8> = message.drain(..32).collect();
+    let params = message.split_off(32);
+    message.extend_from_slice(&client_random);
+    message.extend_from_slice(&params);
+}
+
+#[test]
+fn client_verifies_server_kx_signature() {
+    assert_eq!(server_kx_signature_test(no_tampering), Ok(()));
+}
+
+#[test]
+fn client_rejects_server_kx_signature_without_client_random() {
+    assert_eq!(server_kx_signature_test(drop_client_random),
+               Err(TLSError::WebPKIError(webpki::Error::InvalidSignatureForPublicKey)));
+}
+
+#[test]
+fn client_rejects_server_kx_signature_with_swapped_randoms() {
+    assert_eq!(server_kx_signature_test(swap_randoms),
+               Err(TLSError::WebPKIError(webpki::Error::InvalidSignatureForPublicKey)));
+}
+
+/// Resolves our usual chain, for server or client, with `signer`.
+struct ResolvesWithSigner {
+    signer: Arc<Box<sign::Signer>>,
+}
+
+impl ResolvesServerCert for ResolvesWithSigner {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<sign::CertChainAndSigner> {
+        Some((get_chain(), self.signer.clone()))
+    }
+}
+
+impl ResolvesClientCert for ResolvesWithSigner {
+    fn resolve(&self,
+               _acceptable_issuers: &[&[u8]],
+               _sigschemes: &[SignatureScheme])
+               -> Option<sign::CertChainAndSigner> {
+        Some((get_chain(), self.signer.clone()))
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// Serves the full chain to "localhost", and a chain without
+/// the root to everyone else.
+struct ServerChooseCertBySNI {}
+
+impl ResolvesServerCert for ServerChooseCertBySNI {
+    fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertChainAndSigner> {
+        let mut chain = get_chain();
+        if client_hello.server_name() != Some("localhost") {
+            chain.truncate(2);
+        }
+
+        let signer = sign::RSASigner::new(&get_key()).unwrap();
+        Some((chain, Arc::new(Box::new(signer))))
+    }
+}
+
+fn sent_certificates_test(dns_name: &str, expect_len: usize) {
+    let client_config = make_client_config();
+    let mut server_config = make_server_config();
+    server_config.cert_resolver = Box::new(ServerChooseCertBySNI {});
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name);
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    assert!(server.get_sent_certificates().is_none());
+    do_handshake(&mut client, &mut server);
+
+    let sent = server.get_sent_certificates().unwrap();
+    assert_eq!(sent.len(), expect_len);
+    assert_eq!(sent, &get_chain()[..expect_len]);
+    assert_eq!(client.get_peer_certificates().unwrap(), sent.to_vec());
+}
+
+#[test]
+fn server_get_sent_certificates() {
+    sent_certificates_test("localhost", 3);
+    sent_certificates_test("testserver.com", 2);
+}
+
+/// Chooses an ECDSA certificate for clients offering ECDSA suites,
+/// and RSA otherwise.  We have only RSA signing keys, so choosing
+/// ECDSA fails the handshake.
+struct ServerChooseCertByCipherSuite {
+    chose: Arc<Mutex<Option<&'static str>>>,
+}
+
+impl ResolvesServerCert for ServerChooseCertByCipherSuite {
+    fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertChainAndSigner> {
+        let ecdsa_suites = [
+            CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+            CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+            CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+        ];
+        let offers_ecdsa = client_hello.cipher_suites()
+            .iter()
+            .any(|suite| ecdsa_suites.contains(suite));
+
+        if offers_ecdsa {
+            *self.chose.lock().unwrap() = Some("ecdsa");
+            None
+        } else {
+            *self.chose.lock().unwrap() = Some("rsa");
+            let signer = sign::RSASigner::new(&get_key()).unwrap();
+            Some((get_chain(), Arc::new(Box::new(signer))))
+        }
+    }
+}
+
+fn cert_resolve_by_suite_test(client_suites: Vec<CipherSuite>, expect: &'static str) {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    if !client_suites.is_empty() {
+        client_config.ciphersuites = rustls::ALL_CIPHERSUITES.iter()
+            .filter(|suite| client_suites.contains(&suite.suite))
+            .cloned()
+            .collect();
+        client_config.versions = vec![ ProtocolVersion::TLSv1_2 ];
+    }
+
+    let chose = Arc::new(Mutex::new(None));
+    server_config.cert_resolver = Box::new(ServerChooseCertByCipherSuite { chose: chose.clone() });
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let result = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(*chose.lock().unwrap(), Some(expect));
+    assert_eq!(result.is_ok(), expect == "rsa");
+}
+
+#[test]
+fn server_cert_resolve_by_cipher_suite() {
+    cert_resolve_by_suite_test(vec![], "ecdsa");
+    cert_resolve_by_suite_test(vec![ CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 ],
+                               "rsa");
+}
+
+/// Claims an ECDSA key, but can't sign because ring can't make
+/// ECDSA signatures.  `asked` records whether we were asked to.
+struct UnusableECDSASigner {
+    asked: Arc<atomic::AtomicBool>,
+}
+
+impl sign::Signer for UnusableECDSASigner {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<SignatureScheme> {
+        offered.iter()
+            .find(|scheme| **scheme == SignatureScheme::ECDSA_NISTP256_SHA256)
+            .cloned()
+    }
+
+    fn sign(&self,
+            _scheme: SignatureScheme,
+            _message: &[u8],
+            _rng: &SecureRandom)
+            -> Result<Vec<u8>, ()> {
+        self.asked.store(true, atomic::Ordering::SeqCst);
+        Err(())
+    }
+
+    fn algorithm(&self) -> rustls::internal::msgs::enums::SignatureAlgorithm {
+        rustls::internal::msgs::enums::SignatureAlgorithm::ECDSA
+    }
+}
+
+fn cert_resolve_by_key_type_test(client_suite: CipherSuite,
+                                 client_sigschemes: Option<Vec<SignatureScheme>>)
+                                 -> (Result<(), TLSError>, bool) {
+    let asked = Arc::new(atomic::AtomicBool::new(false));
+    let ecdsa_signer = UnusableECDSASigner { asked: asked.clone() };
+    let rsa_signer = sign::RSASigner::new(&get_key()).unwrap();
+
+    let mut server_config = ServerConfig::new();
+    server_config.set_certs_with_signers(vec![
+        (read_certs("test-ca/ecdsa/end.fullchain"),
+         Arc::new(Box::new(ecdsa_signer) as Box<sign::Signer>)),
+        (get_chain(), Arc::new(Box::new(rsa_signer) as Box<sign::Signer>)),
+    ]);
+
+    let mut client_config = make_tls12_client_config();
+    client_config.ciphersuites = rustls::ALL_CIPHERSUITES.iter()
+        .filter(|suite| suite.suite == client_suite)
+        .cloned()
+        .collect();
+
+    if let Some(schemes) = client_sigschemes {
+        client_config.set_client_hello_callback(move |hello| {
+            for ext in hello.extensions.iter_mut() {
+                if let ClientExtension::SignatureAlgorithms(ref mut offered) = *ext {
+                    *offered = schemes.clone();
+                }
+            }
+        });
+    }
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let result = do_handshake_until_error(&mut client, &mut server);
+    (result, asked.load(atomic::Ordering::SeqCst))
+}
+
+#[test]
+fn server_cert_resolve_by_key_type_rsa() {
+    let (result, used_ecdsa) =
+        cert_resolve_by_key_type_test(CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256, None);
+    assert!(result.is_ok());
+    assert!(!used_ecdsa);
+}
+
+#[test]
+fn server_cert_resolve_by_key_type_ecdsa() {
+    let (result, used_ecdsa) =
+        cert_resolve_by_key_type_test(CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256, None);
+    assert!(result.is_err());
+    assert!(used_ecdsa);
+}
+
+#[test]
+fn server_cert_resolve_by_key_type_neither() {
+    let (result, used_ecdsa) =
+        cert_resolve_by_key_type_test(CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                                      Some(vec![ SignatureScheme::ED25519 ]));
+    assert_eq!(result,
+               Err(TLSError::General("no server certificate chain resolved".to_string())));
+    assert!(!used_ecdsa);
+}
+
+fn add_cert_chain_test(client_suite: CipherSuite) -> (Result<(), TLSError>, bool) {
+    let asked = Arc::new(atomic::AtomicBool::new(false));
+    let ecdsa_signer = UnusableECDSASigner { asked: asked.clone() };
+
+    let mut server_config = ServerConfig::new();
+    server_config.add_cert_chain_with_signer(read_certs("test-ca/ecdsa/end.fullchain"),
+                                             Box::new(ecdsa_signer));
+    server_config.add_cert_chain(get_chain(), get_key());
+
+    let mut client_config = make_tls12_client_config();
+    client_config.ciphersuites = rustls::ALL_CIPHERSUITES.iter()
+        .filter(|suite| suite.suite == client_suite)
+        .cloned()
+        .collect();
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let result = do_handshake_until_error(&mut client, &mut server);
+    (result, asked.load(atomic::Ordering::SeqCst))
+}
+
+#[test]
+fn server_add_cert_chain_rsa() {
+    let (result, used_ecdsa) =
+        add_cert_chain_test(CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256);
+    assert!(result.is_ok());
+    assert!(!used_ecdsa);
+}
+
+#[test]
+fn server_add_cert_chain_ecdsa() {
+    let (result, used_ecdsa) =
+        add_cert_chain_test(CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256);
+    assert!(result.is_err());
+    assert!(used_ecdsa);
+}
+
+#[test]
+fn server_add_cert_chain_allows_same_key_type() {
+    let mut server_config = ServerConfig::new();
+    server_config.add_cert_chain(get_chain(), get_key());
+    server_config.add_cert_chain(get_chain()[..1].to_vec(), get_key());
+
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+
+    // The first one added wins.
+    assert_eq!(client.get_peer_certificates().unwrap().len(), get_chain().len());
+}
+
+#[test]
+fn server_add_cert_chain_after_set_certs_with_signers() {
+    let mut server_config = ServerConfig::new();
+    let rsa_signer = sign::RSASigner::new(&get_key()).unwrap();
+    server_config.set_certs_with_signers(vec![
+        (read_certs("test-ca/ecdsa/end.fullchain"),
+         Arc::new(Box::new(rsa_signer) as Box<sign::Signer>)),
+    ]);
+    server_config.set_single_cert(get_chain(), get_key());
+
+    // set_single_cert dropped the earlier chains.
+    server_config.add_cert_chain(get_chain(), get_key());
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_peer_certificates(), Some(get_chain()));
+}
+
+#[test]
+fn server_add_cert_chain_after_set_single_cert() {
+    let mut server_config = ServerConfig::new();
+    server_config.set_single_cert(get_chain(), get_key());
+    server_config.add_cert_chain_with_signer(get_ed25519_chain(),
+                                             Box::new(get_ed25519_signer()));
+    let server_config = Arc::new(server_config);
+
+    // The single chain is kept, and tried first.
+    let client_config = make_client_config_while_valid();
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_peer_certificates(), Some(get_chain()));
+
+    // But a client which can only use the added one gets that.
+    let mut client_config = make_client_config_while_valid();
+    client_config.set_client_hello_callback(|hello| {
+        for ext in hello.extensions.iter_mut() {
+            if let ClientExtension::SignatureAlgorithms(ref mut schemes) = *ext {
+                *schemes = vec![ SignatureScheme::ED25519 ];
+            }
+        }
+    });
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_peer_certificates(), Some(get_ed25519_chain()));
+}
+
+/// A client config which checks certificates at `WHILE_VALID`,
+/// rather than now.
+fn make_client_config_while_valid() -> ClientConfig {
+    let mut client_config = make_client_config();
+    client_config.set_time_provider(Box::new(FixedTime(WHILE_VALID)));
+    client_config
+}
+
+fn get_ed25519_chain() -> Vec<Certificate> {
+    read_certs("test-ca/rsa/end-ed25519.fullchain")
+}
+
+fn get_ed25519_signer() -> sign::Ed25519Signer {
+    let key = pemfile::pkcs8_private_keys(&mut io::BufReader::new(
+            fs::File::open("test-ca/rsa/end-ed25519.key").unwrap()))
+        .unwrap()[0]
+        .clone();
+    sign::Ed25519Signer::new(&key, &get_ed25519_chain()[0]).unwrap()
+}
+
+fn make_ed25519_server_config(signer: Box<sign::Signer>) -> ServerConfig {
+    let mut server_config = ServerConfig::new();
+    server_config.set_single_cert_with_signer(get_ed25519_chain(), signer);
+    server_config
+}
+
+#[test]
+fn ed25519_server_cert() {
+    let server_config = make_ed25519_server_config(Box::new(get_ed25519_signer()));
+    let client_config = make_client_config_while_valid();
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+    assert_eq!(client.get_server_signature_scheme(), Some(SignatureScheme::ED25519));
+    assert_eq!(server.get_server_signature_scheme(), Some(SignatureScheme::ED25519));
+    assert_eq!(client.get_peer_certificates(), Some(get_ed25519_chain()));
+}
+
+#[test]
+fn ed25519_server_cert_needs_tls13() {
+    let server_config = make_ed25519_server_config(Box::new(get_ed25519_signer()));
+    let mut client = ClientSession::new(&Arc::new(make_tls12_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    // Every TLS1.2 suite needs an RSA or ECDSA key.
+    assert_eq!(do_handshake_until_error(&mut client, &mut server),
+               Err(TLSError::PeerIncompatibleError("no ciphersuites in common".to_string())));
+}
+
+#[test]
+fn ed25519_signer_needs_matching_cert() {
+    let key = pemfile::pkcs8_private_keys(&mut io::BufReader::new(
+            fs::File::open("test-ca/rsa/end-ed25519.key").unwrap()))
+        .unwrap()[0]
+        .clone();
+    assert!(sign::Ed25519Signer::new(&key, &get_chain()[0]).is_err());
+    assert!(sign::Ed25519Signer::new(&get_key(), &get_ed25519_chain()[0]).is_err());
+}
+
+#[test]
+fn tls12_client_does_not_offer_ed25519() {
+    let mut client_config = make_tls12_client_config();
+    client_config.set_client_hello_callback(|hello| {
+        for ext in hello.extensions.iter() {
+            if let ClientExtension::SignatureAlgorithms(ref schemes) = *ext {
+                assert!(!schemes.contains(&SignatureScheme::ED25519));
+            }
+        }
+    });
+    ClientSession::new(&Arc::new(client_config), "localhost");
+}
+
+/// Makes Ed25519 signatures, but flips a bit in each.
+struct CorruptingEd25519Signer(sign::Ed25519Signer);
+
+impl sign::Signer for CorruptingEd25519Signer {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<SignatureScheme> {
+        self.0.choose_scheme(offered)
+    }
+
+    fn sign(&self,
+            scheme: SignatureScheme,
+            message: &[u8],
+            rng: &SecureRandom)
+            -> Result<Vec<u8>, ()> {
+        let mut sig = self.0.sign(scheme, message, rng)?;
+        sig[0] ^= 0x01;
+        Ok(sig)
+    }
+
+    fn algorithm(&self) -> rustls::internal::msgs::enums::SignatureAlgorithm {
+        self.0.algorithm()
+    }
+}
+
+#[test]
+fn client_rejects_tampered_ed25519_signature() {
+    let signer = CorruptingEd25519Signer(get_ed25519_signer());
+    let server_config = make_ed25519_server_config(Box::new(signer));
+    let client_config = make_client_config_while_valid();
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    assert_eq!(do_handshake_until_error(&mut client, &mut server),
+               Err(TLSError::WebPKIError(webpki::Error::InvalidSignatureForPublicKey)));
+}
+
+/// The same key and names as `get_chain`, but the end-entity
+/// certificate has an SHA-1 signature.
+fn get_sha1_chain() -> Vec<Certificate> {
+    read_certs("test-ca/rsa/end-sha1.fullchain")
+}
+
+/// Serves the SHA-1-signed chain if the client accepts SHA-1
+/// signatures in our certificate chain, and the SHA-256 one
+/// otherwise.
+struct ServerChooseCertBySigAlg;
+
+impl ResolvesServerCert for ServerChooseCertBySigAlg {
+    fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertChainAndSigner> {
+        let accepts_sha1 = client_hello.cert_sigschemes()
+            .contains(&SignatureScheme::RSA_PKCS1_SHA1);
+
+        let chain = if accepts_sha1 { get_sha1_chain() } else { get_chain() };
+        let signer = sign::RSASigner::new(&get_key()).unwrap();
+        Some((chain, Arc::new(Box::new(signer))))
+    }
+}
+
+fn served_chain(mut client_config: ClientConfig,
+                server_config: ServerConfig) -> Vec<Certificate> {
+    client_config.set_time_provider(Box::new(FixedTime(WHILE_VALID)));
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    do_handshake(&mut client, &mut server);
+    client.get_peer_certificates().unwrap()
+}
+
+fn make_client_config_refusing_sha1_certs() -> ClientConfig {
+    let mut client_config = make_client_config();
+    client_config.cert_sigschemes.retain(|scheme| *scheme != SignatureScheme::RSA_PKCS1_SHA1);
+    client_config
+}
+
+#[test]
+fn server_cert_resolve_by_sigalgs_cert() {
+    let mut server_config = make_server_config();
+    server_config.cert_resolver = Box::new(ServerChooseCertBySigAlg);
+    assert_eq!(served_chain(make_client_config(), server_config), get_sha1_chain());
+
+    let mut server_config = make_server_config();
+    server_config.cert_resolver = Box::new(ServerChooseCertBySigAlg);
+    assert_eq!(served_chain(make_client_config_refusing_sha1_certs(), server_config),
+               get_chain());
+}
+
+#[test]
+fn server_prefers_chain_signed_as_client_accepts() {
+    let make_config = || {
+        let mut server_config = ServerConfig::new();
+        server_config.add_cert_chain(get_sha1_chain(), get_key());
+        server_config.add_cert_chain(get_chain(), get_key());
+        server_config
+    };
+
+    assert_eq!(served_chain(make_client_config(), make_config()), get_sha1_chain());
+    assert_eq!(served_chain(make_client_config_refusing_sha1_certs(), make_config()),
+               get_chain());
+}
+
+#[test]
+fn server_sends_only_chain_whatever_its_signature() {
+    let mut server_config = ServerConfig::new();
+    server_config.set_single_cert(get_sha1_chain(), get_key());
+
+    assert_eq!(served_chain(make_client_config_refusing_sha1_certs(), server_config),
+               get_sha1_chain());
+}
+
+struct ClientCheckCertResolve {
+    query_count: atomic::AtomicUsize,
+    expect_queries: usize
+}
+
+impl ClientCheckCertResolve {
+    fn new(expect_queries: usize) -> ClientCheckCertResolve {
+        ClientCheckCertResolve {
+            query_count: atomic::AtomicUsize::new(0),
+            expect_queries: expect_queries
+        }
+    }
+}
+
+impl Drop for ClientCheckCertResolve {
+    fn drop(&mut self) {
+        let count = self.query_count.load(atomic::Ordering::SeqCst);
+        assert_eq!(count, self.expect_queries);
+    }
+}
+
+impl ResolvesClientCert for ClientCheckCertResolve {
+    fn resolve(&self,
+               acceptable_issuers: &[&[u8]],
+               sigschemes: &[SignatureScheme])
+        -> Option<sign::CertChainAndSigner> {
+        self.query_count.fetch_add(1, atomic::Ordering::SeqCst);
+
+        if acceptable_issuers.len() == 0 {
+            panic!("no issuers offered by server");
+        }
+
+        if sigschemes.len() == 0 {
+            panic!("no signature schemes shared by server");
+        }
+
+        None
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn client_cert_resolve() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    client_config.client_auth_cert_resolver = Box::new(ClientCheckCertResolve::new(1));
+    server_config.set_client_auth_roots(get_chain(), true);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err.is_err(), true);
+}
+
+#[test]
+fn client_error_is_sticky() {
+    let client_config = make_client_config();
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    client.read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref()).unwrap();
+    let mut err = client.process_new_packets();
+    assert_eq!(err.is_err(), true);
+    err = client.process_new_packets();
+    assert_eq!(err.is_err(), true);
+}
+
+#[test]
+fn server_error_is_sticky() {
+    let server_config = make_server_config();
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    server.read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref()).unwrap();
+    let mut err = server.process_new_packets();
+    assert_eq!(err.is_err(), true);
+    err = server.process_new_packets();
+    assert_eq!(err.is_err(), true);
+}
+
+#[test]
+fn server_is_send() {
+    let server_config = make_server_config();
+    let server = ServerSession::new(&Arc::new(server_config));
+    &server as &Send;
+}
+
+#[test]
+fn client_is_send() {
+    let client_config = make_client_config();
+    let client = ClientSession::new(&Arc::new(client_config), "localhost");
+    &client as &Send;
+}
+
+#[test]
+fn server_respects_buffer_limit_pre_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    server.set_buffer_limit(Some(32));
+
+    assert_eq!(server.write(b"01234567890123456789").unwrap(), 20);
+    assert_eq!(server.write(b"01234567890123456789").unwrap(), 12);
+
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    check_read(&mut client, b"01234567890123456789012345678901");
+}
+
+#[test]
+fn server_respects_buffer_limit_post_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    // this test will vary in behaviour depending on the default suites
+    do_handshake(&mut client, &mut server);
+    server.set_buffer_limit(Some(48));
+
+    assert_eq!(server.write(b"01234567890123456789").unwrap(), 20);
+    assert_eq!(server.write(b"01234567890123456789").unwrap(), 6);
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    check_read(&mut client, b"01234567890123456789012345");
+}
+
+#[test]
+fn client_respects_buffer_limit_pre_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    client.set_buffer_limit(Some(32));
+
+    assert_eq!(client.write(b"01234567890123456789").unwrap(), 20);
+    assert_eq!(client.write(b"01234567890123456789").unwrap(), 12);
+
+    do_handshake(&mut client, &mut server);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    check_read(&mut server, b"01234567890123456789012345678901");
+}
+
+#[test]
+fn client_respects_buffer_limit_post_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+    client.set_buffer_limit(Some(48));
+
+    assert_eq!(client.write(b"01234567890123456789").unwrap(), 20);
+    assert_eq!(client.write(b"01234567890123456789").unwrap(), 6);
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    check_read(&mut server, b"01234567890123456789012345");
+}
+
+#[test]
+fn buffer_limit_is_bounded_by_default() {
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    let data = vec![0x5au8; 100 * 1024];
+    assert_eq!(server.write(&data).unwrap(), 64 * 1024);
+    assert_eq!(server.write(&data).unwrap(), 0);
+}
+
+#[test]
+fn server_unlimited_buffer_limit() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    server.set_buffer_limit(None);
+
+    let data = vec![0x5au8; 100 * 1024];
+    assert_eq!(server.write(&data).unwrap(), data.len());
+    assert_eq!(server.write(&data).unwrap(), data.len());
+
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    let mut expect = data.clone();
+    expect.extend_from_slice(&data);
+    check_read(&mut client, &expect);
+}
+
+#[test]
+fn client_unlimited_buffer_limit_post_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+    client.set_buffer_limit(None);
+
+    let data = vec![0x5au8; 100 * 1024];
+    assert_eq!(client.write(&data).unwrap(), data.len());
+
+    client.set_buffer_limit(Some(48));
+    assert_eq!(client.write(&data).unwrap(), 0);
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, &data);
+}
+
+struct OtherSession<'a> {
+    sess: &'a mut Session,
+    pub reads: usize,
+    pub writes: usize,
+}
+
+impl<'a> OtherSession<'a> {
+    fn new(sess: &'a mut Session) -> OtherSession<'a> {
+        OtherSession { sess, reads: 0, writes: 0 }
+    }
+}
+
+impl<'a> io::Read for OtherSession<'a> {
+    fn read(&mut self, mut b: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        self.sess.write_tls(b.by_ref())
+    }
+}
+
+impl<'a> io::Write for OtherSession<'a> {
+    fn write(&mut self, mut b: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        let l = self.sess.read_tls(b.by_ref())?;
+        self.sess.process_new_packets().unwrap();
+        Ok(l)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn client_complete_io_for_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    assert_eq!(true, client.is_handshaking());
+    let (rdlen, wrlen) = client.complete_io(&mut OtherSession::new(&mut server)).unwrap();
+    assert!(rdlen > 0 && wrlen > 0);
+    assert_eq!(false, client.is_handshaking());
+}
+
+#[test]
+fn client_complete_io_for_write() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+
+    client.write(b"01234567890123456789").unwrap();
+    client.write(b"01234567890123456789").unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
+        assert!(rdlen == 0 && wrlen > 0);
+        assert_eq!(pipe.writes, 2);
+    }
+    check_read(&mut server, b"0123456789012345678901234567890123456789");
+}
+
+#[test]
+fn client_complete_io_for_read() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+
+    server.write(b"01234567890123456789").unwrap();
+    {
         let mut pipe = OtherSession::new(&mut server);
         let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
         assert!(rdlen > 0 && wrlen == 0);
         assert_eq!(pipe.reads, 1);
     }
-    check_read(&mut client, b"01234567890123456789");
+    check_read(&mut client, b"01234567890123456789");
+}
+
+#[test]
+fn server_complete_io_for_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    assert_eq!(true, server.is_handshaking());
+    let (rdlen, wrlen) = server.complete_io(&mut OtherSession::new(&mut client)).unwrap();
+    assert!(rdlen > 0 && wrlen > 0);
+    assert_eq!(false, server.is_handshaking());
+}
+
+#[test]
+fn server_complete_io_for_write() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+
+    server.write(b"01234567890123456789").unwrap();
+    server.write(b"01234567890123456789").unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
+        assert!(rdlen == 0 && wrlen > 0);
+        assert_eq!(pipe.writes, 2);
+    }
+    check_read(&mut client, b"0123456789012345678901234567890123456789");
+}
+
+#[test]
+fn server_complete_io_for_read() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+
+    client.write(b"01234567890123456789").unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
+        assert!(rdlen > 0 && wrlen == 0);
+        assert_eq!(pipe.reads, 1);
+    }
+    check_read(&mut server, b"01234567890123456789");
+}
+
+#[test]
+fn client_stream_write() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let mut stream = Stream::new(&mut client, &mut pipe);
+        assert_eq!(stream.write(b"hello").unwrap(), 5);
+    }
+    check_read(&mut server, b"hello");
+}
+
+#[test]
+fn client_stream_flush() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    // Buffered until the handshake completes.
+    client.write(b"hello").unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let mut stream = Stream::new(&mut client, &mut pipe);
+        stream.flush().unwrap();
+    }
+    check_read(&mut server, b"hello");
+}
+
+#[test]
+fn flush_plaintext_without_plaintext() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+
+    client.flush_plaintext();
+    server.flush_plaintext();
+    assert_eq!(client.wants_write(), false);
+    assert_eq!(server.wants_write(), false);
+
+    client.write(b"hello").unwrap();
+    client.flush_plaintext();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+}
+
+#[test]
+fn client_stream_read() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    server.write(b"world").unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let mut stream = Stream::new(&mut client, &mut pipe);
+        check_read(&mut stream, b"world");
+    }
+}
+
+#[test]
+fn server_stream_write() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let mut stream = Stream::new(&mut server, &mut pipe);
+        assert_eq!(stream.write(b"hello").unwrap(), 5);
+    }
+    check_read(&mut client, b"hello");
+}
+
+#[test]
+fn server_stream_read() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    client.write(b"world").unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let mut stream = Stream::new(&mut server, &mut pipe);
+        check_read(&mut stream, b"world");
+    }
+}
+
+/// After a handshake using `client_config`, the server sends a record
+/// whose byte at `from_end` bytes from its end is flipped, unless it's
+/// None.  Returns how the client takes it.
+fn send_altered_record(client_config: ClientConfig, from_end: Option<usize>)
+                       -> Result<(), TLSError> {
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    server.write(b"hello").unwrap();
+    let mut wire = Vec::new();
+    server.write_tls(&mut wire).unwrap();
+    assert!(!server.wants_write());
+
+    if let Some(from_end) = from_end {
+        let at = wire.len() - from_end;
+        wire[at] ^= 0x01;
+    }
+
+    client.read_tls(&mut wire.as_slice()).unwrap();
+    let result = client.process_new_packets();
+    if result.is_err() {
+        // The record's plaintext is never released, and the server
+        // learns why.
+        check_read(&mut client, b"");
+        transfer(&mut client, &mut server);
+        assert_eq!(server.process_new_packets(),
+                   Err(TLSError::AlertReceived(AlertDescription::BadRecordMac)));
+    } else {
+        check_read(&mut client, b"hello");
+    }
+    result
+}
+
+fn check_record_authentication(client_config: fn() -> ClientConfig) {
+    assert_eq!(send_altered_record(client_config(), None), Ok(()));
+
+    // The 16-byte tag ends the record, after the ciphertext.
+    assert_eq!(send_altered_record(client_config(), Some(17)), Err(TLSError::DecryptError));
+    assert_eq!(send_altered_record(client_config(), Some(1)), Err(TLSError::DecryptError));
+}
+
+#[test]
+fn client_rejects_altered_records_tls13() {
+    check_record_authentication(make_client_config);
+}
+
+#[test]
+fn client_rejects_altered_records_tls12() {
+    check_record_authentication(make_tls12_client_config);
+}
+
+#[test]
+fn split_session_reads_and_writes_concurrently() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    let (mut reader, mut writer) = client.split();
+
+    // The writer produces TLS on another thread, while we read.
+    let writing = thread::spawn(move || {
+        assert_eq!(writer.write(b"hello").unwrap(), 5);
+        let mut wire = Vec::new();
+        while writer.wants_write() {
+            writer.write_tls(&mut wire).unwrap();
+        }
+        (writer, wire)
+    });
+
+    server.write(b"world").unwrap();
+    let mut wire = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut wire).unwrap();
+    }
+    reader.read_tls(&mut wire.as_slice()).unwrap();
+    reader.process_new_packets().unwrap();
+    check_read(&mut reader, b"world");
+
+    let (writer, wire) = writing.join().unwrap();
+    server.read_tls(&mut wire.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+
+    let client = reader.reunite(writer);
+    assert!(!client.is_handshaking());
+}
+
+#[test]
+fn split_session_writes_while_reader_waits_on_socket() {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // The server only answers once it has our data, so the reader
+    // is blocked on the socket while the writer sends that.
+    let serving = thread::spawn(move || {
+        let (mut sock, _) = listener.accept().unwrap();
+        let mut server = ServerSession::new(&Arc::new(make_server_config()));
+        let mut stream = Stream::new(&mut server, &mut sock);
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        stream.write_all(b"world").unwrap();
+    });
+
+    let mut sock = net::TcpStream::connect(addr).unwrap();
+    let client_config = make_client_config_while_valid();
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    client.complete_io(&mut sock).unwrap();
+    let (mut reader, mut writer) = client.split();
+
+    let mut read_sock = sock.try_clone().unwrap();
+    let reading = thread::spawn(move || {
+        let mut plaintext = Vec::new();
+        while plaintext.len() < 5 {
+            assert!(reader.read_tls(&mut read_sock).unwrap() > 0);
+            reader.process_new_packets().unwrap();
+            reader.read_to_end(&mut plaintext).unwrap();
+        }
+        assert_eq!(plaintext, b"world");
+        reader
+    });
+
+    writer.write_all(b"hello").unwrap();
+    while writer.wants_write() {
+        writer.write_tls(&mut sock).unwrap();
+    }
+
+    serving.join().unwrap();
+    let reader = reading.join().unwrap();
+    let client = reader.reunite(writer);
+    assert!(!client.is_handshaking());
+}
+
+/// A 'random' source which always produces the same bytes.
+struct FixedRandom(u8);
+
+impl SecureRandom for FixedRandom {
+    fn fill(&self, bytes: &mut [u8]) -> Result<(), ()> {
+        for b in bytes.iter_mut() {
+            *b = self.0;
+        }
+        Ok(())
+    }
+}
+
+fn client_hello_with_fixed_random() -> Vec<u8> {
+    let mut client_config = make_client_config();
+    client_config.rng = Box::new(FixedRandom(0x5a));
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    hello
+}
+
+#[test]
+fn client_random_comes_from_config_rng() {
+    let first = client_hello_with_fixed_random();
+    let second = client_hello_with_fixed_random();
+
+    // Record and handshake headers, then the version, then the random.
+    assert_eq!(&first[11..43], &[ 0x5a; 32 ][..]);
+    assert_eq!(first, second);
+}
+
+#[test]
+fn handshake_with_fixed_rng() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+    client_config.rng = Box::new(FixedRandom(0x5a));
+    server_config.rng = Box::new(FixedRandom(0xa5));
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+}
+
+/// The server's first flight in a `version` handshake where both
+/// sides have fixed randomness.
+fn server_flight_with_fixed_rng(version: ProtocolVersion) -> Vec<u8> {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+    client_config.versions = vec![ version ];
+    client_config.rng = Box::new(FixedRandom(0x5a));
+    server_config.set_persistence(ServerSessionMemoryCache::new(8));
+    server_config.rng = Box::new(FixedRandom(0xa5));
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let mut flight = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut flight).unwrap();
+    }
+    flight
+}
+
+#[test]
+fn server_signature_and_session_id_come_from_config_rng() {
+    // RSA-PSS signatures are salted, so these only match if the
+    // salt comes from the config's rng.
+    for version in &[ ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2 ] {
+        assert_eq!(server_flight_with_fixed_rng(*version),
+                   server_flight_with_fixed_rng(*version));
+    }
+
+    // Record and handshake headers, then the version and random,
+    // then the session ID's length and the session ID.
+    let flight = server_flight_with_fixed_rng(ProtocolVersion::TLSv1_2);
+    assert_eq!(flight[43], 32);
+    assert_eq!(&flight[44..76], &[ 0xa5; 32 ][..]);
+}
+
+fn get_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    let mut rootbuf = io::BufReader::new(fs::File::open("test-ca/rsa/ca.cert").unwrap());
+    roots.add_pem_file(&mut rootbuf).unwrap();
+    roots
+}
+
+fn at_unix_time(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// 2018-01-01, when all of test-ca/rsa is valid.
+const WHILE_VALID: u64 = 1514764800;
+
+/// 2023-01-01, after test-ca/rsa/end.cert expires.
+const AFTER_EXPIRY: u64 = 1672531200;
+
+#[test]
+fn verify_chain_accepts_valid_chain() {
+    let now = at_unix_time(WHILE_VALID);
+    assert_eq!(verify_chain(&get_roots(), &get_chain(), "localhost", now), Ok(()));
+    assert_eq!(verify_chain(&get_roots(), &get_chain(), "testserver.com", now), Ok(()));
+}
+
+#[test]
+fn verify_chain_rejects_expired_cert() {
+    assert_eq!(verify_chain(&get_roots(), &get_chain(), "localhost", at_unix_time(AFTER_EXPIRY)),
+               Err(TLSError::WebPKIError(webpki::Error::CertExpired)));
+}
+
+#[test]
+fn verify_chain_rejects_wrong_name() {
+    assert_eq!(verify_chain(&get_roots(), &get_chain(), "example.com", at_unix_time(WHILE_VALID)),
+               Err(TLSError::WebPKIError(webpki::Error::CertNotValidForName)));
+}
+
+#[test]
+fn verify_chain_rejects_name_with_nul() {
+    assert_eq!(verify_chain(&get_roots(), &get_chain(), "localhost\0evil.com",
+                            at_unix_time(WHILE_VALID)),
+               Err(TLSError::WebPKIError(webpki::Error::CertNotValidForName)));
+    assert_eq!(verify_chain(&get_roots(), &get_chain(), "a.com\0b.com",
+                            at_unix_time(WHILE_VALID)),
+               Err(TLSError::WebPKIError(webpki::Error::CertNotValidForName)));
+}
+
+#[test]
+fn verify_chain_rejects_unknown_issuer() {
+    assert_eq!(verify_chain(&RootCertStore::empty(), &get_chain(), "localhost",
+                            at_unix_time(WHILE_VALID)),
+               Err(TLSError::WebPKIError(webpki::Error::UnknownIssuer)));
+}
+
+/// SHA-256 hashes of the SubjectPublicKeyInfos of test-ca/rsa/end.cert
+/// and test-ca/rsa/inter.cert.
+const END_SPKI_PIN: [u8; 32] = [ 0xfa, 0xc4, 0x10, 0x99, 0x12, 0x3f, 0xc4, 0x5c,
+                                 0x25, 0x84, 0x92, 0x25, 0x7e, 0xa6, 0xc5, 0x4f,
+                                 0xcc, 0x17, 0xc7, 0xa1, 0xa2, 0xe5, 0xd3, 0x7b,
+                                 0x7c, 0x20, 0x0e, 0xe3, 0xd7, 0x42, 0xf0, 0x9d ];
+const INTER_SPKI_PIN: [u8; 32] = [ 0xa9, 0x3c, 0xf4, 0x03, 0xaf, 0x3d, 0xeb, 0x06,
+                                   0x84, 0xd3, 0x52, 0x87, 0x3c, 0xe3, 0x6e, 0xcf,
+                                   0x70, 0x85, 0xe6, 0x98, 0x7e, 0x66, 0xaa, 0x55,
+                                   0xeb, 0xf4, 0xbf, 0xab, 0x16, 0xe7, 0x83, 0x65 ];
+
+/// The same, for test-ca/rsa/ca.cert and test-ca/ecdsa/inter.cert.
+const CA_SPKI_PIN: [u8; 32] = [ 0x56, 0x0e, 0xe4, 0x0b, 0xa8, 0xea, 0x84, 0x6f,
+                                0x6b, 0xca, 0x92, 0x7c, 0x47, 0x2b, 0x55, 0x0c,
+                                0xd7, 0x98, 0xad, 0x5b, 0x16, 0xe9, 0xce, 0x91,
+                                0x56, 0x7f, 0x95, 0xf5, 0x81, 0xe8, 0x01, 0x3e ];
+const ECDSA_INTER_SPKI_PIN: [u8; 32] = [ 0x30, 0x6d, 0xaa, 0x0f, 0x08, 0x36, 0x56, 0x9c,
+                                        0x3c, 0x9b, 0xbe, 0x88, 0xc3, 0xc3, 0x36, 0xb9,
+                                        0xd3, 0x7e, 0x2b, 0xfc, 0x0b, 0x94, 0xa7, 0xa6,
+                                        0xdc, 0x40, 0xc0, 0xe5, 0x37, 0xd6, 0x76, 0x27 ];
+
+/// The SPKI pin of test-ca/rsa/ca-impostor.cert, which has the
+/// same name as the real CA.
+const IMPOSTOR_CA_SPKI_PIN: [u8; 32] = [ 0xc5, 0x21, 0xb8, 0x64, 0xe4, 0x92, 0x80, 0x20,
+                                         0x54, 0x9e, 0x6f, 0xcd, 0x11, 0xec, 0xee, 0x74,
+                                         0x4e, 0xf0, 0xa0, 0x72, 0x8a, 0x4f, 0xc7, 0xe0,
+                                         0xdc, 0x73, 0x25, 0x0e, 0xda, 0x64, 0x78, 0xaa ];
+
+fn pinned_handshake_with_chain(roots: RootCertStore,
+                               pins: Vec<[u8; 32]>,
+                               chain: Vec<Certificate>) -> Result<(), TLSError> {
+    let mut client_config = make_client_config();
+    client_config.root_store = roots;
+    client_config.spki_pins = pins;
+    client_config.set_time_provider(Box::new(FixedTime(WHILE_VALID)));
+
+    let mut server_config = ServerConfig::new();
+    server_config.set_single_cert(chain, get_key());
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake_until_error(&mut client, &mut server)
+}
+
+fn pinned_handshake(roots: RootCertStore, pins: Vec<[u8; 32]>) -> Result<(), TLSError> {
+    pinned_handshake_with_chain(roots, pins, get_chain())
+}
+
+#[test]
+fn client_accepts_chain_matching_pin() {
+    assert_eq!(pinned_handshake(get_roots(), vec![ END_SPKI_PIN ]), Ok(()));
+    assert_eq!(pinned_handshake(get_roots(), vec![ [0u8; 32], INTER_SPKI_PIN ]), Ok(()));
+}
+
+#[test]
+fn client_rejects_valid_chain_matching_no_pin() {
+    assert_eq!(pinned_handshake(get_roots(), vec![ [0u8; 32] ]),
+               Err(TLSError::PeerIncompatibleError("server certificate chain matches no \
+                                                    pinned key".to_string())));
+}
+
+#[test]
+fn client_accepts_pinned_trust_anchor_not_sent() {
+    let chain = get_chain().into_iter().take(2).collect();
+    assert_eq!(pinned_handshake_with_chain(get_roots(), vec![ CA_SPKI_PIN ], chain), Ok(()));
+}
+
+#[test]
+fn client_rejects_pinned_root_which_did_not_sign_chain() {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs("test-ca/rsa/ca-impostor.cert")
+        .into_iter()
+        .chain(read_certs("test-ca/rsa/ca.cert")) {
+        roots.add(&cert).unwrap();
+    }
+
+    assert_eq!(pinned_handshake(roots, vec![ IMPOSTOR_CA_SPKI_PIN ]),
+               Err(TLSError::PeerIncompatibleError("server certificate chain matches no \
+                                                    pinned key".to_string())));
+}
+
+#[test]
+fn client_rejects_pinned_cert_outside_validated_path() {
+    let mut chain = get_chain();
+    chain.extend(read_certs("test-ca/ecdsa/inter.cert"));
+
+    assert_eq!(pinned_handshake_with_chain(get_roots(), vec![ ECDSA_INTER_SPKI_PIN ], chain),
+               Err(TLSError::PeerIncompatibleError("server certificate chain matches no \
+                                                    pinned key".to_string())));
+}
+
+#[test]
+fn client_rejects_invalid_chain_matching_pin() {
+    assert_eq!(pinned_handshake(RootCertStore::empty(), vec![ END_SPKI_PIN ]),
+               Err(TLSError::WebPKIError(webpki::Error::UnknownIssuer)));
+}
+
+fn small_rsa_key_error(bits: usize, min: usize) -> TLSError {
+    TLSError::PeerIncompatibleError(format!("certificate has {}-bit RSA key, \
+                                             we need at least {} bits", bits, min))
+}
+
+#[test]
+fn verify_chain_rejects_1024_bit_rsa_key() {
+    let file = fs::File::open("test-ca/rsa/end-1024.fullchain").unwrap();
+    let chain = pemfile::certs(&mut io::BufReader::new(file)).unwrap();
+    assert_eq!(verify_chain(&get_roots(), &chain, "localhost", at_unix_time(WHILE_VALID)),
+               Err(small_rsa_key_error(1024, 2048)));
+}
+
+#[test]
+fn verify_chain_accepts_2048_bit_rsa_key() {
+    assert_eq!(verify_chain(&get_roots(), &get_chain(), "localhost", at_unix_time(WHILE_VALID)),
+               Ok(()));
+}
+
+#[test]
+fn client_minimum_rsa_key_size_is_configurable() {
+    for (min, expect) in vec![ (2048, Ok(())), (3072, Err(small_rsa_key_error(2048, 3072))) ] {
+        let mut client_config = make_client_config();
+        client_config.set_time_provider(Box::new(FixedTime(WHILE_VALID)));
+        client_config.set_min_rsa_key_bits(min);
+
+        let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+        let mut server = ServerSession::new(&Arc::new(make_server_config()));
+        assert_eq!(do_handshake_until_error(&mut client, &mut server), expect);
+    }
+}
+
+#[test]
+fn server_minimum_rsa_key_size_is_configurable() {
+    for (min, expect) in vec![ (2048, Ok(())), (3072, Err(small_rsa_key_error(2048, 3072))) ] {
+        let mut client_config = make_client_config();
+        let mut server_config = make_server_config();
+        client_config.set_time_provider(Box::new(FixedTime(WHILE_VALID)));
+        client_config.set_single_client_cert(get_chain(), get_key());
+        server_config.set_client_auth_roots(get_chain(), true);
+        server_config.set_time_provider(Box::new(FixedTime(WHILE_VALID)));
+        server_config.set_min_rsa_key_bits(min);
+
+        let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        assert_eq!(do_handshake_until_error(&mut client, &mut server), expect);
+    }
+}
+
+/// When test-ca/rsa/end.cert's notAfter is.
+const END_NOT_AFTER: u64 = 1654191753;
+
+struct FixedTime(u64);
+
+impl TimeProvider for FixedTime {
+    fn now(&self) -> SystemTime {
+        at_unix_time(self.0)
+    }
+}
+
+/// The default clock skew we tolerate, in seconds.
+const DEFAULT_SKEW: u64 = 5 * 60;
+
+fn server_cert_check_at(secs: u64) -> Result<(), TLSError> {
+    server_cert_check_with_skew(secs, Duration::from_secs(DEFAULT_SKEW))
+}
+
+fn server_cert_check_with_skew(secs: u64, skew: Duration) -> Result<(), TLSError> {
+    let mut client_config = make_client_config();
+    client_config.set_time_provider(Box::new(FixedTime(secs)));
+    client_config.set_clock_skew(skew);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake_until_error(&mut client, &mut server)
+}
+
+fn client_cert_check_at(secs: u64) -> Result<(), TLSError> {
+    client_cert_check_with_skew(secs, Duration::from_secs(DEFAULT_SKEW))
+}
+
+fn client_cert_check_with_skew(secs: u64, skew: Duration) -> Result<(), TLSError> {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    client_config.set_time_provider(Box::new(FixedTime(WHILE_VALID)));
+    client_config.set_single_client_cert(get_chain(), get_key());
+    server_config.set_client_auth_roots(get_chain(), true);
+    server_config.set_time_provider(Box::new(FixedTime(secs)));
+    server_config.set_clock_skew(skew);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake_until_error(&mut client, &mut server)
+}
+
+#[test]
+fn client_checks_expiry_with_time_provider() {
+    assert_eq!(server_cert_check_at(END_NOT_AFTER - 1), Ok(()));
+    assert_eq!(server_cert_check_at(END_NOT_AFTER + DEFAULT_SKEW + 1),
+               Err(TLSError::WebPKIError(webpki::Error::CertExpired)));
+}
+
+#[test]
+fn server_checks_expiry_with_time_provider() {
+    assert_eq!(client_cert_check_at(END_NOT_AFTER - 1), Ok(()));
+    assert_eq!(client_cert_check_at(END_NOT_AFTER + DEFAULT_SKEW + 1),
+               Err(TLSError::WebPKIError(webpki::Error::CertExpired)));
+}
+
+#[test]
+fn client_tolerates_clock_skew() {
+    assert_eq!(server_cert_check_at(END_NOT_AFTER + 1), Ok(()));
+    assert_eq!(server_cert_check_at(END_NOT_AFTER + DEFAULT_SKEW - 1), Ok(()));
+
+    let skew = Duration::from_secs(60 * 60);
+    assert_eq!(server_cert_check_with_skew(END_NOT_AFTER + 30 * 60, skew), Ok(()));
+    assert_eq!(server_cert_check_with_skew(END_NOT_AFTER + 60 * 60 + 1, skew),
+               Err(TLSError::WebPKIError(webpki::Error::CertExpired)));
+
+    assert_eq!(server_cert_check_with_skew(END_NOT_AFTER + 1, Duration::from_secs(0)),
+               Err(TLSError::WebPKIError(webpki::Error::CertExpired)));
+}
+
+#[test]
+fn server_tolerates_clock_skew() {
+    assert_eq!(client_cert_check_at(END_NOT_AFTER + 1), Ok(()));
+    assert_eq!(client_cert_check_at(END_NOT_AFTER + DEFAULT_SKEW - 1), Ok(()));
+
+    let skew = Duration::from_secs(60);
+    assert_eq!(client_cert_check_with_skew(END_NOT_AFTER + 59, skew), Ok(()));
+    assert_eq!(client_cert_check_with_skew(END_NOT_AFTER + 61, skew),
+               Err(TLSError::WebPKIError(webpki::Error::CertExpired)));
+}
+
+fn get_record_lengths(sess: &mut Session) -> Vec<usize> {
+    let mut wire = Vec::new();
+    while sess.wants_write() {
+        sess.write_tls(&mut wire).unwrap();
+    }
+
+    let mut lengths = Vec::new();
+    let mut offs = 0;
+    while offs < wire.len() {
+        let len = read_u16_at(&wire, offs + 3);
+        lengths.push(len);
+        offs += 5 + len;
+    }
+    lengths
+}
+
+#[test]
+fn tls13_record_padding_to_block_size() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+    client_config.set_record_padding(|len| (256 - len % 256) % 256);
+    server_config.set_record_padding(|len| (256 - len % 256) % 256);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+
+    // All our suites have a 16 byte tag.
+    client.write(b"hello").unwrap();
+    let mut buf = [0u8; 512];
+    let sz = client.write_tls(&mut buf.as_mut()).unwrap();
+    assert_eq!(sz, 5 + 256 + 16);
+    server.read_tls(&mut buf[..sz].as_ref()).unwrap();
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+
+    server.write(&[0u8; 300]).unwrap();
+    assert_eq!(get_record_lengths(&mut server), vec![ 512 + 16 ]);
+}
+
+#[test]
+fn tls13_record_padding_is_limited() {
+    let mut client_config = make_client_config();
+    client_config.set_record_padding(|_| 100000);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    client.write(b"hello").unwrap();
+    let mut buf = [0u8; 20000];
+    let sz = client.write_tls(&mut buf.as_mut()).unwrap();
+    assert_eq!(sz, 5 + 16384 + 1 + 16);
+    server.read_tls(&mut buf[..sz].as_ref()).unwrap();
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+}
+
+#[test]
+fn tls12_records_are_not_padded() {
+    let mut client_config = make_tls12_client_config();
+    client_config.set_record_padding(|len| (256 - len % 256) % 256);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    client.write(b"hello").unwrap();
+    assert!(get_record_lengths(&mut client)[0] < 256);
+}
+
+/// Handshake with the given record size limits, and check the smaller
+/// one is agreed and obeyed by both sides.  Records are at most
+/// `overhead` bytes bigger than their plaintext.
+fn check_record_size_limit(mut client_config: ClientConfig,
+                           client_limit: u16,
+                           server_limit: u16,
+                           overhead: usize) {
+    let mut server_config = make_server_config();
+    client_config.record_size_limit = Some(client_limit);
+    server_config.record_size_limit = Some(server_limit);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+
+    let limit = std::cmp::min(client_limit, server_limit) as usize;
+    assert_eq!(client.negotiated_record_size_limit(), limit);
+    assert_eq!(server.negotiated_record_size_limit(), limit);
+
+    server.write_all(&[0u8; 2000]).unwrap();
+    let lengths = get_record_lengths(&mut server);
+    assert!(lengths.len() > 1);
+    assert!(lengths.iter().all(|len| *len <= limit + overhead));
+
+    client.write_all(&[1u8; 2000]).unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, &[1u8; 2000]);
+}
+
+#[test]
+fn record_size_limit_is_the_smaller_tls13() {
+    // The tag is 16 bytes.
+    check_record_size_limit(make_client_config(), 1000, 600, 16);
+    check_record_size_limit(make_client_config(), 600, 1000, 16);
+}
+
+#[test]
+fn record_size_limit_is_the_smaller_tls12() {
+    // Our TLS1.2 suites have at most an 8 byte explicit nonce and a
+    // 16 byte tag.
+    check_record_size_limit(make_tls12_client_config(), 1000, 600, 8 + 16);
+    check_record_size_limit(make_tls12_client_config(), 600, 1000, 8 + 16);
+}
+
+#[test]
+fn record_size_limit_caps_padding() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+    client_config.set_record_padding(|_| 100000);
+    client_config.record_size_limit = Some(300);
+    server_config.record_size_limit = Some(300);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+
+    client.write(b"hello").unwrap();
+    assert_eq!(get_record_lengths(&mut client), vec![ 300 + 16 ]);
+}
+
+#[test]
+fn server_obeys_record_size_limit_without_its_own() {
+    for mut client_config in vec![ make_client_config(), make_tls12_client_config() ] {
+        client_config.record_size_limit = Some(600);
+
+        let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+        let mut server = ServerSession::new(&Arc::new(make_server_config()));
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(client.negotiated_record_size_limit(), 600);
+        assert_eq!(server.negotiated_record_size_limit(), 600);
+
+        server.write_all(&[0u8; 2000]).unwrap();
+        let lengths = get_record_lengths(&mut server);
+        assert!(lengths.len() > 1);
+        assert!(lengths.iter().all(|len| *len <= 600 + 8 + 16));
+    }
 }
 
 #[test]
-fn server_complete_io_for_handshake() {
+fn record_size_limit_needs_client_to_offer() {
+    let mut server_config = make_server_config();
+    server_config.record_size_limit = Some(600);
+
     let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
 
-    assert_eq!(true, server.is_handshaking());
-    let (rdlen, wrlen) = server.complete_io(&mut OtherSession::new(&mut client)).unwrap();
-    assert!(rdlen > 0 && wrlen > 0);
-    assert_eq!(false, server.is_handshaking());
+    assert_eq!(client.negotiated_record_size_limit(), 16384);
+    assert_eq!(server.negotiated_record_size_limit(), 16384);
 }
 
 #[test]
-fn server_complete_io_for_write() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+fn server_rejects_tiny_record_size_limit() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+    client_config.record_size_limit = Some(63);
+    server_config.record_size_limit = Some(600);
 
-    do_handshake(&mut client, &mut server);
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    assert_eq!(do_handshake_until_error(&mut client, &mut server),
+               Err(TLSError::PeerMisbehavedError("peer sent too small record size limit"
+                                                 .to_string())));
+}
 
-    server.write(b"01234567890123456789").unwrap();
-    server.write(b"01234567890123456789").unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
-        assert!(rdlen == 0 && wrlen > 0);
-        assert_eq!(pipe.writes, 2);
+fn out_of_range_record_size_limit(limit: u16) -> Result<(), TLSError> {
+    Err(TLSError::General(format!("record_size_limit of {} is out of range", limit)))
+}
+
+#[test]
+fn server_rejects_own_out_of_range_record_size_limit() {
+    let cases = vec![ (make_client_config(), 0), (make_client_config(), 16386),
+                      (make_tls12_client_config(), 63), (make_tls12_client_config(), 16385) ];
+
+    for (mut client_config, limit) in cases {
+        let mut server_config = make_server_config();
+        client_config.record_size_limit = Some(600);
+        server_config.record_size_limit = Some(limit);
+
+        let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        assert_eq!(do_handshake_until_error(&mut client, &mut server),
+                   out_of_range_record_size_limit(limit));
     }
-    check_read(&mut client, b"0123456789012345678901234567890123456789");
 }
 
 #[test]
-fn server_complete_io_for_read() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+fn client_rejects_own_out_of_range_record_size_limit() {
+    // 16385 is only allowed in TLS1.3.
+    let mut client_config = make_tls12_client_config();
+    client_config.record_size_limit = Some(16385);
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
     let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    assert_eq!(do_handshake_until_error(&mut client, &mut server),
+               out_of_range_record_size_limit(16385));
+}
 
-    do_handshake(&mut client, &mut server);
+/// A run-length 'compression' algorithm, for testing.  Each run
+/// is encoded as a count and a byte.
+struct RunLengthCompressor {
+    alg: CertificateCompressionAlgorithm,
+    decompressions: Arc<atomic::AtomicUsize>,
+    bomb_runs: usize,
+}
 
-    client.write(b"01234567890123456789").unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
-        assert!(rdlen > 0 && wrlen == 0);
-        assert_eq!(pipe.reads, 1);
+impl RunLengthCompressor {
+    fn new(alg: CertificateCompressionAlgorithm) -> RunLengthCompressor {
+        RunLengthCompressor {
+            alg: alg,
+            decompressions: Arc::new(atomic::AtomicUsize::new(0)),
+            bomb_runs: 0,
+        }
+    }
+
+    /// Make a compressor which appends `runs` maximal runs of
+    /// zeroes to its output.
+    fn bomb(alg: CertificateCompressionAlgorithm, runs: usize) -> RunLengthCompressor {
+        RunLengthCompressor {
+            bomb_runs: runs,
+            ..RunLengthCompressor::new(alg)
+        }
+    }
+}
+
+impl CertCompressor for RunLengthCompressor {
+    fn algorithm(&self) -> CertificateCompressionAlgorithm {
+        self.alg
+    }
+
+    fn compress(&self, input: &[u8]) -> Option<Vec<u8>> {
+        let mut ret: Vec<u8> = Vec::new();
+        for byte in input {
+            let len = ret.len();
+            if len > 0 && ret[len - 1] == *byte && ret[len - 2] < 0xff {
+                ret[len - 2] += 1;
+            } else {
+                ret.push(1);
+                ret.push(*byte);
+            }
+        }
+
+        for _ in 0..self.bomb_runs {
+            ret.push(0xff);
+            ret.push(0x00);
+        }
+        Some(ret)
+    }
+
+    fn decompress(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        self.decompressions.fetch_add(1, atomic::Ordering::SeqCst);
+
+        if input.len() % 2 != 0 {
+            return None;
+        }
+
+        let mut used = 0;
+        for run in input.chunks(2) {
+            let len = run[0] as usize;
+            if used + len > output.len() {
+                return None;
+            }
+
+            for byte in &mut output[used..used + len] {
+                *byte = run[1];
+            }
+            used += len;
+        }
+        Some(used)
     }
-    check_read(&mut server, b"01234567890123456789");
 }
 
 #[test]
-fn client_stream_write() {
+fn tls13_compressed_certificate() {
+    let client_compressor = RunLengthCompressor::new(CertificateCompressionAlgorithm::Zlib);
+    let decompressions = client_compressor.decompressions.clone();
+
+    let mut client_config = make_client_config();
+    client_config.cert_compressors.push(Box::new(client_compressor));
+    let mut server_config = make_server_config();
+    server_config.cert_compressors
+        .push(Box::new(RunLengthCompressor::new(CertificateCompressionAlgorithm::Brotli)));
+    server_config.cert_compressors
+        .push(Box::new(RunLengthCompressor::new(CertificateCompressionAlgorithm::Zlib)));
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(decompressions.load(atomic::Ordering::SeqCst), 1);
+    assert_eq!(client.get_peer_certificates().unwrap(), get_chain());
+}
+
+#[test]
+fn tls13_unsupported_cert_compression_falls_back() {
+    let client_compressor = RunLengthCompressor::new(CertificateCompressionAlgorithm::Zstd);
+    let decompressions = client_compressor.decompressions.clone();
+
+    let mut client_config = make_client_config();
+    client_config.cert_compressors.push(Box::new(client_compressor));
+    let mut server_config = make_server_config();
+    server_config.cert_compressors
+        .push(Box::new(RunLengthCompressor::new(CertificateCompressionAlgorithm::Zlib)));
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(decompressions.load(atomic::Ordering::SeqCst), 0);
+    assert_eq!(client.get_peer_certificates().unwrap(), get_chain());
+}
+
+#[test]
+fn tls12_certificates_are_not_compressed() {
+    let client_compressor = RunLengthCompressor::new(CertificateCompressionAlgorithm::Zlib);
+    let decompressions = client_compressor.decompressions.clone();
+
+    let mut client_config = make_tls12_client_config();
+    client_config.cert_compressors.push(Box::new(client_compressor));
+    let mut server_config = make_server_config();
+    server_config.cert_compressors
+        .push(Box::new(RunLengthCompressor::new(CertificateCompressionAlgorithm::Zlib)));
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(decompressions.load(atomic::Ordering::SeqCst), 0);
+}
+
+#[test]
+fn tls13_compressed_certificate_expansion_is_capped() {
+    // The declared length is honest, but the payload expands to
+    // about a megabyte: decompression must stop at the declared length.
+    let mut client_config = make_client_config();
+    client_config.cert_compressors
+        .push(Box::new(RunLengthCompressor::new(CertificateCompressionAlgorithm::Zlib)));
+    let mut server_config = make_server_config();
+    server_config.cert_compressors
+        .push(Box::new(RunLengthCompressor::bomb(CertificateCompressionAlgorithm::Zlib, 4096)));
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::BadCertificate)));
+}
+
+#[test]
+fn client_detects_altered_session_id_tls13() {
     let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
     let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+
+    // Give the ClientHello a session id.
+    let at = HELLO_SESSION_ID_AT;
+    assert_eq!(hello[at], 0);
+    hello[at] = 32;
+    let rest = hello.split_off(at + 1);
+    hello.extend_from_slice(&[ 0xaa; 32 ]);
+    hello.extend_from_slice(&rest);
+    grow_hello(&mut hello, 32, None);
+
+    server.read_tls(&mut hello.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(), Err(TLSError::DecryptError));
+}
 
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let mut stream = Stream::new(&mut client, &mut pipe);
-        assert_eq!(stream.write(b"hello").unwrap(), 5);
+/// Append `count` distinct, empty, unknown extensions to the
+/// ClientHello record `hello`.
+fn add_empty_extensions(hello: &mut Vec<u8>, count: usize) {
+    let exts_at = client_hello_extensions_at(hello);
+
+    for i in 0..count {
+        let typ = 0xe000 + i;
+        hello.extend_from_slice(&[ (typ >> 8) as u8, typ as u8, 0x00, 0x00 ]);
     }
-    check_read(&mut server, b"hello");
+
+    grow_hello(hello, count * 4, Some(exts_at));
 }
 
 #[test]
-fn client_stream_read() {
+fn server_rejects_too_many_extensions() {
     let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+
+    // A few more is fine.
+    let mut few_more = hello.clone();
+    add_empty_extensions(&mut few_more, 10);
     let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    server.read_tls(&mut few_more.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
 
-    server.write(b"world").unwrap();
+    add_empty_extensions(&mut hello, 1000);
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    server.read_tls(&mut hello.as_slice()).unwrap();
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::CorruptMessagePayload(ContentType::Handshake)));
 
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let mut stream = Stream::new(&mut client, &mut pipe);
-        check_read(&mut stream, b"world");
-    }
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::DecodeError)));
 }
 
 #[test]
-fn server_stream_write() {
+fn server_rejects_sslv2_client_hello() {
+    // An SSLv2-format ClientHello offering TLS1.0, as sent by some
+    // old clients for compatibility.
+    let mut hello = vec![ 0x80, 0x2e, 0x01, 0x03, 0x01, 0x00, 0x15, 0x00, 0x00, 0x00, 0x10 ];
+    hello.extend_from_slice(&[ 0x00, 0x00, 0x2f, 0x00, 0x00, 0x35, 0x00, 0x00, 0x0a,
+                               0x01, 0x00, 0x80, 0x07, 0x00, 0xc0, 0x03, 0x00, 0x80,
+                               0x00, 0x00, 0xff ]);
+    hello.extend_from_slice(&[ 0x11; 16 ]);
+
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    server.read_tls(&mut hello.as_slice()).unwrap();
+    let err = Err(TLSError::PeerIncompatibleError("client sent SSLv2-format ClientHello"
+                                                  .to_string()));
+    assert_eq!(server.process_new_packets(), err);
+    assert_eq!(server.process_new_packets(), err);
+
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::ProtocolVersion)));
+}
+
+#[test]
+fn server_rejects_oversized_handshake_message() {
     let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    let hello_len = hello.len() - 5;
+
+    // Exactly big enough for the ClientHello.
+    let mut server_config = make_server_config();
+    server_config.max_handshake_size = hello_len;
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    server.read_tls(&mut hello.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+
+    // One byte too small.
+    let mut server_config = make_server_config();
+    server_config.max_handshake_size = hello_len - 1;
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    server.read_tls(&mut hello.as_slice()).unwrap();
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::CorruptMessagePayload(ContentType::Handshake)));
+
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::DecodeError)));
+}
+
+fn check_certificate_size_limit(make_config: fn() -> ClientConfig) {
+    // Our test chain is a few KB.
+    let mut client_config = make_config();
+    client_config.max_certificate_size = 0x4000;
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
     let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
 
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let mut stream = Stream::new(&mut server, &mut pipe);
-        assert_eq!(stream.write(b"hello").unwrap(), 5);
+    let mut client_config = make_config();
+    client_config.max_certificate_size = 1024;
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err, Err(TLSError::CorruptMessagePayload(ContentType::Handshake)));
+}
+
+#[test]
+fn client_rejects_oversized_certificate() {
+    check_certificate_size_limit(make_client_config);
+}
+
+#[test]
+fn client_rejects_oversized_certificate_tls12() {
+    check_certificate_size_limit(make_tls12_client_config);
+}
+
+#[test]
+fn server_rejects_oversized_client_certificate() {
+    let mut client_config = make_client_config();
+    client_config.set_single_client_cert(get_chain(), get_key());
+
+    let mut server_config = make_server_config();
+    server_config.set_client_auth_roots(get_chain(), true);
+    server_config.max_certificate_size = 1024;
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err, Err(TLSError::CorruptMessagePayload(ContentType::Handshake)));
+}
+
+fn check_key_exchange_params(client_config: ClientConfig,
+                             scheme: SignatureScheme,
+                             group: NamedGroup) {
+    let mut client_config = client_config;
+    client_config.set_client_hello_callback(move |hello| {
+        for ext in hello.extensions.iter_mut() {
+            if let ClientExtension::SignatureAlgorithms(ref mut schemes) = *ext {
+                *schemes = vec![ scheme ];
+            }
+        }
+    });
+
+    let mut server_config = make_server_config();
+    server_config.kx_groups = vec![ group ];
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    assert_eq!(client.get_key_exchange_group(), None);
+    assert_eq!(server.get_server_signature_scheme(), None);
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_key_exchange_group(), Some(group));
+    assert_eq!(server.get_key_exchange_group(), Some(group));
+    assert_eq!(client.get_server_signature_scheme(), Some(scheme));
+    assert_eq!(server.get_server_signature_scheme(), Some(scheme));
+}
+
+#[test]
+fn tls12_ecdhe_rsa_key_exchange_params() {
+    let mut client_config = make_tls12_client_config();
+    client_config.ciphersuites = rustls::ALL_CIPHERSUITES.iter()
+        .filter(|suite| suite.suite == CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256)
+        .cloned()
+        .collect();
+    check_key_exchange_params(client_config,
+                              SignatureScheme::RSA_PKCS1_SHA384,
+                              NamedGroup::secp384r1);
+}
+
+#[test]
+fn tls13_key_exchange_params() {
+    check_key_exchange_params(make_client_config(),
+                              SignatureScheme::RSA_PSS_SHA384,
+                              NamedGroup::secp256r1);
+}
+
+/// test-ca/rsa/end-dc.cert, which allows delegated credentials,
+/// and the rest of its chain.
+fn get_delegation_chain() -> Vec<Certificate> {
+    read_certs("test-ca/rsa/end-dc.fullchain")
+}
+
+/// A delegated credential for test-ca/rsa/dc.rsa, issued by the
+/// first certificate in `chain`, expiring at `expiry`.
+fn make_delegated_credential(chain: &[Certificate], expiry: u64) -> DelegatedCredential {
+    let cert_signer = sign::RSASigner::new(&get_key()).unwrap();
+
+    let mut dc_key_file = io::BufReader::new(fs::File::open("test-ca/rsa/dc.rsa").unwrap());
+    let dc_key = pemfile::rsa_private_keys(&mut dc_key_file).unwrap()[0].clone();
+    let dc_signer = sign::RSASigner::new(&dc_key).unwrap();
+
+    let mut spki = Vec::new();
+    fs::File::open("test-ca/rsa/dc.spki").unwrap().read_to_end(&mut spki).unwrap();
+
+    DelegatedCredential::new(&chain[0], &cert_signer, spki, Box::new(dc_signer),
+                             at_unix_time(expiry), &SystemRandom::new())
+        .unwrap()
+}
+
+/// Do a handshake at `now`, where the server has `chain` and a
+/// delegated credential from it expiring at `expiry`.
+fn delegated_credential_check(chain: Vec<Certificate>,
+                              expiry: u64,
+                              now: u64,
+                              client_accepts_dc: bool) -> Result<(), TLSError> {
+    let mut server_config = ServerConfig::new();
+    server_config.delegated_credential = Some(make_delegated_credential(&chain, expiry));
+    server_config.set_single_cert(chain, get_key());
+
+    let mut client_config = make_client_config();
+    client_config.enable_delegated_credentials = client_accepts_dc;
+    client_config.set_time_provider(Box::new(FixedTime(now)));
+
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake_until_error(&mut client, &mut server)
+}
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[test]
+fn client_accepts_valid_delegated_credential() {
+    assert_eq!(delegated_credential_check(get_delegation_chain(),
+                                          WHILE_VALID + DAY,
+                                          WHILE_VALID,
+                                          true),
+               Ok(()));
+}
+
+#[test]
+fn client_rejects_expired_delegated_credential() {
+    let err = delegated_credential_check(get_delegation_chain(),
+                                         WHILE_VALID - 1,
+                                         WHILE_VALID,
+                                         true);
+    assert_eq!(err,
+               Err(TLSError::PeerMisbehavedError("delegated credential expired".to_string())));
+}
+
+#[test]
+fn client_rejects_long_lived_delegated_credential() {
+    let err = delegated_credential_check(get_delegation_chain(),
+                                         WHILE_VALID + 8 * DAY,
+                                         WHILE_VALID,
+                                         true);
+    assert_eq!(err,
+               Err(TLSError::PeerMisbehavedError("delegated credential valid for too long"
+                                                 .to_string())));
+}
+
+#[test]
+fn client_rejects_delegated_credential_from_normal_certificate() {
+    // test-ca/rsa/end.cert lacks the DelegationUsage extension.
+    let err = delegated_credential_check(get_chain(),
+                                         WHILE_VALID + DAY,
+                                         WHILE_VALID,
+                                         true);
+    assert_eq!(err,
+               Err(TLSError::PeerMisbehavedError("certificate does not allow delegated \
+                                                  credentials"
+                                                 .to_string())));
+}
+
+#[test]
+fn delegated_credential_not_sent_unless_accepted() {
+    // The server signs with its certificate's key instead, so this works
+    // even though the credential has expired.
+    assert_eq!(delegated_credential_check(get_delegation_chain(),
+                                          WHILE_VALID - 1,
+                                          WHILE_VALID,
+                                          false),
+               Ok(()));
+}
+
+/// Read everything `sess` has received using `peek_plaintext` and
+/// `consume_plaintext`, `step` bytes at a time.
+fn peek_all_plaintext(sess: &mut Session, step: usize) -> Vec<u8> {
+    let mut got = Vec::new();
+
+    loop {
+        let used = {
+            let available = sess.peek_plaintext();
+            if available.is_empty() {
+                break;
+            }
+
+            let used = std::cmp::min(step, available.len());
+            got.extend_from_slice(&available[..used]);
+            used
+        };
+        sess.consume_plaintext(used);
     }
-    check_read(&mut client, b"hello");
+
+    got
 }
 
 #[test]
-fn server_stream_read() {
+fn peek_and_consume_plaintext() {
     let mut client = ClientSession::new(&Arc::new(make_client_config()), "localhost");
     let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
 
-    client.write(b"world").unwrap();
+    for step in &[ 1, 3, 1000 ] {
+        client.write_all(b"hello ").unwrap();
+        client.write_all(b"world").unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
 
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let mut stream = Stream::new(&mut server, &mut pipe);
-        check_read(&mut stream, b"world");
+        assert_eq!(server.peek_plaintext(), b"hello ");
+        assert_eq!(peek_all_plaintext(&mut server, *step), b"hello world".to_vec());
+        assert_eq!(server.peek_plaintext(), b"");
     }
+
+    // Consuming some then reading the rest gets it all, once.
+    client.write_all(b"hello world").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    server.consume_plaintext(6);
+    check_read(&mut server, b"world");
 }
@@ -0,0 +1,102 @@
+// Check we log interesting protocol events.
+#![cfg(feature = "logging")]
+
+use std::sync::{Arc, Mutex};
+use std::fs;
+use std::io;
+
+extern crate log;
+use log::{Log, LogRecord, LogMetadata, LogLevelFilter};
+
+extern crate rustls;
+use rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession};
+use rustls::Session;
+use rustls::internal::pemfile;
+
+struct CaptureLogger {
+    lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl Log for CaptureLogger {
+    fn enabled(&self, _metadata: &LogMetadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &LogRecord) {
+        let line = format!("{} {}", record.target(), record.args());
+        self.lines.lock().unwrap().push(line);
+    }
+}
+
+fn transfer(left: &mut Session, right: &mut Session) {
+    let mut buf = Vec::new();
+    while left.wants_write() {
+        left.write_tls(&mut buf).unwrap();
+    }
+    right.read_tls(&mut buf.as_slice()).unwrap();
+}
+
+fn make_configs() -> (ClientConfig, ServerConfig) {
+    let chain = pemfile::certs(&mut io::BufReader::new(fs::File::open("test-ca/rsa/end.fullchain")
+                .unwrap()))
+        .unwrap();
+    let key = pemfile::rsa_private_keys(&mut io::BufReader::new(fs::File::open("test-ca/rsa/end.rsa")
+                .unwrap()))
+        .unwrap()[0]
+        .clone();
+
+    let mut server_config = ServerConfig::new();
+    server_config.set_single_cert(chain, key);
+
+    let mut client_config = ClientConfig::new();
+    let mut rootbuf = io::BufReader::new(fs::File::open("test-ca/rsa/ca.cert").unwrap());
+    client_config.root_store.add_pem_file(&mut rootbuf).unwrap();
+
+    (client_config, server_config)
+}
+
+#[test]
+fn handshake_is_logged() {
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let logger_lines = lines.clone();
+    log::set_logger(|max_level| {
+            max_level.set(LogLevelFilter::Trace);
+            Box::new(CaptureLogger { lines: logger_lines })
+        })
+        .unwrap();
+
+    let (client_config, server_config) = make_configs();
+    let mut client = ClientSession::new(&Arc::new(client_config), "localhost");
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    while server.is_handshaking() || client.is_handshaking() {
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+    }
+
+    server.send_close_notify();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    let lines = lines.lock().unwrap();
+    let expect = [
+        "Sending Handshake message Some(ClientHello)",
+        "Received Handshake message Some(ClientHello)",
+        "negotiated version Some(TLSv1_3)",
+        "decided upon key exchange group X25519",
+        "Received Handshake message Some(ServerHello)",
+        "Key exchange group is X25519",
+        "Received Handshake message Some(Finished)",
+        "Sending warning alert CloseNotify",
+    ];
+
+    for want in expect.iter() {
+        assert!(lines.iter().any(|line| line.contains(want)),
+                "missing log line '{}' in {:#?}", want, *lines);
+    }
+
+    // Keys mustn't be logged.
+    assert!(!lines.iter().any(|line| line.contains("secret")));
+}
@@ -0,0 +1,254 @@
+// Replay handshakes recorded from other implementations, to catch
+// interop regressions.
+//
+// The fixtures in tests/data are what the peer sent, exactly as it
+// appeared on the wire.  Our side of these handshakes uses fresh
+// randomness, so the peer's later messages can't be replayed: the
+// fixtures are the peer's first flight.
+//
+// To record a new one, accept a TCP connection from the peer and
+// save the bytes it sends before waiting for a reply.
+//
+// Between two rustls peers we can do better: with fixed randomness and
+// a deterministic signature scheme, both sides of a whole handshake are
+// the same every time.  So tests/data/rustls-tls13-*.bin hold both
+// sides of one, and we replay each side's half to a fresh peer, which
+// must answer with exactly what was recorded.  If a change to what we
+// send is intended, re-record them from what `record_handshake` returns.
+use std::sync::Arc;
+use std::fs;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+extern crate rustls;
+use rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession};
+use rustls::Session;
+use rustls::{ProtocolVersion, NamedGroup, CipherSuite, SignatureScheme};
+use rustls::TLSError;
+use rustls::SecureRandom;
+use rustls::TimeProvider;
+use rustls::sign;
+use rustls::internal::pemfile;
+use rustls::internal::msgs::enums::HandshakeType;
+
+/// ClientHello from `openssl s_client` 3.0, offering TLS1.3 (RFC8446)
+/// and TLS1.2, with SNI of "localhost".
+const OPENSSL_HELLO_TLS13: &'static [u8] = include_bytes!("data/openssl-client-hello-tls13.bin");
+
+/// TLS1.2-only ClientHello from `openssl s_client -tls1_2` 3.0,
+/// with SNI of "localhost".
+const OPENSSL_HELLO_TLS12: &'static [u8] = include_bytes!("data/openssl-client-hello-tls12.bin");
+
+/// All a rustls client sent in a TLS1.3 handshake with a rustls server,
+/// both configured by `make_deterministic_configs`.
+const RUSTLS_CLIENT_TLS13: &'static [u8] = include_bytes!("data/rustls-tls13-client.bin");
+
+/// All the server sent in that handshake.
+const RUSTLS_SERVER_TLS13: &'static [u8] = include_bytes!("data/rustls-tls13-server.bin");
+
+fn make_server_config() -> ServerConfig {
+    let chain = pemfile::certs(&mut io::BufReader::new(fs::File::open("test-ca/rsa/end.fullchain")
+                .unwrap()))
+        .unwrap();
+    let key = pemfile::rsa_private_keys(&mut io::BufReader::new(fs::File::open("test-ca/rsa/end.rsa")
+                .unwrap()))
+        .unwrap()[0]
+        .clone();
+
+    let mut cfg = ServerConfig::new();
+    cfg.set_single_cert(chain, key);
+    cfg
+}
+
+/// Feed `recorded` to `sess` a few bytes at a time, processing
+/// as we go, as if it arrived over a slow network.
+fn replay(sess: &mut Session, recorded: &[u8]) -> Result<(), TLSError> {
+    for chunk in recorded.chunks(7) {
+        let mut rd = chunk;
+        while !rd.is_empty() {
+            sess.read_tls(&mut rd).unwrap();
+        }
+        sess.process_new_packets()?;
+    }
+
+    Ok(())
+}
+
+fn handshake_types(sess: &ServerSession) -> Vec<HandshakeType> {
+    sess.handshake_transcript()
+        .iter()
+        .map(|msg| msg.typ)
+        .collect()
+}
+
+fn check_server_answers_tls12(recorded: &[u8]) {
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    replay(&mut server, recorded).unwrap();
+
+    assert!(server.is_handshaking());
+    assert!(server.wants_write());
+    assert_eq!(server.get_protocol_version(), Some(ProtocolVersion::TLSv1_2));
+    assert_eq!(server.get_key_exchange_group(), Some(NamedGroup::X25519));
+    assert_eq!(handshake_types(&server),
+               vec![ HandshakeType::ClientHello, HandshakeType::ServerHello,
+                     HandshakeType::Certificate, HandshakeType::ServerKeyExchange,
+                     HandshakeType::ServerHelloDone ]);
+
+    let transcript = server.handshake_transcript();
+    assert_eq!(transcript[0].length, recorded.len() - 5);
+    assert_eq!(transcript[1].cipher_suite,
+               Some(CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384));
+}
+
+#[test]
+fn server_answers_openssl_tls13_hello() {
+    // We only do TLS1.3 draft 18, so this falls back to TLS1.2.
+    check_server_answers_tls12(OPENSSL_HELLO_TLS13);
+}
+
+#[test]
+fn server_answers_openssl_tls12_hello() {
+    check_server_answers_tls12(OPENSSL_HELLO_TLS12);
+}
+
+#[test]
+fn server_waits_for_rest_of_hello() {
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    let half = OPENSSL_HELLO_TLS12.len() / 2;
+    replay(&mut server, &OPENSSL_HELLO_TLS12[..half]).unwrap();
+
+    assert!(!server.wants_write());
+    assert_eq!(server.get_protocol_version(), None);
+
+    replay(&mut server, &OPENSSL_HELLO_TLS12[half..]).unwrap();
+    assert!(server.wants_write());
+}
+
+/// A 'random' source which always produces the same bytes.
+struct FixedRandom(u8);
+
+impl SecureRandom for FixedRandom {
+    fn fill(&self, bytes: &mut [u8]) -> Result<(), ()> {
+        for b in bytes.iter_mut() {
+            *b = self.0;
+        }
+        Ok(())
+    }
+}
+
+/// A clock stopped at 2018-01-01, when all of test-ca/rsa is valid.
+struct FixedTime;
+
+impl TimeProvider for FixedTime {
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1514764800)
+    }
+}
+
+/// Configs for which handshakes are deterministic: the randomness is
+/// fixed, and the server signs with Ed25519, which uses none.
+fn make_deterministic_configs() -> (Arc<ClientConfig>, Arc<ServerConfig>) {
+    let mut client_config = ClientConfig::new();
+    let mut rootbuf = io::BufReader::new(fs::File::open("test-ca/rsa/ca.cert").unwrap());
+    client_config.root_store.add_pem_file(&mut rootbuf).unwrap();
+    client_config.rng = Box::new(FixedRandom(0x5a));
+    client_config.set_time_provider(Box::new(FixedTime));
+
+    let chain = pemfile::certs(&mut io::BufReader::new(
+            fs::File::open("test-ca/rsa/end-ed25519.fullchain").unwrap()))
+        .unwrap();
+    let key = pemfile::pkcs8_private_keys(&mut io::BufReader::new(
+            fs::File::open("test-ca/rsa/end-ed25519.key").unwrap()))
+        .unwrap()[0]
+        .clone();
+    let signer = sign::Ed25519Signer::new(&key, &chain[0]).unwrap();
+
+    let mut server_config = ServerConfig::new();
+    server_config.set_single_cert_with_signer(chain, Box::new(signer));
+    server_config.rng = Box::new(FixedRandom(0xa5));
+
+    (Arc::new(client_config), Arc::new(server_config))
+}
+
+/// Everything `sess` wants to send.
+fn take_output(sess: &mut Session) -> Vec<u8> {
+    let mut buf = Vec::new();
+    while sess.wants_write() {
+        sess.write_tls(&mut buf).unwrap();
+    }
+    buf
+}
+
+/// Do a whole handshake, returning all the client sent, then all
+/// the server sent.
+fn record_handshake() -> (Vec<u8>, Vec<u8>) {
+    let (client_config, server_config) = make_deterministic_configs();
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut server = ServerSession::new(&server_config);
+    let mut client_sent = Vec::new();
+    let mut server_sent = Vec::new();
+
+    while client.is_handshaking() || server.is_handshaking() ||
+          client.wants_write() || server.wants_write() {
+        let flight = take_output(&mut client);
+        replay(&mut server, &flight).unwrap();
+        client_sent.extend(flight);
+
+        let flight = take_output(&mut server);
+        replay(&mut client, &flight).unwrap();
+        server_sent.extend(flight);
+    }
+
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+    assert_eq!(client.get_server_signature_scheme(), Some(SignatureScheme::ED25519));
+    (client_sent, server_sent)
+}
+
+#[test]
+fn rustls_handshake_is_deterministic() {
+    assert_eq!(record_handshake(), record_handshake());
+}
+
+#[test]
+fn rustls_handshake_matches_recording() {
+    let (client_sent, server_sent) = record_handshake();
+    assert_eq!(client_sent, RUSTLS_CLIENT_TLS13);
+    assert_eq!(server_sent, RUSTLS_SERVER_TLS13);
+}
+
+#[test]
+fn server_replays_recorded_rustls_handshake() {
+    let (_, server_config) = make_deterministic_configs();
+
+    let mut server = ServerSession::new(&server_config);
+    replay(&mut server, RUSTLS_CLIENT_TLS13).unwrap();
+
+    assert!(!server.is_handshaking());
+    assert_eq!(take_output(&mut server), RUSTLS_SERVER_TLS13);
+}
+
+#[test]
+fn client_replays_recorded_rustls_handshake() {
+    let (client_config, _) = make_deterministic_configs();
+
+    let mut client = ClientSession::new(&client_config, "localhost");
+    let mut sent = take_output(&mut client);
+    replay(&mut client, RUSTLS_SERVER_TLS13).unwrap();
+    sent.extend(take_output(&mut client));
+
+    assert!(!client.is_handshaking());
+    assert_eq!(sent, RUSTLS_CLIENT_TLS13);
+}
+
+#[test]
+fn server_rejects_hello_with_old_version() {
+    // Downgrade the hello's client_version to TLS1.0.
+    let mut recorded = OPENSSL_HELLO_TLS12.to_vec();
+    assert_eq!(&recorded[9..11], &[ 0x03, 0x03 ]);
+    recorded[10] = 0x01;
+
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    assert_eq!(replay(&mut server, &recorded),
+               Err(TLSError::PeerIncompatibleError("client does not support TLSv1_2"
+                                                   .to_string())));
+}